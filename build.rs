@@ -4,9 +4,6 @@ use anyhow::*;
 use glob::glob;
 use std::fs::{read_to_string, write};
 use std::path::PathBuf;
-use fs_extra::copy_items;
-use fs_extra::dir::CopyOptions;
-use std::env;
 
 struct ShaderData {
     src: String,
@@ -60,6 +57,16 @@ fn main() -> Result<()> {
 
     let mut compiler = shaderc::Compiler::new().context("Unable to create shader compiler")?;
 
+    // Shared code like fog.glsl is pulled in with #include, relative to the including shader.
+    let mut options = shaderc::CompileOptions::new().context("Unable to create shader compile options")?;
+    options.set_include_callback(|name, _include_type, source, _depth| {
+        let path = PathBuf::from(source).with_file_name(name);
+        println!("cargo:rerun-if-changed={}", path.display());
+        read_to_string(&path)
+            .map(|content| shaderc::ResolvedInclude { resolved_name: path.display().to_string(), content })
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    });
+
     // This can't be parallelized. The [shaderc::Compiler] is not
     // thread safe. Also, it creates a lot of resources. You could
     // spawn multiple processes to handle this, but it would probably
@@ -74,18 +81,10 @@ fn main() -> Result<()> {
             shader.kind,
             &shader.src_path.to_str().unwrap(),
             "main",
-            None,
+            Some(&options),
         )?;
         write(shader.spv_path, compiled.as_binary_u8())?;
     }
 
-    println!("cargo:rerun-if-changed=res/*");
-    let out_dir = env::var("OUT_DIR")?;
-    let mut copy_options = CopyOptions::new();
-    copy_options.overwrite = true;
-    let mut paths_to_copy = Vec::new();
-    paths_to_copy.push("res/");
-    copy_items(&paths_to_copy, out_dir, &copy_options)?;
-
     Ok(())
 }
\ No newline at end of file
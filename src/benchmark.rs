@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+//Per frame timings for comparing renderer changes, written out as json
+//wgpu 0.6 has no timestamp queries so the gpu time is from submit until the device is idle
+
+//The first frames include pipeline and texture warm up
+pub const WARMUP_FRAMES: u32 = 10;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FrameTimes {
+    pub update: f32,
+    pub cull: f32,
+    pub encode: f32,
+    pub gpu: f32,
+    pub frame: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Stats {
+    pub min: f32,
+    pub avg: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl Stats {
+
+    pub fn new(values: &[f32]) -> Stats {
+
+        if values.is_empty() {
+            return Stats { min: 0.0, avg: 0.0, p99: 0.0, max: 0.0 };
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let p99 = ((sorted.len() as f32 * 0.99).ceil() as usize).max(1) - 1;
+        Stats {
+            min: sorted[0],
+            avg: sorted.iter().sum::<f32>() / sorted.len() as f32,
+            p99: sorted[p99],
+            max: sorted[sorted.len() - 1],
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{ \"min\": {:.4}, \"avg\": {:.4}, \"p99\": {:.4}, \"max\": {:.4} }}", self.min, self.avg, self.p99, self.max)
+    }
+}
+
+pub struct Benchmark {
+    pub frames: Vec<FrameTimes>,
+    //Filled in by the renderer during a frame
+    pub current: FrameTimes,
+    frame_count: u32,
+    last_frame: Option<Instant>,
+    start: Option<Instant>,
+}
+
+impl Benchmark {
+
+    pub fn new() -> Self {
+        Benchmark { frames: Vec::new(), current: FrameTimes::default(), frame_count: 0, last_frame: None, start: None }
+    }
+
+    pub fn end_frame(&mut self) {
+
+        let now = Instant::now();
+        if let Some(last) = self.last_frame {
+            self.current.frame = millis(now - last);
+        }
+        self.last_frame = Some(now);
+
+        self.frame_count += 1;
+        if self.frame_count > WARMUP_FRAMES {
+            if self.start.is_none() {
+                self.start = Some(now);
+            }
+            self.frames.push(self.current);
+        }
+        self.current = FrameTimes::default();
+    }
+
+    pub fn to_json(&self, map: &str) -> String {
+
+        let stats = |f: fn(&FrameTimes) -> f32| Stats::new(&self.frames.iter().map(f).collect::<Vec<f32>>()).to_json();
+        let seconds = match (self.start, self.last_frame) {
+            (Some(start), Some(last)) => (last - start).as_secs_f32(),
+            _ => 0.0,
+        };
+
+        let mut json = "{\n".to_string();
+        json.push_str(&format!("  \"map\": \"{}\",\n", map.replace('\\', "\\\\").replace('"', "\\\"")));
+        json.push_str(&format!("  \"frames\": {},\n", self.frames.len()));
+        json.push_str(&format!("  \"seconds\": {:.4},\n", seconds));
+        json.push_str("  \"gpu_timing\": \"submit_to_idle\",\n");
+        json.push_str(&format!("  \"update_ms\": {},\n", stats(|f| f.update)));
+        json.push_str(&format!("  \"cull_ms\": {},\n", stats(|f| f.cull)));
+        json.push_str(&format!("  \"encode_ms\": {},\n", stats(|f| f.encode)));
+        json.push_str(&format!("  \"gpu_ms\": {},\n", stats(|f| f.gpu)));
+        json.push_str(&format!("  \"frame_ms\": {}\n", stats(|f| f.frame)));
+        json.push_str("}\n");
+        json
+    }
+
+    pub fn save(&self, path: &std::path::Path, map: &str) {
+        match std::fs::write(path, self.to_json(map)) {
+            Ok(_) => println!("Saved benchmark {} with {} frames", path.display(), self.frames.len()),
+            Err(e) => println!("Could not save benchmark {}: {}", path.display(), e),
+        }
+    }
+}
+
+pub fn millis(duration: Duration) -> f32 {
+    duration.as_secs_f32() * 1000.0
+}
\ No newline at end of file
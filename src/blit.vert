@@ -0,0 +1,9 @@
+#version 450
+
+layout(location = 0) out vec2 v_tex_coords;
+
+//One triangle over the whole screen, texture coordinates start at the top left
+void main() {
+    v_tex_coords = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(v_tex_coords.x * 2.0 - 1.0, 1.0 - v_tex_coords.y * 2.0, 0.0, 1.0);
+}
\ No newline at end of file
@@ -3,24 +3,23 @@ use std::io::prelude::*;
 use std::fs::File;
 use wgpu::util::DeviceExt;
 use std::io::{stdin,stdout,Write};
+use std::collections::HashMap;
+use cgmath::SquareMatrix;
+use cgmath::InnerSpace;
 
 use crate::texture;
 use crate::bsp_look_up;
-
-const PLANE_SIZE: u32 = 16;
-const NODE_SIZE: u32 = 36;
-const LEAF_SIZE: u32 = 48;
-const LEAF_FACE_SIZE: u32 = 4;
-const LEAF_BRUSH_SIZE: u32 = 4;
-const BRUSH_SIZE: u32 = 12;
-const BRUSH_SIDE_SIZE: u32 = 8;
-const VERTEX_SIZE: u32 = 44;
-const MESH_VERT_SIZE: u32 = 4;
-const FACE_SIZE: u32 = 104;
-const LIGHT_MAP_SIZE: u32 = 49152;
-const LIGHT_VOL_SIZE: u32 = 8;
-const TEXTURE_SIZE: u32 = 72;
-const EFFECT_SIZE: u32 = 72;
+use crate::bsp_shader;
+use crate::bsp_fog;
+use crate::bsp_anim;
+use crate::bsp_entity;
+use crate::frustum;
+use crate::bsp_draw;
+use crate::bsp_area;
+use crate::vfs;
+use crate::bsp_data;
+use crate::bsp_light_map;
+use crate::md3;
 
 const EPSILON: f32 = 0.03125;
 
@@ -61,20 +60,20 @@ pub struct LightMap {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Face {
-    texture: i32,
-    effect: i32,
-    type_draw: i32,
-    vertex: i32,
-    num_vertexes: i32,
-    mesh_vert: i32,
-    num_mesh_verts: i32,
-    lightmap_index: i32,
-    lightmap_start: [i32; 2],
-    lightmap_size: [i32; 2],
-    lightmap_origin: [f32; 3],
-    lightmap_vecs: [[f32; 3]; 2],
-    normal: [f32; 3],
-    size: [i32; 2],
+    pub texture: i32,
+    pub effect: i32,
+    pub type_draw: i32,
+    pub vertex: i32,
+    pub num_vertexes: i32,
+    pub mesh_vert: i32,
+    pub num_mesh_verts: i32,
+    pub lightmap_index: i32,
+    pub lightmap_start: [i32; 2],
+    pub lightmap_size: [i32; 2],
+    pub lightmap_origin: [f32; 3],
+    pub lightmap_vecs: [[f32; 3]; 2],
+    pub normal: [f32; 3],
+    pub size: [i32; 2],
 }
 
 #[repr(C)]
@@ -82,7 +81,7 @@ pub struct Face {
 pub struct Effect {
     name: [u8; 64],
     brush: i32,
-    unknown: i32,
+    visible_side: i32,
 }
 
 #[repr(C)]
@@ -94,11 +93,11 @@ pub struct MeshVert {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
-    position: [f32; 3],
-    texcoord_s: [f32; 2],
-    texcoord_l: [f32; 2],
-    normal: [f32; 3],
-    colour: [u8; 4],
+    pub position: [f32; 3],
+    pub texcoord_s: [f32; 2],
+    pub texcoord_l: [f32; 2],
+    pub normal: [f32; 3],
+    pub colour: [u8; 4],
 }
 
 impl Vertex {
@@ -140,27 +139,27 @@ impl Vertex {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct BrushSide {
-    plane: i32,
-    texture: i32,
+    pub plane: i32,
+    pub texture: i32,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Brush {
-    brush_side: i32,
-    num_brush_sides: i32,
-    texture: i32,
+    pub brush_side: i32,
+    pub num_brush_sides: i32,
+    pub texture: i32,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Model {
-    mins: [i32; 3],
-    maxs: [i32; 3],
-    face: i32,
-    num_faces: i32,
-    brush: i32,
-    num_brushes: i32,
+    pub mins: [f32; 3],
+    pub maxs: [f32; 3],
+    pub face: i32,
+    pub num_faces: i32,
+    pub brush: i32,
+    pub num_brushes: i32,
 }
 
 #[repr(C)]
@@ -200,21 +199,236 @@ pub struct Node {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Plane {
-    normal: [f32; 3],
-    distance: f32,
+    pub normal: [f32; 3],
+    pub distance: f32,
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Texture {
-    name: [u8; 64],
-    flags: i32,
-    contents: i32,
+    pub name: [u8; 64],
+    pub flags: i32,
+    pub contents: i32,
+}
+
+//Raven bsp records, Jedi Knight 2, Jedi Academy and Soldier of Fortune 2 light a surface with up to four lightmap styles
+//The renderer uses one style at a time through the Quake 3 records
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RavenVertex {
+    position: [f32; 3],
+    texcoord_s: [f32; 2],
+    texcoord_l: [[f32; 2]; 4],
+    normal: [f32; 3],
+    colour: [[u8; 4]; 4],
+}
+
+impl RavenVertex {
+
+    pub fn to_vertex(&self, style: usize) -> Vertex {
+        Vertex {
+            position: self.position,
+            texcoord_s: self.texcoord_s,
+            texcoord_l: self.texcoord_l[style],
+            normal: self.normal,
+            colour: self.colour[style],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RavenFace {
+    texture: i32,
+    effect: i32,
+    type_draw: i32,
+    vertex: i32,
+    num_vertexes: i32,
+    mesh_vert: i32,
+    num_mesh_verts: i32,
+    lightmap_styles: [u8; 4],
+    vertex_styles: [u8; 4],
+    lightmap_index: [i32; 4],
+    lightmap_x: [i32; 4],
+    lightmap_y: [i32; 4],
+    lightmap_size: [i32; 2],
+    lightmap_origin: [f32; 3],
+    lightmap_vecs: [[f32; 3]; 2],
+    normal: [f32; 3],
+    size: [i32; 2],
+}
+
+impl RavenFace {
+
+    pub fn to_face(&self, style: usize) -> Face {
+        Face {
+            texture: self.texture,
+            effect: self.effect,
+            type_draw: self.type_draw,
+            vertex: self.vertex,
+            num_vertexes: self.num_vertexes,
+            mesh_vert: self.mesh_vert,
+            num_mesh_verts: self.num_mesh_verts,
+            lightmap_index: self.lightmap_index[style],
+            lightmap_start: [self.lightmap_x[style], self.lightmap_y[style]],
+            lightmap_size: self.lightmap_size,
+            lightmap_origin: self.lightmap_origin,
+            lightmap_vecs: self.lightmap_vecs,
+            normal: self.normal,
+            size: self.size,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RavenBrushSide {
+    plane: i32,
+    texture: i32,
+    face: i32,
+}
+
+impl RavenBrushSide {
+
+    pub fn to_brush_side(&self) -> BrushSide {
+        BrushSide { plane: self.plane, texture: self.texture }
+    }
+}
+
+//The light grid lump only holds distinct cells, the light array lump has an index into it for every cell
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RavenLightVol {
+    ambient: [[u8; 3]; 4],
+    directional: [[u8; 3]; 4],
+    styles: [u8; 4],
+    dir: [u8; 2],
+}
+
+impl RavenLightVol {
+
+    pub fn to_light_vol(&self, style: usize) -> LightVol {
+        LightVol { ambient: self.ambient[style], directional: self.directional[style], dir: self.dir }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniforms {
+    alpha_func: [f32; 4],
+    tc_s: [f32; 4],
+    tc_t: [f32; 4],
+    turb: [f32; 4],
+    colour: [f32; 4],
+    deforms: [[f32; 4]; bsp_anim::MAX_DEFORMS * 3],
+}
+
+impl MaterialUniforms {
+
+    pub fn new(state: &bsp_shader::SurfaceState, deforms: &Vec<bsp_anim::Deform>) -> Self {
+
+        let mut uniforms = Self {
+            alpha_func: [state.alpha_func.code(), if state.lightmapped { 1.0 } else { 0.0 }, 0.0, 0.0],
+            tc_s: [1.0, 0.0, 0.0, 0.0],
+            tc_t: [0.0, 1.0, 0.0, 0.0],
+            turb: [0.0; 4],
+            colour: [1.0; 4],
+            deforms: [[0.0; 4]; bsp_anim::MAX_DEFORMS * 3],
+        };
+        for i in 0..deforms.len().min(bsp_anim::MAX_DEFORMS) {
+            let deform = deforms[i].uniform();
+            for j in 0..3 {
+                uniforms.deforms[i * 3 + j] = deform[j];
+            }
+        }
+        uniforms
+    }
+
+    pub fn animate(&mut self, anim: &bsp_anim::AnimState) {
+        self.tc_s = [anim.tc_matrix[0][0], anim.tc_matrix[0][1], anim.tc_matrix[0][2], 0.0];
+        self.tc_t = [anim.tc_matrix[1][0], anim.tc_matrix[1][1], anim.tc_matrix[1][2], 0.0];
+        self.turb = [anim.turb[0], anim.turb[1], 0.0, 0.0];
+        self.colour = [anim.rgb, anim.rgb, anim.rgb, 1.0];
+    }
 }
 
 pub struct Material {
     pub diffuse_texture: texture::Texture,
     pub bind_group: wgpu::BindGroup,
+    pub uniform_buffer: Option<wgpu::Buffer>,
+}
+
+impl Material {
+
+    //Lightmaps have no uniforms, world materials have them at binding 2
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, diffuse_texture: texture::Texture, uniforms: Option<MaterialUniforms>) -> Material {
+
+        let uniform_buffer = uniforms.map(|u| device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Material Buffer"),
+                contents: bytemuck::cast_slice(&[u]),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        ));
+
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+            },
+        ];
+        if let Some(buffer) = uniform_buffer.as_ref() {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Buffer(buffer.slice(..)),
+            });
+        }
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: None,
+        });
+
+        Material { diffuse_texture, bind_group, uniform_buffer }
+    }
+}
+
+//Ambient and directed light in 0 to 255 with the direction the directed light comes from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GridLight {
+    pub ambient: [f32; 3],
+    pub directed: [f32; 3],
+    pub dir: [f32; 3],
+}
+
+impl GridLight {
+
+    //Lambert lighting of a normal as a vertex colour
+    pub fn colour(&self, normal: [f32; 3]) -> [u8; 4] {
+
+        let d = (normal[0] * self.dir[0] + normal[1] * self.dir[1] + normal[2] * self.dir[2]).max(0.0);
+        let mut colour = [255; 4];
+        for i in 0..3 {
+            colour[i] = (self.ambient[i] + self.directed[i] * d).min(255.0) as u8;
+        }
+        colour
+    }
+}
+
+//Blended faces are drawn one by one after everything else, sorted back to front
+pub struct TranslucentFace {
+    pub face: usize,
+    pub texture: usize,
+    pub lightmap: usize,
+    pub first_index: u32,
+    pub num_indices: u32,
+    pub centroid: cgmath::Vector3<f32>,
 }
 
 pub struct Trace {
@@ -253,6 +467,19 @@ pub struct Bsp {
     faces: Vec<Face>,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
+    //Opaque and alpha tested faces that are visible this frame, see update_draw_list
+    pub draw_index_buffer: wgpu::Buffer,
+    pub draw_batches: Vec<bsp_draw::DrawBatch>,
+    face_meshes: Vec<bsp_draw::FaceMesh>,
+    pub area_portals: Vec<bsp_area::AreaPortal>,
+    num_areas: usize,
+    //Areas touched by each submodel
+    model_areas: Vec<Vec<usize>>,
+    //Where each submodel is drawn and traced, movers change these every frame
+    model_transforms: Vec<cgmath::Matrix4<f32>>,
+    //Vertex ranges of the faces of each submodel and the submodel of each face
+    model_vertices: Vec<Vec<std::ops::Range<usize>>>,
+    face_models: Vec<usize>,
     light_maps: Vec<LightMap>,
     light_vols: Vec<LightVol>,
     t_trace: Trace,
@@ -260,398 +487,723 @@ pub struct Bsp {
     pub materials: Vec<Material>,
     textures: Vec<Texture>,
     pub materials_light: Vec<Material>,
+    pub shaders: HashMap<String, bsp_shader::Shader>,
+    pub fogs: Vec<bsp_fog::FogVolume>,
+    pub surface_states: Vec<bsp_shader::SurfaceState>,
+    pub translucent_faces: Vec<TranslucentFace>,
+    material_uniforms: Vec<MaterialUniforms>,
+    animations: Vec<Option<bsp_anim::MaterialAnimation>>,
+    material_frames: Vec<Vec<Material>>,
+    current_frames: Vec<usize>,
+    pub entities: Vec<bsp_entity::Entity>,
+    pub name: String,
+    models: Vec<Model>,
 }
 
 impl Bsp {
 
-    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, light_layout: &wgpu::BindGroupLayout) -> Bsp {
+    //A path to a .bsp on disk is read directly, anything else is a map name in the game data.
+    //A .ent file next to the map replaces its entity lump like the ioquake3 entity override, then the edits are applied
+    pub fn load_map(fs: &mut vfs::FileSystem, s: &str, edits: &Vec<bsp_entity::EntityEdit>) -> Result<(bsp_data::BspData, String), String> {
 
-        let mut planes: Vec<Plane> = Vec::new();
-        let mut nodes: Vec<Node> = Vec::new();
-        let mut leafs: Vec<Leaf> = Vec::new();
-        let mut leaf_faces: Vec<LeafFace> = Vec::new();
-        let mut leaf_brushes: Vec<LeafBrush> = Vec::new();
-        let mut brushes: Vec<Brush> = Vec::new();
-        let mut brush_sides: Vec<BrushSide> = Vec::new();
-        let mut vertexes: Vec<Vertex> = Vec::new();
-        let mut mesh_verts: Vec<MeshVert> = Vec::new();
-        let mut faces: Vec<Face> = Vec::new();
-        let mut light_maps: Vec<LightMap> = Vec::new();
-        let mut light_vols: Vec<LightVol> = Vec::new();
-        let mut textures: Vec<Texture> = Vec::new();
-        let mut effects: Vec<Effect> = Vec::new();
-
-        let res_dir = std::path::Path::new(env!("OUT_DIR")).join("res");
-        let mut s=String::new();
-        print!("Please enter some text: ");
-        let _=stdout().flush();
-        stdin().read_line(&mut s).expect("Did not enter a correct string");
-        if let Some('\n')=s.chars().next_back() {
-            s.pop();
+        let map_path = std::path::Path::new(s);
+        let (bytes, name, ent) = if s.to_lowercase().ends_with(".bsp") && map_path.is_file() {
+            fs.mount_map_dir(map_path);
+            let ent_path = map_path.with_extension("ent");
+            let bytes = std::fs::read(map_path).map_err(|e| format!("Could not read map {}: {}", s, e))?;
+            (bytes, map_path.file_stem().unwrap().to_string_lossy().to_string(), std::fs::read(&ent_path).ok().map(|e| (e, ent_path.display().to_string())))
         }
-        if let Some('\r')=s.chars().next_back() {
-            s.pop();
+        else {
+            let ent_path = format!("maps/{}.ent", s);
+            match fs.read(&format!("maps/{}.bsp", s)) {
+                Some(bytes) => (bytes, s.to_string(), fs.read(&ent_path).map(|e| (e, ent_path))),
+                None => return Err(format!("Could not find map {}", s)),
+            }
+        };
+
+        let mut data = match bsp_data::BspData::read(&bytes) {
+            Ok(data) => data,
+            Err(e) => return Err(format!("Could not load map {}: {}", s, e)),
+        };
+        println!("Loaded {} map {}, IBSP version {}", data.game(), name, data.version);
+        if let Some((ent, path)) = ent {
+            println!("Using entities from {}", path);
+            data.set_entity_text(&ent.iter().map(|b| *b as char).collect::<String>());
+        }
+        if !edits.is_empty() {
+            let entities = bsp_entity::apply_edits(&bsp_entity::parse_entities(&data.entities), edits);
+            data.set_entity_text(&bsp_entity::entities_to_string(&entities)?);
         }
+        Ok((data, name))
+    }
 
-        let mut baseq3_pak0 = "baseq3/pak0.pk3".to_string();
-        let mut f = std::fs::File::open(res_dir.join(baseq3_pak0)).unwrap();
-        let mut reader = std::io::BufReader::new(f);
-        let mut zip = zip::ZipArchive::new(reader).unwrap();
+    //Shaders, scripts from later paks and loose files override earlier ones
+    pub fn load_shaders(fs: &vfs::FileSystem) -> HashMap<String, bsp_shader::Shader> {
 
-        let mut map = "maps/".to_string();
-        map.push_str(&s);
-        map.push_str(".bsp");
-        let bytes = zip.by_name(&map).unwrap().bytes().map(|x| x.unwrap()).collect::<Vec<u8>>();
+        let mut shaders: HashMap<String, bsp_shader::Shader> = HashMap::new();
+        for script in fs.list("scripts", ".shader") {
+            if let Some(bytes) = fs.read(&script) {
+                bsp_shader::parse_shaders(&String::from_utf8_lossy(&bytes), &mut shaders);
+            }
+        }
+        shaders
+    }
 
-        //let bytes = std::fs::read(res_dir.join(s)).unwrap();
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, light_layout: &wgpu::BindGroupLayout, fs: &mut vfs::FileSystem, texture_settings: &texture::TextureSettings, map_name: Option<&str>, entity_edits: &Vec<bsp_entity::EntityEdit>) -> Result<Bsp, String> {
 
-        //Check that it is a bsp file
-        if bytes[0] == 'I' as u8 && bytes[1] == 'B' as u8 && bytes[2] == 'S' as u8 && bytes[3] == 'P' as u8 {
-            
-            //Get version of bsp
-            let bsp_version = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[4], bytes[5], bytes[6], bytes[7]]) }.to_le();
 
-            //Entities
-            let entities_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[8], bytes[9], bytes[10], bytes[11]]) }.to_le();
-            let entities_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[12], bytes[13], bytes[14], bytes[15]]) }.to_le();
-            
-            let mut entites: String = String::new();
-            for i in entities_offset..(entities_length + entities_offset) {
-                entites.push(bytes[i as usize] as char);
-            }
-            
-            //Textures
-            let textures_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[16], bytes[17], bytes[18], bytes[19]]) }.to_le();
-            let textures_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[20], bytes[21], bytes[22], bytes[23]]) }.to_le();
-            
-            for i in 0..(textures_length / TEXTURE_SIZE) {
-                let mut temp: [u8; TEXTURE_SIZE as usize] = [0; TEXTURE_SIZE as usize];
-                for j in 0..TEXTURE_SIZE {
-                    temp[j as usize] = bytes[(textures_offset + (i * TEXTURE_SIZE) + j) as usize];
+        //Ask for the map when it was not given on the command line
+        let mut s=String::new();
+        match map_name {
+            Some(name) => s.push_str(name),
+            None => {
+                print!("Please enter some text: ");
+                let _=stdout().flush();
+                stdin().read_line(&mut s).expect("Did not enter a correct string");
+                if let Some('\n')=s.chars().next_back() {
+                    s.pop();
+                }
+                if let Some('\r')=s.chars().next_back() {
+                    s.pop();
                 }
-                let texture = bytemuck::from_bytes::<Texture>(&temp).clone();
-                textures.push(texture);
             }
+        }
+
+        let (data, name) = Bsp::load_map(fs, &s, entity_edits)?;
+
+        let bsp_data::BspData { entities: entity_text, textures, planes, nodes, leafs, leaf_faces, leaf_brushes, models, brushes, brush_sides,
+            mut vertexes, mesh_verts, effects, faces, light_maps, light_vols, .. } = data;
+        let entities = bsp_entity::parse_entities(&entity_text);
+        //End of loading
+
+        let shaders = Bsp::load_shaders(fs);
 
-            //Planes
-            let planes_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[24], bytes[25], bytes[26], bytes[27]]) }.to_le();
-            let planes_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[28], bytes[29], bytes[30], bytes[31]]) }.to_le();
+        //Fog
+        let fogs = Bsp::build_fog_volumes(&effects, &brushes, &brush_sides, &planes, &shaders);
 
-            for i in 0..(planes_length / PLANE_SIZE) {
-                let mut temp: [u8; PLANE_SIZE as usize] = [0; PLANE_SIZE as usize];
-                for j in 0..PLANE_SIZE {
-                    temp[j as usize] = bytes[(planes_offset + (i * PLANE_SIZE) + j) as usize];
+        //Submodels with an origin brush are built around it and placed at the origin of their entity
+        let mut model_transforms = vec![cgmath::Matrix4::identity(); models.len()];
+        for entity in entities.iter() {
+            if let (Some(model), Some(origin)) = (Bsp::entity_model(entity), entity.origin()) {
+                if model > 0 && model < models.len() {
+                    model_transforms[model] = cgmath::Matrix4::from_translation(cgmath::Vector3::new(origin[0], origin[1], origin[2]));
                 }
-                let plane = bytemuck::from_bytes::<Plane>(&temp).clone();
-                planes.push(plane);
             }
+        }
+
+        let num_areas = leafs.iter().map(|l| l.area + 1).max().unwrap_or(0).max(0) as usize;
+        //Bounds are grown a little like when Quake 3 links an entity so doors reach into both areas
+        let model_areas = models.iter().zip(model_transforms.iter()).map(|(m, transform)| {
+            let (mins, maxs) = Bsp::transform_bounds(m.mins, m.maxs, transform);
+            Bsp::box_areas(&nodes, &planes, &leafs, [mins[0] - 1.0, mins[1] - 1.0, mins[2] - 1.0], [maxs[0] + 1.0, maxs[1] + 1.0, maxs[2] + 1.0])
+        }).collect::<Vec<Vec<usize>>>();
+        let area_portals = Bsp::build_area_portals(&entities, &model_areas);
+        let fog_textures = textures.iter().map(|t| shaders.get(&Bsp::name_to_string(&t.name).to_lowercase()).map_or(false, |s| s.is_fog())).collect::<Vec<bool>>();
+
+        let surface_states = textures.iter().map(|t| match shaders.get(&Bsp::name_to_string(&t.name).to_lowercase()) {
+            Some(shader) => shader.surface_state(),
+            None => bsp_shader::SurfaceState::opaque(),
+        }).collect::<Vec<bsp_shader::SurfaceState>>();
+
+        let animations = textures.iter().map(|t| shaders.get(&Bsp::name_to_string(&t.name).to_lowercase())
+            .and_then(|s| s.diffuse_stage())
+            .and_then(|s| bsp_anim::MaterialAnimation::from_stage(s))).collect::<Vec<Option<bsp_anim::MaterialAnimation>>>();
+
+        let material_uniforms = textures.iter().enumerate().map(|(i, t)| {
+            let deforms = match shaders.get(&Bsp::name_to_string(&t.name).to_lowercase()) {
+                Some(shader) => shader.directives.iter().filter(|d| d[0] == "deformvertexes").filter_map(|d| bsp_anim::Deform::parse(d)).collect(),
+                None => Vec::new(),
+            };
+            MaterialUniforms::new(&surface_states[i], &deforms)
+        }).collect::<Vec<MaterialUniforms>>();
 
+        //Lightmaps, the lump ones and then any external ones
+        let (light_map_images, external_light_maps) = bsp_light_map::load_light_maps(fs, &name, &light_maps);
+        let shader_light_maps = bsp_light_map::shader_light_maps(&textures, &shaders);
 
-            //Nodes
-            let nodes_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[32], bytes[33], bytes[34], bytes[35]]) }.to_le();
-            let nodes_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[36], bytes[37], bytes[38], bytes[39]]) }.to_le();
+        //Start of mesh building
+        let mut indices_per_texture: Vec<Vec<Vec<u32>>> = vec![vec![Vec::new(); textures.len()]; light_map_images.len() + 1];
+        let mut translucent_indices: Vec<Vec<u32>> = Vec::new();
+        let mut translucent_faces: Vec<TranslucentFace> = Vec::new();
+        let mut face_meshes = vec![bsp_draw::FaceMesh::empty(); faces.len()];
+        let mut face_models = vec![0; faces.len()];
+        for (i, model) in models.iter().enumerate().skip(1) {
+            for face in model.face..(model.face + model.num_faces) {
+                face_models[face as usize] = i;
+            }
+        }
+        let mut model_indices: Vec<Vec<u32>> = vec![Vec::new(); models.len()];
+        for i in 0..(faces.len()) {
             
-            for i in 0..(nodes_length / NODE_SIZE) {
-                let mut temp: [u8; NODE_SIZE as usize] = [0; NODE_SIZE as usize];
-                for j in 0..NODE_SIZE {
-                    temp[j as usize] = bytes[(nodes_offset + (i * NODE_SIZE) + j) as usize];
+            let remapped = bsp_light_map::remap(faces[i].lightmap_index, shader_light_maps[faces[i].texture as usize], light_maps.len(), &external_light_maps);
+            let li = remapped.unwrap_or(light_map_images.len());
+            if remapped.is_none() {
+                if faces[i].num_mesh_verts > 0 {
+                    println!("Light map index {} Texture index {} Effect {}", faces[i].lightmap_index, faces[i].texture, faces[i].effect);
+                    println!("{}", std::str::from_utf8(&textures[faces[i].texture as usize].name).unwrap().chars().filter(|c| *c != 0 as char).collect::<String>());
                 }
-                let node = bytemuck::from_bytes::<Node>(&temp).clone();
-                nodes.push(node);
             }
 
-            //Leafs
-            let leafs_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[40], bytes[41], bytes[42], bytes[43]]) }.to_le();
-            let leafs_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[44], bytes[45], bytes[46], bytes[47]]) }.to_le();
+            //Fog brush sides are not drawn, the fog is applied to the surfaces inside it
+            if fog_textures[faces[i].texture as usize] {
+                continue;
+            }
 
-            for i in 0..(leafs_length / LEAF_SIZE) {
-                let mut temp: [u8; LEAF_SIZE as usize] = [0; LEAF_SIZE as usize];
-                for j in 0..LEAF_SIZE {
-                    temp[j as usize] = bytes[(leafs_offset + (i * LEAF_SIZE) + j) as usize];
-                }
-                let leaf = bytemuck::from_bytes::<Leaf>(&temp).clone();
-                leafs.push(leaf);
+            let face_indices = Bsp::build_face_indices(&faces[i], &mesh_verts, &mut vertexes);
+            if face_models[i] > 0 {
+                model_indices[face_models[i]].extend(face_indices.iter());
             }
 
-            //Leaf faces
-            let leaf_faces_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[48], bytes[49], bytes[50], bytes[51]]) }.to_le();
-            let leaf_faces_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[52], bytes[53], bytes[54], bytes[55]]) }.to_le();
+            if surface_states[faces[i].texture as usize].blend.is_some() {
+                if face_indices.len() > 0 {
+                    let mut centroid = cgmath::Vector3::new(0.0, 0.0, 0.0);
+                    for index in face_indices.iter() {
+                        let p = vertexes[*index as usize].position;
+                        centroid += cgmath::Vector3::new(p[0], p[1], p[2]);
+                    }
+                    centroid /= face_indices.len() as f32;
 
-            for i in 0..(leaf_faces_length / LEAF_FACE_SIZE) {
-                let mut temp: [u8; LEAF_FACE_SIZE as usize] = [0; LEAF_FACE_SIZE as usize];
-                for j in 0..LEAF_FACE_SIZE {
-                    temp[j as usize] = bytes[(leaf_faces_offset + (i * LEAF_FACE_SIZE) + j) as usize];
+                    translucent_faces.push(TranslucentFace { face: i, texture: faces[i].texture as usize, lightmap: li, first_index: 0, num_indices: face_indices.len() as u32, centroid });
+                    translucent_indices.push(face_indices);
                 }
-                let leaf_face = bytemuck::from_bytes::<LeafFace>(&temp).clone();
-                leaf_faces.push(leaf_face);
             }
-
-            //Leaf brushes
-            let leaf_brushes_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[56], bytes[57], bytes[58], bytes[59]]) }.to_le();
-            let leaf_brushes_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[60], bytes[61], bytes[62], bytes[63]]) }.to_le();
-
-            for i in 0..(leaf_brushes_length / LEAF_BRUSH_SIZE) {
-                let mut temp: [u8; LEAF_BRUSH_SIZE as usize] = [0; LEAF_BRUSH_SIZE as usize];
-                for j in 0..LEAF_BRUSH_SIZE {
-                    temp[j as usize] = bytes[(leaf_brushes_offset + (i * LEAF_BRUSH_SIZE) + j) as usize];
+            else {
+                //Faces without a lightmap are not drawn
+                if li < light_map_images.len() {
+                    face_meshes[i] = bsp_draw::FaceMesh { lightmap: li, texture: faces[i].texture as usize, indices: face_indices.clone() };
                 }
-                let leaf_brush = bytemuck::from_bytes::<LeafBrush>(&temp).clone();
-                leaf_brushes.push(leaf_brush);
+                indices_per_texture[li][faces[i].texture as usize].extend(face_indices);
             }
+        }
 
-            //Models 
-            let models_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[64], bytes[65], bytes[66], bytes[67]]) }.to_le();
-            let models_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[68], bytes[69], bytes[70], bytes[71]]) }.to_le();
-
-            //Brushes
-            let brushes_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[72], bytes[73], bytes[74], bytes[75]]) }.to_le();
-            let brushes_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[76], bytes[77], bytes[78], bytes[79]]) }.to_le();
+        //Runs of consecutive vertices, a face's vertices are mostly next to each other
+        let model_vertices = model_indices.iter_mut().map(|indices| {
+            indices.sort();
+            indices.dedup();
+            let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+            for index in indices.iter().map(|i| *i as usize) {
+                match ranges.last_mut() {
+                    Some(range) if range.end == index => range.end += 1,
+                    _ => ranges.push(index..(index + 1)),
+                }
+            }
+            ranges
+        }).collect::<Vec<Vec<std::ops::Range<usize>>>>();
+
+        //Mesh building, the vertices of placed submodels are moved into place and rewritten when they move
+        let mut placed_vertexes = vertexes.clone();
+        for (transform, ranges) in model_transforms.iter().zip(model_vertices.iter()) {
+            for index in ranges.iter().flat_map(|r| r.clone()) {
+                placed_vertexes[index] = Bsp::transform_vertex(&vertexes[index], transform);
+            }
+        }
+        let vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Vertex Buffer"),
+                contents: bytemuck::cast_slice(&placed_vertexes),
+                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            }
+        );
 
-            for i in 0..(brushes_length / BRUSH_SIZE) {
-                let mut temp: [u8; BRUSH_SIZE as usize] = [0; BRUSH_SIZE as usize];
-                for j in 0..BRUSH_SIZE {
-                    temp[j as usize] = bytes[(brushes_offset + (i * BRUSH_SIZE) + j) as usize];
+        let mut indices_p_t: Vec<u32> = Vec::new();
+        for j in 0..indices_per_texture.len() {
+            for i in 0..indices_per_texture[j].len() {
+                for l in 0..indices_per_texture[j][i].len() {
+                    indices_p_t.push(indices_per_texture[j][i][l]);
                 }
-                let brush = bytemuck::from_bytes::<Brush>(&temp).clone();
-                brushes.push(brush);
             }
+        }
 
-            //Brush sides
-            let brush_sides_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[80], bytes[81], bytes[82], bytes[83]]) }.to_le();
-            let brush_sides_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[84], bytes[85], bytes[86], bytes[87]]) }.to_le();
+        //Translucent faces go after the opaque ones
+        for i in 0..translucent_faces.len() {
+            translucent_faces[i].first_index = indices_p_t.len() as u32;
+            indices_p_t.extend(translucent_indices[i].iter());
+        }
 
-            for i in 0..(brush_sides_length / BRUSH_SIDE_SIZE) {
-                let mut temp: [u8; BRUSH_SIDE_SIZE as usize] = [0; BRUSH_SIDE_SIZE as usize];
-                for j in 0..BRUSH_SIDE_SIZE {
-                    temp[j as usize] = bytes[(brush_sides_offset + (i * BRUSH_SIDE_SIZE) + j) as usize];
-                }
-                let brush_side = bytemuck::from_bytes::<BrushSide>(&temp).clone();
-                brush_sides.push(brush_side);
+        let index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Index Buffer"),
+                contents: bytemuck::cast_slice(&indices_p_t),
+                usage: wgpu::BufferUsage::INDEX,
             }
+        );
 
+        //Large enough for every face to be visible at once
+        let draw_index_count = face_meshes.iter().map(|m| m.indices.len()).sum::<usize>().max(1);
+        let draw_index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Draw Index Buffer"),
+            size: (draw_index_count * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::INDEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
 
-            //Vertexes
-            let vertexes_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[88], bytes[89], bytes[90], bytes[91]]) }.to_le();
-            let vertexes_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[92], bytes[93], bytes[94], bytes[95]]) }.to_le();
+        //Lightmaps
+        let mut all_light_maps: Vec<[[[u8; 4]; 128]; 128]> = Vec::new();
+        let mut materials_light: Vec<Material> = Vec::new();
+        for image in light_map_images.iter() {
+            let tex = texture::Texture::from_array(device, queue, &image.rgb, image.width, image.height, "lightmaps").unwrap();
 
-            for i in 0..(vertexes_length / VERTEX_SIZE) {
-                let mut temp: [u8; VERTEX_SIZE as usize] = [0; VERTEX_SIZE as usize];
-                for j in 0..VERTEX_SIZE {
-                    temp[j as usize] = bytes[(vertexes_offset + (i * VERTEX_SIZE) + j) as usize];
-                }
-                let vertex = bytemuck::from_bytes::<Vertex>(&temp).clone();
-                vertexes.push(vertex);
-            }
+            materials_light.push(Material::new(device, light_layout, tex, None));
+        }
+        //Blended faces without a lightmap get a grey one, times the scale in bsp.frag it leaves the vertex colour
+        let tex = texture::Texture::from_array(device, queue, &[32, 32, 32], 1, 1, "no lightmap").unwrap();
+        materials_light.push(Material::new(device, light_layout, tex, None));
+
+        let mut materials: Vec<Material> = Vec::new();
 
-            //Mesh verts
-            let mesh_verts_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[96], bytes[97], bytes[98], bytes[99]]) }.to_le();
-            let mesh_verts_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[100], bytes[101], bytes[102], bytes[103]]) }.to_le();
+        let debug_image = Bsp::debug_image(fs);
+        for i in 0..textures.len() {
+            let tex_t = texture::Texture::from_image(device, queue, &debug_image, Some("debug")).unwrap();
 
-            for i in 0..(mesh_verts_length / MESH_VERT_SIZE) {
-                let mut temp: [u8; MESH_VERT_SIZE as usize] = [0; MESH_VERT_SIZE as usize];
-                for j in 0..MESH_VERT_SIZE {
-                    temp[j as usize] = bytes[(mesh_verts_offset + (i * MESH_VERT_SIZE) + j) as usize];
-                }
-                let mesh_vert = bytemuck::from_bytes::<MeshVert>(&temp).clone();
-                mesh_verts.push(mesh_vert);
-            }
+            materials.push(Material::new(device, layout, tex_t, Some(material_uniforms[i])));
+        }
 
-            //Effects
-            let effects_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[104], bytes[105], bytes[106], bytes[107]]) }.to_le();
-            let effects_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[108], bytes[109], bytes[110], bytes[111]]) }.to_le();
+        //Textures
+        Bsp::load_textures(fs, &textures, &shaders, &material_uniforms, &mut materials, device, queue, layout, texture_settings);
 
-            for i in 0..(effects_length / EFFECT_SIZE) {
-                let mut temp: [u8; EFFECT_SIZE as usize] = [0; EFFECT_SIZE as usize];
-                for j in 0..EFFECT_SIZE {
-                    temp[j as usize] = bytes[(effects_offset + (i * EFFECT_SIZE) + j) as usize];
+        //animMap frames, a material whose frames can not be found keeps its static texture
+        let mut material_frames: Vec<Vec<Material>> = Vec::new();
+        for i in 0..textures.len() {
+            let mut frames: Vec<Material> = Vec::new();
+            if let Some(anim_map) = animations[i].as_ref().and_then(|a| a.anim_map.as_ref()) {
+                for frame in anim_map.frames.iter() {
+                    match Bsp::load_image(fs, device, queue, frame, texture_settings) {
+                        Some(tex) => frames.push(Material::new(device, layout, tex, Some(material_uniforms[i]))),
+                        None => println!("Error cant find animMap frame {}", frame),
+                    }
+                }
+                if frames.len() != anim_map.frames.len() {
+                    frames.clear();
                 }
-                let effect = bytemuck::from_bytes::<Effect>(&temp).clone();
-                effects.push(effect);
             }
+            material_frames.push(frames);
+        }
+        let current_frames = vec![0; textures.len()];
+
+
+        let t_trace = Trace::new();
+        Ok(Bsp { planes, nodes, leafs, leaf_faces, leaf_brushes, brushes, brush_sides, vertexes, mesh_verts, faces, vertex_buffer, 
+            index_buffer, light_maps, light_vols, t_trace, indices_per_texture, materials, textures, materials_light, shaders, fogs, surface_states, translucent_faces,
+            material_uniforms, animations, material_frames, current_frames, entities, name, models, draw_index_buffer,
+            draw_batches: Vec::new(), face_meshes, area_portals, num_areas, model_areas, model_transforms, model_vertices, face_models })
+    }
+
+    //Material to draw a texture with, animated materials switch between their animMap frames
+    pub fn material(&self, texture: usize) -> &Material {
+
+        if self.material_frames[texture].len() > 0 {
+            &self.material_frames[texture][self.current_frames[texture]]
+        }
+        else {
+            &self.materials[texture]
+        }
+    }
 
+    pub fn update_materials(&mut self, queue: &wgpu::Queue, time: f32) {
 
-            //Faces
-            let faces_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[112], bytes[113], bytes[114], bytes[115]]) }.to_le();
-            let faces_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[116], bytes[117], bytes[118], bytes[119]]) }.to_le();
+        for i in 0..self.animations.len() {
+            if let Some(animation) = self.animations[i].as_ref() {
+                let anim = animation.evaluate(time);
+                if self.material_frames[i].len() > 0 {
+                    self.current_frames[i] = anim.frame % self.material_frames[i].len();
+                }
 
-            for i in 0..(faces_length / FACE_SIZE) {
-                let mut temp: [u8; FACE_SIZE as usize] = [0; FACE_SIZE as usize];
-                for j in 0..FACE_SIZE {
-                    temp[j as usize] = bytes[(faces_offset + (i * FACE_SIZE) + j) as usize];
+                let mut uniforms = self.material_uniforms[i];
+                uniforms.animate(&anim);
+                if let Some(buffer) = self.material(i).uniform_buffer.as_ref() {
+                    queue.write_buffer(buffer, 0, bytemuck::cast_slice(&[uniforms]));
                 }
-                let face = bytemuck::from_bytes::<Face>(&temp).clone();
-                faces.push(face);
             }
+        }
+    }
+
+    pub fn build_face_indices(face: &Face, mesh_verts: &Vec<MeshVert>, vertexes: &mut Vec<Vertex>) -> Vec<u32> {
 
-            //Lightmaps
-            let lightmaps_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[120], bytes[121], bytes[122], bytes[123]]) }.to_le();
-            let lightmaps_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[124], bytes[125], bytes[126], bytes[127]]) }.to_le();
+        let mut indices: Vec<u32> = Vec::new();
 
-            for i in 0..(lightmaps_length / LIGHT_MAP_SIZE) {
-                let mut temp: [u8; LIGHT_MAP_SIZE as usize] = [0; LIGHT_MAP_SIZE as usize];
-                for j in 0..LIGHT_MAP_SIZE {
-                    temp[j as usize] = bytes[(lightmaps_offset + (i * LIGHT_MAP_SIZE) + j) as usize];
+        if face.type_draw == POLYGON || face.type_draw == MESH {
+            for j in 0..(face.num_mesh_verts) {
+                indices.push((face.vertex + mesh_verts[(face.mesh_vert + j) as usize].offset) as u32);
+            }
+        }
+        else if face.type_draw == PATCH {
+            
+            //https://github.com/mikezila/uQuake3/blob/master/uQuake/Scripts/uQuake/GenerateMap.cs
+            //https://github.com/mikezila/uQuake3/blob/master/uQuake/Scripts/uQuake/Types/BezierMesh.cs
+            let num_patches = ((face.size[0] - 1) / 2) * ((face.size[1] - 1) / 2);
+            for j in 0..num_patches {
+                let (i_vertexes, i_inds) = Bsp::gen_bez_mesh(face, j, vertexes);
+
+                let offset = vertexes.len() as u32;
+                for l in 0..i_vertexes.len() {
+                    vertexes.push(i_vertexes[l]);
+                }
+                for l in 0..i_inds.len() {
+                    indices.push(offset + i_inds[l]);
                 }
-                let light_map = bytemuck::from_bytes::<LightMap>(&temp).clone();
-                light_maps.push(light_map);
             }
+        }
+        else if face.type_draw == BILLBOARD {
+            //Todo
+        }
 
-            //Lightvols
-            let lightvols_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[128], bytes[129], bytes[130], bytes[131]]) }.to_le();
-            let lightvols_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[132], bytes[133], bytes[134], bytes[135]]) }.to_le();
+        indices
+    }
 
-            for i in 0..(lightvols_length / LIGHT_VOL_SIZE) {
-                let mut temp: [u8; LIGHT_VOL_SIZE as usize] = [0; LIGHT_VOL_SIZE as usize];
-                for j in 0..LIGHT_VOL_SIZE {
-                    temp[j as usize] = bytes[(lightvols_offset + (i * LIGHT_VOL_SIZE) + j) as usize];
+    //Visible translucent faces back to front, by shader sort first and then by distance to the face centroid
+    pub fn sort_translucent_faces(&self, eye: cgmath::Vector3<f32>, visible: &Vec<bool>) -> Vec<usize> {
+
+        let mut order = (0..self.translucent_faces.len()).filter(|i| visible[self.translucent_faces[*i].face]).collect::<Vec<usize>>();
+        let distance = |i: usize| {
+            let face = &self.translucent_faces[i];
+            let centroid = (self.model_transforms[self.face_models[face.face]] * face.centroid.extend(1.0)).truncate();
+            (centroid - eye).magnitude2()
+        };
+        order.sort_by(|a, b| {
+            let sort_a = self.surface_states[self.translucent_faces[*a].texture].sort;
+            let sort_b = self.surface_states[self.translucent_faces[*b].texture].sort;
+            sort_a.partial_cmp(&sort_b).unwrap_or(std::cmp::Ordering::Equal)
+                .then(distance(*b).partial_cmp(&distance(*a)).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        order
+    }
+
+    //Faces of the leaves inside the frustum, a node outside of it skips its whole subtree.
+    //Submodel faces are not in any leaf so they are tested against their model bounds
+    //Leaves in areas that closed doors seal off from the eye are skipped too
+    pub fn visible_faces(&self, frustum: &frustum::Frustum, eye: [f32; 3]) -> Vec<bool> {
+
+        let mut visible = vec![false; self.faces.len()];
+        if self.nodes.is_empty() {
+            return visible;
+        }
+
+        let area = self.leafs[self.find_leaf(eye)].area;
+        let connected = bsp_area::connected_areas(self.num_areas, &self.area_portals, if area >= 0 { Some(area as usize) } else { None });
+        self.mark_visible_faces(0, frustum, false, &connected, &mut visible);
+
+        for (i, (model, areas)) in self.models.iter().zip(self.model_areas.iter()).enumerate().skip(1) {
+            if !areas.is_empty() && !areas.iter().any(|a| connected[*a]) {
+                continue;
+            }
+            let (mins, maxs) = self.model_bounds(i);
+            if frustum.test_box(mins, maxs) != frustum::Intersection::Outside {
+                for i in model.face..(model.face + model.num_faces) {
+                    visible[i as usize] = true;
                 }
-                let light_vol = bytemuck::from_bytes::<LightVol>(&temp).clone();
-                light_vols.push(light_vol);
             }
+        }
+        visible
+    }
+
+    //Rebuilds the draw batches from the visible faces and uploads their indices
+    pub fn update_draw_list(&mut self, queue: &wgpu::Queue, visible: &Vec<bool>) {
 
-            //Visdata
-            let visdata_offset = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[136], bytes[137], bytes[138], bytes[139]]) }.to_le();
-            let visdata_length = unsafe { std::mem::transmute::<[u8; 4], u32>([bytes[140], bytes[141], bytes[142], bytes[143]]) }.to_le();
+        let list = bsp_draw::build_draw_list(&self.face_meshes, &bsp_draw::faces_from_mask(visible));
+        if !list.indices.is_empty() {
+            queue.write_buffer(&self.draw_index_buffer, 0, bytemuck::cast_slice(&list.indices));
         }
-        //End of loading
+        self.draw_batches = list.batches;
+    }
 
-        for i in 0..effects.len() {
-            println!("{:?}", std::str::from_utf8(&effects[i].name).unwrap().chars().filter(|c| *c != 0 as char).collect::<String>());
+    //Children of a node that is entirely inside the frustum are not tested again
+    fn mark_visible_faces(&self, index: i32, frustum: &frustum::Frustum, inside: bool, connected: &Vec<bool>, visible: &mut Vec<bool>) {
+
+        if index < 0 {
+            let leaf = &self.leafs[(-(index + 1)) as usize];
+            if leaf.area >= 0 && (leaf.area as usize) < connected.len() && !connected[leaf.area as usize] {
+                return;
+            }
+            if !inside && frustum.test_box(Bsp::to_f32(leaf.mins), Bsp::to_f32(leaf.maxs)) == frustum::Intersection::Outside {
+                return;
+            }
+            for i in leaf.leaf_face..(leaf.leaf_face + leaf.num_leaf_faces) {
+                visible[self.leaf_faces[i as usize].face as usize] = true;
+            }
+            return;
         }
 
-        //Start of mesh building
-        let mut indices_per_texture: Vec<Vec<Vec<u32>>> = vec![vec![Vec::new(); textures.len()]; light_maps.len() + 1];
-        for i in 0..(faces.len()) {
-            
-            let mut li = faces[i].lightmap_index as usize;
-            if li >= light_maps.len() || li < 0 {
-                li = light_maps.len();
-                if faces[i].num_mesh_verts > 0 {
-                    println!("Light map index {} Texture index {} Effect {}", faces[i].lightmap_index, faces[i].texture, faces[i].effect);
-                    println!("{}", std::str::from_utf8(&textures[faces[i].texture as usize].name).unwrap().chars().filter(|c| *c != 0 as char).collect::<String>());
-                }
+        let node = &self.nodes[index as usize];
+        let mut inside = inside;
+        if !inside {
+            match frustum.test_box(Bsp::to_f32(node.mins), Bsp::to_f32(node.maxs)) {
+                frustum::Intersection::Outside => return,
+                frustum::Intersection::Inside => inside = true,
+                frustum::Intersection::Intersecting => {}
             }
+        }
+        self.mark_visible_faces(node.children[0], frustum, inside, connected, visible);
+        self.mark_visible_faces(node.children[1], frustum, inside, connected, visible);
+    }
+
+    pub fn find_leaf(&self, p: [f32; 3]) -> usize {
 
-            if faces[i].type_draw == POLYGON {
-                for j in 0..(faces[i].num_mesh_verts) {
-                    indices_per_texture[li][faces[i].texture as usize].push((faces[i as usize].vertex + mesh_verts[(faces[i as usize].mesh_vert + j) as usize].offset) as u32);
+        let mut index = 0;
+        while index >= 0 {
+            let node = &self.nodes[index as usize];
+            let plane = &self.planes[node.plane as usize];
+            let distance = plane.normal[0] * p[0] + plane.normal[1] * p[1] + plane.normal[2] * p[2] - plane.distance;
+            index = if distance >= 0.0 { node.children[0] } else { node.children[1] };
+        }
+        (-(index + 1)) as usize
+    }
+
+    //Areas of the leaves a box touches
+    fn box_areas(nodes: &Vec<Node>, planes: &Vec<Plane>, leafs: &Vec<Leaf>, mins: [f32; 3], maxs: [f32; 3]) -> Vec<usize> {
+
+        let mut areas: Vec<usize> = Vec::new();
+        if nodes.is_empty() {
+            return areas;
+        }
+        let mut stack = vec![0i32];
+        while let Some(index) = stack.pop() {
+            if index < 0 {
+                let area = leafs[(-(index + 1)) as usize].area;
+                if area >= 0 && !areas.contains(&(area as usize)) {
+                    areas.push(area as usize);
                 }
+                continue;
             }
-            else if faces[i].type_draw == PATCH {
-                
-                //https://github.com/mikezila/uQuake3/blob/master/uQuake/Scripts/uQuake/GenerateMap.cs
-                //https://github.com/mikezila/uQuake3/blob/master/uQuake/Scripts/uQuake/Types/BezierMesh.cs
-                let num_patches = ((faces[i].size[0] - 1) / 2) * ((faces[i].size[1] - 1) / 2);
-                for j in 0..num_patches {
-                    let (i_vertexes, i_inds) = Bsp::gen_bez_mesh(&faces[i], j, &vertexes);
-
-                    let offset = vertexes.len() as u32;
-                    for l in 0..i_vertexes.len() {
-                        vertexes.push(i_vertexes[l]);
-                    }
-                    for l in 0..i_inds.len() {
-                        indices_per_texture[li][faces[i].texture as usize].push(offset + i_inds[l]);
-                    }
+            let node = &nodes[index as usize];
+            let plane = &planes[node.plane as usize];
+            let mut near = 0.0;
+            let mut far = 0.0;
+            for i in 0..3 {
+                if plane.normal[i] >= 0.0 {
+                    near += plane.normal[i] * mins[i];
+                    far += plane.normal[i] * maxs[i];
                 }
-            }
-            else if faces[i].type_draw == MESH {
-                for j in 0..(faces[i].num_mesh_verts) {
-                    indices_per_texture[li][faces[i].texture as usize].push((faces[i as usize].vertex + mesh_verts[(faces[i as usize].mesh_vert + j) as usize].offset) as u32);
+                else {
+                    near += plane.normal[i] * maxs[i];
+                    far += plane.normal[i] * mins[i];
                 }
             }
-            else if faces[i].type_draw == BILLBOARD {
-                //Todo
+            if far >= plane.distance {
+                stack.push(node.children[0]);
+            }
+            if near < plane.distance {
+                stack.push(node.children[1]);
             }
         }
+        areas
+    }
 
-        //Mesh building
-        let vertex_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(&vertexes),
-                usage: wgpu::BufferUsage::VERTEX,
+    //A func_door whose model touches two areas is the portal between them, closed unless it starts open
+    fn build_area_portals(entities: &Vec<bsp_entity::Entity>, model_areas: &Vec<Vec<usize>>) -> Vec<bsp_area::AreaPortal> {
+
+        let mut portals: Vec<bsp_area::AreaPortal> = Vec::new();
+        for (i, entity) in entities.iter().enumerate() {
+            if entity.class_name() != "func_door" {
+                continue;
             }
-        );
+            let model = match Bsp::entity_model(entity) {
+                Some(model) if model < model_areas.len() => model,
+                _ => continue,
+            };
+            let areas = &model_areas[model];
+            if areas.len() >= 2 {
+                let open = entity.float("spawnflags").map(|f| f as i32 & 1 != 0).unwrap_or(false);
+                portals.push(bsp_area::AreaPortal { areas: [areas[0], areas[1]], entity: i, open });
+            }
+        }
+        portals
+    }
 
-        let mut indices_p_t: Vec<u32> = Vec::new();
-        for j in 0..indices_per_texture.len() {
-            for i in 0..indices_per_texture[j].len() {
-                for l in 0..indices_per_texture[j][i].len() {
-                    indices_p_t.push(indices_per_texture[j][i][l]);
+    pub fn set_area_portal(&mut self, portal: usize, open: bool) {
+        if let Some(portal) = self.area_portals.get_mut(portal) {
+            portal.open = open;
+        }
+    }
+
+    //The submodel of a brush entity, its model key is *N
+    pub fn entity_model(entity: &bsp_entity::Entity) -> Option<usize> {
+        entity.get("model").filter(|m| m.starts_with('*')).and_then(|m| m[1..].parse::<usize>().ok())
+    }
+
+    //Bounds of a submodel as it was built, before it is placed
+    pub fn model_local_bounds(&self, model: usize) -> Option<([f32; 3], [f32; 3])> {
+        self.models.get(model).map(|m| (m.mins, m.maxs))
+    }
+
+    //Bounds of a submodel where it is now
+    pub fn model_bounds(&self, model: usize) -> ([f32; 3], [f32; 3]) {
+        Bsp::transform_bounds(self.models[model].mins, self.models[model].maxs, &self.model_transforms[model])
+    }
+
+    //Moves a submodel, its vertices are rewritten in the vertex buffer and traces against its brushes follow it
+    pub fn set_model_transform(&mut self, queue: &wgpu::Queue, model: usize, transform: cgmath::Matrix4<f32>) {
+
+        if model == 0 || model >= self.models.len() || self.model_transforms[model] == transform {
+            return;
+        }
+        self.model_transforms[model] = transform;
+        for range in self.model_vertices[model].iter() {
+            let moved = self.vertexes[range.clone()].iter().map(|v| Bsp::transform_vertex(v, &transform)).collect::<Vec<Vertex>>();
+            queue.write_buffer(&self.vertex_buffer, (range.start * std::mem::size_of::<Vertex>()) as wgpu::BufferAddress, bytemuck::cast_slice(&moved));
+        }
+        let (mins, maxs) = self.model_bounds(model);
+        self.model_areas[model] = Bsp::box_areas(&self.nodes, &self.planes, &self.leafs, [mins[0] - 1.0, mins[1] - 1.0, mins[2] - 1.0], [maxs[0] + 1.0, maxs[1] + 1.0, maxs[2] + 1.0]);
+    }
+
+    fn transform_vertex(v: &Vertex, transform: &cgmath::Matrix4<f32>) -> Vertex {
+
+        let position = *transform * cgmath::Vector4::new(v.position[0], v.position[1], v.position[2], 1.0);
+        let normal = (*transform * cgmath::Vector4::new(v.normal[0], v.normal[1], v.normal[2], 0.0)).truncate();
+        let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { normal };
+        Vertex { position: [position.x, position.y, position.z], normal: [normal.x, normal.y, normal.z], ..*v }
+    }
+
+    //Box around the eight moved corners
+    fn transform_bounds(mins: [f32; 3], maxs: [f32; 3], transform: &cgmath::Matrix4<f32>) -> ([f32; 3], [f32; 3]) {
+
+        let mut bounds = ([f32::MAX; 3], [f32::MIN; 3]);
+        for corner in 0..8 {
+            let p = [if corner & 1 == 0 { mins[0] } else { maxs[0] }, if corner & 2 == 0 { mins[1] } else { maxs[1] }, if corner & 4 == 0 { mins[2] } else { maxs[2] }];
+            let moved = *transform * cgmath::Vector4::new(p[0], p[1], p[2], 1.0);
+            for i in 0..3 {
+                bounds.0[i] = bounds.0[i].min(moved[i]);
+                bounds.1[i] = bounds.1[i].max(moved[i]);
+            }
+        }
+        bounds
+    }
+
+    //Opens or closes the portals of a door entity
+    pub fn set_door_open(&mut self, entity: usize, open: bool) {
+        for portal in self.area_portals.iter_mut().filter(|p| p.entity == entity) {
+            portal.open = open;
+        }
+    }
+
+    //Light grid at a point, the eight samples around it weighted like the game does and samples inside walls skipped
+    pub fn light_grid(&self, p: [f32; 3]) -> GridLight {
+
+        let mut light = GridLight { ambient: [0.0; 3], directed: [0.0; 3], dir: [0.0, 0.0, 1.0] };
+        if self.light_vols.is_empty() || self.models.is_empty() {
+            return light;
+        }
+        let size = self.entities.first().and_then(|e| e.vector("gridsize")).unwrap_or([64.0, 64.0, 128.0]);
+        let (pos, bounds, frac) = Bsp::light_grid_cell(self.models[0].mins, self.models[0].maxs, size, p);
+
+        let mut total = 0.0;
+        let mut dir = [0.0; 3];
+        for corner in 0..8 {
+            let mut factor = 1.0;
+            let mut index = [0; 3];
+            for i in 0..3 {
+                if corner & (1 << i) != 0 {
+                    factor *= frac[i];
+                    index[i] = (pos[i] + 1).min(bounds[i] - 1);
+                }
+                else {
+                    factor *= 1.0 - frac[i];
+                    index[i] = pos[i];
                 }
             }
+            let vol = match self.light_vols.get((index[2] * bounds[0] * bounds[1] + index[1] * bounds[0] + index[0]) as usize) {
+                Some(vol) => vol,
+                None => continue,
+            };
+            if vol.ambient.iter().all(|a| *a == 0) {
+                continue;
+            }
+            total += factor;
+            let normal = md3::decode_normal(vol.dir);
+            for i in 0..3 {
+                light.ambient[i] += factor * vol.ambient[i] as f32;
+                light.directed[i] += factor * vol.directional[i] as f32;
+                dir[i] += factor * normal[i];
+            }
         }
 
-        let index_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(&indices_p_t),
-                usage: wgpu::BufferUsage::INDEX,
+        if total > 0.0 && total < 0.99 {
+            for i in 0..3 {
+                light.ambient[i] /= total;
+                light.directed[i] /= total;
             }
-        );
+        }
+        let length = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+        if length > 0.0 {
+            light.dir = [dir[0] / length, dir[1] / length, dir[2] / length];
+        }
+        light
+    }
 
-        //Lightmaps
-        let mut all_light_maps: Vec<[[[u8; 4]; 128]; 128]> = Vec::new();
-        let mut materials_light: Vec<Material> = Vec::new();
-        for i in 0..light_maps.len() {
-            let tex = texture::Texture::from_array(device, queue, bytemuck::bytes_of::<LightMap>(&light_maps[i]), 128, "lightmaps").unwrap();
-
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: light_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&tex.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&tex.sampler),
-                    },
-                ],
-                label: None,
-            });
+    //The grid starts at the first whole cell inside the world bounds, returns the cell below p, the grid size in cells and how far p is across the cell
+    fn light_grid_cell(mins: [f32; 3], maxs: [f32; 3], size: [f32; 3], p: [f32; 3]) -> ([i32; 3], [i32; 3], [f32; 3]) {
 
-            materials_light.push(Material { diffuse_texture: tex, bind_group });
+        let mut bounds = [0; 3];
+        let mut pos = [0; 3];
+        let mut frac = [0.0; 3];
+        for i in 0..3 {
+            let origin = size[i] * (mins[i] / size[i]).ceil();
+            bounds[i] = (((maxs[i] / size[i]).floor() - (mins[i] / size[i]).ceil()) as i32 + 1).max(1);
+            let v = (p[i] - origin) / size[i];
+            pos[i] = (v.floor() as i32).max(0).min(bounds[i] - 1);
+            frac[i] = (v - v.floor()).max(0.0).min(1.0);
         }
+        (pos, bounds, frac)
+    }
 
-        let mut materials: Vec<Material> = Vec::new();
+    fn to_f32(v: [i32; 3]) -> [f32; 3] {
+        [v[0] as f32, v[1] as f32, v[2] as f32]
+    }
 
-        for i in 0..textures.len() {
-            let tex_t = texture::Texture::load(device, queue, res_dir.join("debug.jpg")).unwrap();
-
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&tex_t.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&tex_t.sampler),
-                    },
-                ],
-                label: None,
-            });
+    pub fn name_to_string(name: &[u8]) -> String {
+        std::str::from_utf8(name).unwrap().chars().filter(|c| *c != 0 as char).collect::<String>()
+    }
 
-            materials.push(Material { diffuse_texture: tex_t, bind_group });
-        }
+    fn build_fog_volumes(effects: &Vec<Effect>, brushes: &Vec<Brush>, brush_sides: &Vec<BrushSide>, planes: &Vec<Plane>, shaders: &HashMap<String, bsp_shader::Shader>) -> Vec<bsp_fog::FogVolume> {
 
-        //Textures
-        Bsp::load_from_pak("pak0.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak1.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak2.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak3.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak4.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak5.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak6.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak7.pk3", &textures, &mut materials, device, queue, layout);
-        Bsp::load_from_pak("pak8.pk3", &textures, &mut materials, device, queue, layout);
+        let mut fogs: Vec<bsp_fog::FogVolume> = Vec::new();
+        for i in 0..effects.len() {
 
+            let name = Bsp::name_to_string(&effects[i].name);
+            let fog_parms = match shaders.get(&name.to_lowercase()).and_then(|s| s.fog_parms) {
+                Some(fog_parms) => fog_parms,
+                None => {
+                    println!("Effect {} has no fogparms", name);
+                    continue;
+                }
+            };
 
-        let t_trace = Trace::new();
-        Bsp { planes, nodes, leafs, leaf_faces, leaf_brushes, brushes, brush_sides, vertexes, mesh_verts, faces, vertex_buffer, 
-            index_buffer, light_maps, light_vols, t_trace, indices_per_texture, materials, textures, materials_light }
+            //A bad brush or side index in a corrupt lump skips the volume instead of crashing the loader
+            let brush = match brushes.get(effects[i].brush as usize) {
+                Some(brush) => *brush,
+                None => {
+                    println!("Effect {} has no brush {}", name, effects[i].brush);
+                    continue;
+                }
+            };
+            let side_plane = |side: i32| brush_sides.get((brush.brush_side + side) as usize).and_then(|s| planes.get(s.plane as usize));
+
+            //The first sides of a brush are axial, they give the bounds of the volume
+            let mut mins = [-65536.0f32; 3];
+            let mut maxs = [65536.0f32; 3];
+            for j in 0..brush.num_brush_sides {
+                let plane = match side_plane(j) {
+                    Some(plane) => plane,
+                    None => continue,
+                };
+                for k in 0..3 {
+                    if plane.normal[k] == 1.0 {
+                        maxs[k] = maxs[k].min(plane.distance);
+                    }
+                    else if plane.normal[k] == -1.0 {
+                        mins[k] = mins[k].max(-plane.distance);
+                    }
+                }
+            }
+
+            let mut visible_plane = None;
+            if effects[i].visible_side >= 0 && effects[i].visible_side < brush.num_brush_sides {
+                visible_plane = side_plane(effects[i].visible_side).map(|plane| (plane.normal, plane.distance));
+            }
+
+            fogs.push(bsp_fog::FogVolume::new(&fog_parms, mins, maxs, visible_plane));
+        }
+
+        fogs
     }
 
     //Player Clipping
@@ -720,6 +1272,9 @@ impl Bsp {
         let output_fraction = 1.0;
 
         self.check_node(0, 0.0, 1.0, self.t_trace.start, self.t_trace.end);
+        for model in 1..self.models.len() {
+            self.check_model(model);
+        }
 
         if self.t_trace.output_fraction == 1.0 {
             self.t_trace.output_end = self.t_trace.end;
@@ -829,6 +1384,55 @@ impl Bsp {
         }
     }
 
+    //Submodel brushes are not in the tree, they are traced in the model's own space where the fraction along the trace is the same.
+    //A box can not be turned with the model, so a turned model is traced with the sphere around the box
+    fn check_model(&mut self, model: usize) {
+
+        let m = self.models[model];
+        let (start, end, t_type, radius) = (self.t_trace.start, self.t_trace.end, self.t_trace.t_type, self.t_trace.radius);
+        let (mut mins, mut maxs) = (cgmath::Vector3::new(0.0, 0.0, 0.0), cgmath::Vector3::new(0.0, 0.0, 0.0));
+        if t_type == SPHERE {
+            mins = cgmath::Vector3::new(-radius, -radius, -radius);
+            maxs = cgmath::Vector3::new(radius, radius, radius);
+        }
+        else if t_type == BOX {
+            mins = self.t_trace.mins;
+            maxs = self.t_trace.maxs;
+        }
+
+        let (model_mins, model_maxs) = self.model_bounds(model);
+        for i in 0..3 {
+            if start[i].min(end[i]) + mins[i] > model_maxs[i] || start[i].max(end[i]) + maxs[i] < model_mins[i] {
+                return;
+            }
+        }
+        let transform = self.model_transforms[model];
+        let inverse = match transform.invert() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+
+        let turned = transform.x.truncate() != cgmath::Vector3::unit_x() || transform.y.truncate() != cgmath::Vector3::unit_y() || transform.z.truncate() != cgmath::Vector3::unit_z();
+        let mut center = cgmath::Vector3::new(0.0, 0.0, 0.0);
+        if turned && t_type == BOX {
+            center = (mins + maxs) * 0.5;
+            self.t_trace.t_type = SPHERE;
+            self.t_trace.radius = ((maxs - mins) * 0.5).magnitude();
+        }
+        self.t_trace.start = (inverse * (start + center).extend(1.0)).truncate();
+        self.t_trace.end = (inverse * (end + center).extend(1.0)).truncate();
+        for i in 0..m.num_brushes {
+            let brush = self.brushes[(m.brush + i) as usize];
+            if brush.num_brush_sides > 0 && (self.textures[brush.texture as usize].contents & 1) == 1 {
+                self.check_brush(brush);
+            }
+        }
+        self.t_trace.start = start;
+        self.t_trace.end = end;
+        self.t_trace.t_type = t_type;
+        self.t_trace.radius = radius;
+    }
+
     fn check_brush(&mut self, brush: Brush) {
 
         let mut start_fraction = -1.0;
@@ -1105,151 +1709,137 @@ impl Bsp {
         Bsp::gen_bezier_mesh(&b_verts)
     }
 
-    //Loading from pak
-    fn load_from_pak(pak: &str, textures: &Vec<Texture>, materials: &mut Vec<Material>, device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout) {
+    //Like Quake 3 a .tga can also be a .jpg
+    pub fn image_files(path: &str) -> Vec<(String, image::ImageFormat)> {
 
-        let res_dir = std::path::Path::new(env!("OUT_DIR")).join("res");
+        let stem = match path.rfind('.') {
+            Some(i) if !path[i..].contains('/') => &path[..i],
+            _ => path,
+        };
+        vec![(format!("{}.tga", stem), image::ImageFormat::Tga), (format!("{}.jpg", stem), image::ImageFormat::Jpeg)]
+    }
 
-        let mut baseq3_pak = "baseq3/".to_string();
-        baseq3_pak.push_str(pak);
+    //Images to try for a texture in order, its own name, the look up table and then the image of its shader.
+    //Shaders q3map2 writes for external lightmaps are named after the original with a hash added
+    pub fn texture_images(name: &str, shaders: &HashMap<String, bsp_shader::Shader>) -> Vec<String> {
 
-        let mut f = std::fs::File::open(res_dir.join(baseq3_pak)).unwrap();
-        let mut reader = std::io::BufReader::new(f);
-        
-        let mut zip = zip::ZipArchive::new(reader).unwrap();
+        let mut images = vec![name.to_string(), bsp_look_up::look_up_table(name)];
+        if let Some(map) = shaders.get(&name.to_lowercase()).and_then(|s| s.diffuse_stage()).and_then(|s| s.map()) {
+            images.push(map.to_string());
+        }
+        images
+    }
 
-        for i in 0..textures.len() {
+    //Loads an image from the game data
+    pub fn load_image(fs: &vfs::FileSystem, device: &wgpu::Device, queue: &wgpu::Queue, path: &str, texture_settings: &texture::TextureSettings) -> Option<texture::Texture> {
 
-            let mut tex: String = std::str::from_utf8(&textures[i].name).unwrap().chars().filter(|c| *c != 0 as char).collect();
-            let mut tex_j = tex.clone();
-            tex_j.push_str(".jpg");
-
-            let mut check_tga = false;
-            let mut use_look_up = false;
-
-            match zip.by_name(&tex_j) {
-                Ok(file) => {
-                    let tex = texture::Texture::from_bytes_format(device, queue, bytemuck::cast_slice(&(file.bytes().map(|x| x.unwrap()).collect::<Vec<u8>>())), image::ImageFormat::Jpeg, "Tex").unwrap();
-                
-                    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                        layout,
-                        entries: &[
-                            wgpu::BindGroupEntry {
-                                binding: 0,
-                                resource: wgpu::BindingResource::TextureView(&tex.view),
-                            },
-                            wgpu::BindGroupEntry {
-                                binding: 1,
-                                resource: wgpu::BindingResource::Sampler(&tex.sampler),
-                            },
-                        ],
-                        label: None,
-                    });
-
-                    materials[i] = Material { diffuse_texture: tex, bind_group };
-                },
-                Err(e) => {
-                    check_tga = true;
-                },
-            };
+        for (file, format) in Bsp::image_files(path) {
+            if let Some(bytes) = fs.read(&file) {
+                match texture::Texture::from_bytes_format(device, queue, &bytes, format, "Tex", texture_settings) {
+                    Ok(texture) => return Some(texture),
+                    Err(e) => println!("Could not load {}: {}", file, e),
+                }
+            }
+        }
+        None
+    }
 
-            if check_tga {
-                let mut tex_t = tex.clone();
-                tex_t.push_str(".tga");
+    //debug.jpg from res, or a checkerboard when it is missing or broken
+    pub fn debug_image(fs: &vfs::FileSystem) -> image::DynamicImage {
 
-                match zip.by_name(&tex_t) {
-                    Ok(file) => {
-                        let tex = texture::Texture::from_bytes_format(device, queue, bytemuck::cast_slice(&(file.bytes().map(|x| x.unwrap()).collect::<Vec<u8>>())), image::ImageFormat::Tga, "Tex").unwrap();
-                    
-                        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                            layout,
-                            entries: &[
-                                wgpu::BindGroupEntry {
-                                    binding: 0,
-                                    resource: wgpu::BindingResource::TextureView(&tex.view),
-                                },
-                                wgpu::BindGroupEntry {
-                                    binding: 1,
-                                    resource: wgpu::BindingResource::Sampler(&tex.sampler),
-                                },
-                            ],
-                            label: None,
-                        });
-    
-                        materials[i] = Material { diffuse_texture: tex, bind_group };
-                    },
-                    Err(e) => {
-                        //println!("Error cant find {}", tex);
-                        use_look_up = true;
-                    },
-                };
+        match fs.read("debug.jpg").map(|bytes| image::load_from_memory(&bytes)) {
+            Some(Ok(image)) => image,
+            result => {
+                if let Some(Err(e)) = result {
+                    println!("Could not load debug.jpg: {}", e);
+                }
+                else {
+                    println!("Could not find debug.jpg");
+                }
+                image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |x, y| {
+                    if (x / 8 + y / 8) % 2 == 0 { image::Rgba([255, 0, 255, 255]) } else { image::Rgba([0, 0, 0, 255]) }
+                }))
             }
+        }
+    }
 
-            //Look up request
-            if use_look_up {
-                tex = bsp_look_up::look_up_table(&tex);
-                tex_j = tex.clone();
-                tex_j.push_str(".jpg");
+    //Textures that can not be found keep the debug texture
+    fn load_textures(fs: &vfs::FileSystem, textures: &Vec<Texture>, shaders: &HashMap<String, bsp_shader::Shader>, material_uniforms: &Vec<MaterialUniforms>, materials: &mut Vec<Material>, device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, texture_settings: &texture::TextureSettings) {
 
-                check_tga = false;
+        for i in 0..textures.len() {
 
-                match zip.by_name(&tex_j) {
-                    Ok(file) => {
-                        let tex = texture::Texture::from_bytes_format(device, queue, bytemuck::cast_slice(&(file.bytes().map(|x| x.unwrap()).collect::<Vec<u8>>())), image::ImageFormat::Jpeg, "Tex").unwrap();
-                    
-                        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                            layout,
-                            entries: &[
-                                wgpu::BindGroupEntry {
-                                    binding: 0,
-                                    resource: wgpu::BindingResource::TextureView(&tex.view),
-                                },
-                                wgpu::BindGroupEntry {
-                                    binding: 1,
-                                    resource: wgpu::BindingResource::Sampler(&tex.sampler),
-                                },
-                            ],
-                            label: None,
-                        });
-
-                        materials[i] = Material { diffuse_texture: tex, bind_group };
-                    },
-                    Err(e) => {
-                        check_tga = true;
-                    },
-                };
+            let tex = Bsp::name_to_string(&textures[i].name);
+            let loaded = Bsp::texture_images(&tex, shaders).iter().find_map(|path| Bsp::load_image(fs, device, queue, path, texture_settings));
 
-                if check_tga {
-                    let mut tex_t = tex.clone();
-                    tex_t.push_str(".tga");
-
-                    match zip.by_name(&tex_t) {
-                        Ok(file) => {
-                            let tex = texture::Texture::from_bytes_format(device, queue, bytemuck::cast_slice(&(file.bytes().map(|x| x.unwrap()).collect::<Vec<u8>>())), image::ImageFormat::Tga, "Tex").unwrap();
-                        
-                            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                                layout,
-                                entries: &[
-                                    wgpu::BindGroupEntry {
-                                        binding: 0,
-                                        resource: wgpu::BindingResource::TextureView(&tex.view),
-                                    },
-                                    wgpu::BindGroupEntry {
-                                        binding: 1,
-                                        resource: wgpu::BindingResource::Sampler(&tex.sampler),
-                                    },
-                                ],
-                                label: None,
-                            });
-        
-                            materials[i] = Material { diffuse_texture: tex, bind_group };
-                        },
-                        Err(e) => {
-                            //println!("Error cant find {}", tex);
-                        },
-                    };
+            match loaded {
+                Some(texture) => materials[i] = Material::new(device, layout, texture, Some(material_uniforms[i])),
+                None => {
+                    //println!("Error cant find {}", tex);
                 }
             }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(area: i32) -> Leaf {
+        Leaf { area, ..bytemuck::Zeroable::zeroed() }
+    }
+
+    fn node(plane: i32, children: [i32; 2]) -> Node {
+        Node { plane, children, ..bytemuck::Zeroable::zeroed() }
+    }
+
+    //x = 0 splits area 1 in front from the back, where y = 0 splits area 0 in front from area 2
+    fn tree() -> (Vec<Node>, Vec<Plane>, Vec<Leaf>) {
+        let planes = vec![Plane { normal: [1.0, 0.0, 0.0], distance: 0.0 }, Plane { normal: [0.0, 1.0, 0.0], distance: 0.0 }];
+        let nodes = vec![node(0, [-1, 1]), node(1, [-2, -3])];
+        (nodes, planes, vec![leaf(1), leaf(0), leaf(2)])
+    }
+
+    #[test]
+    fn model_bounds_are_floats() {
+        let mut words = [-8.0f32, -32.5, 0.0, 8.0, -4.0, 64.0].iter().map(|f| f.to_bits()).collect::<Vec<u32>>();
+        words.extend_from_slice(&[1, 2, 3, 4]);
+        let model = bytemuck::cast_slice::<u32, Model>(&words)[0];
+        assert_eq!((model.mins, model.maxs), ([-8.0, -32.5, 0.0], [8.0, -4.0, 64.0]));
+        assert_eq!((model.face, model.num_faces, model.brush, model.num_brushes), (1, 2, 3, 4));
+    }
+
+    #[test]
+    fn box_areas() {
+        let (nodes, planes, leafs) = tree();
+        let mut areas = Bsp::box_areas(&nodes, &planes, &leafs, [-8.0, -32.0, 0.0], [8.0, -4.0, 64.0]);
+        areas.sort();
+        assert_eq!(areas, vec![1, 2]);
+        assert_eq!(Bsp::box_areas(&nodes, &planes, &leafs, [4.0, -32.0, 0.0], [8.0, 32.0, 64.0]), vec![1]);
+        assert_eq!(Bsp::box_areas(&nodes, &planes, &leafs, [-8.0, 4.0, 0.0], [-4.0, 32.0, 64.0]), vec![0]);
+        assert!(Bsp::box_areas(&Vec::new(), &planes, &leafs, [0.0; 3], [1.0; 3]).is_empty());
+    }
+
+    #[test]
+    fn light_grid_cells() {
+        //The grid origin is -64 -64 0 and it is 6 by 5 by 1 cells
+        let (mins, maxs, size) = ([-100.0, -100.0, -50.0], [300.0, 200.0, 100.0], [64.0, 64.0, 128.0]);
+        assert_eq!(Bsp::light_grid_cell(mins, maxs, size, [-64.0, -64.0, 0.0]), ([0, 0, 0], [6, 5, 1], [0.0, 0.0, 0.0]));
+        assert_eq!(Bsp::light_grid_cell(mins, maxs, size, [16.0, 96.0, 64.0]), ([1, 2, 0], [6, 5, 1], [0.25, 0.5, 0.5]));
+        //Points outside the grid use the edge cells
+        let (pos, _, _) = Bsp::light_grid_cell(mins, maxs, size, [-1000.0, 1000.0, 1000.0]);
+        assert_eq!(pos, [0, 4, 0]);
+    }
+
+    #[test]
+    fn door_area_portals() {
+        let entities = bsp_entity::parse_entities("{\n\"classname\" \"worldspawn\"\n}\n{\n\"classname\" \"func_door\"\n\"model\" \"*1\"\n}\n{\n\"classname\" \"func_door\"\n\"model\" \"*2\"\n\"spawnflags\" \"1\"\n}\n{\n\"classname\" \"func_door\"\n\"model\" \"*3\"\n}\n{\n\"classname\" \"func_plat\"\n\"model\" \"*1\"\n}\n");
+        //Door 3 only touches one area so it is not a portal
+        let model_areas = vec![vec![0, 1, 2], vec![1, 2], vec![0, 2], vec![1]];
+        let portals = Bsp::build_area_portals(&entities, &model_areas);
+        assert_eq!(portals, vec![
+            bsp_area::AreaPortal { areas: [1, 2], entity: 1, open: false },
+            bsp_area::AreaPortal { areas: [0, 2], entity: 2, open: true },
+        ]);
+    }
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
 #version 450
 
+#define MAX_DEFORMS 3
+
 layout(location = 0) in vec3 a_position;
 layout(location = 1) in vec2 a_tex_coords;
 layout(location = 2) in vec2 a_tex_coords_lightmap;
@@ -9,16 +11,102 @@ layout(location = 4) in vec4 a_colour;
 layout(location = 0) out vec4 v_colour;
 layout(location = 1) out vec2 v_tex_coords;
 layout(location = 2) out vec2 v_tex_coords_lightmap;
+layout(location = 3) out vec3 v_position;
+
+layout(set = 0, binding = 2)
+uniform MaterialUniforms {
+    vec4 u_alpha_func;
+    vec4 u_tc_s;
+    vec4 u_tc_t;
+    vec4 u_turb;
+    vec4 u_colour;
+    vec4 u_deforms[MAX_DEFORMS * 3];
+};
 
 layout(set = 2, binding = 0)
 uniform Uniforms {
     mat4 u_view_proj;
     mat4 model;
+    vec4 u_view_position;
+    vec4 u_time;
+    vec4 u_render_mode;
 };
 
+//tcMod matrix and turb from the material
+vec2 animate_tex_coords(vec2 st, vec3 position) {
+    vec2 tc = vec2(dot(u_tc_s.xyz, vec3(st, 1.0)), dot(u_tc_t.xyz, vec3(st, 1.0)));
+    tc.x += sin(((position.x + position.z) * 1.0 / 128.0 * 0.125 + u_turb.y) * 6.28318530718) * u_turb.x;
+    tc.y += sin((position.y * 1.0 / 128.0 * 0.125 + u_turb.y) * 6.28318530718) * u_turb.x;
+    return tc;
+}
+
+//Matches Wave::evaluate in bsp_anim.rs
+float wave_value(float func, vec4 wave, float offset, float time) {
+    float x = wave.z + offset + time * wave.w;
+    float t = fract(x);
+    float value;
+    if (func == 0.0) {
+        value = sin(t * 6.28318530718);
+    }
+    else if (func == 1.0) {
+        value = t < 0.25 ? t * 4.0 : (t < 0.75 ? 2.0 - t * 4.0 : t * 4.0 - 4.0);
+    }
+    else if (func == 2.0) {
+        value = t < 0.5 ? 1.0 : -1.0;
+    }
+    else if (func == 3.0) {
+        value = t;
+    }
+    else if (func == 4.0) {
+        value = 1.0 - t;
+    }
+    else {
+        float i = floor(x);
+        float f = x - i;
+        f = f * f * (3.0 - 2.0 * f);
+        value = mix(fract(sin(i * 12.9898) * 43758.5453) * 2.0 - 1.0, fract(sin((i + 1.0) * 12.9898) * 43758.5453) * 2.0 - 1.0, f);
+    }
+    return wave.x + value * wave.y;
+}
+
+//Smooth noise over time at a vertex, different for each seed
+float deform_noise(vec3 position, float seed, float time) {
+    float i = floor(time);
+    float f = time - i;
+    f = f * f * (3.0 - 2.0 * f);
+    float p = dot(position, vec3(0.1031, 0.1130, 0.0973)) + seed;
+    return mix(fract(sin(p + i * 12.9898) * 43758.5453) * 2.0 - 1.0, fract(sin(p + (i + 1.0) * 12.9898) * 43758.5453) * 2.0 - 1.0, f);
+}
+
+//deformVertexes wave, bulge, move and normal. A normal deform changes the normal the deforms after it move along.
+//The deformed normal is not passed on, lighting comes from the lightmap, so a normal deform on its own shows nothing
+vec3 deform_position(vec3 position, vec3 normal, vec2 st, float time) {
+    for (int i = 0; i < MAX_DEFORMS; i++) {
+        vec4 info = u_deforms[i * 3];
+        vec4 wave = u_deforms[i * 3 + 1];
+        vec4 vector = u_deforms[i * 3 + 2];
+        if (info.x == 1.0) {
+            position += normal * wave_value(info.y, wave, (position.x + position.y + position.z) * info.z, time);
+        }
+        else if (info.x == 2.0) {
+            position += normal * sin(st.x * vector.x + time * vector.z) * vector.y;
+        }
+        else if (info.x == 3.0) {
+            position += vector.xyz * wave_value(info.y, wave, 0.0, time);
+        }
+        else if (info.x == 4.0) {
+            normal += vec3(deform_noise(position, 0.0, time * vector.y), deform_noise(position, 100.0, time * vector.y), deform_noise(position, 200.0, time * vector.y)) * vector.x;
+            normal = normalize(normal);
+        }
+    }
+    return position;
+}
+
 void main() {
     v_colour = a_colour;
-    v_tex_coords = a_tex_coords;
+    v_tex_coords = animate_tex_coords(a_tex_coords, a_position);
     v_tex_coords_lightmap = a_tex_coords_lightmap;
-    gl_Position = u_view_proj * model * vec4(a_position, 1.0);
+    vec4 position = model * vec4(deform_position(a_position, a_normal, a_tex_coords, u_time.x), 1.0);
+    v_position = position.xyz;
+    gl_Position = u_view_proj * position;
 }
\ No newline at end of file
@@ -0,0 +1,406 @@
+use crate::bsp_shader;
+
+//Time driven shader parameters: animMap, rgbGen wave, tcMod and deformVertexes
+//Everything here only depends on the time passed in so a frame can be reproduced
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WaveFunc {
+    Sin,
+    Triangle,
+    Square,
+    Sawtooth,
+    InverseSawtooth,
+    Noise,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Wave {
+    pub func: WaveFunc,
+    pub base: f32,
+    pub amplitude: f32,
+    pub phase: f32,
+    pub frequency: f32,
+}
+
+impl WaveFunc {
+
+    //Value of the function in the material uniforms, see wave_value in bsp.vert
+    pub fn code(&self) -> f32 {
+        match self {
+            WaveFunc::Sin => 0.0,
+            WaveFunc::Triangle => 1.0,
+            WaveFunc::Square => 2.0,
+            WaveFunc::Sawtooth => 3.0,
+            WaveFunc::InverseSawtooth => 4.0,
+            WaveFunc::Noise => 5.0,
+        }
+    }
+}
+
+impl Wave {
+
+    //func base amplitude phase frequency
+    pub fn parse(tokens: &[String]) -> Option<Wave> {
+
+        if tokens.len() < 5 {
+            return None;
+        }
+        let func = match tokens[0].to_lowercase().as_str() {
+            "sin" => WaveFunc::Sin,
+            "triangle" => WaveFunc::Triangle,
+            "square" => WaveFunc::Square,
+            "sawtooth" => WaveFunc::Sawtooth,
+            "inversesawtooth" => WaveFunc::InverseSawtooth,
+            "noise" => WaveFunc::Noise,
+            _ => return None,
+        };
+        let values = bsp_shader::parse_floats(&tokens[1..5]);
+        if values.len() < 4 {
+            return None;
+        }
+        Some(Wave { func, base: values[0], amplitude: values[1], phase: values[2], frequency: values[3] })
+    }
+
+    pub fn evaluate(&self, time: f32) -> f32 {
+
+        let x = self.phase + time * self.frequency;
+        let t = x - x.floor();
+        let value = match self.func {
+            WaveFunc::Sin => (t * 2.0 * std::f32::consts::PI).sin(),
+            WaveFunc::Triangle => {
+                if t < 0.25 {
+                    t * 4.0
+                }
+                else if t < 0.75 {
+                    2.0 - t * 4.0
+                }
+                else {
+                    t * 4.0 - 4.0
+                }
+            }
+            WaveFunc::Square => if t < 0.5 { 1.0 } else { -1.0 },
+            WaveFunc::Sawtooth => t,
+            WaveFunc::InverseSawtooth => 1.0 - t,
+            WaveFunc::Noise => noise(x),
+        };
+        self.base + value * self.amplitude
+    }
+}
+
+//Smooth value noise in -1..1
+fn noise(x: f32) -> f32 {
+
+    let hash = |i: f32| {
+        let h = (i * 12.9898).sin() * 43758.5453;
+        (h - h.floor()) * 2.0 - 1.0
+    };
+    let i = x.floor();
+    let f = x - i;
+    let f = f * f * (3.0 - 2.0 * f);
+    hash(i) * (1.0 - f) + hash(i + 1.0) * f
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TcMod {
+    Scroll([f32; 2]),
+    Scale([f32; 2]),
+    Rotate(f32),
+    Stretch(Wave),
+    Transform([f32; 6]),
+    Turb(Wave),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimMap {
+    pub frequency: f32,
+    pub frames: Vec<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AnimState {
+    pub frame: usize,
+    //Texture coordinates are transformed by [s t 1] * tc_matrix rows
+    pub tc_matrix: [[f32; 3]; 2],
+    //Amplitude and current phase of tcMod turb, the offset depends on the vertex position
+    pub turb: [f32; 2],
+    pub rgb: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialAnimation {
+    pub anim_map: Option<AnimMap>,
+    pub rgb_wave: Option<Wave>,
+    pub tc_mods: Vec<TcMod>,
+}
+
+impl MaterialAnimation {
+
+    //None when the stage has nothing that changes over time
+    pub fn from_stage(stage: &bsp_shader::Stage) -> Option<MaterialAnimation> {
+
+        let mut animation = MaterialAnimation { anim_map: None, rgb_wave: None, tc_mods: Vec::new() };
+
+        for tokens in stage.directives.iter() {
+            match tokens[0].as_str() {
+                "animmap" => {
+                    if tokens.len() > 2 {
+                        if let Ok(frequency) = tokens[1].parse::<f32>() {
+                            animation.anim_map = Some(AnimMap { frequency, frames: tokens[2..].iter().take(8).cloned().collect() });
+                        }
+                    }
+                }
+                "rgbgen" => {
+                    if tokens.len() > 1 && tokens[1].to_lowercase() == "wave" {
+                        animation.rgb_wave = Wave::parse(&tokens[2..]);
+                    }
+                }
+                "tcmod" => {
+                    if tokens.len() > 1 {
+                        let values = bsp_shader::parse_floats(&tokens[2..]);
+                        let tc_mod = match tokens[1].to_lowercase().as_str() {
+                            "scroll" if values.len() >= 2 => Some(TcMod::Scroll([values[0], values[1]])),
+                            "scale" if values.len() >= 2 => Some(TcMod::Scale([values[0], values[1]])),
+                            "rotate" if values.len() >= 1 => Some(TcMod::Rotate(values[0])),
+                            "transform" if values.len() >= 6 => Some(TcMod::Transform([values[0], values[1], values[2], values[3], values[4], values[5]])),
+                            "stretch" => Wave::parse(&tokens[2..]).map(TcMod::Stretch),
+                            //turb has no function, it is always a sine wave
+                            "turb" if values.len() >= 4 => Some(TcMod::Turb(Wave { func: WaveFunc::Sin, base: values[0], amplitude: values[1], phase: values[2], frequency: values[3] })),
+                            _ => None,
+                        };
+                        if let Some(tc_mod) = tc_mod {
+                            animation.tc_mods.push(tc_mod);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if animation.anim_map.is_none() && animation.rgb_wave.is_none() && animation.tc_mods.is_empty() {
+            None
+        }
+        else {
+            Some(animation)
+        }
+    }
+
+    pub fn evaluate(&self, time: f32) -> AnimState {
+
+        let mut state = AnimState { frame: 0, tc_matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]], turb: [0.0, 0.0], rgb: 1.0 };
+
+        if let Some(anim_map) = self.anim_map.as_ref() {
+            if anim_map.frames.len() > 0 {
+                state.frame = ((time * anim_map.frequency).max(0.0) as usize) % anim_map.frames.len();
+            }
+        }
+
+        if let Some(wave) = self.rgb_wave.as_ref() {
+            state.rgb = wave.evaluate(time).max(0.0).min(1.0);
+        }
+
+        //Each tcMod is applied to the result of the previous one
+        for tc_mod in self.tc_mods.iter() {
+            let m = match tc_mod {
+                TcMod::Scroll(speed) => {
+                    let s = speed[0] * time;
+                    let t = speed[1] * time;
+                    [[1.0, 0.0, s - s.floor()], [0.0, 1.0, t - t.floor()]]
+                }
+                TcMod::Scale(scale) => [[scale[0], 0.0, 0.0], [0.0, scale[1], 0.0]],
+                TcMod::Rotate(degrees_per_second) => {
+                    let (sin, cos) = (-degrees_per_second * time).to_radians().sin_cos();
+                    [[cos, -sin, 0.5 - 0.5 * cos + 0.5 * sin], [sin, cos, 0.5 - 0.5 * sin - 0.5 * cos]]
+                }
+                TcMod::Stretch(wave) => {
+                    let value = wave.evaluate(time);
+                    let p = if value != 0.0 { 1.0 / value } else { 1.0 };
+                    [[p, 0.0, 0.5 - 0.5 * p], [0.0, p, 0.5 - 0.5 * p]]
+                }
+                TcMod::Transform(t) => [[t[0], t[2], t[4]], [t[1], t[3], t[5]]],
+                TcMod::Turb(wave) => {
+                    state.turb = [wave.amplitude, wave.phase + time * wave.frequency];
+                    continue;
+                }
+            };
+            state.tc_matrix = multiply(m, state.tc_matrix);
+        }
+
+        state
+    }
+}
+
+//a after b
+fn multiply(a: [[f32; 3]; 2], b: [[f32; 3]; 2]) -> [[f32; 3]; 2] {
+
+    let mut out = [[0.0; 3]; 2];
+    for row in 0..2 {
+        out[row][0] = a[row][0] * b[0][0] + a[row][1] * b[1][0];
+        out[row][1] = a[row][0] * b[0][1] + a[row][1] * b[1][1];
+        out[row][2] = a[row][0] * b[0][2] + a[row][1] * b[1][2] + a[row][2];
+    }
+    out
+}
+
+//Vertex deforms are evaluated in the vertex shader from the time uniform
+pub const MAX_DEFORMS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deform {
+    Wave { spread: f32, wave: Wave },
+    Bulge { width: f32, height: f32, speed: f32 },
+    Move { vector: [f32; 3], wave: Wave },
+    Normal { amplitude: f32, frequency: f32 },
+}
+
+impl Deform {
+
+    //Tokens of a deformVertexes directive, unsupported deforms such as autosprite are None
+    pub fn parse(tokens: &[String]) -> Option<Deform> {
+
+        if tokens.len() < 2 {
+            return None;
+        }
+        match tokens[1].to_lowercase().as_str() {
+            "wave" if tokens.len() >= 8 => {
+                let div = tokens[2].parse::<f32>().ok()?;
+                let spread = if div != 0.0 { 1.0 / div } else { 100.0 };
+                Some(Deform::Wave { spread, wave: Wave::parse(&tokens[3..])? })
+            }
+            "bulge" => {
+                let values = bsp_shader::parse_floats(&tokens[2..]);
+                if values.len() < 3 {
+                    return None;
+                }
+                Some(Deform::Bulge { width: values[0], height: values[1], speed: values[2] })
+            }
+            "move" if tokens.len() >= 10 => {
+                let values = bsp_shader::parse_floats(&tokens[2..5]);
+                if values.len() < 3 {
+                    return None;
+                }
+                Some(Deform::Move { vector: [values[0], values[1], values[2]], wave: Wave::parse(&tokens[5..])? })
+            }
+            "normal" => {
+                let values = bsp_shader::parse_floats(&tokens[2..]);
+                if values.len() < 2 {
+                    return None;
+                }
+                Some(Deform::Normal { amplitude: values[0], frequency: values[1] })
+            }
+            _ => None,
+        }
+    }
+
+    //Kind and wave function, wave parameters, vector
+    pub fn uniform(&self) -> [[f32; 4]; 3] {
+
+        match self {
+            Deform::Wave { spread, wave } => [[1.0, wave.func.code(), *spread, 0.0], [wave.base, wave.amplitude, wave.phase, wave.frequency], [0.0; 4]],
+            Deform::Bulge { width, height, speed } => [[2.0, 0.0, 0.0, 0.0], [0.0; 4], [*width, *height, *speed, 0.0]],
+            Deform::Move { vector, wave } => [[3.0, wave.func.code(), 0.0, 0.0], [wave.base, wave.amplitude, wave.phase, wave.frequency], [vector[0], vector[1], vector[2], 0.0]],
+            Deform::Normal { amplitude, frequency } => [[4.0, 0.0, 0.0, 0.0], [0.0; 4], [*amplitude, *frequency, 0.0, 0.0]],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wave(func: WaveFunc, phase: f32) -> Wave {
+        Wave { func, base: 0.5, amplitude: 2.0, phase, frequency: 1.0 }
+    }
+
+    fn stage(lines: &[&str]) -> bsp_shader::Stage {
+        bsp_shader::Stage { directives: lines.iter().map(|l| l.split_whitespace().map(|t| t.to_string()).collect()).collect() }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn wave_functions() {
+        assert!(close(wave(WaveFunc::Sin, 0.0).evaluate(0.25), 2.5));
+        assert!(close(wave(WaveFunc::Sin, 0.0).evaluate(0.75), -1.5));
+        assert!(close(wave(WaveFunc::Triangle, 0.0).evaluate(0.25), 2.5));
+        assert!(close(wave(WaveFunc::Triangle, 0.0).evaluate(0.5), 0.5));
+        assert!(close(wave(WaveFunc::Triangle, 0.0).evaluate(0.75), -1.5));
+        assert!(close(wave(WaveFunc::Square, 0.0).evaluate(0.2), 2.5));
+        assert!(close(wave(WaveFunc::Square, 0.0).evaluate(0.7), -1.5));
+        assert!(close(wave(WaveFunc::Sawtooth, 0.0).evaluate(0.25), 1.0));
+        assert!(close(wave(WaveFunc::InverseSawtooth, 0.0).evaluate(0.25), 2.0));
+    }
+
+    #[test]
+    fn wave_phase_and_frequency() {
+        //Whole periods of phase change nothing
+        assert!(close(wave(WaveFunc::Sawtooth, 1.25).evaluate(3.0), wave(WaveFunc::Sawtooth, 0.0).evaluate(0.25)));
+        let fast = Wave { frequency: 4.0, ..wave(WaveFunc::Sawtooth, 0.0) };
+        assert!(close(fast.evaluate(0.0625), 1.0));
+        //Noise is repeatable and stays in range
+        let noise = wave(WaveFunc::Noise, 0.0);
+        for i in 0..100 {
+            let t = i as f32 * 0.137;
+            assert_eq!(noise.evaluate(t), noise.evaluate(t));
+            assert!(noise.evaluate(t) >= -1.5 && noise.evaluate(t) <= 2.5);
+        }
+    }
+
+    #[test]
+    fn parse_wave() {
+        let tokens = ["inverseSawtooth", "0", "1", "0.5", "2"].iter().map(|t| t.to_string()).collect::<Vec<String>>();
+        assert_eq!(Wave::parse(&tokens), Some(Wave { func: WaveFunc::InverseSawtooth, base: 0.0, amplitude: 1.0, phase: 0.5, frequency: 2.0 }));
+        assert_eq!(Wave::parse(&tokens[..4]), None);
+    }
+
+    #[test]
+    fn static_stage() {
+        assert_eq!(MaterialAnimation::from_stage(&stage(&["map textures/base/wall.tga", "blendfunc add"])), None);
+    }
+
+    #[test]
+    fn anim_map_frames() {
+        let animation = MaterialAnimation::from_stage(&stage(&["animmap 2 a.tga b.tga c.tga"])).unwrap();
+        assert_eq!(animation.evaluate(0.0).frame, 0);
+        assert_eq!(animation.evaluate(0.6).frame, 1);
+        assert_eq!(animation.evaluate(1.1).frame, 2);
+        assert_eq!(animation.evaluate(1.6).frame, 0);
+        assert_eq!(animation.evaluate(-1.0).frame, 0);
+    }
+
+    #[test]
+    fn rgb_wave_is_clamped() {
+        let animation = MaterialAnimation::from_stage(&stage(&["rgbgen wave sin 0.5 2 0 1"])).unwrap();
+        assert_eq!(animation.evaluate(0.25).rgb, 1.0);
+        assert_eq!(animation.evaluate(0.75).rgb, 0.0);
+        assert!(close(animation.evaluate(0.0).rgb, 0.5));
+    }
+
+    fn apply(m: [[f32; 3]; 2], st: [f32; 2]) -> [f32; 2] {
+        [m[0][0] * st[0] + m[0][1] * st[1] + m[0][2], m[1][0] * st[0] + m[1][1] * st[1] + m[1][2]]
+    }
+
+    #[test]
+    fn tc_mods() {
+        //Scroll wraps to keep the offset small
+        let scroll = MaterialAnimation::from_stage(&stage(&["tcmod scroll 0.75 -0.5"])).unwrap().evaluate(3.0);
+        assert!(close(scroll.tc_matrix[0][2], 0.25) && close(scroll.tc_matrix[1][2], 0.5));
+
+        //Rotation turns around the middle of the texture
+        let rotate = MaterialAnimation::from_stage(&stage(&["tcmod rotate 90"])).unwrap().evaluate(1.0);
+        let middle = apply(rotate.tc_matrix, [0.5, 0.5]);
+        assert!(close(middle[0], 0.5) && close(middle[1], 0.5));
+        let corner = apply(rotate.tc_matrix, [0.0, 0.0]);
+        assert!(close(corner[0], 0.0) && close(corner[1], 1.0));
+
+        //Later tcMods apply to the result of earlier ones
+        let chained = MaterialAnimation::from_stage(&stage(&["tcmod scale 2 3", "tcmod scroll 0.5 0"])).unwrap().evaluate(1.0);
+        let st = apply(chained.tc_matrix, [0.25, 0.25]);
+        assert!(close(st[0], 1.0) && close(st[1], 0.75));
+
+        let turb = MaterialAnimation::from_stage(&stage(&["tcmod turb 0 0.25 0.5 2"])).unwrap().evaluate(1.0);
+        assert_eq!(turb.turb, [0.25, 2.5]);
+        assert_eq!(turb.tc_matrix, [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,41 @@
+//Area portals, Quake 3 has no lump for them. Leaves are split into areas by areaportal brushes
+//and a door that touches two areas joins them while it is open
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AreaPortal {
+    pub areas: [usize; 2],
+    //Door entity in the entity lump
+    pub entity: usize,
+    pub open: bool,
+}
+
+//Areas reachable from start through open portals, everything is connected when the viewer is outside the map
+pub fn connected_areas(num_areas: usize, portals: &Vec<AreaPortal>, start: Option<usize>) -> Vec<bool> {
+
+    let start = match start {
+        Some(start) if start < num_areas => start,
+        _ => return vec![true; num_areas],
+    };
+
+    let mut connected = vec![false; num_areas];
+    let mut stack = vec![start];
+    connected[start] = true;
+    while let Some(area) = stack.pop() {
+        for portal in portals.iter().filter(|p| p.open) {
+            let other = if portal.areas[0] == area {
+                portal.areas[1]
+            }
+            else if portal.areas[1] == area {
+                portal.areas[0]
+            }
+            else {
+                continue;
+            };
+            if other < num_areas && !connected[other] {
+                connected[other] = true;
+                stack.push(other);
+            }
+        }
+    }
+    connected
+}
\ No newline at end of file
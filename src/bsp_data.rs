@@ -0,0 +1,383 @@
+use crate::bsp::{Texture, Plane, Node, Leaf, LeafFace, LeafBrush, Model, Brush, BrushSide, Vertex, MeshVert, Effect, Face, LightMap, LightVol};
+use crate::bsp::{RavenVertex, RavenFace, RavenBrushSide, RavenLightVol};
+
+//Every lump of a bsp file kept as it is on disk, so a map can be read, changed and written back
+//http://www.mralligator.com/q3/
+
+//Quake 3
+pub const Q3_VERSION: i32 = 46;
+//Quake Live, Return to Castle Wolfenstein and Enemy Territory use the same records as Quake 3,
+//Quake Live adds an advertisement lump after the others
+pub const IBSP_47_VERSION: i32 = 47;
+//Raven maps have RBSP magic, bigger vertex, face, brush side and light grid records and a light array lump after the others
+pub const RBSP_VERSION: i32 = 1;
+
+const NUM_LUMPS: usize = 17;
+const HEADER_SIZE: usize = 8 + NUM_LUMPS * 8;
+
+#[derive(Clone)]
+pub struct BspData {
+    pub version: i32,
+    //The entity text including its terminating zero, one char per byte
+    pub entities: String,
+    pub textures: Vec<Texture>,
+    pub planes: Vec<Plane>,
+    pub nodes: Vec<Node>,
+    pub leafs: Vec<Leaf>,
+    pub leaf_faces: Vec<LeafFace>,
+    pub leaf_brushes: Vec<LeafBrush>,
+    pub models: Vec<Model>,
+    pub brushes: Vec<Brush>,
+    pub brush_sides: Vec<BrushSide>,
+    pub vertexes: Vec<Vertex>,
+    pub mesh_verts: Vec<MeshVert>,
+    pub effects: Vec<Effect>,
+    pub faces: Vec<Face>,
+    pub light_maps: Vec<LightMap>,
+    pub light_vols: Vec<LightVol>,
+    //Cluster count, bytes per cluster and the bit vectors, left as raw bytes
+    pub vis_data: Vec<u8>,
+    //Quake Live only, kept as raw bytes so it is written back
+    pub advertisements: Option<Vec<u8>>,
+    pub raven: Option<RavenLumps>,
+}
+
+//The records of a Raven map that differ from Quake 3, these are what gets written back.
+//The Quake 3 brush sides, vertexes, faces and light vols of the BspData are made from them with one lightmap style
+#[derive(Clone)]
+pub struct RavenLumps {
+    pub brush_sides: Vec<RavenBrushSide>,
+    pub vertexes: Vec<RavenVertex>,
+    pub faces: Vec<RavenFace>,
+    pub light_grid: Vec<RavenLightVol>,
+    pub light_array: Vec<u16>,
+}
+
+impl RavenLumps {
+
+    fn read(lumps: &Vec<&[u8]>) -> Self {
+        RavenLumps {
+            brush_sides: read_lump(lumps[9]),
+            vertexes: read_lump(lumps[10]),
+            faces: read_lump(lumps[13]),
+            light_grid: read_lump(lumps[15]),
+            light_array: read_lump(lumps[17]),
+        }
+    }
+
+    pub fn light_vols(&self, style: usize) -> Vec<LightVol> {
+        self.light_array.iter().map(|i| self.light_grid.get(*i as usize).map_or(bytemuck::Zeroable::zeroed(), |l| l.to_light_vol(style))).collect()
+    }
+}
+
+impl BspData {
+
+    pub fn read(bytes: &[u8]) -> Result<BspData, String> {
+
+        if bytes.len() < HEADER_SIZE || (&bytes[0..4] != b"IBSP" && &bytes[0..4] != b"RBSP") {
+            return Err("not an IBSP or RBSP file".to_string());
+        }
+        let is_raven = &bytes[0..4] == b"RBSP";
+        let version = read_i32(bytes, 4);
+        if is_raven && version != RBSP_VERSION {
+            return Err(format!("unsupported RBSP version {}, only {} (Jedi Knight 2, Jedi Academy, SoF2) can be loaded", version, RBSP_VERSION));
+        }
+        if !is_raven && version != Q3_VERSION && version != IBSP_47_VERSION {
+            return Err(format!("unsupported IBSP version {}, only {} (Quake 3) and {} (Quake Live, RTCW, ET) can be loaded", version, Q3_VERSION, IBSP_47_VERSION));
+        }
+
+        let mut lumps: Vec<&[u8]> = Vec::new();
+        for i in 0..(if is_raven { NUM_LUMPS + 1 } else { NUM_LUMPS }) {
+            match read_lump_bytes(bytes, i) {
+                Some(lump) => lumps.push(lump),
+                None => return Err(format!("lump {} is outside the file", i)),
+            }
+        }
+
+        //RTCW and ET have no 18th header entry, their first lump starts where it would be
+        let first_lump = (0..NUM_LUMPS).filter(|i| read_i32(bytes, 12 + i * 8) > 0).map(|i| read_i32(bytes, 8 + i * 8) as usize).min().unwrap_or(bytes.len());
+        let advertisements = if !is_raven && version == IBSP_47_VERSION && first_lump >= HEADER_SIZE + 8 {
+            read_lump_bytes(bytes, NUM_LUMPS).map(|lump| lump.to_vec())
+        }
+        else {
+            None
+        };
+
+        let raven = if is_raven { Some(RavenLumps::read(&lumps)) } else { None };
+
+        let mut data = BspData {
+            version,
+            entities: lumps[0].iter().map(|b| *b as char).collect(),
+            textures: read_lump(lumps[1]),
+            planes: read_lump(lumps[2]),
+            nodes: read_lump(lumps[3]),
+            leafs: read_lump(lumps[4]),
+            leaf_faces: read_lump(lumps[5]),
+            leaf_brushes: read_lump(lumps[6]),
+            models: read_lump(lumps[7]),
+            brushes: read_lump(lumps[8]),
+            brush_sides: read_lump(lumps[9]),
+            vertexes: read_lump(lumps[10]),
+            mesh_verts: read_lump(lumps[11]),
+            effects: read_lump(lumps[12]),
+            faces: read_lump(lumps[13]),
+            light_maps: read_lump(lumps[14]),
+            light_vols: read_lump(lumps[15]),
+            vis_data: lumps[16].to_vec(),
+            advertisements,
+            raven,
+        };
+        data.set_light_style(0);
+        Ok(data)
+    }
+
+    //Remakes the Quake 3 records of a Raven map for one of its four lightmap styles, nothing changes for other maps
+    pub fn set_light_style(&mut self, style: usize) {
+
+        if let Some(raven) = self.raven.as_ref() {
+            self.brush_sides = raven.brush_sides.iter().map(|b| b.to_brush_side()).collect();
+            self.vertexes = raven.vertexes.iter().map(|v| v.to_vertex(style)).collect();
+            self.faces = raven.faces.iter().map(|f| f.to_face(style)).collect();
+            self.light_vols = raven.light_vols(style);
+        }
+    }
+
+    //Lumps go in header order after the header, each starting on a 4 byte boundary like q3map writes them
+    pub fn write(&self) -> Vec<u8> {
+
+        let entities = self.entities.chars().map(|c| c as u8).collect::<Vec<u8>>();
+        let (brush_sides, vertexes, faces, light_vols): (&[u8], &[u8], &[u8], &[u8]) = match self.raven.as_ref() {
+            Some(raven) => (bytemuck::cast_slice(&raven.brush_sides), bytemuck::cast_slice(&raven.vertexes), bytemuck::cast_slice(&raven.faces), bytemuck::cast_slice(&raven.light_grid)),
+            None => (bytemuck::cast_slice(&self.brush_sides), bytemuck::cast_slice(&self.vertexes), bytemuck::cast_slice(&self.faces), bytemuck::cast_slice(&self.light_vols)),
+        };
+        let mut lumps: Vec<&[u8]> = vec![
+            &entities,
+            bytemuck::cast_slice(&self.textures),
+            bytemuck::cast_slice(&self.planes),
+            bytemuck::cast_slice(&self.nodes),
+            bytemuck::cast_slice(&self.leafs),
+            bytemuck::cast_slice(&self.leaf_faces),
+            bytemuck::cast_slice(&self.leaf_brushes),
+            bytemuck::cast_slice(&self.models),
+            bytemuck::cast_slice(&self.brushes),
+            brush_sides,
+            vertexes,
+            bytemuck::cast_slice(&self.mesh_verts),
+            bytemuck::cast_slice(&self.effects),
+            faces,
+            bytemuck::cast_slice(&self.light_maps),
+            light_vols,
+            &self.vis_data,
+        ];
+        if let Some(raven) = self.raven.as_ref() {
+            lumps.push(bytemuck::cast_slice(&raven.light_array));
+        }
+        if let Some(advertisements) = self.advertisements.as_ref() {
+            lumps.push(advertisements);
+        }
+
+        let mut bytes = vec![0u8; 8 + lumps.len() * 8];
+        bytes[0..4].copy_from_slice(if self.raven.is_some() { b"RBSP" } else { b"IBSP" });
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        for (i, lump) in lumps.iter().enumerate() {
+            while bytes.len() % 4 != 0 {
+                bytes.push(0);
+            }
+            let offset = bytes.len() as i32;
+            bytes[8 + i * 8..12 + i * 8].copy_from_slice(&offset.to_le_bytes());
+            bytes[12 + i * 8..16 + i * 8].copy_from_slice(&(lump.len() as i32).to_le_bytes());
+            bytes.extend_from_slice(lump);
+        }
+        bytes
+    }
+
+    //The lump is zero terminated, a .ent file is not
+    pub fn set_entity_text(&mut self, text: &str) {
+        self.entities = text.trim_end_matches('\0').to_string();
+        self.entities.push('\0');
+    }
+
+    pub fn entity_text(&self) -> &str {
+        self.entities.trim_end_matches('\0')
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, self.write())
+    }
+
+    pub fn game(&self) -> &str {
+        match (self.version, self.advertisements.is_some()) {
+            _ if self.raven.is_some() => "Jedi Knight or SoF2",
+            (Q3_VERSION, _) => "Quake 3",
+            (_, true) => "Quake Live",
+            _ => "RTCW or ET",
+        }
+    }
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_lump_bytes(bytes: &[u8], lump: usize) -> Option<&[u8]> {
+
+    if bytes.len() < 16 + lump * 8 {
+        return None;
+    }
+    let offset = read_i32(bytes, 8 + lump * 8);
+    let length = read_i32(bytes, 12 + lump * 8);
+    if offset < 0 || length < 0 || offset as usize + length as usize > bytes.len() {
+        return None;
+    }
+    Some(&bytes[offset as usize..(offset + length) as usize])
+}
+
+//Copied into a vec of the lump type so the file bytes do not have to be aligned, a partial entry at the end is dropped
+fn read_lump<T: bytemuck::Pod>(bytes: &[u8]) -> Vec<T> {
+
+    let size = std::mem::size_of::<T>();
+    let count = bytes.len() / size;
+    let mut items = vec![T::zeroed(); count];
+    bytemuck::cast_slice_mut::<T, u8>(&mut items).copy_from_slice(&bytes[..count * size]);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Records filled with a byte pattern so every byte of every lump is checked
+    fn records<T: bytemuck::Pod>(count: usize, seed: u8) -> Vec<T> {
+        let mut items = vec![T::zeroed(); count];
+        for (i, b) in bytemuck::cast_slice_mut::<T, u8>(&mut items).iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(31).wrapping_add(seed);
+        }
+        items
+    }
+
+    fn bytes<T: bytemuck::Pod>(items: &Vec<T>) -> Vec<u8> {
+        bytemuck::cast_slice::<T, u8>(items).to_vec()
+    }
+
+    fn ibsp(version: i32) -> BspData {
+        BspData {
+            version,
+            entities: "{\n\"classname\" \"worldspawn\"\n}\n\0".to_string(),
+            textures: records(2, 1),
+            planes: records(3, 2),
+            nodes: records(2, 3),
+            leafs: records(3, 4),
+            leaf_faces: records(4, 5),
+            leaf_brushes: records(1, 6),
+            models: records(2, 7),
+            brushes: records(2, 8),
+            brush_sides: records(5, 9),
+            vertexes: records(6, 10),
+            mesh_verts: records(6, 11),
+            effects: records(1, 12),
+            faces: records(2, 13),
+            light_maps: records(1, 14),
+            light_vols: records(7, 15),
+            vis_data: vec![1, 0, 0, 0, 1, 0, 0, 0, 0xff],
+            advertisements: None,
+            raven: None,
+        }
+    }
+
+    fn assert_same_lumps(a: &BspData, b: &BspData) {
+        assert_eq!(a.version, b.version);
+        assert_eq!(a.entities, b.entities);
+        assert_eq!(bytes(&a.textures), bytes(&b.textures));
+        assert_eq!(bytes(&a.planes), bytes(&b.planes));
+        assert_eq!(bytes(&a.nodes), bytes(&b.nodes));
+        assert_eq!(bytes(&a.leafs), bytes(&b.leafs));
+        assert_eq!(bytes(&a.leaf_faces), bytes(&b.leaf_faces));
+        assert_eq!(bytes(&a.leaf_brushes), bytes(&b.leaf_brushes));
+        assert_eq!(bytes(&a.models), bytes(&b.models));
+        assert_eq!(bytes(&a.brushes), bytes(&b.brushes));
+        assert_eq!(bytes(&a.brush_sides), bytes(&b.brush_sides));
+        assert_eq!(bytes(&a.vertexes), bytes(&b.vertexes));
+        assert_eq!(bytes(&a.mesh_verts), bytes(&b.mesh_verts));
+        assert_eq!(bytes(&a.effects), bytes(&b.effects));
+        assert_eq!(bytes(&a.faces), bytes(&b.faces));
+        assert_eq!(bytes(&a.light_maps), bytes(&b.light_maps));
+        assert_eq!(bytes(&a.light_vols), bytes(&b.light_vols));
+        assert_eq!(a.vis_data, b.vis_data);
+        assert_eq!(a.advertisements, b.advertisements);
+    }
+
+    #[test]
+    fn quake3_round_trip() {
+        let data = ibsp(Q3_VERSION);
+        let written = data.write();
+        assert_eq!(&written[0..4], b"IBSP");
+        let read = BspData::read(&written).unwrap();
+        assert_same_lumps(&data, &read);
+        assert_eq!(read.write(), written);
+        assert_eq!(read.game(), "Quake 3");
+        assert_eq!(read.entity_text(), "{\n\"classname\" \"worldspawn\"\n}\n");
+    }
+
+    #[test]
+    fn advertisements_round_trip() {
+        let mut data = ibsp(IBSP_47_VERSION);
+        data.advertisements = Some((0..40).collect());
+        let written = data.write();
+        let read = BspData::read(&written).unwrap();
+        assert_same_lumps(&data, &read);
+        assert_eq!(read.write(), written);
+        assert_eq!(read.game(), "Quake Live");
+
+        //Without the lump a version 47 map is from RTCW or ET
+        let data = ibsp(IBSP_47_VERSION);
+        let read = BspData::read(&data.write()).unwrap();
+        assert_eq!(read.advertisements, None);
+        assert_eq!(read.game(), "RTCW or ET");
+    }
+
+    #[test]
+    fn raven_round_trip() {
+        let mut data = ibsp(RBSP_VERSION);
+        let raven = RavenLumps { brush_sides: records(5, 20), vertexes: records(6, 21), faces: records(2, 22), light_grid: records(3, 23), light_array: vec![2, 0, 1, 1, 9] };
+        data.raven = Some(raven.clone());
+        let written = data.write();
+        assert_eq!(&written[0..4], b"RBSP");
+
+        let read = BspData::read(&written).unwrap();
+        assert_eq!(read.write(), written);
+        let read_raven = read.raven.as_ref().unwrap();
+        assert_eq!(bytes(&read_raven.brush_sides), bytes(&raven.brush_sides));
+        assert_eq!(bytes(&read_raven.vertexes), bytes(&raven.vertexes));
+        assert_eq!(bytes(&read_raven.faces), bytes(&raven.faces));
+        assert_eq!(bytes(&read_raven.light_grid), bytes(&raven.light_grid));
+        assert_eq!(read_raven.light_array, raven.light_array);
+        assert_eq!(bytes(&read.models), bytes(&data.models));
+        //The Quake 3 records are made from the Raven ones, an index past the light grid is black
+        assert_eq!((read.brush_sides.len(), read.vertexes.len(), read.faces.len(), read.light_vols.len()), (5, 6, 2, 5));
+        assert_eq!(bytes(&vec![read.light_vols[4]]), vec![0; std::mem::size_of::<LightVol>()]);
+        assert_eq!(read.game(), "Jedi Knight or SoF2");
+
+        //Changing the style only changes what is made from the Raven records
+        let mut styled = read.clone();
+        styled.set_light_style(1);
+        assert_eq!(styled.write(), written);
+    }
+
+    #[test]
+    fn bad_files() {
+        let written = ibsp(Q3_VERSION).write();
+        assert!(BspData::read(&written[..HEADER_SIZE - 1]).is_err());
+
+        let mut magic = written.clone();
+        magic[0..4].copy_from_slice(b"VBSP");
+        assert!(BspData::read(&magic).is_err());
+
+        let mut version = written.clone();
+        version[4..8].copy_from_slice(&45i32.to_le_bytes());
+        assert!(BspData::read(&version).err().unwrap().contains("45"));
+
+        let mut outside = written.clone();
+        outside[12 + 16 * 8..16 + 16 * 8].copy_from_slice(&(written.len() as i32).to_le_bytes());
+        assert_eq!(BspData::read(&outside).err(), Some("lump 16 is outside the file".to_string()));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,118 @@
+//Per frame draw lists, the visible faces are grouped by lightmap and texture into one compact index list
+
+//Triangles of one face, faces that are not drawn this way have no indices
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceMesh {
+    pub lightmap: usize,
+    pub texture: usize,
+    pub indices: Vec<u32>,
+}
+
+impl FaceMesh {
+
+    pub fn empty() -> Self {
+        FaceMesh { lightmap: 0, texture: 0, indices: Vec::new() }
+    }
+}
+
+//A range of the draw list indices sharing the same lightmap and texture
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DrawBatch {
+    pub lightmap: usize,
+    pub texture: usize,
+    pub first_index: u32,
+    pub num_indices: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawList {
+    pub indices: Vec<u32>,
+    pub batches: Vec<DrawBatch>,
+}
+
+//faces can hold the same face more than once, as faces are shared between leaves
+pub fn build_draw_list(meshes: &Vec<FaceMesh>, faces: &[usize]) -> DrawList {
+
+    let mut seen = vec![false; meshes.len()];
+    let mut unique: Vec<usize> = Vec::new();
+    for face in faces.iter() {
+        if *face < meshes.len() && !seen[*face] && !meshes[*face].indices.is_empty() {
+            seen[*face] = true;
+            unique.push(*face);
+        }
+    }
+    unique.sort_by_key(|f| (meshes[*f].lightmap, meshes[*f].texture, *f));
+
+    let mut list = DrawList { indices: Vec::new(), batches: Vec::new() };
+    for face in unique.iter() {
+        let mesh = &meshes[*face];
+        let first_index = list.indices.len() as u32;
+        list.indices.extend(mesh.indices.iter());
+
+        match list.batches.last_mut() {
+            Some(batch) if batch.lightmap == mesh.lightmap && batch.texture == mesh.texture => {
+                batch.num_indices += mesh.indices.len() as u32;
+            }
+            _ => list.batches.push(DrawBatch { lightmap: mesh.lightmap, texture: mesh.texture, first_index, num_indices: mesh.indices.len() as u32 }),
+        }
+    }
+    list
+}
+
+//Visible face indices from a mask
+pub fn faces_from_mask(visible: &Vec<bool>) -> Vec<usize> {
+    visible.iter().enumerate().filter(|(_, v)| **v).map(|(i, _)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(lightmap: usize, texture: usize, indices: &[u32]) -> FaceMesh {
+        FaceMesh { lightmap, texture, indices: indices.to_vec() }
+    }
+
+    #[test]
+    fn duplicate_faces() {
+        let meshes = vec![mesh(0, 0, &[0, 1, 2]), mesh(0, 0, &[3, 4, 5])];
+        let list = build_draw_list(&meshes, &[1, 0, 1, 1, 0]);
+        assert_eq!(list.indices, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(list.batches, vec![DrawBatch { lightmap: 0, texture: 0, first_index: 0, num_indices: 6 }]);
+    }
+
+    #[test]
+    fn empty_meshes() {
+        let meshes = vec![FaceMesh::empty(), mesh(1, 2, &[7, 8, 9]), FaceMesh::empty()];
+        let list = build_draw_list(&meshes, &[0, 1, 2, 5]);
+        assert_eq!(list.indices, vec![7, 8, 9]);
+        assert_eq!(list.batches, vec![DrawBatch { lightmap: 1, texture: 2, first_index: 0, num_indices: 3 }]);
+
+        let list = build_draw_list(&meshes, &[0, 2]);
+        assert!(list.indices.is_empty() && list.batches.is_empty());
+        assert_eq!(build_draw_list(&Vec::new(), &[]), DrawList { indices: Vec::new(), batches: Vec::new() });
+    }
+
+    #[test]
+    fn batch_merging() {
+        //Faces with the same lightmap and texture end up next to each other whatever order they are visible in
+        let meshes = vec![
+            mesh(1, 0, &[0, 1, 2]),
+            mesh(0, 3, &[3, 4, 5]),
+            mesh(1, 0, &[6, 7, 8, 9, 10, 11]),
+            mesh(0, 3, &[12, 13, 14]),
+            mesh(0, 1, &[15, 16, 17]),
+        ];
+        let list = build_draw_list(&meshes, &[3, 2, 1, 0, 4]);
+        assert_eq!(list.indices, vec![15, 16, 17, 3, 4, 5, 12, 13, 14, 0, 1, 2, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(list.batches, vec![
+            DrawBatch { lightmap: 0, texture: 1, first_index: 0, num_indices: 3 },
+            DrawBatch { lightmap: 0, texture: 3, first_index: 3, num_indices: 6 },
+            DrawBatch { lightmap: 1, texture: 0, first_index: 9, num_indices: 9 },
+        ]);
+    }
+
+    #[test]
+    fn mask_to_faces() {
+        assert_eq!(faces_from_mask(&vec![false, true, true, false, true]), vec![1, 2, 4]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,296 @@
+//Entity lump, a list of { "key" "value" } blocks
+//Keys keep the order they were written in
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entity {
+    pub properties: Vec<(String, String)>,
+}
+
+impl Entity {
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+
+    //Replaces the value in place so the key order is kept, new keys go at the end
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.properties.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some(property) => property.1 = value.to_string(),
+            None => self.properties.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.properties.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    pub fn class_name(&self) -> &str {
+        self.get("classname").unwrap_or("")
+    }
+
+    pub fn vector(&self, key: &str) -> Option<[f32; 3]> {
+
+        let values = self.get(key)?.split_whitespace().filter_map(|v| v.parse::<f32>().ok()).collect::<Vec<f32>>();
+        if values.len() < 3 {
+            return None;
+        }
+        Some([values[0], values[1], values[2]])
+    }
+
+    pub fn float(&self, key: &str) -> Option<f32> {
+        self.get(key)?.trim().parse::<f32>().ok()
+    }
+
+    pub fn origin(&self) -> Option<[f32; 3]> {
+        self.vector("origin")
+    }
+
+    //Pitch, yaw and roll in degrees from "angles" or the yaw only "angle"
+    pub fn angles(&self) -> Option<[f32; 3]> {
+        match self.vector("angles") {
+            Some(angles) => Some(angles),
+            None => self.float("angle").map(|yaw| [0.0, yaw, 0.0]),
+        }
+    }
+}
+
+//Quoted strings between braces, anything else is ignored
+pub fn parse_entities(text: &str) -> Vec<Entity> {
+
+    let mut entities: Vec<Entity> = Vec::new();
+    let mut current: Option<Entity> = None;
+    let mut key: Option<String> = None;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                current = Some(Entity { properties: Vec::new() });
+                key = None;
+            }
+            '}' => {
+                if let Some(entity) = current.take() {
+                    entities.push(entity);
+                }
+            }
+            '"' => {
+                let mut token = String::new();
+                while let Some(c) = chars.next() {
+                    if c == '"' {
+                        break;
+                    }
+                    token.push(c);
+                }
+                if let Some(entity) = current.as_mut() {
+                    match key.take() {
+                        Some(k) => entity.properties.push((k, token)),
+                        None => key = Some(token),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    entities
+}
+
+//The same layout q3map writes, also used for .ent files.
+//The lump has no escapes, so a key or value with a quote in it can not be written
+pub fn entities_to_string(entities: &Vec<Entity>) -> Result<String, String> {
+
+    let mut text = String::new();
+    for entity in entities.iter() {
+        text.push_str("{\n");
+        for (key, value) in entity.properties.iter() {
+            if key.contains('"') || value.contains('"') {
+                return Err(format!("Can not write the entity key {} with a quote in it or its value", key));
+            }
+            text.push_str(&format!("\"{}\" \"{}\"\n", key, value));
+        }
+        text.push_str("}\n");
+    }
+    Ok(text)
+}
+
+//Changes to the entity lump, entities are picked by their index in the lump as it was loaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityEdit {
+    Add(Entity),
+    Remove(usize),
+    Set(usize, String, String),
+    Unset(usize, String),
+}
+
+//One edit per line, values with spaces are quoted and lines starting with // are comments
+//  add "classname" "item_armor_body" "origin" "0 0 64"
+//  remove 12
+//  set 0 "music" "music/fla22k_02.wav"
+//  unset 3 "target"
+pub fn parse_edits(text: &str) -> Vec<EntityEdit> {
+
+    let mut edits: Vec<EntityEdit> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let tokens = tokenize(line);
+        if tokens.is_empty() || line.trim_start().starts_with("//") {
+            continue;
+        }
+        let index = tokens.get(1).and_then(|t| t.parse::<usize>().ok());
+        let edit = match (tokens[0].to_lowercase().as_str(), index) {
+            ("add", _) if tokens.len() >= 3 && tokens.len() % 2 == 1 => Some(EntityEdit::Add(Entity {
+                properties: tokens[1..].chunks(2).map(|kv| (kv[0].clone(), kv[1].clone())).collect(),
+            })),
+            ("remove", Some(i)) if tokens.len() == 2 => Some(EntityEdit::Remove(i)),
+            ("set", Some(i)) if tokens.len() == 4 => Some(EntityEdit::Set(i, tokens[2].clone(), tokens[3].clone())),
+            ("unset", Some(i)) if tokens.len() == 3 => Some(EntityEdit::Unset(i, tokens[2].clone())),
+            _ => None,
+        };
+        match edit {
+            Some(edit) => edits.push(edit),
+            None => println!("Bad entity edit on line {}: {}", n + 1, line.trim()),
+        }
+    }
+    edits
+}
+
+//Removed entities stay removed, edits to them and to indices past the end are skipped
+pub fn apply_edits(entities: &Vec<Entity>, edits: &Vec<EntityEdit>) -> Vec<Entity> {
+
+    let mut slots = entities.iter().cloned().map(Some).collect::<Vec<Option<Entity>>>();
+    let mut added: Vec<Entity> = Vec::new();
+    for edit in edits.iter() {
+        match edit {
+            EntityEdit::Add(entity) => added.push(entity.clone()),
+            EntityEdit::Remove(i) => {
+                if let Some(slot) = slots.get_mut(*i) {
+                    *slot = None;
+                }
+            }
+            EntityEdit::Set(i, key, value) => {
+                if let Some(Some(entity)) = slots.get_mut(*i) {
+                    entity.set(key, value);
+                }
+            }
+            EntityEdit::Unset(i, key) => {
+                if let Some(Some(entity)) = slots.get_mut(*i) {
+                    entity.remove(key);
+                }
+            }
+        }
+    }
+    slots.into_iter().flatten().chain(added.into_iter()).collect()
+}
+
+fn tokenize(line: &str) -> Vec<String> {
+
+    let mut tokens: Vec<String> = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                if c == '"' {
+                    break;
+                }
+                token.push(c);
+            }
+            tokens.push(token);
+        }
+        else if !c.is_whitespace() {
+            let mut token = c.to_string();
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() || *c == '"' {
+                    break;
+                }
+                token.push(*c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(pairs: &[(&str, &str)]) -> Entity {
+        Entity { properties: pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    const LUMP: &str = "{\n\"classname\" \"worldspawn\"\n\"message\" \"The Place\"\n}\n{\n\"origin\" \"0 64 -8\"\n\"classname\" \"info_player_deathmatch\"\n\"angle\" \"90\"\n}\n{\n\"classname\" \"item_armor_body\"\n\"origin\" \"16 0 0\"\n}\n\0";
+
+    #[test]
+    fn parse() {
+        let entities = parse_entities(LUMP);
+        assert_eq!(entities.len(), 3);
+        assert_eq!(entities[0], entity(&[("classname", "worldspawn"), ("message", "The Place")]));
+        //Keys keep their order and are found without case
+        assert_eq!(entities[1].properties[0].0, "origin");
+        assert_eq!(entities[1].get("ClassName"), Some("info_player_deathmatch"));
+        assert_eq!(entities[1].origin(), Some([0.0, 64.0, -8.0]));
+        assert_eq!(entities[1].angles(), Some([0.0, 90.0, 0.0]));
+        assert_eq!(entities[2].angles(), None);
+        //Text outside braces and an unterminated entity are dropped
+        assert_eq!(parse_entities("junk \"key\" \"value\" { \"a\" \"b\" } { \"c\" \"d\""), vec![entity(&[("a", "b")])]);
+    }
+
+    #[test]
+    fn set_and_remove() {
+        let mut e = entity(&[("classname", "light"), ("light", "300"), ("origin", "0 0 0")]);
+        e.set("LIGHT", "500");
+        e.set("color", "1 0 0");
+        assert_eq!(e, entity(&[("classname", "light"), ("light", "500"), ("origin", "0 0 0"), ("color", "1 0 0")]));
+        e.remove("Origin");
+        assert_eq!(e.origin(), None);
+        assert_eq!(e.properties.len(), 3);
+    }
+
+    #[test]
+    fn edits() {
+        let text = "// comment\n\nadd \"classname\" \"item_health\" origin \"0 0 64\"\nremove 12\nset 0 music \"music/fla22k_02.wav\"\nunset 3 \"target\"\n";
+        assert_eq!(parse_edits(text), vec![
+            EntityEdit::Add(entity(&[("classname", "item_health"), ("origin", "0 0 64")])),
+            EntityEdit::Remove(12),
+            EntityEdit::Set(0, "music".to_string(), "music/fla22k_02.wav".to_string()),
+            EntityEdit::Unset(3, "target".to_string()),
+        ]);
+        //A bare add, a key without a value, missing or bad indices and unknown commands are skipped
+        let bad = "add\nadd \"classname\"\nadd classname light origin\nremove\nremove x\nremove 1 2\nset 0 music\nunset 3\nmove 1 2\n";
+        assert!(parse_edits(bad).is_empty());
+    }
+
+    #[test]
+    fn apply() {
+        let entities = parse_entities(LUMP);
+        let edits = vec![
+            EntityEdit::Remove(1),
+            EntityEdit::Set(1, "angle".to_string(), "180".to_string()),
+            EntityEdit::Set(2, "origin".to_string(), "32 0 0".to_string()),
+            EntityEdit::Unset(0, "message".to_string()),
+            EntityEdit::Add(entity(&[("classname", "item_health")])),
+            EntityEdit::Set(7, "origin".to_string(), "0 0 0".to_string()),
+            EntityEdit::Remove(9),
+        ];
+        let edited = apply_edits(&entities, &edits);
+        assert_eq!(edited, vec![
+            entity(&[("classname", "worldspawn")]),
+            entity(&[("classname", "item_armor_body"), ("origin", "32 0 0")]),
+            entity(&[("classname", "item_health")]),
+        ]);
+        //Indices stay those of the loaded lump after a remove
+        assert_eq!(apply_edits(&entities, &vec![EntityEdit::Remove(0), EntityEdit::Remove(1)]), vec![entities[2].clone()]);
+    }
+
+    #[test]
+    fn to_string() {
+        let entities = parse_entities(LUMP);
+        let text = entities_to_string(&entities).unwrap();
+        assert_eq!(text, LUMP.trim_end_matches('\0'));
+        assert_eq!(parse_entities(&text), entities);
+        assert_eq!(entities_to_string(&Vec::new()), Ok(String::new()));
+
+        assert!(entities_to_string(&vec![entity(&[("message", "say \"hi\"")])]).is_err());
+        assert!(entities_to_string(&vec![entity(&[("a\"b", "c")])]).is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,55 @@
+use crate::bsp_shader;
+
+//Fog volumes come from the effects lump, each one is a brush with a fogparms shader
+pub const MAX_FOGS: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogVolume {
+    colour: [f32; 4], //w is the distance to opaque
+    plane: [f32; 4], //Visible side of the brush, normal and distance
+    mins: [f32; 4], //w is 1 when the fog has a visible side
+    maxs: [f32; 4],
+}
+
+impl FogVolume {
+
+    pub fn new(fog_parms: &bsp_shader::FogParms, mins: [f32; 3], maxs: [f32; 3], plane: Option<([f32; 3], f32)>) -> FogVolume {
+
+        let (plane, has_plane) = match plane {
+            Some((normal, distance)) => ([normal[0], normal[1], normal[2], distance], 1.0),
+            None => ([0.0; 4], 0.0),
+        };
+
+        FogVolume {
+            colour: [fog_parms.colour[0], fog_parms.colour[1], fog_parms.colour[2], fog_parms.distance_to_opaque.max(1.0)],
+            plane,
+            mins: [mins[0], mins[1], mins[2], has_plane],
+            maxs: [maxs[0], maxs[1], maxs[2], 0.0],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogUniforms {
+    fogs: [FogVolume; MAX_FOGS],
+    num_fogs: [i32; 4],
+}
+
+impl FogUniforms {
+
+    pub fn new(fogs: &Vec<FogVolume>) -> Self {
+
+        if fogs.len() > MAX_FOGS {
+            println!("Too many fog volumes {}, only drawing {}", fogs.len(), MAX_FOGS);
+        }
+
+        let mut uniforms = FogUniforms { fogs: [bytemuck::Zeroable::zeroed(); MAX_FOGS], num_fogs: [0; 4] };
+        for i in 0..fogs.len().min(MAX_FOGS) {
+            uniforms.fogs[i] = fogs[i];
+        }
+        uniforms.num_fogs[0] = fogs.len().min(MAX_FOGS) as i32;
+        uniforms
+    }
+}
\ No newline at end of file
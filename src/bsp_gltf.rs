@@ -0,0 +1,535 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::bsp;
+use crate::bsp_entity;
+use crate::bsp_light_map;
+use crate::bsp_shader;
+use crate::vfs;
+
+//glTF 2.0 export of a map, the .gltf json with a .bin buffer and png images next to it
+//https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html
+//
+//Every model is a mesh with a primitive for each texture and lightmap pair. The vertexes are shared
+//and interleaved the same way the renderer uploads them. glTF has no lightmap slot in its materials,
+//so the lightmap image of a primitive is the texture index in its extras and its uvs are TEXCOORD_1
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_BYTE: u32 = 5121;
+const UNSIGNED_INT: u32 = 5125;
+
+//Quake is z up, glTF is y up
+const Z_UP_TO_Y_UP: [f32; 4] = [-std::f32::consts::FRAC_1_SQRT_2, 0.0, 0.0, std::f32::consts::FRAC_1_SQRT_2];
+
+struct Primitive {
+    texture: usize,
+    light_map: Option<usize>,
+    indices: Vec<u32>,
+}
+
+pub fn export(fs: &mut vfs::FileSystem, map: &str, edits: &Vec<bsp_entity::EntityEdit>, out_dir: &Path) -> std::io::Result<()> {
+
+    let (data, name) = bsp::Bsp::load_map(fs, map, edits).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let shaders = bsp::Bsp::load_shaders(fs);
+    let (light_map_images, external_light_maps) = bsp_light_map::load_light_maps(fs, &name, &data.light_maps);
+    let shader_light_maps = bsp_light_map::shader_light_maps(&data.textures, &shaders);
+
+    //Patches add their tessellated vertexes to the end
+    let mut vertexes = data.vertexes.clone();
+    let meshes = data.models.iter().map(|model| {
+        let mut groups: BTreeMap<(usize, Option<usize>), Vec<u32>> = BTreeMap::new();
+        for face in data.faces.iter().skip(model.face.max(0) as usize).take(model.num_faces.max(0) as usize) {
+            let indices = bsp::Bsp::build_face_indices(face, &data.mesh_verts, &mut vertexes);
+            if indices.is_empty() || face.texture < 0 || face.texture as usize >= data.textures.len() {
+                continue;
+            }
+            let light_map = bsp_light_map::remap(face.lightmap_index, shader_light_maps[face.texture as usize], data.light_maps.len(), &external_light_maps);
+            groups.entry((face.texture as usize, light_map)).or_insert_with(Vec::new).extend(indices);
+        }
+        groups.into_iter().map(|((texture, light_map), indices)| Primitive { texture, light_map, indices }).collect::<Vec<Primitive>>()
+    }).collect::<Vec<Vec<Primitive>>>();
+
+    //Validators want unit normals, tessellated patch normals are not always
+    for vertex in vertexes.iter_mut() {
+        let n = vertex.normal;
+        let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        vertex.normal = if length > 0.0 { [n[0] / length, n[1] / length, n[2] / length] } else { [0.0, 0.0, 1.0] };
+    }
+
+    std::fs::create_dir_all(out_dir)?;
+
+    //Images, the diffuse images of the used textures and then the lightmaps
+    let mut images: Vec<String> = Vec::new();
+    let mut texture_images: HashMap<usize, usize> = HashMap::new();
+    let mut used_textures = meshes.iter().flatten().map(|p| p.texture).collect::<Vec<usize>>();
+    used_textures.sort();
+    used_textures.dedup();
+    for texture in used_textures.iter() {
+        let texture_name = bsp::Bsp::name_to_string(&data.textures[*texture].name);
+        let found = bsp::Bsp::texture_images(&texture_name, &shaders).iter().flat_map(|path| bsp::Bsp::image_files(path)).find_map(|(file, format)| {
+            fs.read(&file).and_then(|bytes| image::load_from_memory_with_format(&bytes, format).ok()).map(|img| (file, img))
+        });
+        if let Some((file, img)) = found {
+            let uri = format!("{}.png", &file[..file.rfind('.').unwrap()]);
+            save_image(&img, out_dir, &uri)?;
+            texture_images.insert(*texture, images.len());
+            images.push(uri);
+        }
+    }
+    let first_light_map = images.len();
+    for (i, light_map) in light_map_images.iter().enumerate() {
+        let uri = format!("maps/{}/lm_{:04}.png", name, i);
+        match image::RgbImage::from_raw(light_map.width, light_map.height, light_map.rgb.clone()) {
+            Some(img) => save_image(&image::DynamicImage::ImageRgb8(img), out_dir, &uri)?,
+            None => println!("Lightmap {} has the wrong size", i),
+        }
+        images.push(uri);
+    }
+
+    //Buffer, the vertexes and then every index list
+    let mut bin: Vec<u8> = bytemuck::cast_slice(&vertexes).to_vec();
+    let vertex_bytes = bin.len();
+    let mut index_offsets: Vec<Vec<usize>> = Vec::new();
+    for primitives in meshes.iter() {
+        index_offsets.push(primitives.iter().map(|p| {
+            let offset = bin.len() - vertex_bytes;
+            bin.extend_from_slice(bytemuck::cast_slice(&p.indices));
+            offset
+        }).collect());
+    }
+    let bin_name = format!("{}.bin", name);
+    std::fs::write(out_dir.join(&bin_name), &bin)?;
+
+    let mut mins = [f32::MAX; 3];
+    let mut maxs = [f32::MIN; 3];
+    for vertex in vertexes.iter() {
+        for i in 0..3 {
+            mins[i] = mins[i].min(vertex.position[i]);
+            maxs[i] = maxs[i].max(vertex.position[i]);
+        }
+    }
+    if vertexes.is_empty() {
+        mins = [0.0; 3];
+        maxs = [0.0; 3];
+    }
+
+    let mut accessors: Vec<String> = vec![
+        format!("{{ \"bufferView\": 0, \"byteOffset\": 0, \"componentType\": {}, \"count\": {}, \"type\": \"VEC3\", \"min\": {}, \"max\": {} }}", FLOAT, vertexes.len(), json_floats(&mins), json_floats(&maxs)),
+        format!("{{ \"bufferView\": 0, \"byteOffset\": 12, \"componentType\": {}, \"count\": {}, \"type\": \"VEC2\" }}", FLOAT, vertexes.len()),
+        format!("{{ \"bufferView\": 0, \"byteOffset\": 20, \"componentType\": {}, \"count\": {}, \"type\": \"VEC2\" }}", FLOAT, vertexes.len()),
+        format!("{{ \"bufferView\": 0, \"byteOffset\": 28, \"componentType\": {}, \"count\": {}, \"type\": \"VEC3\" }}", FLOAT, vertexes.len()),
+        format!("{{ \"bufferView\": 0, \"byteOffset\": 40, \"componentType\": {}, \"normalized\": true, \"count\": {}, \"type\": \"VEC4\" }}", UNSIGNED_BYTE, vertexes.len()),
+    ];
+
+    //One material per texture
+    let mut materials: Vec<String> = Vec::new();
+    let mut texture_materials: HashMap<usize, usize> = HashMap::new();
+    for texture in used_textures.iter() {
+        let texture_name = bsp::Bsp::name_to_string(&data.textures[*texture].name);
+        let state = match shaders.get(&texture_name.to_lowercase()) {
+            Some(shader) => shader.surface_state(),
+            None => bsp_shader::SurfaceState::opaque(),
+        };
+        let alpha_mode = if state.blend.is_some() { "BLEND" } else if state.is_alpha_tested() { "MASK" } else { "OPAQUE" };
+        let base_colour = match texture_images.get(texture) {
+            Some(image) => format!("\"baseColorTexture\": {{ \"index\": {}, \"texCoord\": 0 }}, ", image),
+            None => String::new(),
+        };
+        texture_materials.insert(*texture, materials.len());
+        materials.push(format!("{{ \"name\": {}, \"pbrMetallicRoughness\": {{ {}\"metallicFactor\": 0, \"roughnessFactor\": 1 }}, \"alphaMode\": \"{}\", \"doubleSided\": {} }}",
+            json_string(&texture_name), base_colour, alpha_mode, state.two_sided));
+    }
+
+    //Meshes and their nodes, models without faces to draw have neither
+    let mut gltf_meshes: Vec<String> = Vec::new();
+    let mut nodes: Vec<String> = vec![String::new()];
+    for (m, primitives) in meshes.iter().enumerate() {
+        if primitives.is_empty() {
+            continue;
+        }
+        let gltf_primitives = primitives.iter().enumerate().map(|(p, primitive)| {
+            let accessor = accessors.len();
+            accessors.push(format!("{{ \"bufferView\": 1, \"byteOffset\": {}, \"componentType\": {}, \"count\": {}, \"type\": \"SCALAR\" }}", index_offsets[m][p], UNSIGNED_INT, primitive.indices.len()));
+            let extras = match primitive.light_map {
+                Some(light_map) => format!(", \"extras\": {{ \"lightmap\": {} }}", first_light_map + light_map),
+                None => String::new(),
+            };
+            format!("{{ \"attributes\": {{ \"POSITION\": 0, \"TEXCOORD_0\": 1, \"TEXCOORD_1\": 2, \"NORMAL\": 3, \"COLOR_0\": 4 }}, \"indices\": {}, \"material\": {}, \"mode\": 4{} }}",
+                accessor, texture_materials[&primitive.texture], extras)
+        }).collect::<Vec<String>>();
+        let mesh_name = if m == 0 { "worldspawn".to_string() } else { format!("*{}", m) };
+        nodes.push(format!("{{ \"name\": {}, \"mesh\": {} }}", json_string(&mesh_name), gltf_meshes.len()));
+        gltf_meshes.push(format!("{{ \"name\": {}, \"primitives\": [\n      {}\n    ] }}", json_string(&mesh_name), gltf_primitives.join(",\n      ")));
+    }
+
+    //Entities keep all of their keys in extras, a later duplicate key wins
+    for entity in bsp_entity::parse_entities(data.entity_text()).iter() {
+        let mut keys: Vec<(String, String)> = Vec::new();
+        for (key, value) in entity.properties.iter() {
+            keys.retain(|(k, _)| k != key);
+            keys.push((key.clone(), value.clone()));
+        }
+        let extras = keys.iter().map(|(k, v)| format!("{}: {}", json_string(k), json_string(v))).collect::<Vec<String>>().join(", ");
+        let node_name = entity.get("targetname").unwrap_or(entity.class_name());
+        let translation = match entity.origin() {
+            Some(origin) => format!(", \"translation\": {}", json_floats(&origin)),
+            None => String::new(),
+        };
+        nodes.push(format!("{{ \"name\": {}{}, \"extras\": {{ {} }} }}", json_string(node_name), translation, extras));
+    }
+    let children = (1..nodes.len()).map(|i| i.to_string()).collect::<Vec<String>>().join(", ");
+    nodes[0] = format!("{{ \"name\": {}, \"rotation\": {}, \"children\": [{}] }}", json_string(&name), json_floats(&Z_UP_TO_Y_UP), children);
+
+    let gltf_images = images.iter().map(|uri| format!("{{ \"uri\": {} }}", json_string(uri))).collect::<Vec<String>>();
+    let gltf_textures = (0..images.len()).map(|i| format!("{{ \"sampler\": 0, \"source\": {} }}", i)).collect::<Vec<String>>();
+    let buffer_views = vec![
+        format!("{{ \"buffer\": 0, \"byteOffset\": 0, \"byteLength\": {}, \"byteStride\": {}, \"target\": {} }}", vertex_bytes, std::mem::size_of::<bsp::Vertex>(), ARRAY_BUFFER),
+        format!("{{ \"buffer\": 0, \"byteOffset\": {}, \"byteLength\": {}, \"target\": {} }}", vertex_bytes, bin.len() - vertex_bytes, ELEMENT_ARRAY_BUFFER),
+    ];
+
+    let section = |key: &str, items: &Vec<String>| format!("  \"{}\": [\n    {}\n  ]", key, items.join(",\n    "));
+    let mut sections = vec![
+        "  \"asset\": { \"version\": \"2.0\", \"generator\": \"crossing\" }".to_string(),
+        "  \"scene\": 0".to_string(),
+        "  \"scenes\": [ { \"nodes\": [0] } ]".to_string(),
+        section("nodes", &nodes),
+        format!("  \"buffers\": [ {{ \"uri\": {}, \"byteLength\": {} }} ]", json_string(&bin_name), bin.len()),
+        section("bufferViews", &buffer_views),
+        section("accessors", &accessors),
+    ];
+    //Empty arrays are not valid glTF
+    if !gltf_meshes.is_empty() {
+        sections.push(section("meshes", &gltf_meshes));
+    }
+    if !materials.is_empty() {
+        sections.push(section("materials", &materials));
+    }
+    if !images.is_empty() {
+        sections.push("  \"samplers\": [ { \"magFilter\": 9729, \"minFilter\": 9987, \"wrapS\": 10497, \"wrapT\": 10497 } ]".to_string());
+        sections.push(section("images", &gltf_images));
+        sections.push(section("textures", &gltf_textures));
+    }
+
+    let gltf_path = out_dir.join(format!("{}.gltf", name));
+    std::fs::write(&gltf_path, format!("{{\n{}\n}}\n", sections.join(",\n")))?;
+    println!("Saved {} with {} meshes, {} materials and {} images", gltf_path.display(), gltf_meshes.len(), materials.len(), images.len());
+    Ok(())
+}
+
+fn save_image(img: &image::DynamicImage, out_dir: &Path, uri: &str) -> std::io::Result<()> {
+
+    let path = out_dir.join(uri);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    img.save_with_format(&path, image::ImageFormat::Png).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+}
+
+fn json_string(s: &str) -> String {
+
+    let mut json = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_floats(values: &[f32]) -> String {
+    format!("[{}]", values.iter().map(|v| if v.is_finite() { v.to_string() } else { "0".to_string() }).collect::<Vec<String>>().join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp_data;
+
+    //Just enough json to read the export back
+    #[derive(Debug, Clone, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn get(&self, key: &str) -> &Json {
+            match self {
+                Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v).unwrap_or(&Json::Null),
+                _ => &Json::Null,
+            }
+        }
+
+        fn at(&self, i: usize) -> &Json {
+            match self {
+                Json::Array(items) => &items[i],
+                _ => &Json::Null,
+            }
+        }
+
+        fn len(&self) -> usize {
+            match self {
+                Json::Array(items) => items.len(),
+                _ => 0,
+            }
+        }
+
+        fn number(&self) -> usize {
+            match self {
+                Json::Number(n) => *n as usize,
+                _ => panic!("{:?} is not a number", self),
+            }
+        }
+
+        fn string(&self) -> &str {
+            match self {
+                Json::String(s) => s,
+                _ => panic!("{:?} is not a string", self),
+            }
+        }
+    }
+
+    fn parse_json(text: &str) -> Json {
+        let chars = text.chars().collect::<Vec<char>>();
+        let mut i = 0;
+        let json = parse_value(&chars, &mut i);
+        skip_space(&chars, &mut i);
+        assert_eq!(i, chars.len(), "text after the json");
+        json
+    }
+
+    fn skip_space(chars: &[char], i: &mut usize) {
+        while *i < chars.len() && chars[*i].is_whitespace() {
+            *i += 1;
+        }
+    }
+
+    fn parse_value(chars: &[char], i: &mut usize) -> Json {
+        skip_space(chars, i);
+        match chars[*i] {
+            '{' => {
+                *i += 1;
+                let mut pairs: Vec<(String, Json)> = Vec::new();
+                loop {
+                    skip_space(chars, i);
+                    if chars[*i] == '}' && pairs.is_empty() {
+                        *i += 1;
+                        return Json::Object(pairs);
+                    }
+                    let key = match parse_value(chars, i) {
+                        Json::String(key) => key,
+                        other => panic!("key {:?}", other),
+                    };
+                    skip_space(chars, i);
+                    assert_eq!(chars[*i], ':');
+                    *i += 1;
+                    pairs.push((key, parse_value(chars, i)));
+                    skip_space(chars, i);
+                    *i += 1;
+                    match chars[*i - 1] {
+                        ',' => {}
+                        '}' => return Json::Object(pairs),
+                        c => panic!("{} in an object", c),
+                    }
+                }
+            }
+            '[' => {
+                *i += 1;
+                let mut items: Vec<Json> = Vec::new();
+                loop {
+                    skip_space(chars, i);
+                    if chars[*i] == ']' && items.is_empty() {
+                        *i += 1;
+                        return Json::Array(items);
+                    }
+                    items.push(parse_value(chars, i));
+                    skip_space(chars, i);
+                    *i += 1;
+                    match chars[*i - 1] {
+                        ',' => {}
+                        ']' => return Json::Array(items),
+                        c => panic!("{} in an array", c),
+                    }
+                }
+            }
+            '"' => {
+                *i += 1;
+                let mut s = String::new();
+                while chars[*i] != '"' {
+                    assert!(chars[*i] as u32 >= 0x20, "control character in a string");
+                    if chars[*i] == '\\' {
+                        *i += 1;
+                        match chars[*i] {
+                            'u' => {
+                                let code = chars[(*i + 1)..(*i + 5)].iter().collect::<String>();
+                                s.push(std::char::from_u32(u32::from_str_radix(&code, 16).unwrap()).unwrap());
+                                *i += 4;
+                            }
+                            'n' => s.push('\n'),
+                            c => s.push(c),
+                        }
+                    }
+                    else {
+                        s.push(chars[*i]);
+                    }
+                    *i += 1;
+                }
+                *i += 1;
+                Json::String(s)
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = *i;
+                while *i < chars.len() && (chars[*i] == '-' || chars[*i] == '+' || chars[*i] == '.' || chars[*i] == 'e' || chars[*i] == 'E' || chars[*i].is_ascii_digit()) {
+                    *i += 1;
+                }
+                Json::Number(chars[start..*i].iter().collect::<String>().parse::<f64>().unwrap())
+            }
+            _ => {
+                let word = ["true", "false", "null"].iter().find(|w| chars[*i..].iter().take(w.len()).collect::<String>() == **w).expect("unknown json value");
+                *i += word.len();
+                match *word {
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    _ => Json::Null,
+                }
+            }
+        }
+    }
+
+    fn texture(name: &str) -> bsp::Texture {
+        let mut texture = bsp::Texture { name: [0; 64], flags: 0, contents: 1 };
+        texture.name[..name.len()].copy_from_slice(name.as_bytes());
+        texture
+    }
+
+    fn vertex(x: f32, y: f32) -> bsp::Vertex {
+        bsp::Vertex { position: [x, y, 0.0], texcoord_s: [x / 64.0, y / 64.0], texcoord_l: [0.5, 0.5], normal: [0.0, 0.0, 2.0], colour: [255; 4] }
+    }
+
+    fn quad_face(texture: i32, vertex: i32, lightmap_index: i32) -> bsp::Face {
+        let mut face: bsp::Face = bytemuck::Zeroable::zeroed();
+        face.texture = texture;
+        face.effect = -1;
+        face.type_draw = 1;
+        face.vertex = vertex;
+        face.num_vertexes = 4;
+        face.num_mesh_verts = 6;
+        face.lightmap_index = lightmap_index;
+        face
+    }
+
+    fn model(face: i32) -> bsp::Model {
+        bsp::Model { mins: [0.0; 3], maxs: [64.0, 64.0, 0.0], face, num_faces: 1, brush: 0, num_brushes: 0 }
+    }
+
+    //A lit floor in the world and an unlit door
+    fn map() -> bsp_data::BspData {
+        bsp_data::BspData {
+            version: bsp_data::Q3_VERSION,
+            entities: "{\n\"classname\" \"worldspawn\"\n}\n{\n\"classname\" \"func_door\"\n\"model\" \"*1\"\n\"targetname\" \"door\\one\"\n\"origin\" \"8 0 0\"\n}\n\0".to_string(),
+            textures: vec![texture("textures/test/floor"), texture("textures/test/door")],
+            planes: Vec::new(),
+            nodes: Vec::new(),
+            leafs: Vec::new(),
+            leaf_faces: Vec::new(),
+            leaf_brushes: Vec::new(),
+            models: vec![model(0), model(1)],
+            brushes: Vec::new(),
+            brush_sides: Vec::new(),
+            vertexes: vec![vertex(0.0, 0.0), vertex(64.0, 0.0), vertex(64.0, 64.0), vertex(0.0, 64.0), vertex(0.0, 0.0), vertex(8.0, 0.0), vertex(8.0, 8.0), vertex(0.0, 8.0)],
+            mesh_verts: bytemuck::cast_slice::<i32, bsp::MeshVert>(&[0, 1, 2, 0, 2, 3]).to_vec(),
+            effects: Vec::new(),
+            faces: vec![quad_face(0, 0, 0), quad_face(1, 4, -1)],
+            light_maps: vec![bytemuck::Zeroable::zeroed()],
+            light_vols: Vec::new(),
+            vis_data: Vec::new(),
+            advertisements: None,
+            raven: None,
+        }
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("textures/base_wall/c_met5_2"), "\"textures/base_wall/c_met5_2\"");
+        assert_eq!(json_string("a \"b\" c\\d"), "\"a \\\"b\\\" c\\\\d\"");
+        assert_eq!(json_string("line\nbreak\t\u{1}"), "\"line\\u000abreak\\u0009\\u0001\"");
+        assert_eq!(parse_json(&json_string("é \"q\" \\ \n")), Json::String("é \"q\" \\ \n".to_string()));
+        assert_eq!(json_floats(&[1.0, -0.5, f32::NAN]), "[1, -0.5, 0]");
+    }
+
+    #[test]
+    fn export_map() {
+        let dir = std::env::temp_dir().join(format!("crossing_gltf_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bsp_path = dir.join("test.bsp");
+        map().save(&bsp_path).unwrap();
+        let out_dir = dir.join("out");
+        export(&mut vfs::FileSystem::new(), bsp_path.to_str().unwrap(), &Vec::new(), &out_dir).unwrap();
+
+        let gltf = parse_json(&std::fs::read_to_string(out_dir.join("test.gltf")).unwrap());
+        let bin = std::fs::read(out_dir.join("test.bin")).unwrap();
+        assert_eq!(gltf.get("asset").get("version").string(), "2.0");
+        assert_eq!(gltf.get("buffers").at(0).get("uri").string(), "test.bin");
+        assert_eq!(gltf.get("buffers").at(0).get("byteLength").number(), bin.len());
+
+        //The vertex attributes cover the whole vertex view
+        let views = gltf.get("bufferViews");
+        let stride = std::mem::size_of::<bsp::Vertex>();
+        assert_eq!(views.at(0).get("byteStride").number(), stride);
+        assert_eq!(views.at(0).get("byteLength").number(), 8 * stride);
+        assert_eq!(views.at(1).get("byteOffset").number(), 8 * stride);
+        assert_eq!(views.at(0).get("byteLength").number() + views.at(1).get("byteLength").number(), bin.len());
+        let accessors = gltf.get("accessors");
+        for i in 0..5 {
+            assert_eq!(accessors.at(i).get("bufferView").number(), 0);
+            assert_eq!(accessors.at(i).get("count").number(), 8);
+        }
+
+        //A mesh for each model with an index accessor inside the index view for each primitive
+        let meshes = gltf.get("meshes");
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes.at(0).get("name").string(), "worldspawn");
+        assert_eq!(meshes.at(1).get("name").string(), "*1");
+        let mut index_bytes = 0;
+        for m in 0..2 {
+            let primitive = meshes.at(m).get("primitives").at(0);
+            let accessor = accessors.at(primitive.get("indices").number());
+            assert_eq!(accessor.get("bufferView").number(), 1);
+            assert_eq!(accessor.get("count").number(), 6);
+            assert!(accessor.get("byteOffset").number() + 6 * 4 <= views.at(1).get("byteLength").number());
+            index_bytes += 6 * 4;
+            assert_eq!(gltf.get("materials").at(primitive.get("material").number()).get("name").string(), if m == 0 { "textures/test/floor" } else { "textures/test/door" });
+        }
+        assert_eq!(accessors.len(), 7);
+        assert_eq!(views.at(1).get("byteLength").number(), index_bytes);
+
+        //Only the floor is lit, its lightmap is the one image since the textures are not in the game data
+        let world = meshes.at(0).get("primitives").at(0);
+        assert_eq!(world.get("extras").get("lightmap").number(), 0);
+        assert_eq!(meshes.at(1).get("primitives").at(0).get("extras"), &Json::Null);
+        assert_eq!(gltf.get("images").len(), 1);
+        assert_eq!(gltf.get("images").at(0).get("uri").string(), "maps/test/lm_0000.png");
+        assert!(out_dir.join("maps/test/lm_0000.png").is_file());
+
+        //The root turns z up to y up, the entities keep their keys
+        let nodes = gltf.get("nodes");
+        assert_eq!(nodes.at(0).get("name").string(), "test");
+        assert_eq!(nodes.at(0).get("children").len(), nodes.len() - 1);
+        let door = (0..nodes.len()).map(|i| nodes.at(i)).find(|n| n.get("extras").get("classname") == &Json::String("func_door".to_string())).unwrap();
+        assert_eq!(door.get("name").string(), "door\\one");
+        assert_eq!(door.get("translation"), &Json::Array(vec![Json::Number(8.0), Json::Number(0.0), Json::Number(0.0)]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,246 @@
+use crate::bsp_data;
+
+//Brushes as closed convex polyhedra for physics engines and for looking at the collision geometry.
+//A brush is the space behind all of its side planes, each side becomes the polygon left after
+//clipping a huge square on its plane by every other side
+
+pub const CONTENTS_SOLID: i32 = 1;
+
+//Clipping is done in f64 like q3map, a square the size of the world loses too much in f32
+const MAX_WORLD_COORD: f64 = 65536.0;
+const ON_EPSILON: f64 = 0.01;
+const WELD_EPSILON: f64 = 0.01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvexHull {
+    pub brush: usize,
+    pub contents: i32,
+    pub vertices: Vec<[f32; 3]>,
+    //Indices into vertices, counter clockwise seen from outside
+    pub faces: Vec<Vec<usize>>,
+}
+
+//Every brush with sides that makes a closed volume, bevel and duplicate planes leave no face
+pub fn convex_hulls(data: &bsp_data::BspData) -> Vec<ConvexHull> {
+
+    let mut hulls: Vec<ConvexHull> = Vec::new();
+    for (i, brush) in data.brushes.iter().enumerate() {
+        let planes = data.brush_sides.iter().skip(brush.brush_side.max(0) as usize).take(brush.num_brush_sides.max(0) as usize)
+            .filter_map(|side| data.planes.get(side.plane as usize))
+            .map(|p| [p.normal[0], p.normal[1], p.normal[2], p.distance]).collect::<Vec<[f32; 4]>>();
+        let contents = data.textures.get(brush.texture as usize).map_or(0, |t| t.contents);
+        if let Some((vertices, faces)) = brush_hull(&planes) {
+            hulls.push(ConvexHull { brush: i, contents, vertices, faces });
+        }
+    }
+    hulls
+}
+
+//Planes are normal and distance with the normal pointing out of the brush
+pub fn brush_hull(planes: &Vec<[f32; 4]>) -> Option<(Vec<[f32; 3]>, Vec<Vec<usize>>)> {
+
+    let planes = planes.iter().map(|p| [p[0] as f64, p[1] as f64, p[2] as f64, p[3] as f64]).collect::<Vec<[f64; 4]>>();
+    let mut vertices: Vec<[f64; 3]> = Vec::new();
+    let mut faces: Vec<Vec<usize>> = Vec::new();
+    for (i, plane) in planes.iter().enumerate() {
+        let mut winding = base_winding(plane);
+        for (j, other) in planes.iter().enumerate() {
+            //The first of duplicate planes keeps the face
+            if j < i && (0..4).all(|k| (plane[k] - other[k]).abs() < ON_EPSILON) {
+                winding.clear();
+            }
+            if i != j && winding.len() >= 3 {
+                winding = clip_winding(&winding, other);
+            }
+        }
+        //Newell normal of the polygon against the plane normal
+        let mut winding_normal = [0.0; 3];
+        for k in 0..winding.len() {
+            let (a, b) = (winding[k], winding[(k + 1) % winding.len()]);
+            winding_normal = [
+                winding_normal[0] + (a[1] - b[1]) * (a[2] + b[2]),
+                winding_normal[1] + (a[2] - b[2]) * (a[0] + b[0]),
+                winding_normal[2] + (a[0] - b[0]) * (a[1] + b[1]),
+            ];
+        }
+        if dot(winding_normal, [plane[0], plane[1], plane[2]]) < 0.0 {
+            winding.reverse();
+        }
+
+        //Sliver faces from bevels collapse to an edge and add no vertices
+        let mut points: Vec<[f64; 3]> = Vec::new();
+        for p in winding.iter() {
+            if !points.iter().any(|q| distance(*q, *p) < WELD_EPSILON) {
+                points.push(*p);
+            }
+        }
+        if points.len() < 3 {
+            continue;
+        }
+
+        let mut face: Vec<usize> = Vec::new();
+        for p in points.iter() {
+            match vertices.iter().position(|v| distance(*v, *p) < WELD_EPSILON) {
+                Some(index) => face.push(index),
+                None => {
+                    vertices.push(*p);
+                    face.push(vertices.len() - 1);
+                }
+            }
+        }
+        faces.push(face);
+    }
+
+    //A closed volume needs at least four faces, faces still reaching the edge of the base winding are open
+    if faces.len() < 4 || vertices.iter().any(|v| v.iter().any(|c| c.abs() > MAX_WORLD_COORD / 2.0)) {
+        return None;
+    }
+    Some((vertices.iter().map(|v| [v[0] as f32, v[1] as f32, v[2] as f32]).collect(), faces))
+}
+
+//Square on the plane larger than the world
+fn base_winding(plane: &[f64; 4]) -> Vec<[f64; 3]> {
+
+    let normal = [plane[0], plane[1], plane[2]];
+    let axis = (0..3).max_by(|a, b| normal[*a].abs().partial_cmp(&normal[*b].abs()).unwrap()).unwrap();
+    let mut up = if axis == 2 { [1.0, 0.0, 0.0] } else { [0.0, 0.0, 1.0] };
+    let d = dot(up, normal);
+    up = normalize([up[0] - d * normal[0], up[1] - d * normal[1], up[2] - d * normal[2]]);
+    let right = cross(up, normal);
+
+    let origin = [normal[0] * plane[3], normal[1] * plane[3], normal[2] * plane[3]];
+    let point = |r: f64, u: f64| [
+        origin[0] + (right[0] * r + up[0] * u) * MAX_WORLD_COORD,
+        origin[1] + (right[1] * r + up[1] * u) * MAX_WORLD_COORD,
+        origin[2] + (right[2] * r + up[2] * u) * MAX_WORLD_COORD,
+    ];
+    vec![point(-1.0, 1.0), point(-1.0, -1.0), point(1.0, -1.0), point(1.0, 1.0)]
+}
+
+//Keeps the part behind the plane
+fn clip_winding(winding: &Vec<[f64; 3]>, plane: &[f64; 4]) -> Vec<[f64; 3]> {
+
+    let side = |p: [f64; 3]| p[0] * plane[0] + p[1] * plane[1] + p[2] * plane[2] - plane[3];
+    let mut clipped: Vec<[f64; 3]> = Vec::new();
+    for i in 0..winding.len() {
+        let a = winding[i];
+        let b = winding[(i + 1) % winding.len()];
+        let (da, db) = (side(a), side(b));
+        if da <= ON_EPSILON {
+            clipped.push(a);
+        }
+        if (da > ON_EPSILON && db < -ON_EPSILON) || (da < -ON_EPSILON && db > ON_EPSILON) {
+            let t = da / (da - db);
+            clipped.push([a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]);
+        }
+    }
+    clipped
+}
+
+//Wavefront OBJ with a group per hull, obj counts vertices from 1 across the whole file
+pub fn to_obj(hulls: &Vec<ConvexHull>) -> String {
+
+    let mut obj = String::new();
+    let mut first = 1;
+    for hull in hulls.iter() {
+        obj.push_str(&format!("g brush_{}\n# contents {:#x}\n", hull.brush, hull.contents));
+        for v in hull.vertices.iter() {
+            obj.push_str(&format!("v {} {} {}\n", v[0], v[1], v[2]));
+        }
+        for face in hull.faces.iter() {
+            obj.push_str(&format!("f {}\n", face.iter().map(|i| (i + first).to_string()).collect::<Vec<String>>().join(" ")));
+        }
+        first += hull.vertices.len();
+    }
+    obj
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let length = dot(a, a).sqrt();
+    [a[0] / length, a[1] / length, a[2] / length]
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    dot(d, d).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //x from -16 to 16, y from -8 to 8 and z from 0 to 32
+    fn box_planes() -> Vec<[f32; 4]> {
+        vec![[1.0, 0.0, 0.0, 16.0], [-1.0, 0.0, 0.0, 16.0], [0.0, 1.0, 0.0, 8.0], [0.0, -1.0, 0.0, 8.0], [0.0, 0.0, 1.0, 32.0], [0.0, 0.0, -1.0, 0.0]]
+    }
+
+    fn hull(planes: &Vec<[f32; 4]>) -> ConvexHull {
+        let (vertices, faces) = brush_hull(planes).unwrap();
+        ConvexHull { brush: 0, contents: CONTENTS_SOLID, vertices, faces }
+    }
+
+    //Every face of the box is a quad on its plane, wound counter clockwise seen from outside
+    fn check_box(hull: &ConvexHull) {
+        assert_eq!(hull.vertices.len(), 8);
+        for v in hull.vertices.iter() {
+            assert!(v[0].abs() == 16.0 && v[1].abs() == 8.0 && (v[2] == 0.0 || v[2] == 32.0));
+        }
+        assert_eq!(hull.faces.len(), 6);
+        for (face, plane) in hull.faces.iter().zip(box_planes().iter()) {
+            assert_eq!(face.len(), 4);
+            let p = face.iter().map(|i| hull.vertices[*i]).map(|v| [v[0] as f64, v[1] as f64, v[2] as f64]).collect::<Vec<[f64; 3]>>();
+            for v in p.iter() {
+                assert!((v[0] * plane[0] as f64 + v[1] * plane[1] as f64 + v[2] * plane[2] as f64 - plane[3] as f64).abs() < 1e-4);
+            }
+            let normal = cross([p[1][0] - p[0][0], p[1][1] - p[0][1], p[1][2] - p[0][2]], [p[2][0] - p[1][0], p[2][1] - p[1][1], p[2][2] - p[1][2]]);
+            assert!(dot(normal, [plane[0] as f64, plane[1] as f64, plane[2] as f64]) > 0.0);
+        }
+    }
+
+    #[test]
+    fn axis_aligned_box() {
+        check_box(&hull(&box_planes()));
+    }
+
+    #[test]
+    fn duplicate_and_bevel_planes() {
+        let mut planes = box_planes();
+        //A second +x side and a bevel touching the +x +y edge
+        planes.push([1.0, 0.0, 0.0, 16.0]);
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+        planes.push([s, s, 0.0, 24.0 * s]);
+        check_box(&hull(&planes));
+    }
+
+    #[test]
+    fn open_planes() {
+        assert!(brush_hull(&box_planes()[..3].to_vec()).is_none());
+        //Without a bottom the sides reach down to the edge of the base winding
+        assert!(brush_hull(&box_planes()[..5].to_vec()).is_none());
+        assert!(brush_hull(&Vec::new()).is_none());
+    }
+
+    #[test]
+    fn obj_indices() {
+        let mut second = hull(&box_planes());
+        second.brush = 3;
+        let obj = to_obj(&vec![hull(&box_planes()), second]);
+        let lines = obj.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.iter().filter(|l| l.starts_with("v ")).count(), 16);
+        assert!(lines.contains(&"g brush_0") && lines.contains(&"g brush_3") && lines.contains(&"# contents 0x1"));
+        //The second group counts on from the 8 vertices of the first, from 1
+        let faces = lines.iter().filter(|l| l.starts_with("f ")).map(|l| l[2..].split(' ').map(|i| i.parse::<usize>().unwrap()).collect::<Vec<usize>>()).collect::<Vec<Vec<usize>>>();
+        assert_eq!(faces.len(), 12);
+        assert!(faces[..6].iter().flatten().all(|i| (1..=8).contains(i)));
+        assert!(faces[6..].iter().flatten().all(|i| (9..=16).contains(i)));
+        assert_eq!(faces[6].iter().map(|i| i - 8).collect::<Vec<usize>>(), faces[0]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use cgmath::{Matrix4, Vector3, Deg};
+
+use crate::bsp;
+use crate::bsp_entity;
+use crate::bsp_shader;
+use crate::md3;
+use crate::texture;
+use crate::vfs;
+
+//misc_model decoration and the pickups, drawn with their md3 models where the entities place them
+
+//World models of the items from the game's item list, the second model is the sphere of health and the ring of powerups
+const ITEMS: &[(&str, &[&str])] = &[
+    ("item_armor_shard", &["models/powerups/armor/shard.md3"]),
+    ("item_armor_combat", &["models/powerups/armor/armor_yel.md3"]),
+    ("item_armor_body", &["models/powerups/armor/armor_red.md3"]),
+    ("item_health_small", &["models/powerups/health/small_cross.md3", "models/powerups/health/small_sphere.md3"]),
+    ("item_health", &["models/powerups/health/medium_cross.md3", "models/powerups/health/medium_sphere.md3"]),
+    ("item_health_large", &["models/powerups/health/large_cross.md3", "models/powerups/health/large_sphere.md3"]),
+    ("item_health_mega", &["models/powerups/health/mega_cross.md3", "models/powerups/health/mega_sphere.md3"]),
+    ("weapon_gauntlet", &["models/weapons2/gauntlet/gauntlet.md3"]),
+    ("weapon_shotgun", &["models/weapons2/shotgun/shotgun.md3"]),
+    ("weapon_machinegun", &["models/weapons2/machinegun/machinegun.md3"]),
+    ("weapon_grenadelauncher", &["models/weapons2/grenadel/grenadel.md3"]),
+    ("weapon_rocketlauncher", &["models/weapons2/rocketl/rocketl.md3"]),
+    ("weapon_lightning", &["models/weapons2/lightning/lightning.md3"]),
+    ("weapon_railgun", &["models/weapons2/railgun/railgun.md3"]),
+    ("weapon_plasmagun", &["models/weapons2/plasma/plasma.md3"]),
+    ("weapon_bfg", &["models/weapons2/bfg/bfg.md3"]),
+    ("weapon_grapplinghook", &["models/weapons2/grapple/grapple.md3"]),
+    ("ammo_shells", &["models/powerups/ammo/shotgunam.md3"]),
+    ("ammo_bullets", &["models/powerups/ammo/machinegunam.md3"]),
+    ("ammo_grenades", &["models/powerups/ammo/grenadeam.md3"]),
+    ("ammo_cells", &["models/powerups/ammo/plasmaam.md3"]),
+    ("ammo_lightning", &["models/powerups/ammo/lightningam.md3"]),
+    ("ammo_rockets", &["models/powerups/ammo/rocketam.md3"]),
+    ("ammo_slugs", &["models/powerups/ammo/railgunam.md3"]),
+    ("ammo_bfg", &["models/powerups/ammo/bfgam.md3"]),
+    ("holdable_teleporter", &["models/powerups/holdable/teleporter.md3"]),
+    ("holdable_medkit", &["models/powerups/holdable/medkit.md3"]),
+    ("item_quad", &["models/powerups/instant/quad.md3", "models/powerups/instant/quad_ring.md3"]),
+    ("item_enviro", &["models/powerups/instant/enviro.md3", "models/powerups/instant/enviro_ring.md3"]),
+    ("item_haste", &["models/powerups/instant/haste.md3", "models/powerups/instant/haste_ring.md3"]),
+    ("item_invis", &["models/powerups/instant/invis.md3", "models/powerups/instant/invis_ring.md3"]),
+    ("item_regen", &["models/powerups/instant/regen.md3", "models/powerups/instant/regen_ring.md3"]),
+    ("item_flight", &["models/powerups/instant/flight.md3", "models/powerups/instant/flight_ring.md3"]),
+    ("team_CTF_redflag", &["models/flags/r_flag.md3"]),
+    ("team_CTF_blueflag", &["models/flags/b_flag.md3"]),
+];
+
+//Weapons are drawn bigger than they are held
+const WEAPON_SCALE: f32 = 1.5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Placement {
+    //misc_model, static at its origin, angles and scale
+    Static,
+    //The item itself, bobbing and spinning
+    Item,
+    //Health spheres, bobbing with the item without spinning
+    Sphere,
+    //Powerup rings, above the item and spinning the other way
+    Ring,
+}
+
+pub struct ModelEntity {
+    pub entity: usize,
+    pub model: usize,
+    pub instance: md3::Md3Instance,
+    pub origin: [f32; 3],
+    pub angles: [f32; 3],
+    pub scale: [f32; 3],
+    //Moves the model before it is rotated, weapons spin around their middle
+    pub offset: [f32; 3],
+    pub placement: Placement,
+    pub light: bsp::GridLight,
+    //Demo entities are only drawn while they are in the snapshot
+    pub visible: bool,
+}
+
+pub struct ModelEntities {
+    pub models: Vec<md3::Md3Model>,
+    pub entities: Vec<ModelEntity>,
+    //Model index by lower case path, None when the file is missing
+    loaded: HashMap<String, Option<usize>>,
+}
+
+impl ModelEntities {
+
+    //Each model file is loaded once and shared by the entities using it
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, fs: &vfs::FileSystem, bsp: &bsp::Bsp, texture_settings: &texture::TextureSettings) -> ModelEntities {
+
+        let mut model_entities = ModelEntities { models: Vec::new(), entities: Vec::new(), loaded: HashMap::new() };
+        for (i, entity) in bsp.entities.iter().enumerate() {
+            for (path, placement) in entity_models(entity) {
+                let origin = entity.origin().unwrap_or([0.0; 3]);
+                let scale = match entity.vector("modelscale_vec") {
+                    Some(scale) => scale,
+                    None => [entity.float("modelscale").unwrap_or(1.0); 3],
+                };
+                let weapon = entity.class_name().starts_with("weapon_");
+                match model_entities.add(device, queue, layout, fs, &bsp.shaders, texture_settings, i, &path, placement, weapon) {
+                    Some(e) => {
+                        e.origin = origin;
+                        e.angles = entity.angles().unwrap_or([0.0; 3]);
+                        if !weapon {
+                            e.scale = scale;
+                        }
+                        e.light = bsp.light_grid(origin);
+                    }
+                    None => println!("Error cant find model {} for {}", path, entity.class_name()),
+                }
+            }
+        }
+        println!("{} model entities with {} models", model_entities.entities.len(), model_entities.models.len());
+        model_entities
+    }
+
+    //A model entity at the origin, None when the model can not be loaded
+    pub fn add(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, fs: &vfs::FileSystem, shaders: &HashMap<String, bsp_shader::Shader>, texture_settings: &texture::TextureSettings, entity: usize, path: &str, placement: Placement, weapon: bool) -> Option<&mut ModelEntity> {
+
+        let models = &mut self.models;
+        let model = (*self.loaded.entry(path.to_lowercase()).or_insert_with(|| {
+            let md3 = md3::Md3::load(fs, path)?;
+            models.push(md3::Md3Model::new(device, queue, layout, fs, md3, shaders, &HashMap::new(), texture_settings));
+            Some(models.len() - 1)
+        }))?;
+
+        let mut scale = [1.0; 3];
+        let mut offset = [0.0; 3];
+        if weapon {
+            scale = [WEAPON_SCALE; 3];
+            if let Some(frame) = self.models[model].md3.frames.first() {
+                offset = [-(frame.mins[0] + frame.maxs[0]) * 0.5, -(frame.mins[1] + frame.maxs[1]) * 0.5, -(frame.mins[2] + frame.maxs[2]) * 0.5];
+            }
+        }
+        let instance = md3::Md3Instance::new(device, &self.models[model]);
+        let light = bsp::GridLight { ambient: [255.0; 3], directed: [0.0; 3], dir: [0.0, 0.0, 1.0] };
+        self.entities.push(ModelEntity { entity, model, instance, origin: [0.0; 3], angles: [0.0; 3], scale, offset, placement, light, visible: true });
+        self.entities.last_mut()
+    }
+
+    pub fn model_index(&self, path: &str) -> Option<usize> {
+        *self.loaded.get(&path.to_lowercase())?
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, time: f32) {
+
+        for e in self.entities.iter().filter(|e| e.visible) {
+            e.instance.update(queue, &self.models[e.model], 0, 0, 0.0, placement_transform(e, time), &e.light);
+        }
+    }
+}
+
+//Model paths of an entity and how each is placed
+pub fn entity_models(entity: &bsp_entity::Entity) -> Vec<(String, Placement)> {
+
+    if entity.class_name() == "misc_model" {
+        return entity.get("model").map(|m| vec![(m.to_string(), Placement::Static)]).unwrap_or_default();
+    }
+    match ITEMS.iter().find(|(name, _)| name.eq_ignore_ascii_case(entity.class_name())) {
+        Some(item) => item_placements(item),
+        None => Vec::new(),
+    }
+}
+
+//Models of an item by its index in the game's item list, which starts at 1 like the modelindex of item entities
+pub fn item_models(index: i32) -> Vec<(String, Placement)> {
+    ITEMS.get((index - 1).max(0) as usize).filter(|_| index > 0).map_or(Vec::new(), item_placements)
+}
+
+fn item_placements((name, models): &(&str, &[&str])) -> Vec<(String, Placement)> {
+    models.iter().enumerate().map(|(i, m)| {
+        let placement = match i {
+            0 => Placement::Item,
+            _ if name.starts_with("item_health") => Placement::Sphere,
+            _ => Placement::Ring,
+        };
+        (m.to_string(), placement)
+    }).collect()
+}
+
+//Items bob 4 units up and down at a rate that differs a little per entity and spin once every 2 seconds, like cg_ents.c
+pub fn placement_transform(e: &ModelEntity, time: f32) -> Matrix4<f32> {
+
+    let ms = (time * 1000.0) as i64;
+    let mut origin = e.origin;
+    let mut angles = e.angles;
+    if e.placement != Placement::Static {
+        let rate = 0.005 + e.entity as f32 * 0.00001;
+        origin[2] += 4.0 + ((ms + 1000) as f32 * rate).cos() * 4.0;
+        angles = [0.0; 3];
+    }
+    match e.placement {
+        Placement::Item => angles[1] = (ms & 2047) as f32 * 360.0 / 2048.0,
+        Placement::Ring => {
+            origin[2] += 12.0;
+            angles[1] = (ms & 1023) as f32 * 360.0 / -1024.0;
+        }
+        _ => {}
+    }
+
+    Matrix4::from_translation(Vector3::new(origin[0], origin[1], origin[2])) * angles_matrix(angles) * Matrix4::from_nonuniform_scale(e.scale[0], e.scale[1], e.scale[2])
+        * Matrix4::from_translation(Vector3::new(e.offset[0], e.offset[1], e.offset[2]))
+}
+
+//Pitch, yaw and roll in degrees to the forward, left and up axes of AnglesToAxis
+pub fn angles_matrix(angles: [f32; 3]) -> Matrix4<f32> {
+    Matrix4::from_angle_z(Deg(angles[1])) * Matrix4::from_angle_y(Deg(angles[0])) * Matrix4::from_angle_x(Deg(angles[2]))
+}
\ No newline at end of file
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use crate::bsp;
+use crate::bsp_shader;
+use crate::vfs;
+
+//Lightmaps from the lightmap lump, which are always 128x128, followed by the external ones q3map2 writes
+//to maps/<name>/lm_XXXX.tga when run with -external or a lightmap size other than 128
+
+pub const LUMP_LIGHT_MAP_SIZE: u32 = 128;
+
+pub struct LightMapImage {
+    pub rgb: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+//The XXXX of a path ending in lm_XXXX.tga
+pub fn external_number(path: &str) -> Option<usize> {
+
+    let file = path.rsplit('/').next()?.to_lowercase();
+    let stem = file.strip_suffix(".tga").unwrap_or(&file);
+    let digits = stem.strip_prefix("lm_")?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<usize>().ok()
+}
+
+//External lightmap files of a map in number order
+pub fn external_files(files: &Vec<String>) -> Vec<(usize, String)> {
+
+    let mut external = files.iter().filter_map(|f| external_number(f).map(|n| (n, f.clone()))).collect::<Vec<(usize, String)>>();
+    external.sort();
+    external
+}
+
+//Slot of a face's lightmap in the lump lightmaps followed by the loaded external ones.
+//Faces lit by an external lightmap have a negative index and a shader whose lightmap stage names the file,
+//without such a shader the index is the external file number when the lump is empty
+pub fn remap(lightmap_index: i32, shader_lightmap: Option<usize>, num_internal: usize, external: &Vec<usize>) -> Option<usize> {
+
+    let external_slot = |number: usize| external.iter().position(|n| *n == number).map(|i| num_internal + i);
+    if let Some(number) = shader_lightmap {
+        return external_slot(number);
+    }
+    if lightmap_index < 0 {
+        return None;
+    }
+    let index = lightmap_index as usize;
+    if index < num_internal {
+        Some(index)
+    }
+    else if num_internal == 0 {
+        external_slot(index)
+    }
+    else {
+        None
+    }
+}
+
+//The images of a map and the numbers of the external files that loaded, in the order they follow the lump ones
+pub fn load_light_maps(fs: &vfs::FileSystem, map: &str, light_maps: &Vec<bsp::LightMap>) -> (Vec<LightMapImage>, Vec<usize>) {
+
+    let mut images = light_maps.iter().map(|l| LightMapImage {
+        rgb: bytemuck::bytes_of(l).to_vec(),
+        width: LUMP_LIGHT_MAP_SIZE,
+        height: LUMP_LIGHT_MAP_SIZE,
+    }).collect::<Vec<LightMapImage>>();
+
+    let mut external: Vec<usize> = Vec::new();
+    for (number, path) in external_files(&fs.list(&format!("maps/{}", map), ".tga")) {
+        match fs.read(&path).and_then(|bytes| image::load_from_memory_with_format(&bytes, image::ImageFormat::Tga).ok()) {
+            Some(img) => {
+                let rgb = img.to_rgb8();
+                images.push(LightMapImage { width: rgb.width(), height: rgb.height(), rgb: rgb.into_raw() });
+                external.push(number);
+            }
+            None => println!("Could not load lightmap {}", path),
+        }
+    }
+    if external.len() > 0 {
+        println!("Loaded {} external lightmaps", external.len());
+    }
+    (images, external)
+}
+
+//External lightmap number named by the lightmap stage of each texture's shader
+pub fn shader_light_maps(textures: &Vec<bsp::Texture>, shaders: &HashMap<String, bsp_shader::Shader>) -> Vec<Option<usize>> {
+    textures.iter().map(|t| shaders.get(&bsp::Bsp::name_to_string(&t.name).to_lowercase())
+        .and_then(|s| s.stages.iter().filter(|s| s.is_lightmap()).find_map(|s| s.map().and_then(external_number)))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(name: &str) -> bsp::Texture {
+        let mut texture = bsp::Texture { name: [0; 64], flags: 0, contents: 0 };
+        texture.name[..name.len()].copy_from_slice(name.as_bytes());
+        texture
+    }
+
+    #[test]
+    fn external_numbers() {
+        assert_eq!(external_number("maps/q3dm1/lm_0003.tga"), Some(3));
+        assert_eq!(external_number("MAPS/Q3DM1/LM_0010.TGA"), Some(10));
+        assert_eq!(external_number("lm_0002"), Some(2));
+        assert_eq!(external_number("maps/q3dm1/lm_.tga"), None);
+        assert_eq!(external_number("maps/q3dm1/lm_00a1.tga"), None);
+        assert_eq!(external_number("maps/q3dm1/lightmap.tga"), None);
+        assert_eq!(external_number("maps/lm_0001/floor.tga"), None);
+
+        let files = vec!["maps/m/lm_0002.tga".to_string(), "maps/m/levelshot.tga".to_string(), "maps/m/lm_0000.tga".to_string()];
+        assert_eq!(external_files(&files), vec![(0, "maps/m/lm_0000.tga".to_string()), (2, "maps/m/lm_0002.tga".to_string())]);
+    }
+
+    #[test]
+    fn lump_only() {
+        assert_eq!(remap(0, None, 5, &Vec::new()), Some(0));
+        assert_eq!(remap(4, None, 5, &Vec::new()), Some(4));
+        assert_eq!(remap(5, None, 5, &Vec::new()), None);
+        assert_eq!(remap(-1, None, 5, &Vec::new()), None);
+    }
+
+    #[test]
+    fn external_only() {
+        //The external files follow the empty lump, lm_0002 did not load
+        let external = vec![0, 1, 3];
+        assert_eq!(remap(1, None, 0, &external), Some(1));
+        assert_eq!(remap(3, None, 0, &external), Some(2));
+        assert_eq!(remap(2, None, 0, &external), None);
+        assert_eq!(remap(-3, None, 0, &external), None);
+    }
+
+    #[test]
+    fn shader_named() {
+        let external = vec![0, 2];
+        assert_eq!(remap(-3, Some(2), 5, &external), Some(6));
+        assert_eq!(remap(-3, Some(0), 5, &external), Some(5));
+        //The shader wins over the index in the face
+        assert_eq!(remap(1, Some(0), 5, &external), Some(5));
+        assert_eq!(remap(-3, Some(7), 5, &external), None);
+        //Lumps and external files without a shader only use the lump
+        assert_eq!(remap(6, None, 5, &external), None);
+    }
+
+    #[test]
+    fn shader_stages() {
+        let mut shaders = HashMap::new();
+        bsp_shader::parse_shaders("
+            textures/m/floor
+            {
+                {
+                    map maps/m/lm_0001.tga
+                    tcGen lightmap
+                }
+                {
+                    map textures/base/floor.tga
+                    blendFunc filter
+                }
+            }
+            textures/m/detail
+            {
+                {
+                    map $lightmap
+                }
+                {
+                    map textures/detail/grain.tga
+                    tcGen lightmap
+                    blendFunc filter
+                }
+            }", &mut shaders);
+        let textures = vec![texture("textures/m/floor"), texture("textures/m/detail"), texture("textures/m/missing")];
+        assert_eq!(shader_light_maps(&textures, &shaders), vec![Some(1), None, None]);
+
+        let detail = &shaders["textures/m/detail"];
+        assert!(detail.stages[0].is_lightmap());
+        assert!(!detail.stages[1].is_lightmap());
+        assert_eq!(detail.diffuse_stage().and_then(|s| s.map()), Some("textures/detail/grain.tga"));
+        assert_eq!(shaders["textures/m/floor"].diffuse_stage().and_then(|s| s.map()), Some("textures/base/floor.tga"));
+    }
+}
\ No newline at end of file
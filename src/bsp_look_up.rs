@@ -14,7 +14,6 @@ pub fn look_up_table(val: &str) -> String {
         "textures/base_light/baslt4_1_2k" => "textures/base_light/baslt4_1".to_string(),
         "textures/base_light/patch10_pj_lite2_1000" => "textures/base_light/patch10_pj_lite2".to_string(),
         "textures/sfx/flameanim_green_pj" => "textures/sfx/g_flame1".to_string(),
-        "textures/sfx/q3dm9fog" => "textures/liquids/kc_fogcloud3".to_string(),
         "textures/sfx/diamond2cjumppad" => "textures/sfx/bouncepad01b_layer1".to_string(),
         "textures/sfx/teslacoil3" => "textures/sfx/cabletest2".to_string(),
         "textures/liquids/slime1" => "textures/liquids/slime7".to_string(),
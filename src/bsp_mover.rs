@@ -0,0 +1,566 @@
+use cgmath::{Matrix4, Vector3};
+
+use crate::bsp;
+use crate::bsp_entity;
+use crate::bsp_item;
+
+//Brush entities that move their *N submodel like g_mover.c does. Doors and plats are binary movers
+//that the camera triggers as if it were a player, bobbing, rotating and trains move on their own
+
+//The camera stands in for a player's eyes, a player origin is this far below them
+const PLAYER_EYE_HEIGHT: f32 = 50.0;
+//Doors open when a player comes this close in the direction they are thinnest
+const DOOR_TRIGGER_SIZE: f32 = 120.0;
+//Plat triggers are smaller than the plat so a player has to stand on it
+const PLAT_TRIGGER_INSET: f32 = 33.0;
+
+const DOOR_START_OPEN: i32 = 1;
+const BOBBING_X_AXIS: i32 = 1;
+const BOBBING_Y_AXIS: i32 = 2;
+const ROTATING_X_AXIS: i32 = 4;
+const ROTATING_Y_AXIS: i32 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryState {
+    //At pos1
+    Rest,
+    Opening,
+    //At pos2 until the time
+    Open(f32),
+    Closing,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Corner {
+    pub origin: [f32; 3],
+    //Speed to the next corner, 0 uses the train speed
+    pub speed: f32,
+    //Seconds to wait before leaving for the next corner
+    pub wait: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Motion {
+    //Doors and plats, fraction goes from 0 at pos1 to 1 at pos2. A negative wait never goes back
+    Binary { pos1: [f32; 3], pos2: [f32; 3], speed: f32, wait: f32, trigger: Option<([f32; 3], [f32; 3])>, trigger_moves: bool, state: BinaryState, fraction: f32 },
+    //Moves height along an axis and back every period seconds, phase is the part of the period it lags behind
+    Bobbing { delta: [f32; 3], period: f32, phase: f32 },
+    //Degrees per second around each axis
+    Rotating { delta: [f32; 3] },
+    //The origin brush of the train visits each corner, looped when the last corner targets the first
+    Train { corners: Vec<Corner>, looped: bool, speed: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mover {
+    pub entity: usize,
+    pub model: usize,
+    pub team: Option<String>,
+    pub origin: [f32; 3],
+    pub angles: [f32; 3],
+    pub motion: Motion,
+    //Current position and angles, the model is drawn at the translation and rotation of these
+    pub position: [f32; 3],
+    pub rotation: [f32; 3],
+}
+
+impl Mover {
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(Vector3::new(self.position[0], self.position[1], self.position[2])) * bsp_item::angles_matrix(self.rotation)
+    }
+
+    //Doors that are away from where they were built open their area portals
+    pub fn is_open(&self) -> bool {
+        self.position != self.origin
+    }
+
+    fn triggered(&self, eye: [f32; 3]) -> bool {
+
+        match &self.motion {
+            Motion::Binary { pos1, trigger: Some((mins, maxs)), trigger_moves, .. } => {
+                let offset = if *trigger_moves { [self.position[0] - pos1[0], self.position[1] - pos1[1], self.position[2] - pos1[2]] } else { [0.0; 3] };
+                (0..3).all(|i| eye[i] >= mins[i] + offset[i] && eye[i] <= maxs[i] + offset[i] + if i == 2 { PLAYER_EYE_HEIGHT } else { 0.0 })
+            }
+            _ => false,
+        }
+    }
+
+    //dt is the seconds since the last update
+    fn update(&mut self, time: f32, dt: f32, triggered: bool) {
+
+        match &mut self.motion {
+            Motion::Binary { pos1, pos2, speed, wait, state, fraction, .. } => {
+                let distance = (0..3).map(|i| (pos2[i] - pos1[i]) * (pos2[i] - pos1[i])).sum::<f32>().sqrt();
+                let step = if distance > 0.0 { dt * *speed / distance } else { 1.0 };
+                *state = match *state {
+                    BinaryState::Rest | BinaryState::Closing if triggered => BinaryState::Opening,
+                    BinaryState::Open(_) if triggered => BinaryState::Open(time + *wait),
+                    BinaryState::Open(until) if *wait >= 0.0 && time >= until => BinaryState::Closing,
+                    state => state,
+                };
+                match *state {
+                    BinaryState::Opening => {
+                        *fraction += step;
+                        if *fraction >= 1.0 {
+                            *fraction = 1.0;
+                            *state = BinaryState::Open(time + *wait);
+                        }
+                    }
+                    BinaryState::Closing => {
+                        *fraction -= step;
+                        if *fraction <= 0.0 {
+                            *fraction = 0.0;
+                            *state = BinaryState::Rest;
+                        }
+                    }
+                    _ => {}
+                }
+                self.position = lerp(*pos1, *pos2, *fraction);
+            }
+            Motion::Bobbing { delta, period, phase } => {
+                let s = ((time / *period - *phase) * std::f32::consts::PI * 2.0).sin();
+                self.position = [self.origin[0] + delta[0] * s, self.origin[1] + delta[1] * s, self.origin[2] + delta[2] * s];
+            }
+            Motion::Rotating { delta } => {
+                self.rotation = [(self.angles[0] + delta[0] * time) % 360.0, (self.angles[1] + delta[1] * time) % 360.0, (self.angles[2] + delta[2] * time) % 360.0];
+            }
+            Motion::Train { corners, looped, speed } => {
+                self.position = train_position(corners, *looped, *speed, time);
+            }
+        }
+    }
+}
+
+pub struct Movers {
+    pub movers: Vec<Mover>,
+    time: Option<f32>,
+}
+
+impl Movers {
+
+    pub fn new(bsp: &bsp::Bsp) -> Movers {
+
+        let mut movers: Vec<Mover> = Vec::new();
+        for (i, entity) in bsp.entities.iter().enumerate() {
+            let model = match bsp::Bsp::entity_model(entity) {
+                Some(model) if model > 0 => model,
+                _ => continue,
+            };
+            let (mins, maxs) = match bsp.model_local_bounds(model) {
+                Some(bounds) => bounds,
+                None => {
+                    println!("{} has no model {}", entity.class_name(), model);
+                    continue;
+                }
+            };
+            let origin = entity.origin().unwrap_or([0.0; 3]);
+            let angles = entity.angles().unwrap_or([0.0; 3]);
+            let motion = match entity.class_name() {
+                "func_door" => door(entity, origin, mins, maxs),
+                "func_plat" => plat(entity, origin, mins, maxs),
+                "func_bobbing" => bobbing(entity),
+                "func_rotating" => rotating(entity),
+                "func_train" => match train(entity, &bsp.entities) {
+                    Some(motion) => motion,
+                    None => {
+                        println!("func_train {} has no path_corner", i);
+                        continue;
+                    }
+                },
+                _ => continue,
+            };
+            //Doors and plats rest at pos1, which a plat is below where it was built and a door starting open is away from it
+            let position = match &motion {
+                Motion::Binary { pos1, .. } => *pos1,
+                Motion::Train { corners, .. } => corners[0].origin,
+                _ => origin,
+            };
+            //Only rotating movers start turned by their angles, the others use them for a move direction or not at all
+            let rotation = match &motion {
+                Motion::Rotating { .. } => angles,
+                _ => [0.0; 3],
+            };
+            movers.push(Mover { entity: i, model, team: entity.get("team").map(|t| t.to_string()), origin, angles, motion, position, rotation });
+        }
+        println!("{} movers", movers.len());
+        Movers { movers, time: None }
+    }
+
+    //Doors in a team open together when any of them is triggered, returns the doors whose area portals changed
+    pub fn update(&mut self, time: f32, eye: [f32; 3]) -> Vec<(usize, bool)> {
+
+        let dt = (time - self.time.unwrap_or(time)).max(0.0);
+        self.time = Some(time);
+        let mut triggered = self.movers.iter().map(|m| m.triggered(eye)).collect::<Vec<bool>>();
+        for i in 0..self.movers.len() {
+            if let Some(team) = self.movers[i].team.as_ref() {
+                triggered[i] = self.movers.iter().zip(triggered.iter()).any(|(m, t)| *t && m.team.as_ref() == Some(team));
+            }
+        }
+
+        let mut changed: Vec<(usize, bool)> = Vec::new();
+        for (mover, triggered) in self.movers.iter_mut().zip(triggered.into_iter()) {
+            let open = mover.is_open();
+            mover.update(time, dt, triggered);
+            if mover.is_open() != open {
+                changed.push((mover.entity, mover.is_open()));
+            }
+        }
+        changed
+    }
+
+    //Places the mover of a model where a demo entity is, returns its door if its area portal changed
+    pub fn place(&mut self, model: usize, position: [f32; 3], rotation: [f32; 3]) -> Option<(usize, bool)> {
+
+        let mover = self.movers.iter_mut().find(|m| m.model == model)?;
+        let open = mover.is_open();
+        mover.position = position;
+        mover.rotation = rotation;
+        Some((mover.entity, mover.is_open())).filter(|_| mover.is_open() != open)
+    }
+}
+
+//G_SetMovedir, angle -1 is up and -2 down
+fn move_dir(angles: [f32; 3]) -> [f32; 3] {
+
+    if angles == [0.0, -1.0, 0.0] {
+        return [0.0, 0.0, 1.0];
+    }
+    if angles == [0.0, -2.0, 0.0] {
+        return [0.0, 0.0, -1.0];
+    }
+    let (pitch, yaw) = (angles[0].to_radians(), angles[1].to_radians());
+    [pitch.cos() * yaw.cos(), pitch.cos() * yaw.sin(), -pitch.sin()]
+}
+
+//Slides along its angle by its size less lip, the trigger around it is grown in its thinnest direction
+fn door(entity: &bsp_entity::Entity, origin: [f32; 3], mins: [f32; 3], maxs: [f32; 3]) -> Motion {
+
+    let speed = entity.float("speed").unwrap_or(400.0);
+    let wait = entity.float("wait").unwrap_or(2.0);
+    let lip = entity.float("lip").unwrap_or(8.0);
+    let dir = move_dir(entity.angles().unwrap_or([0.0; 3]));
+    let distance = (0..3).map(|i| dir[i].abs() * (maxs[i] - mins[i])).sum::<f32>() - lip;
+    let moved = [origin[0] + dir[0] * distance, origin[1] + dir[1] * distance, origin[2] + dir[2] * distance];
+    let (pos1, pos2) = if entity.float("spawnflags").map_or(false, |f| f as i32 & DOOR_START_OPEN != 0) { (moved, origin) } else { (origin, moved) };
+
+    //Doors opened by a button or by shooting them have no trigger
+    let trigger = if entity.get("targetname").is_some() || entity.float("health").map_or(false, |h| h > 0.0) {
+        None
+    }
+    else {
+        let (mut tmins, mut tmaxs) = ([mins[0] + pos1[0], mins[1] + pos1[1], mins[2] + pos1[2]], [maxs[0] + pos1[0], maxs[1] + pos1[1], maxs[2] + pos1[2]]);
+        let thinnest = (0..3).min_by(|a, b| (maxs[*a] - mins[*a]).partial_cmp(&(maxs[*b] - mins[*b])).unwrap()).unwrap();
+        tmins[thinnest] -= DOOR_TRIGGER_SIZE;
+        tmaxs[thinnest] += DOOR_TRIGGER_SIZE;
+        Some((tmins, tmaxs))
+    };
+    Motion::Binary { pos1, pos2, speed, wait, trigger, trigger_moves: false, state: BinaryState::Rest, fraction: 0.0 }
+}
+
+//Rests height below where it was built and rises while someone stands on it
+fn plat(entity: &bsp_entity::Entity, origin: [f32; 3], mins: [f32; 3], maxs: [f32; 3]) -> Motion {
+
+    let speed = entity.float("speed").unwrap_or(200.0);
+    let wait = entity.float("wait").unwrap_or(1.0);
+    let lip = entity.float("lip").unwrap_or(8.0);
+    let height = entity.float("height").unwrap_or(maxs[2] - mins[2] - lip);
+    let pos1 = [origin[0], origin[1], origin[2] - height];
+
+    let mut tmins = [pos1[0] + mins[0] + PLAT_TRIGGER_INSET, pos1[1] + mins[1] + PLAT_TRIGGER_INSET, pos1[2] + mins[2]];
+    let mut tmaxs = [pos1[0] + maxs[0] - PLAT_TRIGGER_INSET, pos1[1] + maxs[1] - PLAT_TRIGGER_INSET, pos1[2] + maxs[2] + 8.0];
+    for i in 0..2 {
+        if tmaxs[i] <= tmins[i] {
+            tmins[i] = pos1[i] + (mins[i] + maxs[i]) * 0.5;
+            tmaxs[i] = tmins[i] + 1.0;
+        }
+    }
+    Motion::Binary { pos1, pos2: origin, speed, wait, trigger: Some((tmins, tmaxs)), trigger_moves: true, state: BinaryState::Rest, fraction: 0.0 }
+}
+
+fn bobbing(entity: &bsp_entity::Entity) -> Motion {
+
+    let period = entity.float("speed").unwrap_or(4.0).max(0.001);
+    let height = entity.float("height").unwrap_or(32.0);
+    let phase = entity.float("phase").unwrap_or(0.0);
+    let flags = entity.float("spawnflags").unwrap_or(0.0) as i32;
+    let axis = if flags & BOBBING_X_AXIS != 0 { 0 } else if flags & BOBBING_Y_AXIS != 0 { 1 } else { 2 };
+    let mut delta = [0.0; 3];
+    delta[axis] = height;
+    Motion::Bobbing { delta, period, phase }
+}
+
+fn rotating(entity: &bsp_entity::Entity) -> Motion {
+
+    let speed = entity.float("speed").filter(|s| *s != 0.0).unwrap_or(100.0);
+    let flags = entity.float("spawnflags").unwrap_or(0.0) as i32;
+    let axis = if flags & ROTATING_X_AXIS != 0 { 2 } else if flags & ROTATING_Y_AXIS != 0 { 0 } else { 1 };
+    let mut delta = [0.0; 3];
+    delta[axis] = speed;
+    Motion::Rotating { delta }
+}
+
+//Follows target keys from the train through the path_corners until the chain ends or comes back round
+fn train(entity: &bsp_entity::Entity, entities: &Vec<bsp_entity::Entity>) -> Option<Motion> {
+
+    let mut corners: Vec<Corner> = Vec::new();
+    let mut visited: Vec<usize> = Vec::new();
+    let mut target = entity.get("target");
+    let mut looped = false;
+    while let Some(name) = target {
+        let next = match entities.iter().position(|e| e.class_name() == "path_corner" && e.get("targetname") == Some(name)) {
+            Some(next) => next,
+            None => break,
+        };
+        if visited.contains(&next) {
+            looped = visited[0] == next;
+            break;
+        }
+        visited.push(next);
+        let corner = &entities[next];
+        corners.push(Corner { origin: corner.origin().unwrap_or([0.0; 3]), speed: corner.float("speed").unwrap_or(0.0), wait: corner.float("wait").unwrap_or(0.0) });
+        target = corner.get("target");
+    }
+    if corners.is_empty() {
+        return None;
+    }
+    Some(Motion::Train { corners, looped, speed: entity.float("speed").filter(|s| *s != 0.0).unwrap_or(100.0) })
+}
+
+//Where a train is after time seconds, it waits at each corner and then moves to the next at the corner's speed
+pub fn train_position(corners: &Vec<Corner>, looped: bool, speed: f32, time: f32) -> [f32; 3] {
+
+    let legs = if looped { corners.len() } else { corners.len() - 1 };
+    let leg_time = |i: usize| {
+        let (from, to) = (corners[i].origin, corners[(i + 1) % corners.len()].origin);
+        let distance = (0..3).map(|k| (to[k] - from[k]) * (to[k] - from[k])).sum::<f32>().sqrt();
+        let speed = if corners[i].speed > 0.0 { corners[i].speed } else { speed };
+        (corners[i].wait.max(0.0), distance / speed.max(1.0))
+    };
+    //A negative wait stops the train at that corner for good, so it never goes round again
+    let period = (0..legs).map(|i| { let (wait, travel) = leg_time(i); wait + travel }).sum::<f32>();
+    let stops = corners.iter().take(legs).any(|c| c.wait < 0.0);
+    let mut t = if looped && !stops && period > 0.0 { time % period } else { time };
+    for i in 0..legs {
+        if corners[i].wait < 0.0 {
+            return corners[i].origin;
+        }
+        let (wait, travel) = leg_time(i);
+        if t < wait {
+            return corners[i].origin;
+        }
+        t -= wait;
+        if t < travel {
+            return lerp(corners[i].origin, corners[(i + 1) % corners.len()].origin, t / travel);
+        }
+        t -= travel;
+    }
+    corners[legs % corners.len()].origin
+}
+
+fn lerp(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mover(motion: Motion) -> Mover {
+        Mover { entity: 0, model: 1, team: None, origin: [0.0, 0.0, 100.0], angles: [0.0; 3], motion, position: [0.0, 0.0, 100.0], rotation: [0.0; 3] }
+    }
+
+    #[test]
+    fn bobbing_phase() {
+        //A quarter period of phase puts the mover a quarter period behind, at the bottom when it starts
+        let mut bobbing = mover(Motion::Bobbing { delta: [0.0, 0.0, 32.0], period: 4.0, phase: 0.25 });
+        let z = |m: &mut Mover, time: f32| {
+            m.update(time, 0.0, false);
+            m.position[2]
+        };
+        assert!((z(&mut bobbing, 0.0) - 68.0).abs() < 0.001);
+        assert!((z(&mut bobbing, 1.0) - 100.0).abs() < 0.001);
+        assert!((z(&mut bobbing, 2.0) - 132.0).abs() < 0.001);
+        assert!((z(&mut bobbing, 4.0) - 68.0).abs() < 0.001);
+        assert_eq!(bobbing.rotation, [0.0; 3]);
+    }
+
+    fn entity(properties: &[(&str, &str)]) -> bsp_entity::Entity {
+        bsp_entity::Entity { properties: properties.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    fn binary(wait: f32) -> Motion {
+        Motion::Binary { pos1: [0.0; 3], pos2: [100.0, 0.0, 0.0], speed: 100.0, wait, trigger: None, trigger_moves: false, state: BinaryState::Rest, fraction: 0.0 }
+    }
+
+    fn state(mover: &Mover) -> (BinaryState, f32) {
+        match mover.motion {
+            Motion::Binary { state, fraction, .. } => (state, fraction),
+            _ => panic!("not a binary mover"),
+        }
+    }
+
+    #[test]
+    fn binary_states() {
+        let mut door = mover(binary(2.0));
+        door.origin = [0.0; 3];
+        door.position = [0.0; 3];
+        door.update(0.0, 0.0, true);
+        assert_eq!(state(&door), (BinaryState::Opening, 0.0));
+        door.update(0.5, 0.5, false);
+        assert_eq!(state(&door), (BinaryState::Opening, 0.5));
+        assert_eq!(door.position, [50.0, 0.0, 0.0]);
+        assert!(door.is_open());
+        door.update(1.0, 0.5, false);
+        assert_eq!(state(&door), (BinaryState::Open(3.0), 1.0));
+
+        //Triggering an open door holds it open for another wait
+        door.update(2.0, 1.0, true);
+        assert_eq!(state(&door), (BinaryState::Open(4.0), 1.0));
+        door.update(3.5, 1.5, false);
+        assert_eq!(state(&door), (BinaryState::Open(4.0), 1.0));
+        door.update(4.0, 0.5, false);
+        assert_eq!(state(&door), (BinaryState::Closing, 0.5));
+
+        //A closing door goes back up when triggered
+        door.update(4.25, 0.25, true);
+        assert_eq!(state(&door), (BinaryState::Opening, 0.75));
+        door.update(5.0, 0.75, false);
+        door.update(8.0, 3.0, false);
+        door.update(9.0, 1.0, false);
+        assert_eq!(state(&door), (BinaryState::Rest, 0.0));
+        assert_eq!(door.position, [0.0; 3]);
+        assert!(!door.is_open());
+    }
+
+    #[test]
+    fn binary_negative_wait_stays_open() {
+        let mut door = mover(binary(-1.0));
+        door.update(0.0, 0.0, true);
+        door.update(1.0, 1.0, false);
+        door.update(1000.0, 999.0, false);
+        assert_eq!(state(&door), (BinaryState::Open(0.0), 1.0));
+        assert_eq!(door.position, [100.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn door_motion() {
+        let (mins, maxs) = ([-8.0, -32.0, 0.0], [8.0, 32.0, 64.0]);
+        let origin = [10.0, 20.0, 30.0];
+        match door(&entity(&[("angle", "0"), ("speed", "200"), ("lip", "4")]), origin, mins, maxs) {
+            Motion::Binary { pos1, pos2, speed, wait, trigger: Some((tmins, tmaxs)), trigger_moves, .. } => {
+                //Moves its 16 unit width less the lip along x, the trigger grows in x
+                assert_eq!((pos1, pos2, speed, wait, trigger_moves), (origin, [22.0, 20.0, 30.0], 200.0, 2.0, false));
+                assert_eq!(tmins, [2.0 - DOOR_TRIGGER_SIZE, -12.0, 30.0]);
+                assert_eq!(tmaxs, [18.0 + DOOR_TRIGGER_SIZE, 52.0, 94.0]);
+            }
+            motion => panic!("{:?}", motion),
+        }
+
+        //Up, starting open, and shot open so there is no trigger
+        match door(&entity(&[("angle", "-1"), ("spawnflags", "1"), ("health", "10")]), origin, mins, maxs) {
+            Motion::Binary { pos1, pos2, trigger, .. } => assert_eq!((pos1, pos2, trigger), ([10.0, 20.0, 86.0], origin, None)),
+            motion => panic!("{:?}", motion),
+        }
+        match door(&entity(&[("angle", "90"), ("targetname", "button")]), origin, mins, maxs) {
+            Motion::Binary { pos2, trigger, .. } => {
+                assert!((pos2[1] - 76.0).abs() < 0.001 && (pos2[0] - 10.0).abs() < 0.001);
+                assert_eq!(trigger, None);
+            }
+            motion => panic!("{:?}", motion),
+        }
+    }
+
+    #[test]
+    fn plat_motion() {
+        let origin = [0.0, 0.0, 100.0];
+        match plat(&entity(&[]), origin, [-64.0, -48.0, -8.0], [64.0, 48.0, 8.0]) {
+            Motion::Binary { pos1, pos2, trigger: Some((tmins, tmaxs)), trigger_moves, .. } => {
+                //Rests its height less the lip below, the trigger is inset on the sides and reaches 8 units above
+                assert_eq!((pos1, pos2, trigger_moves), ([0.0, 0.0, 92.0], origin, true));
+                assert_eq!((tmins, tmaxs), ([-31.0, -15.0, 84.0], [31.0, 15.0, 108.0]));
+            }
+            motion => panic!("{:?}", motion),
+        }
+
+        //Too narrow for the inset, the trigger becomes a unit wide strip through the middle
+        match plat(&entity(&[("height", "50")]), origin, [-16.0, 0.0, -8.0], [16.0, 64.0, 8.0]) {
+            Motion::Binary { pos1, trigger: Some((tmins, tmaxs)), .. } => {
+                assert_eq!(pos1, [0.0, 0.0, 50.0]);
+                assert_eq!((tmins, tmaxs), ([0.0, 32.0, 42.0], [1.0, 33.0, 66.0]));
+            }
+            motion => panic!("{:?}", motion),
+        }
+    }
+
+    #[test]
+    fn plat_trigger_moves_with_it() {
+        let mut plat = mover(plat(&entity(&[]), [0.0; 3], [-64.0, -64.0, -8.0], [64.0, 64.0, 8.0]));
+        plat.origin = [0.0; 3];
+        plat.position = [0.0, 0.0, -8.0];
+        assert!(plat.triggered([0.0, 0.0, 40.0]));
+        assert!(!plat.triggered([40.0, 0.0, 40.0]));
+        plat.position = [0.0; 3];
+        assert!(!plat.triggered([0.0, 0.0, -16.0]));
+        assert!(plat.triggered([0.0, 0.0, 48.0]));
+    }
+
+    #[test]
+    fn team_doors_open_together() {
+        let door = |entity: usize, team: Option<&str>, x: f32| {
+            let motion = Motion::Binary { pos1: [x, 0.0, 0.0], pos2: [x, 0.0, 100.0], speed: 100.0, wait: 2.0, trigger: Some(([x - 10.0, -10.0, 0.0], [x + 10.0, 10.0, 10.0])), trigger_moves: false, state: BinaryState::Rest, fraction: 0.0 };
+            Mover { entity, model: entity, team: team.map(|t| t.to_string()), origin: [x, 0.0, 0.0], angles: [0.0; 3], motion, position: [x, 0.0, 0.0], rotation: [0.0; 3] }
+        };
+        let mut movers = Movers { movers: vec![door(1, Some("gate"), 0.0), door(2, Some("gate"), 500.0), door(3, None, 1000.0)], time: None };
+        assert!(movers.update(0.0, [0.0, 0.0, 50.0]).is_empty());
+        assert_eq!(movers.update(0.5, [0.0, 0.0, 50.0]), vec![(1, true), (2, true)]);
+        assert!(!movers.movers[2].is_open());
+
+        //Closed again once the wait is over, the untriggered door never moved
+        let away = [0.0, 500.0, 0.0];
+        assert!(movers.update(1.0, away).is_empty());
+        assert!(movers.update(2.5, away).is_empty());
+        assert_eq!(movers.update(3.5, away), vec![(1, false), (2, false)]);
+    }
+
+    #[test]
+    fn trains() {
+        let corner = |x: f32, y: f32, wait: f32| Corner { origin: [x, y, 0.0], speed: 0.0, wait };
+        let corners = vec![corner(0.0, 0.0, 1.0), corner(100.0, 0.0, 0.0), corner(100.0, 100.0, 0.0)];
+
+        //Waits at the first corner, then 1 second per 100 units
+        assert_eq!(train_position(&corners, false, 100.0, 0.5), [0.0, 0.0, 0.0]);
+        assert_eq!(train_position(&corners, false, 100.0, 1.5), [50.0, 0.0, 0.0]);
+        assert_eq!(train_position(&corners, false, 100.0, 2.5), [100.0, 50.0, 0.0]);
+        assert_eq!(train_position(&corners, false, 100.0, 100.0), [100.0, 100.0, 0.0]);
+
+        //Looped it goes back to the first corner and starts over
+        let period = 3.0 + 2.0f32.sqrt();
+        let back = train_position(&corners, true, 100.0, 3.0 + 2.0f32.sqrt() / 2.0);
+        assert!((back[0] - 50.0).abs() < 0.01 && (back[1] - 50.0).abs() < 0.01);
+        let again = train_position(&corners, true, 100.0, period * 3.0 + 1.5);
+        assert!((again[0] - 50.0).abs() < 0.01 && again[1].abs() < 0.01);
+
+        //A corner's own speed is used to leave it and a negative wait stops the train there
+        let mut slow = corners.clone();
+        slow[0].speed = 50.0;
+        assert_eq!(train_position(&slow, false, 100.0, 2.0), [50.0, 0.0, 0.0]);
+        let mut stop = corners.clone();
+        stop[1].wait = -1.0;
+        assert_eq!(train_position(&stop, true, 100.0, 50.0), [100.0, 0.0, 0.0]);
+
+        let single = vec![corner(5.0, 6.0, 0.0)];
+        assert_eq!(train_position(&single, false, 100.0, 10.0), [5.0, 6.0, 0.0]);
+        assert_eq!(train_position(&single, true, 100.0, 10.0), [5.0, 6.0, 0.0]);
+    }
+
+    #[test]
+    fn rotating() {
+        let mut rotating = mover(Motion::Rotating { delta: [0.0, 90.0, 0.0] });
+        rotating.angles = [0.0, 45.0, 0.0];
+        rotating.update(5.0, 0.0, false);
+        assert_eq!(rotating.rotation, [0.0, 135.0, 0.0]);
+        assert_eq!(rotating.position, rotating.origin);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use crate::bsp_light_map;
+
+//Quake 3 shader scripts (scripts/*.shader)
+//https://icculus.org/gtkradiant/documentation/Q3AShader_Manual/
+
+//Sort keys, lower is drawn first
+pub const SORT_PORTAL: f32 = 1.0;
+pub const SORT_ENVIRONMENT: f32 = 2.0;
+pub const SORT_OPAQUE: f32 = 3.0;
+pub const SORT_DECAL: f32 = 4.0;
+pub const SORT_SEE_THROUGH: f32 = 5.0;
+pub const SORT_BANNER: f32 = 6.0;
+pub const SORT_UNDERWATER: f32 = 8.0;
+pub const SORT_BLEND0: f32 = 9.0;
+pub const SORT_ADDITIVE: f32 = 10.0;
+pub const SORT_NEAREST: f32 = 16.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Alpha,
+    Additive,
+    Filter,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AlphaFunc {
+    None,
+    Gt0,
+    Lt128,
+    Ge128,
+}
+
+impl AlphaFunc {
+
+    //Value of alpha_func in the material uniforms
+    pub fn code(&self) -> f32 {
+        match self {
+            AlphaFunc::None => 0.0,
+            AlphaFunc::Gt0 => 1.0,
+            AlphaFunc::Lt128 => 2.0,
+            AlphaFunc::Ge128 => 3.0,
+        }
+    }
+}
+
+//How the renderer draws a surface, opaque unless a shader says otherwise
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceState {
+    pub sort: f32,
+    pub blend: Option<BlendMode>,
+    pub alpha_func: AlphaFunc,
+    pub two_sided: bool,
+    pub lightmapped: bool,
+}
+
+impl SurfaceState {
+
+    pub fn opaque() -> SurfaceState {
+        SurfaceState { sort: SORT_OPAQUE, blend: None, alpha_func: AlphaFunc::None, two_sided: false, lightmapped: true }
+    }
+
+    pub fn is_alpha_tested(&self) -> bool {
+        self.blend.is_none() && self.alpha_func != AlphaFunc::None
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FogParms {
+    pub colour: [f32; 3],
+    pub distance_to_opaque: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stage {
+    pub directives: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Shader {
+    pub name: String,
+    pub surface_parms: Vec<String>,
+    pub fog_parms: Option<FogParms>,
+    pub sort: Option<f32>,
+    pub cull_none: bool,
+    pub polygon_offset: bool,
+    pub directives: Vec<Vec<String>>,
+    pub stages: Vec<Stage>,
+}
+
+impl Shader {
+
+    fn new(name: &str) -> Shader {
+        Shader { name: name.to_string(), surface_parms: Vec::new(), fog_parms: None, sort: None, cull_none: false, polygon_offset: false, directives: Vec::new(), stages: Vec::new() }
+    }
+
+    //Blending and alpha testing come from the first stage like in Quake 3
+    pub fn surface_state(&self) -> SurfaceState {
+
+        let mut state = SurfaceState::opaque();
+        state.two_sided = self.cull_none;
+        //Stages without the lightmap are drawn at full brightness, like rgbGen identity
+        state.lightmapped = self.stages.is_empty() || self.stages.iter().any(|s| s.is_lightmap());
+
+        if let Some(stage) = self.stages.first() {
+            state.blend = stage.blend_mode();
+            state.alpha_func = stage.alpha_func();
+        }
+
+        state.sort = match self.sort {
+            Some(sort) => sort,
+            None => {
+                if state.blend.is_some() {
+                    SORT_BLEND0
+                }
+                else if self.polygon_offset {
+                    SORT_DECAL
+                }
+                else {
+                    SORT_OPAQUE
+                }
+            }
+        };
+        state
+    }
+
+    pub fn is_fog(&self) -> bool {
+        self.fog_parms.is_some()
+    }
+
+    //First stage that is not the lightmap, it holds the texture the renderer draws
+    pub fn diffuse_stage(&self) -> Option<&Stage> {
+        self.stages.iter().find(|s| !s.is_lightmap())
+    }
+
+    fn add_directive(&mut self, tokens: Vec<String>) {
+
+        match tokens[0].as_str() {
+            "surfaceparm" => {
+                if tokens.len() > 1 {
+                    self.surface_parms.push(tokens[1].to_lowercase());
+                }
+            }
+            "sort" => {
+                if tokens.len() > 1 {
+                    self.sort = match tokens[1].to_lowercase().as_str() {
+                        "portal" => Some(SORT_PORTAL),
+                        "sky" => Some(SORT_ENVIRONMENT),
+                        "opaque" => Some(SORT_OPAQUE),
+                        "decal" => Some(SORT_DECAL),
+                        "seethrough" => Some(SORT_SEE_THROUGH),
+                        "banner" => Some(SORT_BANNER),
+                        "underwater" => Some(SORT_UNDERWATER),
+                        "additive" => Some(SORT_ADDITIVE),
+                        "nearest" => Some(SORT_NEAREST),
+                        value => value.parse::<f32>().ok(),
+                    };
+                }
+            }
+            "cull" => {
+                if tokens.len() > 1 {
+                    let mode = tokens[1].to_lowercase();
+                    self.cull_none = mode == "none" || mode == "disable" || mode == "twosided";
+                }
+            }
+            "polygonoffset" => {
+                self.polygon_offset = true;
+            }
+            "fogparms" => {
+                //Both "fogparms ( r g b ) dist" and the older "fogparms r g b dist" are in use
+                let values = parse_floats(&tokens[1..]);
+                if values.len() >= 4 {
+                    self.fog_parms = Some(FogParms { colour: [values[0], values[1], values[2]], distance_to_opaque: values[3] });
+                }
+            }
+            _ => {}
+        }
+        self.directives.push(tokens);
+    }
+}
+
+impl Stage {
+
+    fn directive(&self, name: &str) -> Option<&Vec<String>> {
+        self.directives.iter().find(|d| d[0] == name)
+    }
+
+    //Image of a map or clampMap stage
+    pub fn map(&self) -> Option<&str> {
+        self.directive("map").or(self.directive("clampmap")).and_then(|t| t.get(1)).map(|t| t.as_str())
+    }
+
+    //$lightmap, or an external lm_XXXX image that q3map2 put in the shaders it writes for the map.
+    //Other images with tcGen lightmap are detail or glow textures laid over the lightmap coordinates
+    pub fn is_lightmap(&self) -> bool {
+        match self.directive("map") {
+            Some(tokens) if tokens.len() > 1 && tokens[1].to_lowercase() == "$lightmap" => true,
+            _ => self.directive("tcgen").map_or(false, |t| t.len() > 1 && t[1].to_lowercase() == "lightmap")
+                && self.map().and_then(bsp_light_map::external_number).is_some(),
+        }
+    }
+
+    pub fn blend_mode(&self) -> Option<BlendMode> {
+
+        let tokens = self.directive("blendfunc")?;
+        let args = tokens[1..].iter().map(|t| t.to_uppercase()).collect::<Vec<String>>();
+        match args.len() {
+            0 => None,
+            1 => match args[0].as_str() {
+                "ADD" => Some(BlendMode::Additive),
+                "FILTER" => Some(BlendMode::Filter),
+                "BLEND" => Some(BlendMode::Alpha),
+                _ => None,
+            },
+            _ => {
+                let (src, dst) = (args[0].as_str(), args[1].as_str());
+                if src == "GL_ONE" && dst == "GL_ZERO" {
+                    None
+                }
+                else if dst == "GL_ONE" {
+                    Some(BlendMode::Additive)
+                }
+                else if src == "GL_DST_COLOR" || src == "GL_ZERO" {
+                    Some(BlendMode::Filter)
+                }
+                else {
+                    Some(BlendMode::Alpha)
+                }
+            }
+        }
+    }
+
+    pub fn alpha_func(&self) -> AlphaFunc {
+
+        match self.directive("alphafunc").and_then(|t| t.get(1)).map(|t| t.to_uppercase()) {
+            Some(func) => match func.as_str() {
+                "GT0" => AlphaFunc::Gt0,
+                "LT128" => AlphaFunc::Lt128,
+                "GE128" => AlphaFunc::Ge128,
+                _ => AlphaFunc::None,
+            },
+            None => AlphaFunc::None,
+        }
+    }
+}
+
+//Numbers of a directive, ignoring the brackets around vectors
+pub fn parse_floats(tokens: &[String]) -> Vec<f32> {
+    tokens.iter().filter(|t| *t != "(" && *t != ")").filter_map(|t| t.parse::<f32>().ok()).collect()
+}
+
+fn tokenize_line(line: &str) -> Vec<String> {
+
+    let line = match line.find("//") {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    let mut tokens: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for c in line.chars() {
+        if c.is_whitespace() || c == '{' || c == '}' || c == '(' || c == ')' {
+            if !current.is_empty() {
+                tokens.push(current.clone());
+                current.clear();
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        }
+        else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+//Parses a shader script into shaders, keyed by lower case name. The first definition of a shader wins like in Quake 3
+pub fn parse_shaders(text: &str, shaders: &mut HashMap<String, Shader>) {
+
+    let mut depth = 0;
+    let mut in_comment = false;
+    let mut shader: Option<Shader> = None;
+
+    for raw_line in text.lines() {
+
+        //Block comments
+        let mut line = raw_line.to_string();
+        if in_comment {
+            match line.find("*/") {
+                Some(i) => {
+                    line = line[(i + 2)..].to_string();
+                    in_comment = false;
+                }
+                None => continue,
+            }
+        }
+        if let Some(i) = line.find("/*") {
+            match line[i..].find("*/") {
+                Some(j) => line = format!("{}{}", &line[..i], &line[(i + j + 2)..]),
+                None => {
+                    line = line[..i].to_string();
+                    in_comment = true;
+                }
+            }
+        }
+
+        let mut directive: Vec<String> = Vec::new();
+        for token in tokenize_line(&line) {
+            if token == "{" || token == "}" {
+                flush_directive(&mut directive, depth, &mut shader);
+                if token == "{" {
+                    depth += 1;
+                    if depth == 2 {
+                        if let Some(s) = shader.as_mut() {
+                            s.stages.push(Stage { directives: Vec::new() });
+                        }
+                    }
+                }
+                else {
+                    depth -= 1;
+                    if depth <= 0 {
+                        depth = 0;
+                        if let Some(s) = shader.take() {
+                            shaders.entry(s.name.to_lowercase()).or_insert(s);
+                        }
+                    }
+                }
+            }
+            else if depth == 0 {
+                shader = Some(Shader::new(&token));
+            }
+            else {
+                directive.push(token);
+            }
+        }
+        flush_directive(&mut directive, depth, &mut shader);
+    }
+}
+
+fn flush_directive(directive: &mut Vec<String>, depth: i32, shader: &mut Option<Shader>) {
+
+    if directive.is_empty() {
+        return;
+    }
+    let mut tokens: Vec<String> = directive.drain(..).collect();
+    tokens[0] = tokens[0].to_lowercase();
+
+    if let Some(s) = shader.as_mut() {
+        if depth == 1 {
+            s.add_directive(tokens);
+        }
+        else if depth == 2 {
+            if let Some(stage) = s.stages.last_mut() {
+                stage.directives.push(tokens);
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -170,6 +170,18 @@ impl CameraController {
         camera.pitch = Rad(self.rotate_vertical);
     }
 
+    //Quake angles in degrees, positive pitch looks down
+    pub fn set_angles(&mut self, pitch: f32, yaw: f32) {
+
+        let max_look_up: f32 = 89.0_f32.to_radians();
+        self.rotate_horizontal = yaw.to_radians();
+        self.rotate_vertical = (-pitch).to_radians().max(-max_look_up).min(max_look_up);
+    }
+
+    pub fn angles(&self) -> [f32; 2] {
+        [-self.rotate_vertical.to_degrees(), self.rotate_horizontal.to_degrees()]
+    }
+
     pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
         /*self.scroll = match delta {
             MouseScrollDelta::LineDelta(_, scroll) => -scroll * 100.0,
@@ -0,0 +1,215 @@
+use crate::bsp_entity;
+
+//Camera poses over time, recorded from the free camera or built from the intermission entities
+//Positions are interpolated linearly and angles with a Catmull-Rom spline
+
+//How long a single intermission point is shown for
+pub const STATIC_DURATION: f32 = 10.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraKey {
+    pub time: f32,
+    pub position: [f32; 3],
+    //Pitch and yaw in degrees, positive pitch looks down
+    pub angles: [f32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraPath {
+    pub keys: Vec<CameraKey>,
+}
+
+impl CameraPath {
+
+    pub fn new() -> Self {
+        CameraPath { keys: Vec::new() }
+    }
+
+    //Yaw is unwrapped against the previous key so the spline never turns the long way round
+    pub fn push(&mut self, mut key: CameraKey) {
+
+        if let Some(last) = self.keys.last() {
+            while key.angles[1] - last.angles[1] > 180.0 {
+                key.angles[1] -= 360.0;
+            }
+            while key.angles[1] - last.angles[1] < -180.0 {
+                key.angles[1] += 360.0;
+            }
+        }
+        self.keys.push(key);
+    }
+
+    pub fn duration(&self) -> f32 {
+        match (self.keys.first(), self.keys.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.0,
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> Option<CameraKey> {
+
+        let first = self.keys.first()?;
+        let last = self.keys.last()?;
+        let time = time + first.time;
+        if time <= first.time {
+            return Some(*first);
+        }
+        if time >= last.time {
+            return Some(*last);
+        }
+
+        let i = self.keys.iter().position(|k| k.time > time).unwrap() - 1;
+        let k1 = &self.keys[i];
+        let k2 = &self.keys[i + 1];
+        let k0 = if i > 0 { &self.keys[i - 1] } else { k1 };
+        let k3 = if i + 2 < self.keys.len() { &self.keys[i + 2] } else { k2 };
+        let f = if k2.time > k1.time { (time - k1.time) / (k2.time - k1.time) } else { 0.0 };
+
+        let mut key = CameraKey { time: time - first.time, position: [0.0; 3], angles: [0.0; 2] };
+        for j in 0..3 {
+            key.position[j] = k1.position[j] + (k2.position[j] - k1.position[j]) * f;
+        }
+        for j in 0..2 {
+            key.angles[j] = catmull_rom(k0.angles[j], k1.angles[j], k2.angles[j], k3.angles[j], f);
+        }
+        Some(key)
+    }
+
+    //One key per line: time x y z pitch yaw
+    pub fn parse(text: &str) -> CameraPath {
+
+        let mut path = CameraPath::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let values = line.split_whitespace().filter_map(|v| v.parse::<f32>().ok()).collect::<Vec<f32>>();
+            if values.len() < 6 {
+                println!("Bad camera path line {}", line);
+                continue;
+            }
+            path.push(CameraKey { time: values[0], position: [values[1], values[2], values[3]], angles: [values[4], values[5]] });
+        }
+        path
+    }
+
+    pub fn to_text(&self) -> String {
+
+        let mut text = "//time x y z pitch yaw\n".to_string();
+        for key in self.keys.iter() {
+            text.push_str(&format!("{:.4} {} {} {} {} {}\n", key.time, key.position[0], key.position[1], key.position[2], key.angles[0], key.angles[1]));
+        }
+        text
+    }
+
+    pub fn load(path: &std::path::Path) -> std::io::Result<CameraPath> {
+        Ok(CameraPath::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    //The camera starts at info_player_intermission and moves along its chain of targets at speed units per second,
+    //always looking at the next one. The last target is only looked at so a plain Quake 3 intermission is a still shot
+    pub fn from_intermission(entities: &Vec<bsp_entity::Entity>, speed: f32) -> Option<CameraPath> {
+
+        let start = entities.iter().position(|e| e.class_name() == "info_player_intermission")?;
+        let mut visited = vec![start];
+        let mut points = vec![entities[start].origin()?];
+        let mut current = start;
+        while let Some(target_name) = entities[current].get("target") {
+            let next = match entities.iter().position(|e| e.get("targetname") == Some(target_name)) {
+                Some(next) => next,
+                None => break,
+            };
+            if visited.contains(&next) {
+                break;
+            }
+            match entities[next].origin() {
+                Some(origin) => points.push(origin),
+                None => break,
+            }
+            visited.push(next);
+            current = next;
+        }
+
+        let mut path = CameraPath::new();
+        if points.len() == 1 {
+            let angles = entities[start].angles().unwrap_or([0.0; 3]);
+            path.push(CameraKey { time: 0.0, position: points[0], angles: [angles[0], angles[1]] });
+            path.push(CameraKey { time: STATIC_DURATION, position: points[0], angles: [angles[0], angles[1]] });
+            return Some(path);
+        }
+
+        let mut time = 0.0;
+        for i in 0..(points.len() - 1) {
+            if i > 0 {
+                time += distance(points[i - 1], points[i]) / speed.max(1.0);
+            }
+            path.push(CameraKey { time, position: points[i], angles: look_at(points[i], points[i + 1]) });
+        }
+        if path.keys.len() == 1 {
+            let key = CameraKey { time: STATIC_DURATION, ..path.keys[0] };
+            path.push(key);
+        }
+        Some(path)
+    }
+}
+
+//Fixed viewpoints, one per line: x y z pitch yaw
+pub fn parse_viewpoints(text: &str) -> Vec<CameraKey> {
+
+    let mut viewpoints = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let values = line.split_whitespace().filter_map(|v| v.parse::<f32>().ok()).collect::<Vec<f32>>();
+        if values.len() < 5 {
+            println!("Bad viewpoint line {}", line);
+            continue;
+        }
+        viewpoints.push(CameraKey { time: 0.0, position: [values[0], values[1], values[2]], angles: [values[3], values[4]] });
+    }
+    viewpoints
+}
+
+//Adds a key every 1 / rate seconds
+pub struct CameraRecorder {
+    pub path: CameraPath,
+    interval: f32,
+    next_time: f32,
+}
+
+impl CameraRecorder {
+
+    pub fn new(rate: f32) -> Self {
+        CameraRecorder { path: CameraPath::new(), interval: 1.0 / rate.max(1.0), next_time: 0.0 }
+    }
+
+    pub fn record(&mut self, time: f32, position: [f32; 3], angles: [f32; 2]) {
+
+        if time >= self.next_time {
+            self.path.push(CameraKey { time, position, angles });
+            self.next_time = time + self.interval;
+        }
+    }
+}
+
+fn catmull_rom(p0: f32, p1: f32, p2: f32, p3: f32, t: f32) -> f32 {
+    0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t)
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((b[0] - a[0]).powi(2) + (b[1] - a[1]).powi(2) + (b[2] - a[2]).powi(2)).sqrt()
+}
+
+//Pitch and yaw from a towards b
+fn look_at(a: [f32; 3], b: [f32; 3]) -> [f32; 2] {
+
+    let (x, y, z) = (b[0] - a[0], b[1] - a[1], b[2] - a[2]);
+    [-z.atan2((x * x + y * y).sqrt()).to_degrees(), y.atan2(x).to_degrees()]
+}
\ No newline at end of file
@@ -0,0 +1,690 @@
+use std::collections::HashMap;
+
+use crate::bsp_item;
+use crate::camera_path::CameraKey;
+use crate::demo_msg::{self, EntityState, PlayerState};
+use crate::vfs;
+
+//Quake 3 demos, the server messages a client received in blocks of sequence number, length and data.
+//The first message holds the gamestate with the configstrings and entity baselines, the rest are mostly
+//snapshots delta compressed against an earlier one
+//https://github.com/ioquake/ioq3/blob/main/code/client/cl_parse.c
+
+pub const MAX_CONFIGSTRINGS: usize = 1024;
+pub const CS_SERVERINFO: usize = 0;
+pub const CS_MODELS: usize = 32;
+pub const CS_PLAYERS: usize = 544;
+const MAX_CLIENTS: usize = 64;
+
+const SVC_NOP: i32 = 1;
+const SVC_GAMESTATE: i32 = 2;
+const SVC_CONFIGSTRING: i32 = 3;
+const SVC_BASELINE: i32 = 4;
+const SVC_SERVER_COMMAND: i32 = 5;
+const SVC_DOWNLOAD: i32 = 6;
+const SVC_SNAPSHOT: i32 = 7;
+const SVC_EOF: i32 = 8;
+
+const MAX_MSGLEN: usize = 16384;
+const MAX_STRING_CHARS: usize = 1024;
+const BIG_INFO_STRING: usize = 8192;
+//Snapshots older than this can not be deltas
+const PACKET_BACKUP: i32 = 32;
+
+pub const ET_GENERAL: i32 = 0;
+pub const ET_PLAYER: i32 = 1;
+pub const ET_ITEM: i32 = 2;
+pub const ET_MOVER: i32 = 4;
+
+//Brush entities have this solid and their submodel number as the model index
+pub const SOLID_BMODEL: i32 = 0xffffff;
+
+const EF_TELEPORT_BIT: i32 = 0x04;
+const EF_NODRAW: i32 = 0x80;
+pub const ANIM_TOGGLEBIT: i32 = 128;
+const DEFAULT_GRAVITY: f32 = 800.0;
+const ANIMATION_LOOKBACK: usize = 200;
+
+const TR_INTERPOLATE: i32 = 1;
+const TR_LINEAR: i32 = 2;
+const TR_LINEAR_STOP: i32 = 3;
+const TR_SINE: i32 = 4;
+const TR_GRAVITY: i32 = 5;
+
+//Position or angles over time, times are server milliseconds
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Trajectory {
+    pub tr_type: i32,
+    pub time: i32,
+    pub duration: i32,
+    pub base: [f32; 3],
+    pub delta: [f32; 3],
+}
+
+impl Trajectory {
+
+    fn from_entity(e: &EntityState, name: &str) -> Self {
+        Trajectory {
+            tr_type: e.int(&format!("{}.trType", name)),
+            time: e.int(&format!("{}.trTime", name)),
+            duration: e.int(&format!("{}.trDuration", name)),
+            base: e.vector(&format!("{}.trBase", name)),
+            delta: e.vector(&format!("{}.trDelta", name)),
+        }
+    }
+
+    //BG_EvaluateTrajectory at offset milliseconds after the server time start. The integer times are
+    //subtracted first so large server times keep their precision
+    pub fn evaluate(&self, start: i32, offset: f32) -> [f32; 3] {
+
+        let along = |t: f32| [self.base[0] + self.delta[0] * t, self.base[1] + self.delta[1] * t, self.base[2] + self.delta[2] * t];
+        let elapsed = start.wrapping_sub(self.time) as f32 + offset;
+        match self.tr_type {
+            TR_LINEAR => along(elapsed * 0.001),
+            TR_SINE => along((elapsed / self.duration.max(1) as f32 * std::f32::consts::PI * 2.0).sin()),
+            TR_LINEAR_STOP => along(elapsed.min(self.duration as f32).max(0.0) * 0.001),
+            TR_GRAVITY => {
+                let t = elapsed * 0.001;
+                let mut p = along(t);
+                p[2] -= 0.5 * DEFAULT_GRAVITY * t * t;
+                p
+            }
+            _ => self.base,
+        }
+    }
+}
+
+//The parts of an entity the viewer draws, the rest of the state is only needed while decoding
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DemoEntity {
+    pub number: usize,
+    pub e_type: i32,
+    pub e_flags: i32,
+    pub model_index: i32,
+    pub solid: i32,
+    pub client_num: i32,
+    pub weapon: i32,
+    pub legs_anim: i32,
+    pub torso_anim: i32,
+    pub pos: Trajectory,
+    pub apos: Trajectory,
+}
+
+impl DemoEntity {
+
+    fn new(e: &EntityState) -> Self {
+        DemoEntity {
+            number: e.number,
+            e_type: e.int("eType"),
+            e_flags: e.int("eFlags"),
+            model_index: e.int("modelindex"),
+            solid: e.int("solid"),
+            client_num: e.int("clientNum"),
+            weapon: e.int("weapon"),
+            legs_anim: e.int("legsAnim"),
+            torso_anim: e.int("torsoAnim"),
+            pos: Trajectory::from_entity(e, "pos"),
+            apos: Trajectory::from_entity(e, "apos"),
+        }
+    }
+
+    //The *N model of a brush entity, SV_SetBrushModel sends N and not a model configstring
+    pub fn submodel(&self) -> Option<usize> {
+        Some(self.model_index as usize).filter(|_| self.solid == SOLID_BMODEL && self.model_index > 0)
+    }
+}
+
+//An entity where it is at a time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EntityPose {
+    pub entity: DemoEntity,
+    pub origin: [f32; 3],
+    pub angles: [f32; 3],
+}
+
+pub struct Frame {
+    pub server_time: i32,
+    pub player_state: PlayerState,
+    pub entities: Vec<DemoEntity>,
+}
+
+//A decoded snapshot kept to decode the ones that are deltas from it
+struct Snapshot {
+    message_num: i32,
+    valid: bool,
+    player_state: PlayerState,
+    entities: Vec<EntityState>,
+}
+
+pub struct Demo {
+    pub configstrings: Vec<String>,
+    pub client_num: i32,
+    //Server commands by the sequence number of the message they came in
+    pub server_commands: Vec<(i32, String)>,
+    pub frames: Vec<Frame>,
+}
+
+impl Demo {
+
+    //A path on disk, or a name in the demos directory of the game data
+    pub fn load(fs: &vfs::FileSystem, path: &str) -> Option<Demo> {
+
+        let data = match std::fs::read(path).ok().or_else(|| fs.read(path)).or_else(|| fs.read(&format!("demos/{}", path))) {
+            Some(data) => data,
+            None => {
+                println!("Error cant find demo {}", path);
+                return None;
+            }
+        };
+        match Demo::read(&data) {
+            Ok(demo) => {
+                println!("Demo {} on {} with {} snapshots, {:.1} s", path, demo.map_name().unwrap_or_default(), demo.frames.len(), demo.duration());
+                Some(demo)
+            }
+            Err(e) => {
+                println!("Could not read demo {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn read(data: &[u8]) -> Result<Demo, String> {
+
+        let huffman = demo_msg::Huffman::new();
+        let mut demo = Demo { configstrings: vec![String::new(); MAX_CONFIGSTRINGS], client_num: 0, server_commands: Vec::new(), frames: Vec::new() };
+        let mut baselines: Vec<EntityState> = (0..demo_msg::MAX_GENTITIES).map(EntityState::new).collect();
+        let mut snapshots: Vec<Option<Snapshot>> = (0..PACKET_BACKUP).map(|_| None).collect();
+        let mut gamestate = false;
+
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let message_num = i32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+            let len = i32::from_le_bytes([data[offset + 4], data[offset + 5], data[offset + 6], data[offset + 7]]);
+            offset += 8;
+            //A length of -1 ends the demo
+            if len < 0 {
+                break;
+            }
+            let len = len as usize;
+            if len > MAX_MSGLEN || offset + len > data.len() {
+                println!("Demo message {} is cut off", message_num);
+                break;
+            }
+            let mut msg = demo_msg::Message::new(&data[offset..offset + len], &huffman);
+            offset += len;
+
+            //The acknowledged reliable command
+            msg.read_long();
+            loop {
+                let cmd = msg.read_byte();
+                if cmd == SVC_EOF {
+                    break;
+                }
+                match cmd {
+                    SVC_NOP => {}
+                    SVC_GAMESTATE => {
+                        demo.read_gamestate(&mut msg, &mut baselines)?;
+                        gamestate = true;
+                    }
+                    SVC_SERVER_COMMAND => {
+                        let sequence = msg.read_long();
+                        let command = msg.read_string(MAX_STRING_CHARS);
+                        demo.server_command(&command);
+                        demo.server_commands.push((sequence, command));
+                    }
+                    SVC_SNAPSHOT => {
+                        if !gamestate {
+                            return Err(format!("snapshot before the gamestate in message {}", message_num));
+                        }
+                        let snapshot = demo.read_snapshot(&mut msg, message_num, &baselines, &snapshots)?;
+                        let slot = (message_num & (PACKET_BACKUP - 1)) as usize;
+                        snapshots[slot] = Some(snapshot);
+                    }
+                    SVC_DOWNLOAD => return Err(format!("download in message {}", message_num)),
+                    _ => return Err(format!("bad command {} in message {}", cmd, message_num)),
+                }
+                if msg.overflowed {
+                    return Err(format!("read past the end of message {}", message_num));
+                }
+            }
+        }
+        if !gamestate {
+            return Err("no gamestate".to_string());
+        }
+        Ok(demo)
+    }
+
+    fn read_gamestate(&mut self, msg: &mut demo_msg::Message, baselines: &mut Vec<EntityState>) -> Result<(), String> {
+
+        //The server command sequence
+        msg.read_long();
+        loop {
+            let cmd = msg.read_byte();
+            match cmd {
+                SVC_EOF => break,
+                SVC_CONFIGSTRING => {
+                    let index = msg.read_short();
+                    if index < 0 || index as usize >= MAX_CONFIGSTRINGS {
+                        return Err(format!("configstring {} out of range", index));
+                    }
+                    self.configstrings[index as usize] = msg.read_string(BIG_INFO_STRING);
+                }
+                SVC_BASELINE => {
+                    let number = msg.read_bits(demo_msg::GENTITYNUM_BITS) as usize;
+                    if let Some(e) = msg.read_delta_entity(&EntityState::new(number), number)? {
+                        baselines[number] = e;
+                    }
+                }
+                _ => return Err(format!("bad command {} in the gamestate", cmd)),
+            }
+            if msg.overflowed {
+                return Err("read past the end of the gamestate".to_string());
+            }
+        }
+        self.client_num = msg.read_long();
+        //The checksum feed
+        msg.read_long();
+        Ok(())
+    }
+
+    fn read_snapshot(&mut self, msg: &mut demo_msg::Message, message_num: i32, baselines: &Vec<EntityState>, snapshots: &Vec<Option<Snapshot>>) -> Result<Snapshot, String> {
+
+        let server_time = msg.read_long();
+        let delta_num = msg.read_byte();
+        //snapFlags
+        msg.read_byte();
+        let area_mask_len = msg.read_byte().max(0) as usize;
+        msg.read_data(area_mask_len);
+
+        //A delta from a snapshot that is gone still has to be read through, it is just not shown
+        let old = if delta_num == 0 {
+            None
+        }
+        else {
+            let old_num = message_num - delta_num;
+            snapshots[(old_num & (PACKET_BACKUP - 1)) as usize].as_ref().filter(|s| s.message_num == old_num && message_num - old_num < PACKET_BACKUP)
+        };
+        let valid = delta_num == 0 || old.map_or(false, |s| s.valid);
+
+        let empty = PlayerState::new();
+        let player_state = msg.read_delta_player_state(old.map_or(&empty, |s| &s.player_state))?;
+
+        //Entity numbers come in increasing order, entities missing from the list stay as they were in the old snapshot
+        let old_entities: &[EntityState] = old.map_or(&[], |s| &s.entities);
+        let mut entities: Vec<EntityState> = Vec::new();
+        let mut old_index = 0;
+        loop {
+            let number = msg.read_bits(demo_msg::GENTITYNUM_BITS) as usize;
+            if msg.overflowed {
+                return Err(format!("read past the end of snapshot {}", message_num));
+            }
+            if number == demo_msg::MAX_GENTITIES - 1 {
+                break;
+            }
+            while old_index < old_entities.len() && old_entities[old_index].number < number {
+                entities.push(old_entities[old_index].clone());
+                old_index += 1;
+            }
+            let from = if old_index < old_entities.len() && old_entities[old_index].number == number {
+                old_index += 1;
+                &old_entities[old_index - 1]
+            }
+            else {
+                &baselines[number]
+            };
+            if let Some(e) = msg.read_delta_entity(from, number)? {
+                entities.push(e);
+            }
+        }
+        entities.extend(old_entities[old_index..].iter().cloned());
+
+        if valid {
+            self.frames.push(Frame { server_time, player_state: player_state.clone(), entities: entities.iter().map(DemoEntity::new).collect() });
+        }
+        Ok(Snapshot { message_num, valid, player_state, entities })
+    }
+
+    //Configstrings change during the game with cs commands, bcs0 to bcs2 send a long one in pieces
+    fn server_command(&mut self, command: &str) {
+
+        let mut tokens = command.splitn(3, ' ');
+        let name = tokens.next().unwrap_or("");
+        let index = tokens.next().and_then(|t| t.parse::<usize>().ok()).filter(|i| *i < MAX_CONFIGSTRINGS);
+        let value = tokens.next().unwrap_or("").trim_matches('"');
+        if let Some(index) = index {
+            match name {
+                "cs" | "bcs0" => self.configstrings[index] = value.to_string(),
+                "bcs1" | "bcs2" => self.configstrings[index].push_str(value),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn map_name(&self) -> Option<String> {
+        info_value(&self.configstrings[CS_SERVERINFO], "mapname")
+    }
+
+    pub fn duration(&self) -> f32 {
+        match (self.frames.first(), self.frames.last()) {
+            (Some(first), Some(last)) => (last.server_time - first.server_time) as f32 / 1000.0,
+            _ => 0.0,
+        }
+    }
+
+    //Server time of the first snapshot and the milliseconds after it at a time in seconds from the start.
+    //Server times stay integers, a float of them loses the milliseconds after a few hours of uptime
+    pub fn server_time(&self, time: f32) -> (i32, f32) {
+        (self.frames.first().map_or(0, |f| f.server_time), time.max(0.0) * 1000.0)
+    }
+
+    //The frame at or before a server time and the one after it with how far between them the time is
+    fn frames_at(&self, start: i32, offset: f32) -> Option<(&Frame, &Frame, f32)> {
+
+        if self.frames.is_empty() {
+            return None;
+        }
+        let next = self.frames.iter().position(|f| frame_offset(f, start) > offset).unwrap_or(self.frames.len());
+        let current = &self.frames[next.max(1) - 1];
+        let next = self.frames.get(next).unwrap_or(current);
+        let span = next.server_time.wrapping_sub(current.server_time) as f32;
+        let lerp = if span > 0.0 { ((offset - frame_offset(current, start)) / span).min(1.0).max(0.0) } else { 0.0 };
+        Some((current, next, lerp))
+    }
+
+    //The eyes of the recorded player, not interpolated across teleports
+    pub fn camera(&self, time: f32) -> Option<CameraKey> {
+
+        let (start, offset) = self.server_time(time);
+        let (current, next, mut lerp) = self.frames_at(start, offset)?;
+        let (ps0, ps1) = (&current.player_state, &next.player_state);
+        if (ps0.int("eFlags") ^ ps1.int("eFlags")) & EF_TELEPORT_BIT != 0 {
+            lerp = 0.0;
+        }
+        let (o0, o1) = (ps0.vector("origin"), ps1.vector("origin"));
+        let mut position = lerp_vector(o0, o1, lerp);
+        position[2] += ps0.int("viewheight") as f32 + (ps1.int("viewheight") - ps0.int("viewheight")) as f32 * lerp;
+        let (a0, a1) = (ps0.vector("viewangles"), ps1.vector("viewangles"));
+        Some(CameraKey { time, position, angles: [lerp_angle(a0[0], a1[0], lerp), lerp_angle(a0[1], a1[1], lerp)] })
+    }
+
+    //Entities in the snapshot at a time, interpolated players move between snapshots and the rest follow their trajectories
+    pub fn entities(&self, time: f32) -> Vec<EntityPose> {
+
+        let (start, offset) = self.server_time(time);
+        let (current, next, lerp) = match self.frames_at(start, offset) {
+            Some(frames) => frames,
+            None => return Vec::new(),
+        };
+        current.entities.iter().filter(|e| e.e_flags & EF_NODRAW == 0).map(|e| {
+            let after = next.entities.iter().find(|n| n.number == e.number && (n.e_flags ^ e.e_flags) & EF_TELEPORT_BIT == 0);
+            let (origin, angles) = match after {
+                Some(n) if e.pos.tr_type == TR_INTERPOLATE => (lerp_vector(e.pos.base, n.pos.base, lerp), lerp_angles(e.apos.base, n.apos.base, lerp)),
+                _ => (e.pos.evaluate(start, offset), e.apos.evaluate(start, offset)),
+            };
+            EntityPose { entity: *e, origin, angles }
+        }).collect()
+    }
+
+    //Seconds the legs and torso animations of an entity have been playing, a changed toggle bit restarts the same animation.
+    //Only the last ANIMATION_LOOKBACK snapshots are searched, long animations loop or hold by then
+    pub fn animation_times(&self, number: usize, time: f32) -> (f32, f32) {
+
+        let (start_time, offset) = self.server_time(time);
+        let last = self.frames.iter().position(|f| frame_offset(f, start_time) > offset).unwrap_or(self.frames.len());
+        let first = last.saturating_sub(ANIMATION_LOOKBACK);
+        let anims = |f: &Frame| f.entities.iter().find(|e| e.number == number).map(|e| (e.legs_anim, e.torso_anim, f.server_time));
+        let mut history = self.frames[first..last].iter().rev().map_while(anims);
+        let (legs, torso, mut start) = match history.next() {
+            Some(current) => current,
+            None => return (0.0, 0.0),
+        };
+        let (mut legs_start, mut torso_start) = (None, None);
+        for (l, t, frame_time) in history {
+            if legs_start.is_none() && l != legs {
+                legs_start = Some(start);
+            }
+            if torso_start.is_none() && t != torso {
+                torso_start = Some(start);
+            }
+            start = frame_time;
+        }
+        let since = |t: i32| (offset - t.wrapping_sub(start_time) as f32) / 1000.0;
+        (since(legs_start.unwrap_or(start)), since(torso_start.unwrap_or(start)))
+    }
+
+    //Player model and skin of a client from its configstring
+    pub fn player_model(&self, client: usize) -> Option<String> {
+        info_value(self.configstrings.get(CS_PLAYERS + client)?, "model").filter(|m| !m.is_empty())
+    }
+
+    //Client numbers of the players with a model, for loading them before playback
+    pub fn players(&self) -> HashMap<usize, String> {
+        (0..MAX_CLIENTS).filter_map(|i| self.player_model(i).map(|m| (i, m))).collect()
+    }
+
+    //Items by their index in the item list and md3 models of general entities from the model configstrings
+    pub fn entity_models(&self, e: &DemoEntity) -> Vec<(String, bsp_item::Placement)> {
+        match e.e_type {
+            ET_ITEM => bsp_item::item_models(e.model_index),
+            ET_GENERAL if e.submodel().is_none() => self.model_path(e.model_index).filter(|p| p.to_lowercase().ends_with(".md3")).map(|p| vec![(p.to_string(), bsp_item::Placement::Static)]).unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn model_path(&self, model_index: i32) -> Option<&str> {
+        self.configstrings.get(CS_MODELS + model_index.max(0) as usize).map(|s| s.as_str()).filter(|s| model_index > 0 && !s.is_empty())
+    }
+}
+
+//Info strings are \key\value pairs
+pub fn info_value(info: &str, key: &str) -> Option<String> {
+
+    let mut tokens = info.trim_start_matches('\\').split('\\');
+    while let (Some(k), Some(v)) = (tokens.next(), tokens.next()) {
+        if k.eq_ignore_ascii_case(key) {
+            return Some(v.to_string());
+        }
+    }
+    None
+}
+
+//Milliseconds from the first snapshot to a frame
+fn frame_offset(frame: &Frame, start: i32) -> f32 {
+    frame.server_time.wrapping_sub(start) as f32
+}
+
+fn lerp_vector(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+//The short way round
+fn lerp_angle(a: f32, b: f32, t: f32) -> f32 {
+    let mut d = b - a;
+    while d > 180.0 {
+        d -= 360.0;
+    }
+    while d < -180.0 {
+        d += 360.0;
+    }
+    a + d * t
+}
+
+fn lerp_angles(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [lerp_angle(a[0], b[0], t), lerp_angle(a[1], b[1], t), lerp_angle(a[2], b[2], t)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo_msg::tests::Writer;
+
+    //Server times past 2^24 milliseconds, about four and a half hours of uptime, can't be held exactly by a float
+    const LATE: i32 = 200_000_000;
+
+    fn demo(times: &[i32]) -> Demo {
+        let frames = times.iter().map(|&server_time| Frame { server_time, player_state: PlayerState::new(), entities: Vec::new() }).collect();
+        Demo { configstrings: vec![String::new(); MAX_CONFIGSTRINGS], client_num: 0, server_commands: Vec::new(), frames }
+    }
+
+    fn entity(number: usize, fields: &[(&str, u32)]) -> EntityState {
+        let mut e = EntityState::new(number);
+        for (name, value) in fields.iter() {
+            e.fields[demo_msg::field(&demo_msg::ENTITY_FIELDS, name)] = *value;
+        }
+        e
+    }
+
+    //A demo message block, the acknowledged command and the commands the body writes
+    fn message(huffman: &demo_msg::Huffman, message_num: i32, body: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut w = Writer::new(huffman);
+        w.bits(0, 32);
+        body(&mut w);
+        w.bits(SVC_EOF, 8);
+        [message_num.to_le_bytes(), (w.data.len() as i32).to_le_bytes()].concat().into_iter().chain(w.data).collect()
+    }
+
+    fn snapshot(w: &mut Writer, server_time: i32, delta_num: i32, from: &PlayerState, to: &PlayerState, entities: impl FnOnce(&mut Writer)) {
+        w.bits(SVC_SNAPSHOT, 8);
+        w.bits(server_time, 32);
+        w.bits(delta_num, 8);
+        w.bits(0, 8);
+        w.bits(1, 8);
+        w.bits(0xff, 8);
+        w.delta_player_state(from, to);
+        entities(w);
+        w.bits(demo_msg::MAX_GENTITIES as i32 - 1, demo_msg::GENTITYNUM_BITS);
+    }
+
+    #[test]
+    fn read_messages() {
+        let huffman = demo_msg::Huffman::new();
+        let empty = PlayerState::new();
+        let mut ps = PlayerState::new();
+        ps.fields[demo_msg::field(&demo_msg::PLAYER_FIELDS, "origin[0]")] = 128.0f32.to_bits();
+        ps.stats[0] = 100;
+        let mut ps2 = ps.clone();
+        ps2.fields[demo_msg::field(&demo_msg::PLAYER_FIELDS, "origin[1]")] = 0.5f32.to_bits();
+
+        let baseline = entity(10, &[("eType", ET_MOVER as u32), ("modelindex", 3), ("solid", SOLID_BMODEL as u32)]);
+        let door = entity(10, &[("eType", ET_MOVER as u32), ("modelindex", 3), ("solid", SOLID_BMODEL as u32), ("pos.trBase[0]", 64.0f32.to_bits())]);
+        let player = entity(20, &[("eType", ET_PLAYER as u32), ("clientNum", 2)]);
+        let item = entity(30, &[("eType", ET_ITEM as u32), ("modelindex", 7)]);
+
+        let mut data = message(&huffman, 1, |w| {
+            w.bits(SVC_GAMESTATE, 8);
+            w.bits(0, 32);
+            w.bits(SVC_CONFIGSTRING, 8);
+            w.bits(CS_SERVERINFO as i32, 16);
+            w.string("\\sv_hostname\\test\\mapname\\q3dm17");
+            w.bits(SVC_BASELINE, 8);
+            w.delta_entity(&EntityState::new(10), &baseline);
+            w.bits(SVC_EOF, 8);
+            w.bits(2, 32);
+            w.bits(0, 32);
+        });
+        //A full snapshot, the door is a delta from its baseline
+        data.extend(message(&huffman, 2, |w| {
+            for (sequence, command) in [(1, "bcs0 5 \"hello "), (2, "bcs1 5 wor"), (3, "bcs2 5 ld\"")].iter() {
+                w.bits(SVC_SERVER_COMMAND, 8);
+                w.bits(*sequence, 32);
+                w.string(command);
+            }
+            snapshot(w, 1000, 0, &empty, &ps, |w| {
+                w.delta_entity(&baseline, &door);
+                w.delta_entity(&EntityState::new(20), &player);
+            });
+        }));
+        //A delta from it, the door is left out so it stays, the player is removed and an item added
+        data.extend(message(&huffman, 3, |w| {
+            snapshot(w, 1050, 1, &ps, &ps2, |w| {
+                w.removed_entity(20);
+                w.delta_entity(&EntityState::new(30), &item);
+            });
+        }));
+        //Deltas from a dropped snapshot and from that one are read through but not shown
+        data.extend(message(&huffman, 5, |w| snapshot(w, 1150, 1, &ps2, &ps, |_| {})));
+        data.extend(message(&huffman, 6, |w| snapshot(w, 1200, 1, &ps, &ps2, |_| {})));
+        data.extend(message(&huffman, 7, |w| {
+            snapshot(w, 1250, 4, &ps2, &ps, |w| w.delta_entity(&door, &entity(10, &[("eType", ET_MOVER as u32)])));
+        }));
+        data.extend(message(&huffman, 8, |w| snapshot(w, 1300, 0, &empty, &ps, |w| w.delta_entity(&baseline, &baseline))));
+        data.extend([9i32.to_le_bytes(), (-1i32).to_le_bytes()].concat());
+
+        let demo = Demo::read(&data).unwrap();
+        assert_eq!(demo.map_name(), Some("q3dm17".to_string()));
+        assert_eq!(demo.client_num, 2);
+        assert_eq!(demo.configstrings[5], "hello world");
+        assert_eq!(demo.server_commands.iter().map(|(s, _)| *s).collect::<Vec<i32>>(), vec![1, 2, 3]);
+
+        let times = demo.frames.iter().map(|f| f.server_time).collect::<Vec<i32>>();
+        assert_eq!(times, vec![1000, 1050, 1250, 1300]);
+        let numbers = |f: &Frame| f.entities.iter().map(|e| e.number).collect::<Vec<usize>>();
+        assert_eq!(numbers(&demo.frames[0]), vec![10, 20]);
+        assert_eq!(numbers(&demo.frames[1]), vec![10, 30]);
+        assert_eq!(numbers(&demo.frames[2]), vec![10, 30]);
+        assert_eq!(numbers(&demo.frames[3]), vec![10]);
+
+        let door = demo.frames[1].entities[0];
+        assert_eq!((door.e_type, door.submodel(), door.pos.base), (ET_MOVER, Some(3), [64.0, 0.0, 0.0]));
+        assert_eq!(demo.frames[1].entities[1].submodel(), None);
+        assert_eq!((demo.frames[0].entities[1].e_type, demo.frames[0].entities[1].client_num), (ET_PLAYER, 2));
+        assert_eq!(demo.frames[1].entities[1].model_index, 7);
+        //Delta from message 3 two steps back, skipping the invalid ones
+        assert_eq!(demo.frames[2].entities[0].model_index, 0);
+        assert_eq!(demo.frames[3].entities[0].model_index, 3);
+
+        assert_eq!(demo.frames[1].player_state.vector("origin"), [128.0, 0.5, 0.0]);
+        assert_eq!(demo.frames[1].player_state.stats[0], 100);
+        assert_eq!(demo.frames[2].player_state.vector("origin"), [128.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn bad_demos() {
+        let huffman = demo_msg::Huffman::new();
+        assert!(Demo::read(&[]).is_err());
+        let snapshot_first = message(&huffman, 1, |w| snapshot(w, 0, 0, &PlayerState::new(), &PlayerState::new(), |_| {}));
+        assert!(Demo::read(&snapshot_first).err().unwrap().contains("before the gamestate"));
+        let bad_command = message(&huffman, 1, |w| w.bits(42, 8));
+        assert!(Demo::read(&bad_command).err().unwrap().contains("bad command"));
+    }
+
+    #[test]
+    fn trajectories() {
+        let linear = Trajectory { tr_type: TR_LINEAR, time: LATE, duration: 0, base: [1.0, 2.0, 3.0], delta: [100.0, 0.0, -100.0] };
+        assert_eq!(linear.evaluate(LATE, 12.5), [2.25, 2.0, 1.75]);
+        assert_eq!(linear.evaluate(LATE + 1000, 0.0), [101.0, 2.0, -97.0]);
+
+        let stop = Trajectory { tr_type: TR_LINEAR_STOP, duration: 500, ..linear };
+        assert_eq!(stop.evaluate(LATE - 100, 0.0), [1.0, 2.0, 3.0]);
+        assert_eq!(stop.evaluate(LATE, 5000.0), [51.0, 2.0, -47.0]);
+
+        let gravity = Trajectory { tr_type: TR_GRAVITY, delta: [0.0; 3], ..linear };
+        assert_eq!(gravity.evaluate(LATE, 1000.0), [1.0, 2.0, 3.0 - 0.5 * DEFAULT_GRAVITY]);
+
+        let stationary = Trajectory { tr_type: 0, ..linear };
+        assert_eq!(stationary.evaluate(LATE, 12.5), [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn frames_at_late_server_times() {
+        let demo = demo(&[LATE, LATE + 50, LATE + 100]);
+        assert_eq!(demo.duration(), 0.1);
+        assert_eq!(demo.server_time(0.075), (LATE, 75.0));
+
+        let (current, next, lerp) = demo.frames_at(LATE, 75.0).unwrap();
+        assert_eq!((current.server_time, next.server_time, lerp), (LATE + 50, LATE + 100, 0.5));
+        let (current, next, lerp) = demo.frames_at(LATE, 1.0).unwrap();
+        assert_eq!((current.server_time, next.server_time, lerp), (LATE, LATE + 50, 0.02));
+        let (current, next, lerp) = demo.frames_at(LATE, 1000.0).unwrap();
+        assert_eq!((current.server_time, next.server_time, lerp), (LATE + 100, LATE + 100, 0.0));
+    }
+
+    #[test]
+    fn empty_demo() {
+        let demo = demo(&[]);
+        assert!(demo.frames_at(0, 0.0).is_none());
+        assert!(demo.camera(1.0).is_none());
+        assert!(demo.entities(1.0).is_empty());
+        assert_eq!(demo.animation_times(0, 1.0), (0.0, 0.0));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,889 @@
+//Quake 3 network messages as stored in demos, Huffman coded bytes with delta compressed entity and player states
+//https://github.com/ioquake/ioq3/blob/main/code/qcommon/msg.c
+
+//Byte frequencies the game feeds its adaptive Huffman coder with once at startup, msg_hData in msg.c
+const HUFFMAN_FREQUENCIES: [u32; 256] = [
+    250315, 41193, 6292, 7106, 3730, 3750, 6110, 23283, 33317, 6950, 7838, 9714, 9257, 17259, 3949, 1778,
+    8288, 1604, 1590, 1663, 1100, 1213, 1238, 1134, 1749, 1059, 1246, 1149, 1273, 4486, 2805, 3472,
+    21819, 1159, 1670, 1066, 1043, 1012, 1053, 1070, 1726, 888, 1180, 850, 960, 780, 1752, 3296,
+    10630, 4514, 5881, 2685, 4650, 3837, 2093, 1867, 2584, 1949, 1972, 940, 1134, 1788, 1670, 1206,
+    5719, 6128, 7222, 6654, 3710, 3795, 1492, 1524, 2215, 1140, 1355, 971, 2180, 1248, 1328, 1195,
+    1770, 1078, 1264, 1266, 1168, 965, 1155, 1186, 1347, 1228, 1529, 1600, 2617, 2048, 2546, 3275,
+    2410, 3585, 2504, 2800, 2675, 6146, 3663, 2840, 14253, 3164, 2221, 1687, 3208, 2739, 3512, 4796,
+    4091, 3515, 5288, 4016, 7937, 6031, 5360, 3924, 4892, 3743, 4566, 4807, 5852, 6400, 6225, 8291,
+    23243, 7838, 7073, 8935, 5437, 4483, 3641, 5256, 5312, 5328, 5370, 3492, 2458, 1694, 1821, 2121,
+    1916, 1149, 1516, 1367, 1236, 1029, 1258, 1104, 1245, 1006, 1149, 1025, 1241, 952, 1287, 997,
+    1713, 1009, 1187, 879, 1099, 929, 1078, 951, 1656, 930, 1153, 1030, 1262, 1062, 1214, 1060,
+    1621, 930, 1106, 912, 1034, 892, 1158, 990, 1175, 850, 1121, 903, 1087, 920, 1144, 1056,
+    3462, 2240, 4397, 12136, 7758, 1345, 1307, 3278, 1950, 886, 1023, 1112, 1077, 1042, 1061, 1071,
+    1484, 1001, 1096, 915, 1052, 995, 1070, 876, 1111, 851, 1059, 805, 1112, 923, 1103, 817,
+    1899, 1872, 976, 841, 1127, 956, 1159, 950, 7791, 954, 1289, 933, 1127, 3207, 1020, 927,
+    1355, 768, 1040, 745, 952, 805, 1073, 740, 1013, 805, 1008, 796, 996, 1057, 11457, 13504,
+];
+
+const NYT: i32 = 256;
+const INTERNAL_NODE: i32 = 257;
+const NONE: usize = usize::MAX;
+
+pub const GENTITYNUM_BITS: i32 = 10;
+pub const MAX_GENTITIES: usize = 1 << GENTITYNUM_BITS;
+const FLOAT_INT_BITS: i32 = 13;
+const FLOAT_INT_BIAS: i32 = 1 << (FLOAT_INT_BITS - 1);
+const MAX_STATS: usize = 16;
+const MAX_PERSISTANT: usize = 16;
+const MAX_WEAPONS: usize = 16;
+const MAX_POWERUPS: usize = 16;
+
+//Fields in the order they are sent with their bit count, 0 is a float and negative is signed
+pub const ENTITY_FIELDS: [(&str, i32); 51] = [
+    ("pos.trTime", 32), ("pos.trBase[0]", 0), ("pos.trBase[1]", 0), ("pos.trDelta[0]", 0), ("pos.trDelta[1]", 0), ("pos.trBase[2]", 0),
+    ("apos.trBase[1]", 0), ("pos.trDelta[2]", 0), ("apos.trBase[0]", 0), ("event", 10), ("angles2[1]", 0), ("eType", 8),
+    ("torsoAnim", 8), ("eventParm", 8), ("legsAnim", 8), ("groundEntityNum", GENTITYNUM_BITS), ("pos.trType", 8), ("eFlags", 19),
+    ("otherEntityNum", GENTITYNUM_BITS), ("weapon", 8), ("clientNum", 8), ("angles[1]", 0), ("pos.trDuration", 32), ("apos.trType", 8),
+    ("origin[0]", 0), ("origin[1]", 0), ("origin[2]", 0), ("solid", 24), ("powerups", MAX_POWERUPS as i32), ("modelindex", 8),
+    ("otherEntityNum2", GENTITYNUM_BITS), ("loopSound", 8), ("generic1", 8), ("origin2[2]", 0), ("origin2[0]", 0), ("origin2[1]", 0),
+    ("modelindex2", 8), ("angles[0]", 0), ("time", 32), ("apos.trTime", 32), ("apos.trDuration", 32), ("apos.trBase[2]", 0),
+    ("apos.trDelta[0]", 0), ("apos.trDelta[1]", 0), ("apos.trDelta[2]", 0), ("time2", 32), ("angles[2]", 0), ("angles2[0]", 0),
+    ("angles2[2]", 0), ("constantLight", 32), ("frame", 16),
+];
+
+pub const PLAYER_FIELDS: [(&str, i32); 48] = [
+    ("commandTime", 32), ("origin[0]", 0), ("origin[1]", 0), ("bobCycle", 8), ("velocity[0]", 0), ("velocity[1]", 0),
+    ("viewangles[1]", 0), ("viewangles[0]", 0), ("weaponTime", -16), ("origin[2]", 0), ("velocity[2]", 0), ("legsTimer", 8),
+    ("pm_time", -16), ("eventSequence", 16), ("torsoAnim", 8), ("movementDir", 4), ("events[0]", 8), ("legsAnim", 8),
+    ("events[1]", 8), ("pm_flags", 16), ("groundEntityNum", GENTITYNUM_BITS), ("weaponstate", 4), ("eFlags", 16), ("externalEvent", 10),
+    ("gravity", 16), ("speed", 16), ("delta_angles[1]", 16), ("externalEventParm", 8), ("viewheight", -8), ("damageEvent", 8),
+    ("damageYaw", 8), ("damagePitch", 8), ("damageCount", 8), ("generic1", 8), ("pm_type", 8), ("delta_angles[0]", 16),
+    ("delta_angles[2]", 16), ("torsoTimer", 12), ("eventParms[0]", 8), ("eventParms[1]", 8), ("clientNum", 8), ("weapon", 5),
+    ("viewangles[2]", 0), ("grapplePoint[0]", 0), ("grapplePoint[1]", 0), ("grapplePoint[2]", 0), ("jumppad_ent", GENTITYNUM_BITS), ("loopSound", 16),
+];
+
+//Index of a field by its name in the game source
+pub fn field(fields: &[(&str, i32)], name: &str) -> usize {
+    fields.iter().position(|(n, _)| *n == name).unwrap()
+}
+
+//Fields are kept as the 32 bits the game copies around, floats as their bit pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityState {
+    pub number: usize,
+    pub fields: [u32; 51],
+}
+
+impl EntityState {
+
+    pub fn new(number: usize) -> Self {
+        EntityState { number, fields: [0; 51] }
+    }
+
+    pub fn int(&self, name: &str) -> i32 {
+        self.fields[field(&ENTITY_FIELDS, name)] as i32
+    }
+
+    pub fn float(&self, name: &str) -> f32 {
+        f32::from_bits(self.fields[field(&ENTITY_FIELDS, name)])
+    }
+
+    pub fn vector(&self, name: &str) -> [f32; 3] {
+        [self.float(&format!("{}[0]", name)), self.float(&format!("{}[1]", name)), self.float(&format!("{}[2]", name))]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerState {
+    pub fields: [u32; 48],
+    pub stats: [i32; MAX_STATS],
+    pub persistant: [i32; MAX_PERSISTANT],
+    pub ammo: [i32; MAX_WEAPONS],
+    pub powerups: [i32; MAX_POWERUPS],
+}
+
+impl PlayerState {
+
+    pub fn new() -> Self {
+        PlayerState { fields: [0; 48], stats: [0; MAX_STATS], persistant: [0; MAX_PERSISTANT], ammo: [0; MAX_WEAPONS], powerups: [0; MAX_POWERUPS] }
+    }
+
+    pub fn int(&self, name: &str) -> i32 {
+        self.fields[field(&PLAYER_FIELDS, name)] as i32
+    }
+
+    pub fn float(&self, name: &str) -> f32 {
+        f32::from_bits(self.fields[field(&PLAYER_FIELDS, name)])
+    }
+
+    pub fn vector(&self, name: &str) -> [f32; 3] {
+        [self.float(&format!("{}[0]", name)), self.float(&format!("{}[1]", name)), self.float(&format!("{}[2]", name))]
+    }
+}
+
+//The tree the adaptive coder ends up with after the frequencies are added, only used for decoding.
+//Nodes live in a vector and the shared block heads in slots, like the node and pointer arrays of huffman.c
+struct HuffmanNode {
+    left: usize,
+    right: usize,
+    parent: usize,
+    next: usize,
+    prev: usize,
+    head: usize,
+    weight: u32,
+    symbol: i32,
+}
+
+pub struct Huffman {
+    nodes: Vec<HuffmanNode>,
+    heads: Vec<usize>,
+    free_heads: Vec<usize>,
+    tree: usize,
+    lhead: usize,
+    loc: Vec<usize>,
+}
+
+impl Huffman {
+
+    pub fn new() -> Self {
+
+        let nyt = HuffmanNode { left: NONE, right: NONE, parent: NONE, next: NONE, prev: NONE, head: NONE, weight: 0, symbol: NYT };
+        let mut huffman = Huffman { nodes: vec![nyt], heads: Vec::new(), free_heads: Vec::new(), tree: 0, lhead: 0, loc: vec![NONE; 257] };
+        huffman.loc[NYT as usize] = 0;
+        for (symbol, count) in HUFFMAN_FREQUENCIES.iter().enumerate() {
+            for _ in 0..*count {
+                huffman.add_ref(symbol as u8);
+            }
+        }
+        huffman
+    }
+
+    fn get_head(&mut self) -> usize {
+        match self.free_heads.pop() {
+            Some(head) => head,
+            None => {
+                self.heads.push(NONE);
+                self.heads.len() - 1
+            }
+        }
+    }
+
+    fn new_node(&mut self, symbol: i32) -> usize {
+        self.nodes.push(HuffmanNode { left: NONE, right: NONE, parent: NONE, next: NONE, prev: NONE, head: NONE, weight: 1, symbol });
+        self.nodes.len() - 1
+    }
+
+    //Swaps two nodes in the tree
+    fn swap(&mut self, node1: usize, node2: usize) {
+
+        let (par1, par2) = (self.nodes[node1].parent, self.nodes[node2].parent);
+        if par1 != NONE {
+            if self.nodes[par1].left == node1 {
+                self.nodes[par1].left = node2;
+            }
+            else {
+                self.nodes[par1].right = node2;
+            }
+        }
+        else {
+            self.tree = node2;
+        }
+        if par2 != NONE {
+            if self.nodes[par2].left == node2 {
+                self.nodes[par2].left = node1;
+            }
+            else {
+                self.nodes[par2].right = node1;
+            }
+        }
+        else {
+            self.tree = node1;
+        }
+        self.nodes[node1].parent = par2;
+        self.nodes[node2].parent = par1;
+    }
+
+    //Swaps two nodes in the list ordered by weight
+    fn swap_list(&mut self, node1: usize, node2: usize) {
+
+        let next = self.nodes[node1].next;
+        self.nodes[node1].next = self.nodes[node2].next;
+        self.nodes[node2].next = next;
+        let prev = self.nodes[node1].prev;
+        self.nodes[node1].prev = self.nodes[node2].prev;
+        self.nodes[node2].prev = prev;
+
+        if self.nodes[node1].next == node1 {
+            self.nodes[node1].next = node2;
+        }
+        if self.nodes[node2].next == node2 {
+            self.nodes[node2].next = node1;
+        }
+        for node in [node1, node2].iter() {
+            let (next, prev) = (self.nodes[*node].next, self.nodes[*node].prev);
+            if next != NONE {
+                self.nodes[next].prev = *node;
+            }
+            if prev != NONE {
+                self.nodes[prev].next = *node;
+            }
+        }
+    }
+
+    fn increment(&mut self, node: usize) {
+
+        if node == NONE {
+            return;
+        }
+        let next = self.nodes[node].next;
+        if next != NONE && self.nodes[next].weight == self.nodes[node].weight {
+            let lnode = self.heads[self.nodes[node].head];
+            if lnode != self.nodes[node].parent {
+                self.swap(lnode, node);
+            }
+            self.swap_list(lnode, node);
+        }
+        let prev = self.nodes[node].prev;
+        let head = self.nodes[node].head;
+        if prev != NONE && self.nodes[prev].weight == self.nodes[node].weight {
+            self.heads[head] = prev;
+        }
+        else {
+            self.heads[head] = NONE;
+            self.free_heads.push(head);
+        }
+        self.nodes[node].weight += 1;
+        let next = self.nodes[node].next;
+        if next != NONE && self.nodes[next].weight == self.nodes[node].weight {
+            self.nodes[node].head = self.nodes[next].head;
+        }
+        else {
+            let head = self.get_head();
+            self.heads[head] = node;
+            self.nodes[node].head = head;
+        }
+        let parent = self.nodes[node].parent;
+        if parent != NONE {
+            self.increment(parent);
+            if self.nodes[node].prev == parent {
+                self.swap_list(node, parent);
+                let head = self.nodes[node].head;
+                if self.heads[head] == node {
+                    self.heads[head] = parent;
+                }
+            }
+        }
+    }
+
+    //A new symbol splits the not yet transmitted node into it and a new not yet transmitted node
+    fn add_ref(&mut self, ch: u8) {
+
+        if self.loc[ch as usize] != NONE {
+            let node = self.loc[ch as usize];
+            self.increment(node);
+            return;
+        }
+
+        let lhead = self.lhead;
+        let tnode = self.new_node(ch as i32);
+        let tnode2 = self.new_node(INTERNAL_NODE);
+        for node in [tnode2, tnode].iter() {
+            let first = self.nodes[lhead].next;
+            self.nodes[*node].next = first;
+            if first != NONE {
+                self.nodes[first].prev = *node;
+                if self.nodes[first].weight == 1 {
+                    self.nodes[*node].head = self.nodes[first].head;
+                }
+                else {
+                    let head = self.get_head();
+                    self.heads[head] = *node;
+                    self.nodes[*node].head = head;
+                }
+            }
+            else {
+                let head = self.get_head();
+                self.heads[head] = *node;
+                self.nodes[*node].head = head;
+            }
+            self.nodes[lhead].next = *node;
+            self.nodes[*node].prev = lhead;
+        }
+
+        let parent = self.nodes[lhead].parent;
+        if parent != NONE {
+            if self.nodes[parent].left == lhead {
+                self.nodes[parent].left = tnode2;
+            }
+            else {
+                self.nodes[parent].right = tnode2;
+            }
+        }
+        else {
+            self.tree = tnode2;
+        }
+        self.nodes[tnode2].right = tnode;
+        self.nodes[tnode2].left = lhead;
+        self.nodes[tnode2].parent = parent;
+        self.nodes[lhead].parent = tnode2;
+        self.nodes[tnode].parent = tnode2;
+        self.loc[ch as usize] = tnode;
+        self.increment(parent);
+    }
+
+    //Follows the bits from the root to a symbol, None past the end of the data
+    pub fn receive(&self, data: &[u8], bit: &mut usize) -> Option<i32> {
+
+        let mut node = self.tree;
+        while node != NONE && self.nodes[node].symbol == INTERNAL_NODE {
+            let b = get_bit(data, bit)?;
+            node = if b == 1 { self.nodes[node].right } else { self.nodes[node].left };
+        }
+        if node == NONE {
+            return Some(0);
+        }
+        Some(self.nodes[node].symbol)
+    }
+}
+
+//Bits are read from the lowest bit of each byte up
+fn get_bit(data: &[u8], bit: &mut usize) -> Option<u8> {
+    let b = (data.get(*bit >> 3)? >> (*bit & 7)) & 1;
+    *bit += 1;
+    Some(b)
+}
+
+pub struct Message<'a> {
+    data: &'a [u8],
+    bit: usize,
+    huffman: &'a Huffman,
+    pub overflowed: bool,
+}
+
+impl<'a> Message<'a> {
+
+    pub fn new(data: &'a [u8], huffman: &'a Huffman) -> Self {
+        Message { data, bit: 0, huffman, overflowed: false }
+    }
+
+    //Whole bytes go through the Huffman tree, any odd bits before them are raw. Reading past the end gives 0
+    pub fn read_bits(&mut self, bits: i32) -> i32 {
+
+        if self.overflowed {
+            return 0;
+        }
+        let signed = bits < 0;
+        let bits = bits.abs();
+        let mut value: u32 = 0;
+        let odd = bits & 7;
+        for i in 0..odd {
+            match get_bit(self.data, &mut self.bit) {
+                Some(b) => value |= (b as u32) << i,
+                None => {
+                    self.overflowed = true;
+                    return 0;
+                }
+            }
+        }
+        let mut i = 0;
+        while i < bits - odd {
+            match self.huffman.receive(self.data, &mut self.bit) {
+                Some(byte) => value |= (byte as u32) << (i + odd),
+                None => {
+                    self.overflowed = true;
+                    return 0;
+                }
+            }
+            i += 8;
+        }
+        if signed && bits < 32 && value & (1 << (bits - 1)) != 0 {
+            value |= !((1 << bits) - 1);
+        }
+        value as i32
+    }
+
+    //-1 past the end
+    pub fn read_byte(&mut self) -> i32 {
+        let c = self.read_bits(8) & 0xff;
+        if self.overflowed { -1 } else { c }
+    }
+
+    pub fn read_short(&mut self) -> i32 {
+        self.read_bits(16) as i16 as i32
+    }
+
+    pub fn read_long(&mut self) -> i32 {
+        self.read_bits(32)
+    }
+
+    //Strings end with a zero, % and bytes above 127 become dots like the game does
+    pub fn read_string(&mut self, max: usize) -> String {
+
+        let mut s = String::new();
+        loop {
+            let c = self.read_byte();
+            if c <= 0 {
+                break;
+            }
+            let c = if c == '%' as i32 || c > 127 { '.' } else { c as u8 as char };
+            if s.len() < max - 1 {
+                s.push(c);
+            }
+        }
+        s
+    }
+
+    pub fn read_data(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.read_byte() as u8).collect()
+    }
+
+    //Floats are zero, a 13 bit integer or all 32 bits
+    fn read_float(&mut self) -> u32 {
+        if self.read_bits(1) == 0 {
+            ((self.read_bits(FLOAT_INT_BITS) - FLOAT_INT_BIAS) as f32).to_bits()
+        }
+        else {
+            self.read_bits(32) as u32
+        }
+    }
+
+    //None when the entity was removed
+    pub fn read_delta_entity(&mut self, from: &EntityState, number: usize) -> Result<Option<EntityState>, String> {
+
+        if self.read_bits(1) == 1 {
+            return Ok(None);
+        }
+        let mut to = from.clone();
+        to.number = number;
+        if self.read_bits(1) == 0 {
+            return Ok(Some(to));
+        }
+        let count = self.read_byte();
+        if count < 0 || count as usize > ENTITY_FIELDS.len() {
+            return Err(format!("entity {} has {} fields", number, count));
+        }
+        for (i, (_, bits)) in ENTITY_FIELDS.iter().enumerate().take(count as usize) {
+            if self.read_bits(1) == 0 {
+                continue;
+            }
+            to.fields[i] = if self.read_bits(1) == 0 {
+                0
+            }
+            else if *bits == 0 {
+                self.read_float()
+            }
+            else {
+                self.read_bits(*bits) as u32
+            };
+        }
+        Ok(Some(to))
+    }
+
+    //Player state fields have no zero bit, the stat arrays follow with a bit mask each
+    pub fn read_delta_player_state(&mut self, from: &PlayerState) -> Result<PlayerState, String> {
+
+        let mut to = from.clone();
+        let count = self.read_byte();
+        if count < 0 || count as usize > PLAYER_FIELDS.len() {
+            return Err(format!("player state has {} fields", count));
+        }
+        for (i, (_, bits)) in PLAYER_FIELDS.iter().enumerate().take(count as usize) {
+            if self.read_bits(1) == 0 {
+                continue;
+            }
+            to.fields[i] = if *bits == 0 { self.read_float() } else { self.read_bits(*bits) as u32 };
+        }
+
+        if self.read_bits(1) == 1 {
+            if self.read_bits(1) == 1 {
+                let bits = self.read_bits(MAX_STATS as i32);
+                for i in 0..MAX_STATS {
+                    if bits & (1 << i) != 0 {
+                        to.stats[i] = self.read_short();
+                    }
+                }
+            }
+            if self.read_bits(1) == 1 {
+                let bits = self.read_bits(MAX_PERSISTANT as i32);
+                for i in 0..MAX_PERSISTANT {
+                    if bits & (1 << i) != 0 {
+                        to.persistant[i] = self.read_short();
+                    }
+                }
+            }
+            if self.read_bits(1) == 1 {
+                let bits = self.read_bits(MAX_WEAPONS as i32);
+                for i in 0..MAX_WEAPONS {
+                    if bits & (1 << i) != 0 {
+                        to.ammo[i] = self.read_short();
+                    }
+                }
+            }
+            if self.read_bits(1) == 1 {
+                let bits = self.read_bits(MAX_POWERUPS as i32);
+                for i in 0..MAX_POWERUPS {
+                    if bits & (1 << i) != 0 {
+                        to.powerups[i] = self.read_long();
+                    }
+                }
+            }
+        }
+        Ok(to)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    //Writes bits the way msg.c does, whole bytes by walking the tree up from the symbol's leaf.
+    //The demo tests build their messages with it too
+    pub struct Writer<'a> {
+        huffman: &'a Huffman,
+        pub data: Vec<u8>,
+        bit: usize,
+    }
+
+    impl<'a> Writer<'a> {
+
+        pub fn new(huffman: &'a Huffman) -> Self {
+            Writer { huffman, data: Vec::new(), bit: 0 }
+        }
+
+        fn put_bit(&mut self, b: i32) {
+            if self.bit & 7 == 0 {
+                self.data.push(0);
+            }
+            self.data[self.bit >> 3] |= ((b & 1) as u8) << (self.bit & 7);
+            self.bit += 1;
+        }
+
+        fn symbol(&mut self, symbol: u8) {
+            let mut code = Vec::new();
+            let mut node = self.huffman.loc[symbol as usize];
+            while self.huffman.nodes[node].parent != NONE {
+                let parent = self.huffman.nodes[node].parent;
+                code.push((self.huffman.nodes[parent].right == node) as i32);
+                node = parent;
+            }
+            for b in code.iter().rev() {
+                self.put_bit(*b);
+            }
+        }
+
+        pub fn bits(&mut self, value: i32, bits: i32) {
+            let bits = bits.abs();
+            let odd = bits & 7;
+            for i in 0..odd {
+                self.put_bit(value >> i);
+            }
+            for i in (odd..bits).step_by(8) {
+                self.symbol((value >> i) as u8);
+            }
+        }
+
+        pub fn float(&mut self, value: f32) {
+            let truncated = value as i32;
+            if truncated as f32 == value && truncated + FLOAT_INT_BIAS >= 0 && truncated + FLOAT_INT_BIAS < 1 << FLOAT_INT_BITS {
+                self.bits(0, 1);
+                self.bits(truncated + FLOAT_INT_BIAS, FLOAT_INT_BITS);
+            }
+            else {
+                self.bits(1, 1);
+                self.bits(value.to_bits() as i32, 32);
+            }
+        }
+
+        pub fn string(&mut self, s: &str) {
+            for b in s.bytes().chain(std::iter::once(0)) {
+                self.symbol(b);
+            }
+        }
+
+        //MSG_WriteDeltaEntity, the entity number first and then only the fields up to the last one that changed
+        pub fn delta_entity(&mut self, from: &EntityState, to: &EntityState) {
+            self.bits(to.number as i32, GENTITYNUM_BITS);
+            self.bits(0, 1);
+            let count = (0..ENTITY_FIELDS.len()).rev().find(|i| from.fields[*i] != to.fields[*i]).map_or(0, |i| i + 1);
+            if count == 0 {
+                self.bits(0, 1);
+                return;
+            }
+            self.bits(1, 1);
+            self.bits(count as i32, 8);
+            for (i, (_, bits)) in ENTITY_FIELDS.iter().enumerate().take(count) {
+                if from.fields[i] == to.fields[i] {
+                    self.bits(0, 1);
+                    continue;
+                }
+                self.bits(1, 1);
+                if to.fields[i] == 0 {
+                    self.bits(0, 1);
+                    continue;
+                }
+                self.bits(1, 1);
+                if *bits == 0 {
+                    self.float(f32::from_bits(to.fields[i]));
+                }
+                else {
+                    self.bits(to.fields[i] as i32, *bits);
+                }
+            }
+        }
+
+        pub fn removed_entity(&mut self, number: usize) {
+            self.bits(number as i32, GENTITYNUM_BITS);
+            self.bits(1, 1);
+        }
+
+        //MSG_WriteDeltaPlayerstate
+        pub fn delta_player_state(&mut self, from: &PlayerState, to: &PlayerState) {
+            let count = (0..PLAYER_FIELDS.len()).rev().find(|i| from.fields[*i] != to.fields[*i]).map_or(0, |i| i + 1);
+            self.bits(count as i32, 8);
+            for (i, (_, bits)) in PLAYER_FIELDS.iter().enumerate().take(count) {
+                if from.fields[i] == to.fields[i] {
+                    self.bits(0, 1);
+                    continue;
+                }
+                self.bits(1, 1);
+                if *bits == 0 {
+                    self.float(f32::from_bits(to.fields[i]));
+                }
+                else {
+                    self.bits(to.fields[i] as i32, *bits);
+                }
+            }
+            let mask = |from: &[i32], to: &[i32]| (0..from.len()).filter(|i| from[*i] != to[*i]).fold(0, |m, i| m | 1 << i);
+            let masks = [mask(&from.stats, &to.stats), mask(&from.persistant, &to.persistant), mask(&from.ammo, &to.ammo), mask(&from.powerups, &to.powerups)];
+            if masks.iter().all(|m| *m == 0) {
+                self.bits(0, 1);
+                return;
+            }
+            self.bits(1, 1);
+            for (k, (mask, values)) in masks.iter().zip([&to.stats, &to.persistant, &to.ammo, &to.powerups].iter()).enumerate() {
+                if *mask == 0 {
+                    self.bits(0, 1);
+                    continue;
+                }
+                self.bits(1, 1);
+                self.bits(*mask, 16);
+                for (i, value) in values.iter().enumerate().filter(|(i, _)| mask & (1 << i) != 0) {
+                    self.bits(*value, if k == 3 { 32 } else { 16 });
+                }
+            }
+        }
+    }
+
+    fn depth(huffman: &Huffman, symbol: usize) -> usize {
+        let mut node = huffman.loc[symbol];
+        let mut depth = 0;
+        while huffman.nodes[node].parent != NONE {
+            node = huffman.nodes[node].parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    #[test]
+    fn huffman_codes() {
+        let huffman = Huffman::new();
+        for symbol in 0..256 {
+            assert_ne!(huffman.loc[symbol], NONE);
+            assert_eq!(huffman.nodes[huffman.loc[symbol]].symbol, symbol as i32);
+        }
+        //A complete prefix code, the most common byte gets the shortest code and rarer ones are never shorter
+        let depths: Vec<usize> = (0..256).map(|s| depth(&huffman, s)).collect();
+        let kraft: f64 = depths.iter().map(|d| 0.5f64.powi(*d as i32)).sum();
+        assert!(kraft > 0.99 && kraft <= 1.0);
+        for a in 0..256 {
+            for b in 0..256 {
+                if HUFFMAN_FREQUENCIES[a] > HUFFMAN_FREQUENCIES[b] {
+                    assert!(depths[a] <= depths[b], "{} {}", a, b);
+                }
+            }
+        }
+        assert!(depths.iter().all(|d| depths[0] <= *d));
+    }
+
+    //Codes of the tree ioq3's Huff_addRef builds from msg_hData, root first. Any slip in the adaptive
+    //updates gives a different tree that is still a valid prefix code
+    const CODEWORDS: [(u8, &str); 12] = [
+        (0x00, "01"), (0x01, "11011"), (0x02, "0001001"), (0x07, "111111"), (0x08, "10101"), (b'\n', "1001011"),
+        (b' ', "111011"), (b'A', "0000101"), (b'a', "00111100"), (0x80, "111110"), (0xfe, "1111010"), (0xff, "001001"),
+    ];
+
+    #[test]
+    fn huffman_codewords() {
+        let huffman = Huffman::new();
+        for (symbol, code) in CODEWORDS.iter() {
+            let mut data = vec![0u8; 2];
+            for (i, c) in code.chars().enumerate() {
+                data[i >> 3] |= ((c == '1') as u8) << (i & 7);
+            }
+            let mut bit = 0;
+            assert_eq!(huffman.receive(&data, &mut bit), Some(*symbol as i32), "{:02x}", symbol);
+            assert_eq!(bit, code.len());
+
+            let mut writer = Writer::new(&huffman);
+            writer.symbol(*symbol);
+            assert_eq!((writer.bit, &writer.data[..]), (code.len(), &data[..(code.len() + 7) / 8]));
+        }
+    }
+
+    #[test]
+    fn huffman_round_trip() {
+        let huffman = Huffman::new();
+        let mut writer = Writer::new(&huffman);
+        let bytes: Vec<u8> = (0..=255).chain([0, 0, 255, 7, 7].iter().copied()).collect();
+        for b in &bytes {
+            writer.symbol(*b);
+        }
+        let mut bit = 0;
+        let read: Vec<i32> = bytes.iter().map(|_| huffman.receive(&writer.data, &mut bit).unwrap()).collect();
+        assert_eq!(read, bytes.iter().map(|b| *b as i32).collect::<Vec<_>>());
+        assert_eq!(bit, writer.bit);
+        assert_eq!(huffman.receive(&writer.data, &mut (writer.data.len() * 8)), None);
+    }
+
+    #[test]
+    fn raw_bits() {
+        //Odd bits are read from the low bit of each byte up, with no Huffman coding
+        let huffman = Huffman::new();
+        let data = [0b1010_1101, 0b0000_0011];
+        let mut msg = Message::new(&data, &huffman);
+        assert_eq!(msg.read_bits(3), 0b101);
+        assert_eq!(msg.read_bits(1), 1);
+        assert_eq!(msg.read_bits(-5), -6);
+        assert_eq!(msg.read_bits(2), 0b01);
+        assert!(!msg.overflowed);
+        assert_eq!(msg.read_bits(7), 0);
+        assert!(msg.overflowed);
+    }
+
+    #[test]
+    fn read_bits() {
+        let huffman = Huffman::new();
+        let mut writer = Writer::new(&huffman);
+        writer.bits(5, 3);
+        writer.bits(0xab, 8);
+        writer.bits(-1234, -12);
+        writer.bits(-2, 16);
+        writer.bits(0x12345678, 32);
+        writer.bits(-100000, 32);
+        for b in b"sv_%hostname\xe9\0" {
+            writer.symbol(*b);
+        }
+        writer.bits(300, 16);
+
+        let mut msg = Message::new(&writer.data, &huffman);
+        assert_eq!(msg.read_bits(3), 5);
+        assert_eq!(msg.read_byte(), 0xab);
+        assert_eq!(msg.read_bits(-12), -1234);
+        assert_eq!(msg.read_short(), -2);
+        assert_eq!(msg.read_long(), 0x12345678);
+        assert_eq!(msg.read_long(), -100000);
+        assert_eq!(msg.read_string(1024), "sv_.hostname.");
+        assert_eq!(msg.read_short(), 300);
+        assert!(!msg.overflowed);
+
+        //Past the end everything is zero, bytes -1
+        while msg.bit < writer.data.len() * 8 - 8 {
+            msg.read_bits(1);
+        }
+        assert_eq!(msg.read_byte(), -1);
+        assert!(msg.overflowed);
+        assert_eq!(msg.read_long(), 0);
+    }
+
+    #[test]
+    fn delta_entity() {
+        let huffman = Huffman::new();
+        let mut from = EntityState::new(5);
+        from.fields[field(&ENTITY_FIELDS, "eType")] = 3;
+        from.fields[field(&ENTITY_FIELDS, "pos.trBase[1]")] = 64.0f32.to_bits();
+
+        let mut writer = Writer::new(&huffman);
+        //Removed
+        writer.bits(1, 1);
+        //Unchanged
+        writer.bits(0, 1);
+        writer.bits(0, 1);
+        //Changed up to eType: the time, one float as an integer, one as all bits, one float zeroed, the type zeroed
+        writer.bits(0, 1);
+        writer.bits(1, 1);
+        writer.bits(field(&ENTITY_FIELDS, "eType") as i32 + 1, 8);
+        for (i, _) in ENTITY_FIELDS.iter().enumerate().take(field(&ENTITY_FIELDS, "eType") + 1) {
+            match ENTITY_FIELDS[i].0 {
+                "pos.trTime" => { writer.bits(1, 1); writer.bits(1, 1); writer.bits(123456789, 32); }
+                "pos.trBase[0]" => { writer.bits(1, 1); writer.bits(1, 1); writer.float(-96.0); }
+                "pos.trDelta[0]" => { writer.bits(1, 1); writer.bits(1, 1); writer.float(0.25); }
+                "pos.trBase[1]" | "eType" => { writer.bits(1, 1); writer.bits(0, 1); }
+                _ => writer.bits(0, 1),
+            }
+        }
+        //Too many fields
+        writer.bits(0, 1);
+        writer.bits(1, 1);
+        writer.bits(ENTITY_FIELDS.len() as i32 + 1, 8);
+
+        let mut msg = Message::new(&writer.data, &huffman);
+        assert_eq!(msg.read_delta_entity(&from, 5), Ok(None));
+        let unchanged = msg.read_delta_entity(&from, 6).unwrap().unwrap();
+        assert_eq!((unchanged.number, unchanged.fields), (6, from.fields));
+
+        let to = msg.read_delta_entity(&from, 5).unwrap().unwrap();
+        assert_eq!(to.int("pos.trTime"), 123456789);
+        assert_eq!(to.vector("pos.trBase"), [-96.0, 0.0, 0.0]);
+        assert_eq!(to.vector("pos.trDelta"), [0.25, 0.0, 0.0]);
+        assert_eq!(to.int("eType"), 0);
+        assert_eq!(to.fields.iter().filter(|f| **f != 0).count(), 3);
+
+        assert!(msg.read_delta_entity(&from, 5).is_err());
+        assert!(!msg.overflowed);
+    }
+
+    #[test]
+    fn delta_player_state() {
+        let huffman = Huffman::new();
+        let mut from = PlayerState::new();
+        from.fields[field(&PLAYER_FIELDS, "origin[1]")] = 8.0f32.to_bits();
+        from.stats[2] = 7;
+
+        let mut writer = Writer::new(&huffman);
+        let last = field(&PLAYER_FIELDS, "weaponTime");
+        writer.bits(last as i32 + 1, 8);
+        for (i, _) in PLAYER_FIELDS.iter().enumerate().take(last + 1) {
+            match PLAYER_FIELDS[i].0 {
+                "commandTime" => { writer.bits(1, 1); writer.bits(987654, 32); }
+                "origin[0]" => { writer.bits(1, 1); writer.float(1.5); }
+                "viewangles[1]" => { writer.bits(1, 1); writer.float(90.0); }
+                "weaponTime" => { writer.bits(1, 1); writer.bits(-40, -16); }
+                _ => writer.bits(0, 1),
+            }
+        }
+        //Stats 0 and 3 and powerup 15, nothing for persistant and ammo
+        writer.bits(1, 1);
+        writer.bits(1, 1);
+        writer.bits(0b1001, 16);
+        writer.bits(100, 16);
+        writer.bits(-25, 16);
+        writer.bits(0, 1);
+        writer.bits(0, 1);
+        writer.bits(1, 1);
+        writer.bits(1 << 15, 16);
+        writer.bits(123456, 32);
+        //Nothing changed
+        writer.bits(0, 8);
+        writer.bits(0, 1);
+
+        let mut msg = Message::new(&writer.data, &huffman);
+        let to = msg.read_delta_player_state(&from).unwrap();
+        assert_eq!(to.int("commandTime"), 987654);
+        assert_eq!(to.vector("origin"), [1.5, 8.0, 0.0]);
+        assert_eq!(to.vector("viewangles"), [0.0, 90.0, 0.0]);
+        assert_eq!(to.int("weaponTime"), -40);
+        assert_eq!(&to.stats[..4], &[100, 0, 7, -25]);
+        assert_eq!(to.powerups[15], 123456);
+        assert_eq!(to.persistant, from.persistant);
+        assert_eq!(to.ammo, from.ammo);
+
+        assert_eq!(msg.read_delta_player_state(&to), Ok(to.clone()));
+        assert!(!msg.overflowed);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,46 @@
+//Fog volumes, shared by the shaders that apply them
+
+#define MAX_FOGS 16
+
+struct Fog {
+    vec4 colour;
+    vec4 plane;
+    vec4 mins;
+    vec4 maxs;
+};
+
+//Length of the eye to fragment segment inside the fog box, clipped to the visible side
+float fog_distance(Fog fog, vec3 eye, vec3 pos) {
+    vec3 dir = pos - eye;
+    float t0 = 0.0;
+    float t1 = 1.0;
+    for (int i = 0; i < 3; i++) {
+        if (abs(dir[i]) < 0.0001) {
+            if (eye[i] < fog.mins[i] || eye[i] > fog.maxs[i]) {
+                return 0.0;
+            }
+        }
+        else {
+            float a = (fog.mins[i] - eye[i]) / dir[i];
+            float b = (fog.maxs[i] - eye[i]) / dir[i];
+            t0 = max(t0, min(a, b));
+            t1 = min(t1, max(a, b));
+        }
+    }
+
+    if (fog.mins.w != 0.0) {
+        float s0 = dot(eye, fog.plane.xyz) - fog.plane.w;
+        float s1 = dot(pos, fog.plane.xyz) - fog.plane.w;
+        if (s0 >= 0.0 && s1 >= 0.0) {
+            return 0.0;
+        }
+        else if (s0 >= 0.0) {
+            t0 = max(t0, s0 / (s0 - s1));
+        }
+        else if (s1 >= 0.0) {
+            t1 = min(t1, s0 / (s0 - s1));
+        }
+    }
+
+    return max(t1 - t0, 0.0) * length(dir);
+}
\ No newline at end of file
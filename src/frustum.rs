@@ -0,0 +1,119 @@
+//View frustum planes and box tests for culling
+//Planes point inwards, a point p is inside when dot(normal, p) + d >= 0
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Intersection {
+    Outside,
+    Inside,
+    Intersecting,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frustum {
+    pub planes: [[f32; 4]; 6],
+}
+
+impl Frustum {
+
+    //Planes of a view projection matrix with depth 0 to 1 like wgpu, left right bottom top near far
+    pub fn from_matrix(m: cgmath::Matrix4<f32>) -> Frustum {
+
+        let row = |i: usize| [m.x[i], m.y[i], m.z[i], m.w[i]];
+        let add = |a: [f32; 4], b: [f32; 4]| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]];
+        let sub = |a: [f32; 4], b: [f32; 4]| [a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]];
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+
+        let mut planes = [add(r3, r0), sub(r3, r0), add(r3, r1), sub(r3, r1), r2, sub(r3, r2)];
+        for plane in planes.iter_mut() {
+            let length = (plane[0] * plane[0] + plane[1] * plane[1] + plane[2] * plane[2]).sqrt();
+            if length > 0.0 {
+                for v in plane.iter_mut() {
+                    *v /= length;
+                }
+            }
+        }
+        Frustum { planes }
+    }
+
+    //Uses the corner furthest along and furthest against each plane normal
+    pub fn test_box(&self, mins: [f32; 3], maxs: [f32; 3]) -> Intersection {
+
+        let mut result = Intersection::Inside;
+        for plane in self.planes.iter() {
+            let mut far = [0.0; 3];
+            let mut near = [0.0; 3];
+            for i in 0..3 {
+                if plane[i] >= 0.0 {
+                    far[i] = maxs[i];
+                    near[i] = mins[i];
+                }
+                else {
+                    far[i] = mins[i];
+                    near[i] = maxs[i];
+                }
+            }
+            if plane[0] * far[0] + plane[1] * far[1] + plane[2] * far[2] + plane[3] < 0.0 {
+                return Intersection::Outside;
+            }
+            if plane[0] * near[0] + plane[1] * near[1] + plane[2] * near[2] + plane[3] < 0.0 {
+                result = Intersection::Intersecting;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera;
+
+    //Looking down -z, 20 units wide and tall from 1 to 100 units away
+    fn ortho() -> Frustum {
+        Frustum::from_matrix(camera::OPENGL_TO_WGPU_MATRIX * cgmath::ortho(-10.0, 10.0, -10.0, 10.0, 1.0, 100.0))
+    }
+
+    #[test]
+    fn planes_are_normalized() {
+        let frustum = Frustum::from_matrix(camera::OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(70.0), 1.5, 1.0, 1000.0));
+        for plane in frustum.planes.iter() {
+            let length = (plane[0] * plane[0] + plane[1] * plane[1] + plane[2] * plane[2]).sqrt();
+            assert!((length - 1.0).abs() < 1e-5);
+        }
+        //The left plane goes through x = -10 and faces right
+        let left = ortho().planes[0];
+        assert!((left[0] - 1.0).abs() < 1e-5 && (left[3] - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn box_sides() {
+        let frustum = ortho();
+        assert_eq!(frustum.test_box([-1.0, -1.0, -50.0], [1.0, 1.0, -40.0]), Intersection::Inside);
+        assert_eq!(frustum.test_box([-20.0, -1.0, -50.0], [-15.0, 1.0, -40.0]), Intersection::Outside);
+        assert_eq!(frustum.test_box([-12.0, -1.0, -50.0], [-8.0, 1.0, -40.0]), Intersection::Intersecting);
+        assert_eq!(frustum.test_box([-1.0, 11.0, -50.0], [1.0, 12.0, -40.0]), Intersection::Outside);
+        assert_eq!(frustum.test_box([-1.0, 9.0, -50.0], [1.0, 12.0, -40.0]), Intersection::Intersecting);
+    }
+
+    #[test]
+    fn box_depth() {
+        let frustum = ortho();
+        assert_eq!(frustum.test_box([-1.0, -1.0, 0.0], [1.0, 1.0, 5.0]), Intersection::Outside);
+        assert_eq!(frustum.test_box([-1.0, -1.0, -200.0], [1.0, 1.0, -150.0]), Intersection::Outside);
+        assert_eq!(frustum.test_box([-1.0, -1.0, -150.0], [1.0, 1.0, -50.0]), Intersection::Intersecting);
+        assert_eq!(frustum.test_box([-1.0, -1.0, -5.0], [1.0, 1.0, 5.0]), Intersection::Intersecting);
+        //A box around the whole frustum crosses every plane
+        assert_eq!(frustum.test_box([-500.0; 3], [500.0; 3]), Intersection::Intersecting);
+    }
+
+    #[test]
+    fn perspective_boxes() {
+        //90 degrees, at 10 units away the view is 20 units wide
+        let frustum = Frustum::from_matrix(camera::OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(90.0), 1.0, 1.0, 100.0));
+        assert_eq!(frustum.test_box([-1.0, -1.0, -11.0], [1.0, 1.0, -10.0]), Intersection::Inside);
+        assert_eq!(frustum.test_box([12.0, -1.0, -11.0], [14.0, 1.0, -10.0]), Intersection::Outside);
+        assert_eq!(frustum.test_box([12.0, -1.0, -30.0], [14.0, 1.0, -20.0]), Intersection::Inside);
+        //Beside the camera is outside even though it is close
+        assert_eq!(frustum.test_box([5.0, -1.0, -1.0], [6.0, 1.0, 1.0]), Intersection::Outside);
+    }
+}
\ No newline at end of file
@@ -3,6 +3,27 @@ mod model;
 mod texture;
 mod bsp;
 mod bsp_look_up;
+mod bsp_shader;
+mod bsp_fog;
+mod bsp_anim;
+mod screenshot;
+mod bsp_entity;
+mod camera_path;
+mod benchmark;
+mod frustum;
+mod bsp_draw;
+mod bsp_area;
+mod vfs;
+mod bsp_data;
+mod bsp_light_map;
+mod bsp_gltf;
+mod bsp_hull;
+mod md3;
+mod bsp_item;
+mod md3_player;
+mod demo_msg;
+mod demo;
+mod bsp_mover;
 
 use winit::{
     event::*,
@@ -18,14 +39,19 @@ use cgmath::Rotation3;
 use cgmath::Zero;
 use std::mem;
 use std::time::{Instant, Duration};
+use std::collections::{HashMap, HashSet};
 
 use model::{DrawModel, Vertex};
+use md3::DrawMd3;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct Uniforms {
     view_proj: [[f32; 4]; 4],
     model: [[f32; 4]; 4],
+    view_position: [f32; 4],
+    time: [f32; 4],
+    render_mode: [f32; 4],
 }
 
 impl Uniforms {
@@ -33,11 +59,23 @@ impl Uniforms {
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
             model: cgmath::Matrix4::identity().into(),
+            view_position: [0.0; 4],
+            time: [0.0; 4],
+            render_mode: [0.0; 4],
         }
     }
 
     fn update_view_proj(&mut self, camera: &camera::Camera, projection: &camera::Projection) {
         self.view_proj = (projection.calc_matrix() * camera.view).into();
+        self.view_position = [camera.position.x, camera.position.y, camera.position.z, 1.0];
+    }
+
+    fn update_time(&mut self, time: f32) {
+        self.time = [time, 0.0, 0.0, 0.0];
+    }
+
+    fn update_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = [render_mode.code(), 0.0, 0.0, 0.0];
     }
 
     //fn update_model(&mut self, model: cgmath::Matrix4<f32>) {
@@ -45,15 +83,368 @@ impl Uniforms {
     //}
 }
 
+//What the bsp shader outputs, see bsp.frag
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RenderMode {
+    Full,
+    Lighting,
+    Textures,
+}
+
+impl RenderMode {
+
+    fn parse(name: &str) -> Option<RenderMode> {
+        match name {
+            "full" => Some(RenderMode::Full),
+            "lighting" => Some(RenderMode::Lighting),
+            "textures" => Some(RenderMode::Textures),
+            _ => None,
+        }
+    }
+
+    fn code(&self) -> f32 {
+        match self {
+            RenderMode::Full => 0.0,
+            RenderMode::Lighting => 1.0,
+            RenderMode::Textures => 2.0,
+        }
+    }
+}
+
+//Seconds the arrow keys skip in a demo
+const DEMO_SKIP: f32 = 5.0;
+
+//Frames a benchmark draws when there is nothing to play back
+const BENCHMARK_FRAMES: u32 = 1000;
+
+//Command line options
+struct Options {
+    texture_settings: texture::TextureSettings,
+    //A map name in the game data or a path to a .bsp file
+    map: Option<String>,
+    //res holds debug.jpg, by default next to the executable or else in the working directory
+    res_dir: String,
+    //Game data, the pk3s and loose files of game_dir and then any extra mounted directories.
+    //Without a game_dir it is baseq3 in res_dir
+    game_dir: Option<String>,
+    mounts: Vec<String>,
+    //File of entity edits applied when the map is loaded, see bsp_entity::parse_edits
+    entity_edits: Option<String>,
+    //Write the loaded map, or only its entities as a .ent file, without opening a window
+    write_bsp: Option<String>,
+    write_ent: Option<String>,
+    //crossing export-gltf <map> writes the map as glTF and crossing export-obj <map> its solid brushes as OBJ into the output directory
+    export: Option<String>,
+    output: String,
+    //Render one frame without a window and save it to this path
+    screenshot: Option<String>,
+    position: Option<[f32; 3]>,
+    //Pitch and yaw in degrees
+    angles: Option<[f32; 2]>,
+    width: u32,
+    height: u32,
+    render_mode: RenderMode,
+    //png or tga
+    screenshot_format: String,
+    //Save every frame to this directory
+    dump_frames: Option<String>,
+    //When dumping frames or benchmarking time advances by 1 / dump_fps each frame
+    dump_fps: u32,
+    //Camera paths, see camera_path.rs
+    record_path: Option<String>,
+    record_rate: u32,
+    play_path: Option<String>,
+    intermission: bool,
+    //Write frame timings to this json file, see benchmark.rs
+    benchmark: Option<String>,
+    //File of fixed viewpoints to benchmark, each one is drawn for viewpoint_frames frames
+    viewpoints: Option<String>,
+    viewpoint_frames: u32,
+    //Player model or model/skin placed at the first spawn point, holding a weapon from models/weapons2
+    player: Option<String>,
+    player_weapon: String,
+    //Legs and torso animations, see md3_player::ANIMATION_NAMES
+    player_legs: usize,
+    player_torso: usize,
+    //A .dm_68 demo on disk or in demos/, played through the camera on the map it was recorded on
+    demo: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            texture_settings: texture::TextureSettings::default(),
+            map: None,
+            res_dir: default_res_dir(),
+            game_dir: None,
+            mounts: Vec::new(),
+            entity_edits: None,
+            write_bsp: None,
+            write_ent: None,
+            export: None,
+            output: ".".to_string(),
+            screenshot: None,
+            position: None,
+            angles: None,
+            width: 1280,
+            height: 720,
+            render_mode: RenderMode::Full,
+            screenshot_format: "png".to_string(),
+            dump_frames: None,
+            dump_fps: 30,
+            record_path: None,
+            record_rate: 20,
+            play_path: None,
+            intermission: false,
+            benchmark: None,
+            viewpoints: None,
+            viewpoint_frames: 100,
+            player: None,
+            player_weapon: "machinegun".to_string(),
+            player_legs: md3_player::LEGS_IDLE,
+            player_torso: md3_player::TORSO_STAND,
+            demo: None,
+        }
+    }
+}
+
+impl Options {
+
+    fn parse() -> Self {
+
+        let mut options = Options::default();
+        let args = std::env::args().collect::<Vec<String>>();
+        let mut i = 1;
+        if args.len() > 2 && (args[1] == "export-gltf" || args[1] == "export-obj") {
+            options.export = Some(args[1].clone());
+            options.map = Some(args[2].clone());
+            i = 3;
+        }
+        while i < args.len() {
+            let value = args.get(i + 1).and_then(|v| v.parse::<u32>().ok());
+            let text = args.get(i + 1).cloned();
+            let vector = |count: usize| args.get((i + 1)..(i + 1 + count)).and_then(|v| v.iter().map(|x| x.parse::<f32>().ok()).collect::<Option<Vec<f32>>>());
+            match (args[i].as_str(), value) {
+                ("--picmip", Some(v)) => {
+                    options.texture_settings.picmip = v;
+                    i += 1;
+                }
+                ("--max-texture-size", Some(v)) => {
+                    options.texture_settings.max_size = v.max(1);
+                    i += 1;
+                }
+                ("--anisotropy", Some(v)) => {
+                    options.texture_settings.anisotropy = if v > 1 { Some(v.min(16).next_power_of_two() as u8) } else { None };
+                    i += 1;
+                }
+                ("--no-mipmaps", _) => options.texture_settings.mipmaps = false,
+                ("--map", _) if text.is_some() => {
+                    options.map = text;
+                    i += 1;
+                }
+                ("--res-dir", _) if text.is_some() => {
+                    options.res_dir = text.unwrap();
+                    i += 1;
+                }
+                ("--game-dir", _) if text.is_some() => {
+                    options.game_dir = text;
+                    i += 1;
+                }
+                ("--mount", _) if text.is_some() => {
+                    options.mounts.push(text.unwrap());
+                    i += 1;
+                }
+                ("--entity-edits", _) if text.is_some() => {
+                    options.entity_edits = text;
+                    i += 1;
+                }
+                ("--write-bsp", _) if text.is_some() => {
+                    options.write_bsp = text;
+                    i += 1;
+                }
+                ("--write-ent", _) if text.is_some() => {
+                    options.write_ent = text;
+                    i += 1;
+                }
+                ("--output", _) if text.is_some() => {
+                    options.output = text.unwrap();
+                    i += 1;
+                }
+                ("--screenshot", _) if text.is_some() => {
+                    options.screenshot = text;
+                    i += 1;
+                }
+                ("--position", _) if vector(3).is_some() => {
+                    let v = vector(3).unwrap();
+                    options.position = Some([v[0], v[1], v[2]]);
+                    i += 3;
+                }
+                ("--angles", _) if vector(2).is_some() => {
+                    let v = vector(2).unwrap();
+                    options.angles = Some([v[0], v[1]]);
+                    i += 2;
+                }
+                ("--width", Some(v)) => {
+                    options.width = v.max(1);
+                    i += 1;
+                }
+                ("--height", Some(v)) => {
+                    options.height = v.max(1);
+                    i += 1;
+                }
+                ("--screenshot-format", _) if text.is_some() => {
+                    let format = text.unwrap().to_lowercase();
+                    if format == "png" || format == "tga" {
+                        options.screenshot_format = format;
+                    }
+                    else {
+                        println!("Unknown screenshot format {}", format);
+                    }
+                    i += 1;
+                }
+                ("--dump-frames", _) if text.is_some() => {
+                    options.dump_frames = text;
+                    i += 1;
+                }
+                ("--dump-fps", Some(v)) => {
+                    options.dump_fps = v.max(1);
+                    i += 1;
+                }
+                ("--record-path", _) if text.is_some() => {
+                    options.record_path = text;
+                    i += 1;
+                }
+                ("--record-rate", Some(v)) => {
+                    options.record_rate = v.max(1);
+                    i += 1;
+                }
+                ("--play-path", _) if text.is_some() => {
+                    options.play_path = text;
+                    i += 1;
+                }
+                ("--intermission", _) => options.intermission = true,
+                ("--benchmark", _) if text.is_some() => {
+                    options.benchmark = text;
+                    i += 1;
+                }
+                ("--viewpoints", _) if text.is_some() => {
+                    options.viewpoints = text;
+                    i += 1;
+                }
+                ("--viewpoint-frames", Some(v)) => {
+                    options.viewpoint_frames = v.max(1);
+                    i += 1;
+                }
+                ("--render-mode", _) if text.is_some() => {
+                    match RenderMode::parse(text.as_ref().unwrap()) {
+                        Some(render_mode) => options.render_mode = render_mode,
+                        None => println!("Unknown render mode {}", text.as_ref().unwrap()),
+                    }
+                    i += 1;
+                }
+                ("--player", _) if text.is_some() => {
+                    options.player = text;
+                    i += 1;
+                }
+                ("--player-weapon", _) if text.is_some() => {
+                    options.player_weapon = text.unwrap();
+                    i += 1;
+                }
+                ("--player-legs", _) | ("--player-torso", _) if text.is_some() => {
+                    match md3_player::animation_index(text.as_ref().unwrap()) {
+                        Some(animation) if args[i] == "--player-legs" => options.player_legs = animation,
+                        Some(animation) => options.player_torso = animation,
+                        None => println!("Unknown animation {}", text.as_ref().unwrap()),
+                    }
+                    i += 1;
+                }
+                ("--demo", _) if text.is_some() => {
+                    options.demo = text;
+                    i += 1;
+                }
+                _ => println!("Unknown option {}", args[i]),
+            }
+            i += 1;
+        }
+        options
+    }
+}
+
+fn create_bsp_pipeline(device: &wgpu::Device, label: &str, layout: &wgpu::PipelineLayout, vs_module: &wgpu::ShaderModule, fs_module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat, cull_mode: wgpu::CullMode, blend: Option<bsp_shader::BlendMode>) -> wgpu::RenderPipeline {
+
+    let (src_factor, dst_factor) = match blend {
+        None => (wgpu::BlendFactor::One, wgpu::BlendFactor::Zero),
+        Some(bsp_shader::BlendMode::Alpha) => (wgpu::BlendFactor::SrcAlpha, wgpu::BlendFactor::OneMinusSrcAlpha),
+        Some(bsp_shader::BlendMode::Additive) => (wgpu::BlendFactor::One, wgpu::BlendFactor::One),
+        Some(bsp_shader::BlendMode::Filter) => (wgpu::BlendFactor::DstColor, wgpu::BlendFactor::Zero),
+    };
+    let blend_descriptor = wgpu::BlendDescriptor {
+        src_factor,
+        dst_factor,
+        operation: wgpu::BlendOperation::Add,
+    };
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: vs_module,
+            entry_point: "main",
+        },
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: fs_module,
+            entry_point: "main",
+        }),
+        rasterization_state: Some(
+            wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+                clamp_depth: false,
+            }
+        ),
+        color_states: &[
+            wgpu::ColorStateDescriptor {
+                format,
+                color_blend: blend_descriptor.clone(),
+                alpha_blend: blend_descriptor,
+                write_mask: wgpu::ColorWrite::ALL,
+            },
+        ],
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList, //LineList TriangleList
+        depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+            format: texture::Texture::DEPTH_FORMAT,
+            //Blended surfaces test against the depth buffer but do not write to it
+            depth_write_enabled: blend.is_none(),
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilStateDescriptor::default(),
+        }),
+        vertex_state: wgpu::VertexStateDescriptor {
+            index_format: wgpu::IndexFormat::Uint32,
+            vertex_buffers: &[bsp::Vertex::desc()],
+        },
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+    })
+}
+
 struct State {
-    surface: wgpu::Surface,
+    //No surface or swap chain when running headless
+    surface: Option<wgpu::Surface>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     sc_desc: wgpu::SwapChainDescriptor,
-    swap_chain: wgpu::SwapChain,
+    swap_chain: Option<wgpu::SwapChain>,
     size: winit::dpi::PhysicalSize<u32>,
     render_pipeline: wgpu::RenderPipeline,
-    bsp_model_render_pipeline: wgpu::RenderPipeline,
+    alpha_test_render_pipeline: wgpu::RenderPipeline,
+    translucent_render_pipelines: HashMap<(bsp_shader::BlendMode, bool), wgpu::RenderPipeline>,
+    //Md3 surfaces by blend mode and two sidedness
+    md3_render_pipelines: HashMap<(Option<bsp_shader::BlendMode>, bool), wgpu::RenderPipeline>,
     camera: camera::Camera,
     projection: camera::Projection,
     camera_controller: camera::CameraController,
@@ -63,22 +454,58 @@ struct State {
     //obj_model: model::Model,
     depth_texture: texture::Texture,
     bsp: bsp::Bsp,
+    model_entities: bsp_item::ModelEntities,
+    movers: bsp_mover::Movers,
+    player: Option<md3_player::Player>,
+    demo: Option<demo::Demo>,
+    //Seconds skipped forward or back in the demo
+    demo_offset: f32,
+    //Model entities from here on belong to demo entities
+    demo_models: usize,
+    demo_players: HashMap<usize, md3_player::Player>,
+    visible_players: Vec<usize>,
+    start_time: Instant,
+    screenshot_format: String,
+    screenshot_requested: bool,
+    blit: screenshot::Blit,
+    dump_frames: Option<std::path::PathBuf>,
+    dump_fps: f32,
+    frame: u32,
+    camera_path: Option<camera_path::CameraPath>,
+    recorder: Option<camera_path::CameraRecorder>,
+    record_path: Option<std::path::PathBuf>,
+    viewpoints: Vec<camera_path::CameraKey>,
+    viewpoint_frames: u32,
+    benchmark: Option<benchmark::Benchmark>,
+    benchmark_path: Option<std::path::PathBuf>,
 }
 
 impl State {
 
-    async fn new(window: &Window) -> Self {
+    async fn new(window: &Window, options: &Options) -> Result<Self, String> {
 
         let size = window.inner_size();
-
         let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
         let surface = unsafe { instance.create_surface(window) };
+        Self::create(&instance, Some(surface), size, wgpu::TextureFormat::Bgra8UnormSrgb, options).await
+    }
+
+    //Draws into textures with render_image, works with software adapters like lavapipe
+    async fn new_headless(options: &Options) -> Result<Self, String> {
+
+        let size = winit::dpi::PhysicalSize::new(options.width, options.height);
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        Self::create(&instance, None, size, screenshot::FORMAT, options).await
+    }
+
+    async fn create(instance: &wgpu::Instance, surface: Option<wgpu::Surface>, size: winit::dpi::PhysicalSize<u32>, format: wgpu::TextureFormat, options: &Options) -> Result<Self, String> {
+
         let adapter = instance.request_adapter(
             &wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::Default,
-                compatible_surface: Some(&surface),
+                compatible_surface: surface.as_ref(),
             },
-        ).await.unwrap();
+        ).await.ok_or("Could not find a graphics adapter")?;
 
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
@@ -89,22 +516,30 @@ impl State {
             None,
         ).await.unwrap();
 
-        //Fifo or Immediate (vsync on and off)
+        //Fifo or Immediate (vsync on and off), benchmarks run without vsync
         let sc_desc = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
-            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            format,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: if options.benchmark.is_some() { wgpu::PresentMode::Immediate } else { wgpu::PresentMode::Fifo },
         };
-        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
+        let swap_chain = surface.as_ref().map(|surface| device.create_swap_chain(surface, &sc_desc));
 
-        let camera = camera::Camera::new();
+        let mut camera = camera::Camera::new();
         let projection = camera::Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(90.0), 0.1, 4000.0);
-        let camera_controller = camera::CameraController::new(3.0, 3.0);
+        let mut camera_controller = camera::CameraController::new(3.0, 3.0);
+        if let Some(position) = options.position {
+            camera.position = cgmath::Point3::new(position[0], position[1], position[2]);
+        }
+        if let Some(angles) = options.angles {
+            camera_controller.set_angles(angles[0], angles[1]);
+        }
+        camera_controller.update_camera(&mut camera);
 
         let mut uniforms = Uniforms::new();
         uniforms.update_view_proj(&camera, &projection);
+        uniforms.update_render_mode(options.render_mode);
 
         let uniform_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -118,7 +553,16 @@ impl State {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
                     ty: wgpu::BindingType::UniformBuffer {
                         dynamic: false,
                         min_binding_size: None,
@@ -129,18 +573,29 @@ impl State {
             label: Some("uniform_bind_group_layout"),
         });
 
-        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
+        let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
-                wgpu::BindGroupEntry {
+                wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
-                }
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Uint,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
             ],
-            label: Some("uniform_bind_group"),
+            label: Some("texture_bind_group_layout"),
         });
 
-        let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        let material_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
@@ -158,8 +613,17 @@ impl State {
                     ty: wgpu::BindingType::Sampler { comparison: false },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
-            label: Some("texture_bind_group_layout"),
+            label: Some("material_bind_group_layout"),
         });
 
         let lightmap_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -184,129 +648,142 @@ impl State {
             label: Some("texture_bind_group_layout"),
         });
 
-        let res_dir = std::path::Path::new(env!("OUT_DIR")).join("res");
         //let obj_model = model::Model::load(&device, &queue, &texture_bind_group_layout, res_dir.join("cube.obj"),).unwrap();
 
-        let vs_bsp_model_module = device.create_shader_module(wgpu::include_spirv!("bsp_model.vert.spv"));
-        let fs_bsp_model_module = device.create_shader_module(wgpu::include_spirv!("bsp_model.frag.spv"));
         let vs_module = device.create_shader_module(wgpu::include_spirv!("bsp.vert.spv"));
+        let vs_md3_module = device.create_shader_module(wgpu::include_spirv!("md3.vert.spv"));
+        let fs_md3_module = device.create_shader_module(wgpu::include_spirv!("md3.frag.spv"));
         let fs_module = device.create_shader_module(wgpu::include_spirv!("bsp.frag.spv"));
 
-        let mut bsp = bsp::Bsp::new(&device, &queue, &texture_bind_group_layout, &lightmap_bind_group_layout);
+        let mut fs = file_system(options);
+        //A demo brings its own map unless one is given
+        let demo = options.demo.as_ref().and_then(|path| demo::Demo::load(&fs, path));
+        let map = options.map.clone().or_else(|| demo.as_ref().and_then(|d| d.map_name()));
+        let mut bsp = bsp::Bsp::new(&device, &queue, &material_bind_group_layout, &lightmap_bind_group_layout, &mut fs, &options.texture_settings, map.as_deref(), &entity_edits(options))?;
+        let mut model_entities = bsp_item::ModelEntities::new(&device, &queue, &material_bind_group_layout, &fs, &bsp, &options.texture_settings);
+        let movers = bsp_mover::Movers::new(&bsp);
 
-        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
+        //Items in the demo replace the ones of the map, the recording player is the camera and is not drawn
+        let mut demo_models = model_entities.entities.len();
+        let mut demo_players = HashMap::new();
+        if let Some(demo) = demo.as_ref() {
+            model_entities.entities.retain(|e| e.placement == bsp_item::Placement::Static);
+            demo_models = model_entities.entities.len();
+            add_demo_models(&device, &queue, &material_bind_group_layout, &fs, &bsp, demo, &mut model_entities, &options.texture_settings);
+            for (client, model) in demo.players().into_iter().filter(|(client, _)| *client as i32 != demo.client_num) {
+                if let Some(player) = md3_player::Player::load(&device, &queue, &material_bind_group_layout, &fs, &model, None, &bsp.shaders, &options.texture_settings) {
+                    demo_players.insert(client, player);
+                }
+            }
+        }
 
-        let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: Some("Render Pipeline Layout"),
-            bind_group_layouts: &[&texture_bind_group_layout, &lightmap_bind_group_layout, &uniform_bind_group_layout],
-            push_constant_ranges: &[],
+        //The player stands on the first spawn point with the camera behind it
+        let weapon = Some(options.player_weapon.as_str()).filter(|w| *w != "none");
+        let player = options.player.as_ref().and_then(|name| md3_player::Player::load(&device, &queue, &material_bind_group_layout, &fs, name, weapon, &bsp.shaders, &options.texture_settings)).map(|mut player| {
+            let spawn = bsp.entities.iter().find(|e| e.class_name() == "info_player_deathmatch" || e.class_name() == "info_player_start");
+            player.origin = spawn.and_then(|e| e.origin()).unwrap_or([0.0; 3]);
+            player.yaw = spawn.and_then(|e| e.angles()).map_or(0.0, |a| a[1]);
+            player.light = bsp.light_grid(player.origin);
+            player.legs_animation = options.player_legs;
+            player.torso_animation = options.player_torso;
+            if options.position.is_none() {
+                let (sin, cos) = player.yaw.to_radians().sin_cos();
+                camera.position = cgmath::Point3::new(player.origin[0] - cos * 100.0, player.origin[1] - sin * 100.0, player.origin[2] + 16.0);
+                camera_controller.set_angles(10.0, player.yaw);
+                camera_controller.update_camera(&mut camera);
+            }
+            player
         });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex_stage: wgpu::ProgrammableStageDescriptor {
-                module: &vs_module,
-                entry_point: "main",
-            },
-            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                module: &fs_module,
-                entry_point: "main",
-            }),
-            rasterization_state: Some(
-                wgpu::RasterizationStateDescriptor {
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: wgpu::CullMode::Front,
-                    depth_bias: 0,
-                    depth_bias_slope_scale: 0.0,
-                    depth_bias_clamp: 0.0,
-                    clamp_depth: false,
-                }
-            ),
-            color_states: &[
-                wgpu::ColorStateDescriptor {
-                    format: sc_desc.format,
-                    color_blend: wgpu::BlendDescriptor::REPLACE, //color_blend: wgpu::BlendDescriptor::REPLACE, //alpha_blend: wgpu::BlendDescriptor::REPLACE,
-                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
-                    write_mask: wgpu::ColorWrite::ALL,
+        //Only the bind group needs the fog buffer, the volumes don't change after loading
+        let fog_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Fog Buffer"),
+                contents: bytemuck::cast_slice(&[bsp_fog::FogUniforms::new(&bsp.fogs)]),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(fog_buffer.slice(..)),
+                }
             ],
-            primitive_topology: wgpu::PrimitiveTopology::TriangleList, //LineList TriangleList
-            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
-                format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilStateDescriptor::default(),
-            }),
-            vertex_state: wgpu::VertexStateDescriptor {
-                index_format: wgpu::IndexFormat::Uint32,
-                vertex_buffers: &[bsp::Vertex::desc()],
-            },
-            sample_count: 1,
-            sample_mask: !0,
-            alpha_to_coverage_enabled: false,
+            label: Some("uniform_bind_group"),
         });
 
-        let bsp_model_render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
+
+        let mut camera_path = None;
+        if let Some(path) = options.play_path.as_ref() {
+            match camera_path::CameraPath::load(std::path::Path::new(path)) {
+                Ok(loaded) => camera_path = Some(loaded),
+                Err(e) => println!("Could not load camera path {}: {}", path, e),
+            }
+        }
+        else if options.intermission || (options.benchmark.is_some() && options.viewpoints.is_none()) {
+            camera_path = camera_path::CameraPath::from_intermission(&bsp.entities, 200.0);
+            if camera_path.is_none() {
+                println!("No info_player_intermission in the map");
+                if options.benchmark.is_some() && demo.is_none() {
+                    println!("Benchmarking {} frames from the current view", BENCHMARK_FRAMES);
+                }
+            }
+        }
+        let mut viewpoints = Vec::new();
+        if let Some(path) = options.viewpoints.as_ref() {
+            match std::fs::read_to_string(path) {
+                Ok(text) => viewpoints = camera_path::parse_viewpoints(&text),
+                Err(e) => println!("Could not load viewpoints {}: {}", path, e),
+            }
+        }
+        let recorder = options.record_path.as_ref().map(|_| camera_path::CameraRecorder::new(options.record_rate as f32));
+
+        let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Render Pipeline Layout"),
-            bind_group_layouts: &[&texture_bind_group_layout, &uniform_bind_group_layout],
+            bind_group_layouts: &[&material_bind_group_layout, &lightmap_bind_group_layout, &uniform_bind_group_layout],
             push_constant_ranges: &[],
         });
 
-        let bsp_model_render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Bsp model Pipeline"),
-            layout: Some(&bsp_model_render_pipeline_layout),
-            vertex_stage: wgpu::ProgrammableStageDescriptor {
-                module: &vs_bsp_model_module,
-                entry_point: "main",
-            },
-            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                module: &fs_bsp_model_module,
-                entry_point: "main",
-            }),
-            rasterization_state: Some(
-                wgpu::RasterizationStateDescriptor {
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: wgpu::CullMode::Front,
-                    depth_bias: 0,
-                    depth_bias_slope_scale: 0.0,
-                    depth_bias_clamp: 0.0,
-                    clamp_depth: false,
-                }
-            ),
-            color_states: &[
-                wgpu::ColorStateDescriptor {
-                    format: sc_desc.format,
-                    color_blend: wgpu::BlendDescriptor {
-                        src_factor: wgpu::BlendFactor::SrcAlpha,
-                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                        operation: wgpu::BlendOperation::Add
-                    },
-                    alpha_blend: wgpu::BlendDescriptor {
-                        src_factor: wgpu::BlendFactor::SrcAlpha,
-                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                        operation: wgpu::BlendOperation::Add
-                    },
-                    write_mask: wgpu::ColorWrite::ALL,
-                },
-            ],
-            primitive_topology: wgpu::PrimitiveTopology::TriangleList, //LineList TriangleList
-            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
-                format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilStateDescriptor::default(),
-            }),
-            vertex_state: wgpu::VertexStateDescriptor {
-                index_format: wgpu::IndexFormat::Uint32,
-                vertex_buffers: &[bsp::Vertex::desc()],
-            },
-            sample_count: 1,
-            sample_mask: !0,
-            alpha_to_coverage_enabled: false,
+        let render_pipeline = create_bsp_pipeline(&device, "Render Pipeline", &render_pipeline_layout, &vs_module, &fs_module, sc_desc.format, wgpu::CullMode::Front, None);
+
+        //Alpha tested surfaces are mostly grates and foliage, they are drawn from both sides
+        let alpha_test_render_pipeline = create_bsp_pipeline(&device, "Alpha Test Pipeline", &render_pipeline_layout, &vs_module, &fs_module, sc_desc.format, wgpu::CullMode::None, None);
+
+        let mut translucent_render_pipelines = HashMap::new();
+        for blend in [bsp_shader::BlendMode::Alpha, bsp_shader::BlendMode::Additive, bsp_shader::BlendMode::Filter].iter() {
+            for two_sided in [false, true].iter() {
+                let cull_mode = if *two_sided { wgpu::CullMode::None } else { wgpu::CullMode::Front };
+                let pipeline = create_bsp_pipeline(&device, "Translucent Pipeline", &render_pipeline_layout, &vs_module, &fs_module, sc_desc.format, cull_mode, Some(*blend));
+                translucent_render_pipelines.insert((*blend, *two_sided), pipeline);
+            }
+        }
+
+        let md3_render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Md3 Pipeline Layout"),
+            bind_group_layouts: &[&material_bind_group_layout, &uniform_bind_group_layout],
+            push_constant_ranges: &[],
         });
 
-        Self {
+        let mut md3_render_pipelines = HashMap::new();
+        for blend in [None, Some(bsp_shader::BlendMode::Alpha), Some(bsp_shader::BlendMode::Additive), Some(bsp_shader::BlendMode::Filter)].iter() {
+            for two_sided in [false, true].iter() {
+                let cull_mode = if *two_sided { wgpu::CullMode::None } else { wgpu::CullMode::Front };
+                let pipeline = create_bsp_pipeline(&device, "Md3 Pipeline", &md3_render_pipeline_layout, &vs_md3_module, &fs_md3_module, sc_desc.format, cull_mode, *blend);
+                md3_render_pipelines.insert((*blend, *two_sided), pipeline);
+            }
+        }
+
+        let blit = screenshot::Blit::new(&device, sc_desc.format);
+
+        Ok(Self {
             surface,
             device,
             queue,
@@ -314,7 +791,9 @@ impl State {
             swap_chain,
             size,
             render_pipeline,
-            bsp_model_render_pipeline,
+            alpha_test_render_pipeline,
+            translucent_render_pipelines,
+            md3_render_pipelines,
             camera,
             projection,
             camera_controller,
@@ -323,7 +802,29 @@ impl State {
             uniform_bind_group,
             depth_texture,
             bsp,
-        }
+            model_entities,
+            movers,
+            player,
+            demo,
+            demo_offset: 0.0,
+            demo_models,
+            demo_players,
+            visible_players: Vec::new(),
+            start_time: Instant::now(),
+            screenshot_format: options.screenshot_format.clone(),
+            screenshot_requested: false,
+            blit,
+            dump_frames: options.dump_frames.as_ref().map(std::path::PathBuf::from),
+            dump_fps: options.dump_fps as f32,
+            frame: 0,
+            camera_path,
+            recorder,
+            record_path: options.record_path.as_ref().map(std::path::PathBuf::from),
+            viewpoints,
+            viewpoint_frames: options.viewpoint_frames,
+            benchmark: options.benchmark.as_ref().map(|_| benchmark::Benchmark::new()),
+            benchmark_path: options.benchmark.as_ref().map(std::path::PathBuf::from),
+        })
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -333,7 +834,9 @@ impl State {
         self.sc_desc.height = new_size.height;
         self.projection.resize(new_size.width, new_size.height);
         self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.sc_desc, "depth_texture");
-        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        if let Some(surface) = self.surface.as_ref() {
+            self.swap_chain = Some(self.device.create_swap_chain(surface, &self.sc_desc));
+        }
     }
 
     fn input(&mut self, event: &WindowEvent, window: &Window) -> bool {
@@ -348,6 +851,23 @@ impl State {
                     },
                 ..
             } => {
+                if *keycode == VirtualKeyCode::F12 && *state == ElementState::Pressed {
+                    self.screenshot_requested = true;
+                }
+                //Opens or closes every area portal
+                if *keycode == VirtualKeyCode::P && *state == ElementState::Pressed {
+                    let open = !self.bsp.area_portals.iter().all(|p| p.open);
+                    for i in 0..self.bsp.area_portals.len() {
+                        self.bsp.set_area_portal(i, open);
+                    }
+                    println!("Area portals {}", if open { "open" } else { "closed" });
+                }
+                //Skips through the demo
+                if (*keycode == VirtualKeyCode::Left || *keycode == VirtualKeyCode::Right) && *state == ElementState::Pressed && self.demo.is_some() {
+                    let skip = if *keycode == VirtualKeyCode::Left { -DEMO_SKIP } else { DEMO_SKIP };
+                    self.demo_offset = (self.demo_offset + skip).max(-self.time());
+                    println!("Demo at {:.1} s", self.time() + self.demo_offset);
+                }
                 self.camera_controller.process_keyboard(*keycode, *state);
                 true
             }
@@ -375,27 +895,226 @@ impl State {
     fn update(&mut self) {
 
         //println!("{:?}", self.camera.position);
+        let update_start = Instant::now();
         let start = cgmath::Vector3::new(self.camera.position[0], self.camera.position[1], self.camera.position[2]);
+        let time = self.time();
+        let mut key = self.camera_path.as_ref().and_then(|path| path.sample(time));
+        if !self.viewpoints.is_empty() {
+            let i = (self.frame / self.viewpoint_frames) as usize;
+            key = Some(self.viewpoints[i.min(self.viewpoints.len() - 1)]);
+        }
+        if let Some(demo) = self.demo.as_ref().filter(|_| key.is_none()) {
+            key = demo.camera(time + self.demo_offset);
+        }
+        if let Some(key) = key {
+            self.camera.position = cgmath::Point3::new(key.position[0], key.position[1], key.position[2]);
+            self.camera_controller.set_angles(key.angles[0], key.angles[1]);
+        }
         self.camera_controller.update_camera(&mut self.camera);
-        self.uniforms.update_view_proj(&self.camera, &self.projection);
-        self.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(time, [self.camera.position.x, self.camera.position.y, self.camera.position.z], self.camera_controller.angles());
+        }
+        self.update_uniforms(time);
+        //Movers in a demo are placed by its entities instead of the camera
+        let changed = match self.demo.is_some() {
+            true => self.update_demo(time + self.demo_offset),
+            false => self.movers.update(time, [self.camera.position.x, self.camera.position.y, self.camera.position.z]),
+        };
+        for (entity, open) in changed {
+            self.bsp.set_door_open(entity, open);
+        }
+        for mover in self.movers.movers.iter() {
+            self.bsp.set_model_transform(&self.queue, mover.model, mover.transform());
+        }
+        self.model_entities.update(&self.queue, time);
+        if let Some(player) = self.player.as_ref() {
+            player.update(&self.queue, time, time);
+        }
 
         self.bsp.trace_ray(start, cgmath::Vector3::new(self.camera.position[0], self.camera.position[1], self.camera.position[2]));
+
+        if let Some(benchmark) = self.benchmark.as_mut() {
+            benchmark.current.update = benchmark::millis(update_start.elapsed());
+        }
+    }
+
+    //Moves the models of the demo entities in the snapshot at the time and hides the rest
+    fn update_demo(&mut self, time: f32) -> Vec<(usize, bool)> {
+
+        let demo = match self.demo.as_ref() {
+            Some(demo) => demo,
+            None => return Vec::new(),
+        };
+        let poses = demo.entities(time);
+        let mut changed: Vec<(usize, bool)> = Vec::new();
+        let movers = &mut self.movers;
+        for pose in poses.iter().filter(|p| p.entity.e_type == demo::ET_MOVER) {
+            if let Some(change) = pose.entity.submodel().and_then(|model| movers.place(model, pose.origin, pose.angles)) {
+                changed.push(change);
+            }
+        }
+        let mut shown: HashMap<(usize, usize, bsp_item::Placement), ([f32; 3], [f32; 3])> = HashMap::new();
+        for pose in poses.iter() {
+            for (path, placement) in demo.entity_models(&pose.entity) {
+                if let Some(model) = self.model_entities.model_index(&path) {
+                    let angles = if placement == bsp_item::Placement::Static { pose.angles } else { [0.0; 3] };
+                    shown.insert((pose.entity.number, model, placement), (pose.origin, angles));
+                }
+            }
+        }
+        for e in self.model_entities.entities[self.demo_models..].iter_mut() {
+            e.visible = false;
+            if let Some((origin, angles)) = shown.get(&(e.entity, e.model, e.placement)) {
+                e.visible = true;
+                e.origin = *origin;
+                e.angles = *angles;
+                e.light = self.bsp.light_grid(e.origin);
+            }
+        }
+
+        self.visible_players.clear();
+        for pose in poses.iter().filter(|p| p.entity.e_type == demo::ET_PLAYER) {
+            if let Some(player) = self.demo_players.get_mut(&(pose.entity.client_num as usize)) {
+                let legs = (pose.entity.legs_anim & !demo::ANIM_TOGGLEBIT) as usize;
+                let torso = (pose.entity.torso_anim & !demo::ANIM_TOGGLEBIT) as usize;
+                player.legs_animation = if legs < md3_player::ANIMATION_NAMES.len() { legs } else { md3_player::LEGS_IDLE };
+                player.torso_animation = if torso < md3_player::ANIMATION_NAMES.len() { torso } else { md3_player::TORSO_STAND };
+                player.origin = pose.origin;
+                player.yaw = pose.angles[1];
+                player.light = self.bsp.light_grid(pose.origin);
+                let (legs_time, torso_time) = demo.animation_times(pose.entity.number, time);
+                player.update(&self.queue, legs_time, torso_time);
+                self.visible_players.push(pose.entity.client_num as usize);
+            }
+        }
+        changed
+    }
+
+    //Dumped and benchmarked frames are evenly spaced so every run draws the same frames
+    fn time(&self) -> f32 {
+        if self.dump_frames.is_some() || self.benchmark.is_some() {
+            self.frame as f32 / self.dump_fps
+        }
+        else {
+            self.start_time.elapsed().as_secs_f32()
+        }
+    }
+
+    fn playback_finished(&self) -> bool {
+        if !self.viewpoints.is_empty() {
+            return self.frame >= self.viewpoints.len() as u32 * self.viewpoint_frames;
+        }
+        match (self.camera_path.as_ref(), self.demo.as_ref()) {
+            (Some(path), _) => self.time() > path.duration(),
+            (None, Some(demo)) => self.time() + self.demo_offset > demo.duration(),
+            (None, None) => self.benchmark.is_some() && self.frame >= BENCHMARK_FRAMES,
+        }
+    }
+
+    //Saves the recorded camera path and prints how the playback ran
+    fn finish(&mut self) {
+
+        if let (Some(recorder), Some(path)) = (self.recorder.as_ref(), self.record_path.as_ref()) {
+            match recorder.path.save(path) {
+                Ok(_) => println!("Saved camera path {} with {} keys", path.display(), recorder.path.keys.len()),
+                Err(e) => println!("Could not save camera path {}: {}", path.display(), e),
+            }
+        }
+        if let (Some(benchmark), Some(path)) = (self.benchmark.as_ref(), self.benchmark_path.as_ref()) {
+            benchmark.save(path, &self.bsp.name);
+        }
+        if self.camera_path.is_some() {
+            let seconds = self.start_time.elapsed().as_secs_f32();
+            println!("Camera path played {} frames in {:.2} s, average fps {:.1}", self.frame, seconds, self.frame as f32 / seconds);
+        }
+    }
+
+    fn update_uniforms(&mut self, time: f32) {
+
+        self.uniforms.update_view_proj(&self.camera, &self.projection);
+        self.uniforms.update_time(time);
+        self.bsp.update_materials(&self.queue, time);
+        self.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
     }
 
     fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
 
-        let frame = self.swap_chain.get_current_frame()?.output;
+        let frame = self.swap_chain.as_mut().unwrap().get_current_frame()?.output;
+        if self.screenshot_requested || self.dump_frames.is_some() {
+            //Drawn once into a texture that is read back and copied to the window
+            let target = self.render_target();
+            self.blit.draw(&self.device, &self.queue, &target.create_view(&wgpu::TextureViewDescriptor::default()), &frame.view);
+            let image = screenshot::read_texture(&self.device, &self.queue, &target, self.sc_desc.width, self.sc_desc.height, self.sc_desc.format);
+            if self.screenshot_requested {
+                self.screenshot_requested = false;
+                screenshot::save(&image, std::path::Path::new("screenshots"), &screenshot::timestamped_name(&self.screenshot_format));
+            }
+            if let Some(dir) = self.dump_frames.as_ref() {
+                screenshot::save(&image, dir, &screenshot::frame_name(self.frame, &self.screenshot_format));
+            }
+        }
+        else {
+            self.draw(&frame.view);
+        }
+        if let Some(benchmark) = self.benchmark.as_mut() {
+            benchmark.end_frame();
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    //Renders the current view into a new texture
+    fn render_target(&mut self) -> wgpu::Texture {
+
+        let target = screenshot::create_target(&self.device, self.sc_desc.width, self.sc_desc.height, self.sc_desc.format);
+        self.draw(&target.create_view(&wgpu::TextureViewDescriptor::default()));
+        target
+    }
+
+    //Renders the current view and reads it back
+    fn render_image(&mut self) -> image::RgbaImage {
+
+        let target = self.render_target();
+        screenshot::read_texture(&self.device, &self.queue, &target, self.sc_desc.width, self.sc_desc.height, self.sc_desc.format)
+    }
+
+    //Blended model surfaces go after the blended world faces without sorting
+    fn draw_models<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, blended: bool) {
+
+        let mut models = self.model_entities.entities.iter().filter(|e| e.visible).map(|e| (&self.model_entities.models[e.model], &e.instance)).collect::<Vec<(&md3::Md3Model, &md3::Md3Instance)>>();
+        let players = self.player.iter().chain(self.visible_players.iter().filter_map(|client| self.demo_players.get(client)));
+        for player in players {
+            models.extend(player.parts().iter().map(|p| (&p.model, &p.instance)));
+        }
+        for (model, instance) in models {
+            for mesh in instance.meshes.iter() {
+                let state = model.surface_states[mesh.material];
+                if state.blend.is_some() == blended {
+                    render_pass.set_pipeline(&self.md3_render_pipelines[&(state.blend, state.two_sided)]);
+                    render_pass.draw_md3_mesh(mesh, &model.materials[mesh.material], &self.uniform_bind_group);
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self, view: &wgpu::TextureView) {
+
+        let cull_start = Instant::now();
+        let frustum = frustum::Frustum::from_matrix(self.projection.calc_matrix() * self.camera.view);
+        let visible_faces = self.bsp.visible_faces(&frustum, [self.camera.position.x, self.camera.position.y, self.camera.position.z]);
+        self.bsp.update_draw_list(&self.queue, &visible_faces);
+        let translucent_order = self.bsp.sort_translucent_faces(cgmath::Vector3::new(self.camera.position.x, self.camera.position.y, self.camera.position.z), &visible_faces);
+        let cull_time = cull_start.elapsed();
+
+        let encode_start = Instant::now();
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
-        //let mut now = Instant::now();
-        let mut offset = 0usize;
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[
                     wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: &frame.view,
+                        attachment: view,
                         resolve_target: None,
                         ops: wgpu::Operations {
                             load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -417,45 +1136,209 @@ impl State {
                     stencil_ops: None,
                 }),
             });
-            //Draw bsp
-            let model_index = (self.bsp.indices_per_texture.len() - 1);
-            render_pass.set_pipeline(&self.render_pipeline);
+            //Draw the visible bsp faces, opaque surfaces first and then the alpha tested ones
+            render_pass.set_vertex_buffer(0, self.bsp.vertex_buffer.slice(..));
+            render_pass.set_index_buffer(self.bsp.draw_index_buffer.slice(..));
+            render_pass.set_bind_group(2, &self.uniform_bind_group, &[]);
+            for alpha_tested in [false, true].iter() {
+                if *alpha_tested {
+                    render_pass.set_pipeline(&self.alpha_test_render_pipeline);
+                }
+                else {
+                    render_pass.set_pipeline(&self.render_pipeline);
+                }
+                for batch in self.bsp.draw_batches.iter() {
+                    if self.bsp.surface_states[batch.texture].is_alpha_tested() == *alpha_tested {
+                        render_pass.set_bind_group(0, &self.bsp.material(batch.texture).bind_group, &[]);
+                        render_pass.set_bind_group(1, &self.bsp.materials_light[batch.lightmap].bind_group, &[]);
+                        render_pass.draw_indexed(batch.first_index..(batch.first_index + batch.num_indices), 0, 0..1);
+                    }
+                }
+            }
+
+            //Opaque model surfaces
+            self.draw_models(&mut render_pass, false);
+
+            //Blended surfaces, back to front, the models changed the buffers and the uniform group
             render_pass.set_vertex_buffer(0, self.bsp.vertex_buffer.slice(..));
             render_pass.set_index_buffer(self.bsp.index_buffer.slice(..));
             render_pass.set_bind_group(2, &self.uniform_bind_group, &[]);
-            for j in 0..(self.bsp.indices_per_texture.len() - 1) {
-                for i in 0..self.bsp.indices_per_texture[j].len() {
-                    render_pass.set_bind_group(0, &self.bsp.materials[i].bind_group, &[]);
-                    render_pass.set_bind_group(1, &self.bsp.materials_light[j].bind_group, &[]);
-                    render_pass.draw_indexed((offset as u32)..((offset + self.bsp.indices_per_texture[j][i].len()) as u32), 0, 0..1);
-                    offset += self.bsp.indices_per_texture[j][i].len();
+            for face_index in translucent_order.iter() {
+                let face = &self.bsp.translucent_faces[*face_index];
+                let state = self.bsp.surface_states[face.texture];
+                if let Some(blend) = state.blend {
+                    render_pass.set_pipeline(&self.translucent_render_pipelines[&(blend, state.two_sided)]);
+                    render_pass.set_bind_group(0, &self.bsp.material(face.texture).bind_group, &[]);
+                    render_pass.set_bind_group(1, &self.bsp.materials_light[face.lightmap].bind_group, &[]);
+                    render_pass.draw_indexed(face.first_index..(face.first_index + face.num_indices), 0, 0..1);
                 }
             }
+            self.draw_models(&mut render_pass, true);
+        }
+        let command_buffer = encoder.finish();
+        let encode_time = encode_start.elapsed();
 
-            //Draw models
-            /*render_pass.set_pipeline(&self.bsp_model_render_pipeline);
-            render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-            for i in 0..self.bsp.indices_per_texture[model_index].len() {
-                if self.bsp.indices_per_texture[model_index][i].len() != 0 {
-                    render_pass.set_bind_group(0, &self.bsp.materials[i].bind_group, &[]);
-                    render_pass.draw_indexed((offset as u32)..((offset + self.bsp.indices_per_texture[model_index][i].len()) as u32), 0, 0..1);
-                    offset += self.bsp.indices_per_texture[model_index][i].len();
+        let gpu_start = Instant::now();
+        self.queue.submit(std::iter::once(command_buffer));
+        if let Some(benchmark) = self.benchmark.as_mut() {
+            self.device.poll(wgpu::Maintain::Wait);
+            benchmark.current.gpu = benchmark::millis(gpu_start.elapsed());
+            benchmark.current.cull = benchmark::millis(cull_time);
+            benchmark.current.encode = benchmark::millis(encode_time);
+        }
+    }
+}
+
+//res next to the executable, so it runs from any directory, or else res in the working directory
+fn default_res_dir() -> String {
+
+    let exe_res = std::env::current_exe().ok().and_then(|exe| exe.parent().map(|dir| dir.join("res")));
+    match exe_res {
+        Some(dir) if dir.is_dir() => dir.display().to_string(),
+        _ => "res".to_string(),
+    }
+}
+
+//res holds debug.jpg, the game data goes on top of it
+fn file_system(options: &Options) -> vfs::FileSystem {
+
+    let res_dir = std::path::Path::new(&options.res_dir);
+    let mut fs = vfs::FileSystem::new();
+    fs.mount_dir(res_dir);
+    match options.game_dir.as_ref() {
+        Some(dir) => fs.mount_game_dir(std::path::Path::new(dir)),
+        None => fs.mount_game_dir(&res_dir.join("baseq3")),
+    }
+    for dir in options.mounts.iter() {
+        fs.mount_dir(std::path::Path::new(dir));
+    }
+    fs
+}
+
+fn entity_edits(options: &Options) -> Vec<bsp_entity::EntityEdit> {
+
+    match options.entity_edits.as_ref() {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => bsp_entity::parse_edits(&text),
+            Err(e) => {
+                println!("Could not read entity edits {}: {}", path, e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    }
+}
+
+//The map with its .ent override and entity edits applied
+fn export_map(options: &Options) {
+
+    let map = match options.map.as_ref() {
+        Some(map) => map,
+        None => {
+            println!("--write-bsp and --write-ent need a --map");
+            return;
+        }
+    };
+    let data = match bsp::Bsp::load_map(&mut file_system(options), map, &entity_edits(options)) {
+        Ok((data, _)) => data,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let saved = |path: &String, result: std::io::Result<()>| match result {
+        Ok(_) => println!("Saved {}", path),
+        Err(e) => println!("Could not save {}: {}", path, e),
+    };
+    if let Some(path) = options.write_bsp.as_ref() {
+        saved(path, data.save(std::path::Path::new(path)));
+    }
+    if let Some(path) = options.write_ent.as_ref() {
+        saved(path, std::fs::write(path, data.entity_text().chars().map(|c| c as u8).collect::<Vec<u8>>()));
+    }
+}
+
+fn export(options: &Options, command: &str, map: &str) -> std::io::Result<()> {
+
+    let out_dir = std::path::Path::new(&options.output);
+    if command == "export-gltf" {
+        return bsp_gltf::export(&mut file_system(options), map, &entity_edits(options), out_dir);
+    }
+
+    let (data, name) = bsp::Bsp::load_map(&mut file_system(options), map, &entity_edits(options)).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let hulls = bsp_hull::convex_hulls(&data).into_iter().filter(|h| h.contents & bsp_hull::CONTENTS_SOLID != 0).collect::<Vec<bsp_hull::ConvexHull>>();
+    std::fs::create_dir_all(out_dir)?;
+    let path = out_dir.join(format!("{}.obj", name));
+    std::fs::write(&path, bsp_hull::to_obj(&hulls))?;
+    println!("Saved {} with {} of {} brushes", path.display(), hulls.len(), data.brushes.len());
+    Ok(())
+}
+
+//Every model an entity number shows during the demo, hidden until the entity is in the snapshot
+fn add_demo_models(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, fs: &vfs::FileSystem, bsp: &bsp::Bsp, demo: &demo::Demo, model_entities: &mut bsp_item::ModelEntities, texture_settings: &texture::TextureSettings) {
+
+    let mut added: HashSet<(usize, String)> = HashSet::new();
+    let mut missing: HashSet<String> = HashSet::new();
+    for e in demo.frames.iter().flat_map(|f| f.entities.iter()) {
+        for (path, placement) in demo.entity_models(e) {
+            if missing.contains(&path) || !added.insert((e.number, path.clone())) {
+                continue;
+            }
+            match model_entities.add(device, queue, layout, fs, &bsp.shaders, texture_settings, e.number, &path, placement, path.starts_with("models/weapons2/")) {
+                Some(model_entity) => model_entity.visible = false,
+                None => {
+                    println!("Error cant find model {}", path);
+                    missing.insert(path);
                 }
-            }*/
+            }
         }
-        //println!("Frame time {}", (now.elapsed().as_nanos() as f32) / 1000000.0);
-        self.queue.submit(std::iter::once(encoder.finish()));
-        Ok(())
     }
+    println!("{} demo entity models", added.len());
+}
+
+//One frame at time 0 from the camera in the options, for regression tests against golden images
+fn screenshot(options: &Options) -> Result<image::RgbaImage, String> {
+
+    let mut state = block_on(State::new_headless(options))?;
+    state.update_uniforms(0.0);
+    Ok(state.render_image())
 }
 
 fn main() {
     env_logger::init();
+    let options = Options::parse();
+    if let Some(command) = options.export.as_ref() {
+        let map = options.map.as_ref().unwrap();
+        if let Err(e) = export(&options, command, map) {
+            println!("Could not export {}: {}", map, e);
+        }
+        return;
+    }
+    if options.write_bsp.is_some() || options.write_ent.is_some() {
+        export_map(&options);
+        return;
+    }
+    if let Some(path) = options.screenshot.as_ref() {
+        match screenshot(&options) {
+            Ok(image) => match image.save(path) {
+                Ok(_) => println!("Saved screenshot {}", path),
+                Err(e) => println!("Could not save screenshot {}: {}", path, e),
+            },
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
     window.set_cursor_grab(true);
     window.set_cursor_visible(false);
-    let mut state = block_on(State::new(&window));
+    let mut state = match block_on(State::new(&window, &options)) {
+        Ok(state) => state,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
     let mut fps: i32 = 0;
     let mut run_time = Instant::now();
 
@@ -501,6 +1384,9 @@ fn main() {
                 }
             }
             Event::MainEventsCleared => {
+                if state.playback_finished() {
+                    *control_flow = ControlFlow::Exit;
+                }
                 fps += 1;
                 if run_time.elapsed().as_millis() >= 1000 {
                     println!("fps {}", fps);
@@ -509,7 +1395,26 @@ fn main() {
                 }
                 window.request_redraw();
             }
+            Event::LoopDestroyed => state.finish(),
             _ => {}
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Needs game data and a graphics adapter, run with CROSSING_TEST_MAP=<map> cargo test -- --ignored
+    //to render the map from the game data in res/baseq3
+    #[test]
+    #[ignore]
+    fn headless_screenshot() {
+        let map = std::env::var("CROSSING_TEST_MAP").expect("CROSSING_TEST_MAP is not set");
+        let options = Options { map: Some(map), width: 64, height: 48, ..Options::default() };
+        let image = screenshot(&options).unwrap();
+        assert_eq!(image.dimensions(), (64, 48));
+        let first = *image.get_pixel(0, 0);
+        assert!(image.pixels().any(|p| *p != first));
+    }
+}
@@ -0,0 +1,65 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "fog.glsl"
+
+#define MAX_DEFORMS 3
+
+layout(location = 0) in vec4 v_colour;
+layout(location = 1) in vec2 v_tex_coords;
+layout(location = 2) in vec3 v_position;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_diffuse;
+layout(set = 0, binding = 1) uniform sampler s_diffuse;
+
+layout(set = 0, binding = 2)
+uniform MaterialUniforms {
+    vec4 u_alpha_func;
+    vec4 u_tc_s;
+    vec4 u_tc_t;
+    vec4 u_turb;
+    vec4 u_colour;
+    vec4 u_deforms[MAX_DEFORMS * 3];
+};
+
+layout(set = 1, binding = 0)
+uniform Uniforms {
+    mat4 u_view_proj;
+    mat4 model;
+    vec4 u_view_position;
+    vec4 u_time;
+    vec4 u_render_mode;
+};
+
+layout(set = 1, binding = 1)
+uniform Fogs {
+    Fog fogs[MAX_FOGS];
+    ivec4 num_fogs;
+};
+
+void main() {
+    vec4 diffuse = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+
+    //alphaFunc GT0, LT128 and GE128
+    if ((u_alpha_func.x == 1.0 && diffuse.a <= 0.0) || (u_alpha_func.x == 2.0 && diffuse.a >= 0.5) || (u_alpha_func.x == 3.0 && diffuse.a < 0.5)) {
+        discard;
+    }
+
+    //Render modes, 1 is lighting only and 2 is textures only
+    if (u_render_mode.x == 1.0) {
+        f_color = vec4(v_colour.rgb, 1.0);
+    }
+    else if (u_render_mode.x == 2.0) {
+        f_color = diffuse;
+    }
+    else {
+        f_color = vec4(diffuse.rgb * v_colour.rgb, diffuse.a);
+    }
+    f_color.rgb *= u_colour.rgb;
+
+    for (int i = 0; i < num_fogs.x; i++) {
+        float fog = clamp(fog_distance(fogs[i], u_view_position.xyz, v_position) / fogs[i].colour.w, 0.0, 1.0);
+        f_color.rgb = mix(f_color.rgb, fogs[i].colour.rgb, fog);
+    }
+}
\ No newline at end of file
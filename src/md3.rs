@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use cgmath::InnerSpace;
+use wgpu::util::DeviceExt;
+
+use crate::bsp;
+use crate::bsp_shader;
+use crate::texture;
+use crate::vfs;
+
+//Quake 3 models, frames of compressed vertices with tags to attach other models to
+//https://icculus.org/homepages/phaethon/q3a/formats/md3format.html
+
+const IDENT: &[u8] = b"IDP3";
+const VERSION: i32 = 15;
+const HEADER_SIZE: usize = 108;
+const FRAME_SIZE: usize = 56;
+const TAG_SIZE: usize = 112;
+const SURFACE_HEADER_SIZE: usize = 108;
+const SHADER_SIZE: usize = 68;
+//Vertex positions are stored in 1/64 units
+const XYZ_SCALE: f32 = 1.0 / 64.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub mins: [f32; 3],
+    pub maxs: [f32; 3],
+    pub origin: [f32; 3],
+    pub radius: f32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub origin: [f32; 3],
+    //Forward, left and up
+    pub axis: [[f32; 3]; 3],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Surface {
+    pub name: String,
+    pub shaders: Vec<String>,
+    pub triangles: Vec<[u32; 3]>,
+    pub tex_coords: Vec<[f32; 2]>,
+    //Positions and normals of every vertex for each frame
+    pub positions: Vec<Vec<[f32; 3]>>,
+    pub normals: Vec<Vec<[f32; 3]>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Md3 {
+    pub name: String,
+    pub frames: Vec<Frame>,
+    //The tags of each frame
+    pub tags: Vec<Vec<Tag>>,
+    pub surfaces: Vec<Surface>,
+}
+
+impl Md3 {
+
+    pub fn read(bytes: &[u8]) -> Result<Md3, String> {
+
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != IDENT {
+            return Err("not an IDP3 file".to_string());
+        }
+        let version = read_i32(bytes, 4)?;
+        if version != VERSION {
+            return Err(format!("unsupported md3 version {}, only {} can be loaded", version, VERSION));
+        }
+        let name = read_string(bytes, 8, 64)?;
+        let num_frames = read_count(bytes, 76)?;
+        let num_tags = read_count(bytes, 80)?;
+        let num_surfaces = read_count(bytes, 84)?;
+        let ofs_frames = read_count(bytes, 92)?;
+        let ofs_tags = read_count(bytes, 96)?;
+        let ofs_surfaces = read_count(bytes, 100)?;
+
+        let mut frames: Vec<Frame> = Vec::new();
+        for i in 0..num_frames {
+            let offset = ofs_frames + i * FRAME_SIZE;
+            frames.push(Frame {
+                mins: read_vec3(bytes, offset)?,
+                maxs: read_vec3(bytes, offset + 12)?,
+                origin: read_vec3(bytes, offset + 24)?,
+                radius: read_f32(bytes, offset + 36)?,
+                name: read_string(bytes, offset + 40, 16)?,
+            });
+        }
+
+        let mut tags: Vec<Vec<Tag>> = Vec::new();
+        for i in 0..num_frames {
+            let mut frame_tags: Vec<Tag> = Vec::new();
+            for j in 0..num_tags {
+                let offset = ofs_tags + (i * num_tags + j) * TAG_SIZE;
+                frame_tags.push(Tag {
+                    name: read_string(bytes, offset, 64)?,
+                    origin: read_vec3(bytes, offset + 64)?,
+                    axis: [read_vec3(bytes, offset + 76)?, read_vec3(bytes, offset + 88)?, read_vec3(bytes, offset + 100)?],
+                });
+            }
+            tags.push(frame_tags);
+        }
+
+        //Offsets inside a surface are from the start of the surface
+        let mut surfaces: Vec<Surface> = Vec::new();
+        let mut start = ofs_surfaces;
+        for _ in 0..num_surfaces {
+            if bytes.len() < start + SURFACE_HEADER_SIZE || &bytes[start..start + 4] != IDENT {
+                return Err(format!("bad surface at {}", start));
+            }
+            let surface_frames = read_count(bytes, start + 72)?;
+            let num_shaders = read_count(bytes, start + 76)?;
+            let num_verts = read_count(bytes, start + 80)?;
+            let num_triangles = read_count(bytes, start + 84)?;
+            let ofs_triangles = start + read_count(bytes, start + 88)?;
+            let ofs_shaders = start + read_count(bytes, start + 92)?;
+            let ofs_st = start + read_count(bytes, start + 96)?;
+            let ofs_xyz_normals = start + read_count(bytes, start + 100)?;
+            let ofs_end = read_count(bytes, start + 104)?;
+            if surface_frames != num_frames {
+                return Err(format!("surface has {} frames, the model has {}", surface_frames, num_frames));
+            }
+
+            let mut shaders: Vec<String> = Vec::new();
+            for i in 0..num_shaders {
+                shaders.push(read_string(bytes, ofs_shaders + i * SHADER_SIZE, 64)?);
+            }
+
+            let mut triangles: Vec<[u32; 3]> = Vec::new();
+            for i in 0..num_triangles {
+                let mut triangle = [0; 3];
+                for j in 0..3 {
+                    let index = read_count(bytes, ofs_triangles + i * 12 + j * 4)?;
+                    if index >= num_verts {
+                        return Err(format!("triangle {} uses vertex {} of {}", i, index, num_verts));
+                    }
+                    triangle[j] = index as u32;
+                }
+                triangles.push(triangle);
+            }
+
+            let mut tex_coords: Vec<[f32; 2]> = Vec::new();
+            for i in 0..num_verts {
+                tex_coords.push([read_f32(bytes, ofs_st + i * 8)?, read_f32(bytes, ofs_st + i * 8 + 4)?]);
+            }
+
+            let mut positions: Vec<Vec<[f32; 3]>> = Vec::new();
+            let mut normals: Vec<Vec<[f32; 3]>> = Vec::new();
+            for i in 0..num_frames {
+                let mut frame_positions: Vec<[f32; 3]> = Vec::new();
+                let mut frame_normals: Vec<[f32; 3]> = Vec::new();
+                for j in 0..num_verts {
+                    let offset = ofs_xyz_normals + (i * num_verts + j) * 8;
+                    let v = read_bytes(bytes, offset, 8)?;
+                    let xyz = [i16::from_le_bytes([v[0], v[1]]), i16::from_le_bytes([v[2], v[3]]), i16::from_le_bytes([v[4], v[5]])];
+                    frame_positions.push([xyz[0] as f32 * XYZ_SCALE, xyz[1] as f32 * XYZ_SCALE, xyz[2] as f32 * XYZ_SCALE]);
+                    frame_normals.push(decode_normal([v[6], v[7]]));
+                }
+                positions.push(frame_positions);
+                normals.push(frame_normals);
+            }
+
+            surfaces.push(Surface { name: read_string(bytes, start + 4, 64)?, shaders, triangles, tex_coords, positions, normals });
+            if ofs_end == 0 {
+                return Err("surface has no size".to_string());
+            }
+            start += ofs_end;
+        }
+
+        Ok(Md3 { name, frames, tags, surfaces })
+    }
+
+    pub fn load(fs: &vfs::FileSystem, path: &str) -> Option<Md3> {
+
+        let bytes = fs.read(path)?;
+        match Md3::read(&bytes) {
+            Ok(md3) => Some(md3),
+            Err(e) => {
+                println!("Could not load {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    //Frames past the end hold the last one
+    fn frame(&self, frame: usize) -> usize {
+        frame.min(self.frames.len().max(1) - 1)
+    }
+
+    //Positions and normals of a surface between two frames
+    pub fn interpolate(&self, surface: usize, frame0: usize, frame1: usize, lerp: f32) -> (Vec<[f32; 3]>, Vec<[f32; 3]>) {
+
+        let surface = &self.surfaces[surface];
+        if self.frames.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let (f0, f1) = (self.frame(frame0), self.frame(frame1));
+        let positions = surface.positions[f0].iter().zip(surface.positions[f1].iter()).map(|(a, b)| lerp3(*a, *b, lerp)).collect();
+        let normals = surface.normals[f0].iter().zip(surface.normals[f1].iter()).map(|(a, b)| normalize(lerp3(*a, *b, lerp))).collect();
+        (positions, normals)
+    }
+
+    //A tag between two frames with its axis normalised again like the game does
+    pub fn tag(&self, name: &str, frame0: usize, frame1: usize, lerp: f32) -> Option<Tag> {
+
+        if self.frames.is_empty() {
+            return None;
+        }
+        let (f0, f1) = (self.frame(frame0), self.frame(frame1));
+        let i = self.tags[f0].iter().position(|t| t.name == name)?;
+        let (a, b) = (&self.tags[f0][i], &self.tags[f1][i]);
+        Some(Tag {
+            name: a.name.clone(),
+            origin: lerp3(a.origin, b.origin, lerp),
+            axis: [normalize(lerp3(a.axis[0], b.axis[0], lerp)), normalize(lerp3(a.axis[1], b.axis[1], lerp)), normalize(lerp3(a.axis[2], b.axis[2], lerp))],
+        })
+    }
+}
+
+//Normals are two angles of 256 steps, the same order as the game's lookup
+pub fn decode_normal(n: [u8; 2]) -> [f32; 3] {
+
+    let lng = n[0] as f32 * 2.0 * std::f32::consts::PI / 256.0;
+    let lat = n[1] as f32 * 2.0 * std::f32::consts::PI / 256.0;
+    [lat.cos() * lng.sin(), lat.sin() * lng.sin(), lng.cos()]
+}
+
+fn lerp3(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+fn normalize(a: [f32; 3]) -> [f32; 3] {
+    let length = (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    if length == 0.0 {
+        return a;
+    }
+    [a[0] / length, a[1] / length, a[2] / length]
+}
+
+fn read_bytes(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    bytes.get(offset..offset + len).ok_or(format!("read past the end of the file at {}", offset))
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> Result<i32, String> {
+    let b = read_bytes(bytes, offset, 4)?;
+    Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_count(bytes: &[u8], offset: usize) -> Result<usize, String> {
+    let n = read_i32(bytes, offset)?;
+    if n < 0 {
+        return Err(format!("negative count {} at {}", n, offset));
+    }
+    Ok(n as usize)
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> Result<f32, String> {
+    let b = read_bytes(bytes, offset, 4)?;
+    Ok(f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_vec3(bytes: &[u8], offset: usize) -> Result<[f32; 3], String> {
+    Ok([read_f32(bytes, offset)?, read_f32(bytes, offset + 4)?, read_f32(bytes, offset + 8)?])
+}
+
+fn read_string(bytes: &[u8], offset: usize, len: usize) -> Result<String, String> {
+    //Names often have leftover bytes after the terminating zero
+    let b = read_bytes(bytes, offset, len)?;
+    let end = b.iter().position(|c| *c == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&b[..end]).to_string())
+}
+
+//A loaded model with a material for each surface, shared by every instance of it
+pub struct Md3Model {
+    pub md3: Md3,
+    pub materials: Vec<bsp::Material>,
+    pub surface_states: Vec<bsp_shader::SurfaceState>,
+}
+
+impl Md3Model {
+
+    //Surfaces use the shader the skin gives them or else their first shader, a missing image keeps the debug texture
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, fs: &vfs::FileSystem, md3: Md3, shaders: &HashMap<String, bsp_shader::Shader>, skin: &HashMap<String, String>, texture_settings: &texture::TextureSettings) -> Md3Model {
+
+        let mut materials: Vec<bsp::Material> = Vec::new();
+        let mut surface_states: Vec<bsp_shader::SurfaceState> = Vec::new();
+        //Read once for all the surfaces that need it
+        let mut debug_image: Option<image::DynamicImage> = None;
+        for surface in md3.surfaces.iter() {
+            let name = skin.get(&surface.name.to_lowercase()).or(surface.shaders.first()).cloned().unwrap_or_default();
+            let state = shaders.get(&name.to_lowercase()).map_or(bsp_shader::SurfaceState::opaque(), |s| s.surface_state());
+            let loaded = bsp::Bsp::texture_images(&name, shaders).iter().find_map(|path| bsp::Bsp::load_image(fs, device, queue, path, texture_settings));
+            let texture = match loaded {
+                Some(texture) => texture,
+                None => {
+                    println!("Error cant find {} for {}", name, md3.name);
+                    texture::Texture::from_image(device, queue, debug_image.get_or_insert_with(|| bsp::Bsp::debug_image(fs)), Some("debug")).unwrap()
+                }
+            };
+            materials.push(bsp::Material::new(device, layout, texture, Some(bsp::MaterialUniforms::new(&state, &Vec::new()))));
+            surface_states.push(state);
+        }
+        Md3Model { md3, materials, surface_states }
+    }
+}
+
+pub struct Md3Mesh {
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub num_elements: u32,
+    pub material: usize,
+}
+
+//Vertex buffers of one placed model, the frames are interpolated on the cpu into world space
+pub struct Md3Instance {
+    pub meshes: Vec<Md3Mesh>,
+}
+
+impl Md3Instance {
+
+    pub fn new(device: &wgpu::Device, model: &Md3Model) -> Md3Instance {
+
+        let mut meshes: Vec<Md3Mesh> = Vec::new();
+        for (i, surface) in model.md3.surfaces.iter().enumerate() {
+            let indices = surface.triangles.iter().flat_map(|t| t.iter().cloned()).collect::<Vec<u32>>();
+            let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Md3 Vertex Buffer"),
+                size: (surface.tex_coords.len().max(1) * std::mem::size_of::<bsp::Vertex>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let index_buffer = device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Md3 Index Buffer"),
+                    contents: bytemuck::cast_slice(&indices),
+                    usage: wgpu::BufferUsage::INDEX,
+                }
+            );
+            meshes.push(Md3Mesh { vertex_buffer, index_buffer, num_elements: indices.len() as u32, material: i });
+        }
+        Md3Instance { meshes }
+    }
+
+    //The transform places the model, the normals only get its rotation and are lit by the light grid
+    pub fn update(&self, queue: &wgpu::Queue, model: &Md3Model, frame0: usize, frame1: usize, lerp: f32, transform: cgmath::Matrix4<f32>, light: &bsp::GridLight) {
+
+        let rotation = cgmath::Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            let (positions, normals) = model.md3.interpolate(i, frame0, frame1, lerp);
+            let vertices = positions.iter().zip(normals.iter()).zip(model.md3.surfaces[i].tex_coords.iter()).map(|((p, n), st)| {
+                let position = transform * cgmath::Vector4::new(p[0], p[1], p[2], 1.0);
+                let normal = (rotation * cgmath::Vector3::new(n[0], n[1], n[2])).normalize();
+                let normal = [normal.x, normal.y, normal.z];
+                bsp::Vertex { position: [position.x, position.y, position.z], texcoord_s: *st, texcoord_l: [0.0; 2], normal, colour: light.colour(normal) }
+            }).collect::<Vec<bsp::Vertex>>();
+            if !vertices.is_empty() {
+                queue.write_buffer(&mesh.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+            }
+        }
+    }
+}
+
+pub trait DrawMd3<'a, 'b>
+where
+    'b: 'a,
+{
+    fn draw_md3_mesh(&mut self, mesh: &'b Md3Mesh, material: &'b bsp::Material, uniforms: &'b wgpu::BindGroup);
+}
+
+impl<'a, 'b> DrawMd3<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_md3_mesh(&mut self, mesh: &'b Md3Mesh, material: &'b bsp::Material, uniforms: &'b wgpu::BindGroup) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..));
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.set_bind_group(1, &uniforms, &[]);
+        self.draw_indexed(0..mesh.num_elements, 0, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_i32(bytes: &mut Vec<u8>, offset: usize, v: i32) {
+        bytes[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_f32s(bytes: &mut Vec<u8>, offset: usize, v: &[f32]) {
+        for (i, f) in v.iter().enumerate() {
+            bytes[offset + i * 4..offset + i * 4 + 4].copy_from_slice(&f.to_le_bytes());
+        }
+    }
+
+    //Leftover bytes after the zero like real exporters leave them
+    fn put_name(bytes: &mut Vec<u8>, offset: usize, len: usize, name: &str) {
+        for i in 0..len {
+            bytes[offset + i] = 0xcc;
+        }
+        bytes[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+        bytes[offset + name.len()] = 0;
+    }
+
+    const FRAMES: usize = HEADER_SIZE;
+    const TAGS: usize = FRAMES + FRAME_SIZE;
+    const SURFACE: usize = TAGS + TAG_SIZE;
+    const SHADERS: usize = SURFACE_HEADER_SIZE;
+    const TRIANGLES: usize = SHADERS + SHADER_SIZE;
+    const ST: usize = TRIANGLES + 12;
+    const XYZ_NORMALS: usize = ST + 3 * 8;
+    const SURFACE_END: usize = XYZ_NORMALS + 3 * 8;
+
+    //One frame, one tag and one surface with a single triangle
+    fn model() -> Vec<u8> {
+        let mut bytes = vec![0u8; SURFACE + SURFACE_END];
+        bytes[0..4].copy_from_slice(IDENT);
+        put_i32(&mut bytes, 4, VERSION);
+        put_name(&mut bytes, 8, 64, "models/test.md3");
+        for (offset, v) in [(76, 1), (80, 1), (84, 1), (92, FRAMES), (96, TAGS), (100, SURFACE), (104, bytes.len())].iter() {
+            put_i32(&mut bytes, *offset, *v as i32);
+        }
+
+        put_f32s(&mut bytes, FRAMES, &[-1.0, -2.0, -3.0, 1.0, 2.0, 3.0, 0.5, 0.0, 0.0, 4.0]);
+        put_name(&mut bytes, FRAMES + 40, 16, "frame0");
+
+        put_name(&mut bytes, TAGS, 64, "tag_weapon");
+        put_f32s(&mut bytes, TAGS + 64, &[8.0, 0.0, 16.0, 0.0, 1.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+
+        bytes[SURFACE..SURFACE + 4].copy_from_slice(IDENT);
+        put_name(&mut bytes, SURFACE + 4, 64, "body");
+        for (offset, v) in [(72, 1), (76, 1), (80, 3), (84, 1), (88, TRIANGLES), (92, SHADERS), (96, ST), (100, XYZ_NORMALS), (104, SURFACE_END)].iter() {
+            put_i32(&mut bytes, SURFACE + *offset, *v as i32);
+        }
+        put_name(&mut bytes, SURFACE + SHADERS, 64, "models/test.tga");
+        for (i, index) in [0, 2, 1].iter().enumerate() {
+            put_i32(&mut bytes, SURFACE + TRIANGLES + i * 4, *index);
+        }
+        put_f32s(&mut bytes, SURFACE + ST, &[0.0, 0.0, 1.0, 0.0, 0.0, 1.0]);
+        let vertices: [([i16; 3], [u8; 2]); 3] = [([0, 0, 0], [0, 0]), ([64, 0, 0], [64, 0]), ([0, -128, 32], [64, 64])];
+        for (i, (xyz, normal)) in vertices.iter().enumerate() {
+            let offset = SURFACE + XYZ_NORMALS + i * 8;
+            for j in 0..3 {
+                bytes[offset + j * 2..offset + j * 2 + 2].copy_from_slice(&xyz[j].to_le_bytes());
+            }
+            bytes[offset + 6..offset + 8].copy_from_slice(normal);
+        }
+        bytes
+    }
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        (0..3).all(|i| (a[i] - b[i]).abs() < 1e-5)
+    }
+
+    #[test]
+    fn frames_and_tags() {
+        let md3 = Md3::read(&model()).unwrap();
+        assert_eq!(md3.name, "models/test.md3");
+        assert_eq!(md3.frames, vec![Frame { mins: [-1.0, -2.0, -3.0], maxs: [1.0, 2.0, 3.0], origin: [0.5, 0.0, 0.0], radius: 4.0, name: "frame0".to_string() }]);
+        assert_eq!(md3.tags, vec![vec![Tag { name: "tag_weapon".to_string(), origin: [8.0, 0.0, 16.0], axis: [[0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]] }]]);
+        assert_eq!(md3.tag("tag_weapon", 0, 5, 0.5), Some(md3.tags[0][0].clone()));
+        assert_eq!(md3.tag("tag_head", 0, 0, 0.0), None);
+    }
+
+    #[test]
+    fn surfaces() {
+        let md3 = Md3::read(&model()).unwrap();
+        assert_eq!(md3.surfaces.len(), 1);
+        let surface = &md3.surfaces[0];
+        assert_eq!(surface.name, "body");
+        assert_eq!(surface.shaders, vec!["models/test.tga".to_string()]);
+        assert_eq!(surface.triangles, vec![[0, 2, 1]]);
+        assert_eq!(surface.tex_coords, vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]);
+        assert_eq!(surface.positions, vec![vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, -2.0, 0.5]]]);
+        assert!(close(surface.normals[0][0], [0.0, 0.0, 1.0]));
+        assert!(close(surface.normals[0][1], [1.0, 0.0, 0.0]));
+        assert!(close(surface.normals[0][2], [0.0, 1.0, 0.0]));
+        let (positions, normals) = md3.interpolate(0, 0, 0, 0.0);
+        assert_eq!(positions, surface.positions[0]);
+        assert_eq!(normals.len(), 3);
+    }
+
+    #[test]
+    fn normals() {
+        assert!(close(decode_normal([0, 0]), [0.0, 0.0, 1.0]));
+        assert!(close(decode_normal([128, 0]), [0.0, 0.0, -1.0]));
+        assert!(close(decode_normal([64, 0]), [1.0, 0.0, 0.0]));
+        assert!(close(decode_normal([64, 64]), [0.0, 1.0, 0.0]));
+        assert!(close(decode_normal([64, 128]), [-1.0, 0.0, 0.0]));
+        for lng in 0..=255 {
+            let n = decode_normal([lng, 37]);
+            assert!(((n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn bad_files() {
+        let good = model();
+        assert_eq!(Md3::read(&good[..HEADER_SIZE - 1]).err(), Some("not an IDP3 file".to_string()));
+
+        let mut version = good.clone();
+        put_i32(&mut version, 4, 16);
+        assert!(Md3::read(&version).is_err());
+
+        let mut frames = good.clone();
+        put_i32(&mut frames, 92, good.len() as i32);
+        assert!(Md3::read(&frames).err().unwrap().starts_with("read past the end"));
+
+        let mut surfaces = good.clone();
+        put_i32(&mut surfaces, 100, good.len() as i32 - 8);
+        assert_eq!(Md3::read(&surfaces).err(), Some(format!("bad surface at {}", good.len() - 8)));
+
+        let mut negative = good.clone();
+        put_i32(&mut negative, 80, -1);
+        assert_eq!(Md3::read(&negative).err(), Some("negative count -1 at 80".to_string()));
+
+        let mut triangle = good.clone();
+        put_i32(&mut triangle, SURFACE + TRIANGLES + 4, 3);
+        assert_eq!(Md3::read(&triangle).err(), Some("triangle 0 uses vertex 3 of 3".to_string()));
+
+        let mut vertices = good.clone();
+        put_i32(&mut vertices, SURFACE + 100, SURFACE_END as i32);
+        assert!(Md3::read(&vertices).err().unwrap().starts_with("read past the end"));
+
+        let mut surface_frames = good.clone();
+        put_i32(&mut surface_frames, SURFACE + 72, 2);
+        assert!(Md3::read(&surface_frames).is_err());
+    }
+}
\ No newline at end of file
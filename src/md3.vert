@@ -8,15 +8,21 @@ layout(location = 4) in vec4 a_colour;
 
 layout(location = 0) out vec4 v_colour;
 layout(location = 1) out vec2 v_tex_coords;
+layout(location = 2) out vec3 v_position;
 
 layout(set = 1, binding = 0)
 uniform Uniforms {
     mat4 u_view_proj;
     mat4 model;
+    vec4 u_view_position;
+    vec4 u_time;
+    vec4 u_render_mode;
 };
 
+//Md3 vertices are interpolated, placed and lit from the light grid on the cpu, see md3.rs
 void main() {
     v_colour = a_colour;
     v_tex_coords = a_tex_coords;
-    gl_Position = u_view_proj * model * vec4(a_position, 1.0);
+    v_position = a_position;
+    gl_Position = u_view_proj * vec4(a_position, 1.0);
 }
\ No newline at end of file
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use cgmath::{Matrix4, Vector3, Deg};
+
+use crate::bsp;
+use crate::bsp_shader;
+use crate::md3;
+use crate::texture;
+use crate::vfs;
+
+//Quake 3 player models, lower, upper and head md3s in models/players/<name>/ joined by their tags,
+//textured by .skin files and animated by animation.cfg
+
+//The order of animation.cfg
+pub const ANIMATION_NAMES: [&str; 25] = [
+    "both_death1", "both_dead1", "both_death2", "both_dead2", "both_death3", "both_dead3",
+    "torso_gesture", "torso_attack", "torso_attack2", "torso_drop", "torso_raise", "torso_stand", "torso_stand2",
+    "legs_walkcr", "legs_walk", "legs_run", "legs_back", "legs_swim", "legs_jump", "legs_land", "legs_jumpb", "legs_landb",
+    "legs_idle", "legs_idlecr", "legs_turn",
+];
+const TORSO_GESTURE: usize = 6;
+pub const TORSO_STAND: usize = 11;
+const LEGS_WALKCR: usize = 13;
+pub const LEGS_IDLE: usize = 22;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Animation {
+    pub first: usize,
+    pub num: usize,
+    //The last looping frames repeat once the animation has played, 0 holds the last frame
+    pub looping: usize,
+    pub fps: f32,
+    //A negative frame count plays the frames backwards
+    pub reversed: bool,
+}
+
+impl Animation {
+
+    //Frame pair and blend between them at a time since the animation started, like CG_RunLerpFrame
+    pub fn frames(&self, time: f32) -> (usize, usize, f32) {
+
+        let f = (time * self.fps).max(0.0);
+        let n = f.floor() as usize;
+        (self.first + self.frame(n), self.first + self.frame(n + 1), f - f.floor())
+    }
+
+    fn frame(&self, n: usize) -> usize {
+        let frame = if n < self.num {
+            n
+        }
+        else if self.looping > 0 && self.looping <= self.num {
+            self.num - self.looping + (n - self.num) % self.looping
+        }
+        else {
+            self.num.max(1) - 1
+        };
+        if self.reversed {
+            self.num.max(1) - 1 - frame
+        }
+        else {
+            frame
+        }
+    }
+}
+
+pub fn animation_index(name: &str) -> Option<usize> {
+    ANIMATION_NAMES.iter().position(|n| n.eq_ignore_ascii_case(name))
+}
+
+//Lines of first frame, frame count, looping frames and fps after optional sex, footsteps and headoffset lines.
+//lower.md3 has no torso frames, so the legs animations are moved down by them
+pub fn parse_animations(text: &str) -> Result<Vec<Animation>, String> {
+
+    let mut animations: Vec<Animation> = Vec::new();
+    for line in text.lines() {
+        let line = match line.find("//") {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let tokens = line.split_whitespace().collect::<Vec<&str>>();
+        if tokens.len() < 4 || tokens[0].parse::<i32>().is_err() {
+            continue;
+        }
+        let values = tokens[..4].iter().map(|t| t.parse::<f32>().ok()).collect::<Option<Vec<f32>>>().ok_or(format!("bad animation line {}", line.trim()))?;
+        animations.push(Animation { first: values[0].max(0.0) as usize, num: values[1].abs() as usize, looping: values[2].max(0.0) as usize, fps: values[3].max(1.0), reversed: values[1] < 0.0 });
+        if animations.len() == ANIMATION_NAMES.len() {
+            break;
+        }
+    }
+    if animations.len() < ANIMATION_NAMES.len() {
+        return Err(format!("{} of {} animations", animations.len(), ANIMATION_NAMES.len()));
+    }
+
+    let skip = animations[LEGS_WALKCR].first.saturating_sub(animations[TORSO_GESTURE].first);
+    for animation in animations[LEGS_WALKCR..].iter_mut() {
+        animation.first = animation.first.saturating_sub(skip);
+    }
+    Ok(animations)
+}
+
+//Surface name to shader lines, tag lines have no shader
+pub fn parse_skin(text: &str) -> HashMap<String, String> {
+
+    let mut skin = HashMap::new();
+    for line in text.lines() {
+        if let Some(i) = line.find(',') {
+            let (surface, shader) = (line[..i].trim(), line[i + 1..].trim().trim_matches('"'));
+            if !surface.is_empty() && !shader.is_empty() {
+                skin.insert(surface.to_lowercase(), shader.to_string());
+            }
+        }
+    }
+    skin
+}
+
+//A tag as the transform from the model it belongs to
+pub fn tag_matrix(tag: &md3::Tag) -> Matrix4<f32> {
+    Matrix4::new(
+        tag.axis[0][0], tag.axis[0][1], tag.axis[0][2], 0.0,
+        tag.axis[1][0], tag.axis[1][1], tag.axis[1][2], 0.0,
+        tag.axis[2][0], tag.axis[2][1], tag.axis[2][2], 0.0,
+        tag.origin[0], tag.origin[1], tag.origin[2], 1.0,
+    )
+}
+
+pub struct Part {
+    pub model: md3::Md3Model,
+    pub instance: md3::Md3Instance,
+}
+
+impl Part {
+
+    fn load(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, fs: &vfs::FileSystem, path: &str, skin: &HashMap<String, String>, shaders: &HashMap<String, bsp_shader::Shader>, texture_settings: &texture::TextureSettings) -> Option<Part> {
+
+        let md3 = match md3::Md3::load(fs, path) {
+            Some(md3) => md3,
+            None => {
+                println!("Error cant find model {}", path);
+                return None;
+            }
+        };
+        let model = md3::Md3Model::new(device, queue, layout, fs, md3, shaders, skin, texture_settings);
+        let instance = md3::Md3Instance::new(device, &model);
+        Some(Part { model, instance })
+    }
+}
+
+pub struct Player {
+    pub legs: Part,
+    pub torso: Part,
+    pub head: Part,
+    pub weapon: Option<Part>,
+    pub animations: Vec<Animation>,
+    pub legs_animation: usize,
+    pub torso_animation: usize,
+    pub origin: [f32; 3],
+    pub yaw: f32,
+    pub light: bsp::GridLight,
+}
+
+impl Player {
+
+    //name is a model or model/skin like the model cvar, the skin defaults to default
+    pub fn load(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, fs: &vfs::FileSystem, name: &str, weapon: Option<&str>, shaders: &HashMap<String, bsp_shader::Shader>, texture_settings: &texture::TextureSettings) -> Option<Player> {
+
+        let (model, skin) = match name.find('/') {
+            Some(i) => (&name[..i], &name[i + 1..]),
+            None => (name, "default"),
+        };
+        let dir = format!("models/players/{}", model);
+        let animations = match fs.read(&format!("{}/animation.cfg", dir)).map(|b| parse_animations(&String::from_utf8_lossy(&b))) {
+            Some(Ok(animations)) => animations,
+            Some(Err(e)) => {
+                println!("Could not load {}/animation.cfg: {}", dir, e);
+                return None;
+            }
+            None => {
+                println!("Error cant find {}/animation.cfg", dir);
+                return None;
+            }
+        };
+
+        let mut parts: Vec<Part> = Vec::new();
+        for part in ["lower", "upper", "head"].iter() {
+            let skin = fs.read(&format!("{}/{}_{}.skin", dir, part, skin)).map(|b| parse_skin(&String::from_utf8_lossy(&b))).unwrap_or_default();
+            parts.push(Part::load(device, queue, layout, fs, &format!("{}/{}.md3", dir, part), &skin, shaders, texture_settings)?);
+        }
+        let weapon = weapon.and_then(|w| Part::load(device, queue, layout, fs, &format!("models/weapons2/{}/{}.md3", w, w), &HashMap::new(), shaders, texture_settings));
+
+        let head = parts.pop().unwrap();
+        let torso = parts.pop().unwrap();
+        let legs = parts.pop().unwrap();
+        Some(Player { legs, torso, head, weapon, animations, legs_animation: LEGS_IDLE, torso_animation: TORSO_STAND, origin: [0.0; 3], yaw: 0.0, light: bsp::GridLight { ambient: [255.0; 3], directed: [0.0; 3], dir: [0.0, 0.0, 1.0] } })
+    }
+
+    //The torso sits on tag_torso of the legs, the head and weapon on tag_head and tag_weapon of the torso.
+    //Times are since the legs and torso animations started
+    pub fn update(&self, queue: &wgpu::Queue, legs_time: f32, torso_time: f32) {
+
+        let (legs0, legs1, legs_lerp) = self.animations[self.legs_animation].frames(legs_time);
+        let (torso0, torso1, torso_lerp) = self.animations[self.torso_animation].frames(torso_time);
+
+        let legs = Matrix4::from_translation(Vector3::new(self.origin[0], self.origin[1], self.origin[2])) * Matrix4::from_angle_z(Deg(self.yaw));
+        let torso = legs * self.legs.model.md3.tag("tag_torso", legs0, legs1, legs_lerp).map_or(Matrix4::from_scale(1.0), |t| tag_matrix(&t));
+        let head = torso * self.torso.model.md3.tag("tag_head", torso0, torso1, torso_lerp).map_or(Matrix4::from_scale(1.0), |t| tag_matrix(&t));
+
+        self.legs.instance.update(queue, &self.legs.model, legs0, legs1, legs_lerp, legs, &self.light);
+        self.torso.instance.update(queue, &self.torso.model, torso0, torso1, torso_lerp, torso, &self.light);
+        self.head.instance.update(queue, &self.head.model, 0, 0, 0.0, head, &self.light);
+        if let Some(weapon) = self.weapon.as_ref() {
+            let transform = torso * self.torso.model.md3.tag("tag_weapon", torso0, torso1, torso_lerp).map_or(Matrix4::from_scale(1.0), |t| tag_matrix(&t));
+            weapon.instance.update(queue, &weapon.model, 0, 0, 0.0, transform, &self.light);
+        }
+    }
+
+    pub fn parts(&self) -> Vec<&Part> {
+        let mut parts = vec![&self.legs, &self.torso, &self.head];
+        parts.extend(self.weapon.as_ref());
+        parts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Both and torso animations 10 frames apart from 0, legs from 130 like a model with 70 torso only frames
+    fn config(lines: usize) -> String {
+        let mut text = "// animation config file\n\nsex m\nfootsteps normal\nheadoffset 0 0 0\n\n".to_string();
+        for (i, name) in ANIMATION_NAMES.iter().enumerate().take(lines) {
+            let first = if i < LEGS_WALKCR { i * 10 } else { 130 + (i - LEGS_WALKCR) * 10 };
+            let num = if i == animation_index("legs_back").unwrap() { "-10" } else { "10" };
+            text.push_str(&format!("{}\t{}\t{}\t15\t\t// {}\n", first, num, i % 3, name.to_uppercase()));
+        }
+        text
+    }
+
+    #[test]
+    fn animations() {
+        let animations = parse_animations(&config(25)).unwrap();
+        assert_eq!(animations.len(), 25);
+        assert_eq!(animations[0], Animation { first: 0, num: 10, looping: 0, fps: 15.0, reversed: false });
+        assert_eq!(animations[TORSO_STAND], Animation { first: 110, num: 10, looping: 2, fps: 15.0, reversed: false });
+        //The legs follow the both animations in lower.md3
+        assert_eq!(animations[LEGS_WALKCR].first, 60);
+        assert_eq!(animations[LEGS_IDLE].first, 150);
+        let back = animations[animation_index("LEGS_BACK").unwrap()];
+        assert_eq!((back.first, back.num, back.reversed), (90, 10, true));
+    }
+
+    #[test]
+    fn bad_animations() {
+        assert_eq!(parse_animations(&config(24)), Err("24 of 25 animations".to_string()));
+        let text = config(25).replacen("110\t10", "110\tten", 1);
+        assert!(parse_animations(&text).is_err());
+        assert!(parse_animations("").is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let looping = Animation { first: 100, num: 4, looping: 2, fps: 10.0, reversed: false };
+        assert_eq!(looping.frames(0.0), (100, 101, 0.0));
+        let (a, b, lerp) = looping.frames(0.35);
+        assert_eq!((a, b), (103, 102));
+        assert!((lerp - 0.5).abs() < 1e-4);
+        //The last two frames repeat
+        assert_eq!(looping.frames(0.5).0, 103);
+        assert_eq!(looping.frames(0.6).0, 102);
+        assert_eq!(looping.frames(0.7).0, 103);
+
+        let holding = Animation { looping: 0, ..looping };
+        assert_eq!(holding.frames(0.35).1, 103);
+        assert_eq!(holding.frames(10.0), (103, 103, 0.0));
+        assert_eq!(holding.frames(-1.0), (100, 101, 0.0));
+
+        let reversed = Animation { reversed: true, ..holding };
+        assert_eq!(reversed.frames(0.0), (103, 102, 0.0));
+        assert_eq!(reversed.frames(10.0), (100, 100, 0.0));
+
+        let empty = Animation { num: 0, ..looping };
+        assert_eq!(empty.frames(1.0), (100, 100, 0.0));
+    }
+
+    #[test]
+    fn skin() {
+        let skin = parse_skin("tag_head,\ntag_weapon,\nh_head,models/players/sarge/band.tga\r\nH_Helmet, \"models/players/sarge/helmet.tga\"\n\n");
+        assert_eq!(skin.len(), 2);
+        assert_eq!(skin["h_head"], "models/players/sarge/band.tga");
+        assert_eq!(skin["h_helmet"], "models/players/sarge/helmet.tga");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,233 @@
+//Offscreen render targets and reading them back into images
+//Swap chain frames can not be copied from so saved frames are drawn into their own texture and blitted to the window
+
+pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+//The format has to match the pipelines, the window uses bgra
+pub fn create_target(device: &wgpu::Device, width: u32, height: u32, format: wgpu::TextureFormat) -> wgpu::Texture {
+
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Screenshot Texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC | wgpu::TextureUsage::SAMPLED,
+    })
+}
+
+//Copies a drawn target to the window with one triangle over the screen
+pub struct Blit {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+}
+
+impl Blit {
+
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Blit {
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+            label: Some("blit_bind_group_layout"),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Blit Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(wgpu::include_spirv!("blit.vert.spv"));
+        let fs_module = device.create_shader_module(wgpu::include_spirv!("blit.frag.spv"));
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Blit Pipeline"),
+            layout: Some(&layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor::default()),
+            color_states: &[
+                wgpu::ColorStateDescriptor {
+                    format,
+                    color_blend: wgpu::BlendDescriptor::REPLACE,
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                },
+            ],
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint16,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        Blit { pipeline, bind_group_layout, sampler }
+    }
+
+    //Source and target are the same size, every pixel is copied as it is
+    pub fn draw(&self, device: &wgpu::Device, queue: &wgpu::Queue, source: &wgpu::TextureView, target: &wgpu::TextureView) {
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("blit_bind_group"),
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Blit Encoder"),
+        });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: target,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Load,
+                            store: true,
+                        },
+                    },
+                ],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+//Texture to buffer copies need rows aligned to 256 bytes
+pub fn padded_bytes_per_row(width: u32) -> u32 {
+
+    let bytes_per_row = width * 4;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    (bytes_per_row + align - 1) / align * align
+}
+
+pub fn unpad_rows(data: &[u8], width: u32, height: u32, padded_bytes_per_row: u32) -> Vec<u8> {
+
+    let bytes_per_row = (width * 4) as usize;
+    let mut pixels = Vec::with_capacity(bytes_per_row * height as usize);
+    for row in 0..height as usize {
+        let start = row * padded_bytes_per_row as usize;
+        pixels.extend_from_slice(&data[start..(start + bytes_per_row)]);
+    }
+    pixels
+}
+
+//Blocks until the gpu has finished drawing into the texture
+pub fn read_texture(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture, width: u32, height: u32, format: wgpu::TextureFormat) -> image::RgbaImage {
+
+    let padded_bytes_per_row = padded_bytes_per_row(width);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Screenshot Buffer"),
+        size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Screenshot Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        wgpu::TextureCopyView {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::BufferCopyView {
+            buffer: &buffer,
+            layout: wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: padded_bytes_per_row,
+                rows_per_image: height,
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let mapping = slice.map_async(wgpu::MapMode::Read);
+    device.poll(wgpu::Maintain::Wait);
+    futures::executor::block_on(mapping).unwrap();
+
+    let mut pixels = unpad_rows(&slice.get_mapped_range(), width, height, padded_bytes_per_row);
+    buffer.unmap();
+    if format == wgpu::TextureFormat::Bgra8UnormSrgb || format == wgpu::TextureFormat::Bgra8Unorm {
+        for pixel in pixels.chunks_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+    image::RgbaImage::from_raw(width, height, pixels).unwrap()
+}
+
+//Timestamped name like shot-1602876543-042.png, the extension picks the image format
+pub fn timestamped_name(extension: &str) -> String {
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    format!("shot-{}-{:03}.{}", now.as_secs(), now.subsec_millis(), extension)
+}
+
+//Numbered frames for making videos, frame_00000.png
+pub fn frame_name(frame: u32, extension: &str) -> String {
+    format!("frame_{:05}.{}", frame, extension)
+}
+
+pub fn save(image: &image::RgbaImage, dir: &std::path::Path, name: &str) {
+
+    std::fs::create_dir_all(dir).unwrap();
+    let path = dir.join(name);
+    match image.save(&path) {
+        Ok(_) => println!("Saved {}", path.display()),
+        Err(e) => println!("Could not save {}: {}", path.display(), e),
+    }
+}
\ No newline at end of file
@@ -1,6 +1,81 @@
 use image::GenericImageView;
 use anyhow::*;
 use std::path::Path;
+use std::num::NonZeroU8;
+
+#[derive(Debug, Copy, Clone)]
+pub struct TextureSettings {
+    pub mipmaps: bool,
+    //Valid values are 1, 2, 4, 8 and 16, ignored when the adapter has no anisotropic filtering
+    pub anisotropy: Option<u8>,
+    //Number of top mip levels dropped like r_picmip
+    pub picmip: u32,
+    //Largest width or height uploaded, bigger images drop mip levels until they fit
+    pub max_size: u32,
+}
+
+impl Default for TextureSettings {
+    fn default() -> Self {
+        Self {
+            mipmaps: true,
+            anisotropy: None,
+            picmip: 0,
+            max_size: 2048,
+        }
+    }
+}
+
+//Next mip level of an rgba image, a 2x2 box filter that clamps at the edges of odd sizes
+pub fn downsample(rgba: &[u8], width: u32, height: u32) -> (Vec<u8>, u32, u32) {
+
+    let new_width = (width / 2).max(1);
+    let new_height = (height / 2).max(1);
+    let mut out: Vec<u8> = vec![0; (new_width * new_height * 4) as usize];
+
+    for y in 0..new_height {
+        for x in 0..new_width {
+            let x0 = (x * 2).min(width - 1);
+            let x1 = (x * 2 + 1).min(width - 1);
+            let y0 = (y * 2).min(height - 1);
+            let y1 = (y * 2 + 1).min(height - 1);
+            for c in 0..4 {
+                let sum = rgba[((y0 * width + x0) * 4 + c) as usize] as u32 +
+                        rgba[((y0 * width + x1) * 4 + c) as usize] as u32 +
+                        rgba[((y1 * width + x0) * 4 + c) as usize] as u32 +
+                        rgba[((y1 * width + x1) * 4 + c) as usize] as u32;
+                out[((y * new_width + x) * 4 + c) as usize] = ((sum + 2) / 4) as u8;
+            }
+        }
+    }
+
+    (out, new_width, new_height)
+}
+
+//Mip levels to upload for an rgba image, picmip and max_size drop the largest levels
+pub fn build_mip_chain(rgba: &[u8], width: u32, height: u32, settings: &TextureSettings) -> Vec<(Vec<u8>, u32, u32)> {
+
+    let mut levels: Vec<(Vec<u8>, u32, u32)> = vec![(rgba.to_vec(), width, height)];
+    let mut skip = settings.picmip;
+    loop {
+        let (last, w, h) = levels.last().unwrap();
+        let (w, h) = (*w, *h);
+        let needed = skip > 0 || w > settings.max_size || h > settings.max_size || (settings.mipmaps && (w > 1 || h > 1));
+        if !needed || (w == 1 && h == 1) {
+            break;
+        }
+        let level = downsample(last, w, h);
+        if skip > 0 || w > settings.max_size || h > settings.max_size {
+            levels.clear();
+            skip = skip.saturating_sub(1);
+        }
+        levels.push(level);
+    }
+
+    if !settings.mipmaps {
+        levels.truncate(1);
+    }
+    levels
+}
 
 pub struct Texture {
     pub texture: wgpu::Texture,
@@ -65,7 +140,8 @@ impl Texture {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8],
-        i_size: u32,
+        width: u32,
+        height: u32,
         i_label: &str
     ) -> Result<Self> {
 
@@ -83,8 +159,8 @@ impl Texture {
         }
 
         let size = wgpu::Extent3d {
-            width: i_size,
-            height: i_size,
+            width,
+            height,
             depth: 1,
         };
         let texture = device.create_texture(
@@ -108,8 +184,8 @@ impl Texture {
             &rgba,
             wgpu::TextureDataLayout {
                 offset: 0,
-                bytes_per_row: 4 * i_size,
-                rows_per_image: i_size,
+                bytes_per_row: 4 * width,
+                rows_per_image: height,
             },
             size,
         );
@@ -121,7 +197,7 @@ impl Texture {
                 address_mode_v: wgpu::AddressMode::Repeat,
                 address_mode_w: wgpu::AddressMode::Repeat,
                 mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Linear,
                 mipmap_filter: wgpu::FilterMode::Nearest,
                 ..Default::default()
             }
@@ -146,11 +222,12 @@ impl Texture {
         queue: &wgpu::Queue,
         bytes: &[u8],
         format: image::ImageFormat,
-        label: &str
+        label: &str,
+        settings: &TextureSettings
     ) -> Result<Self> {
 
         let img = image::load_from_memory_with_format(bytes, format)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image_settings(device, queue, &img, Some(label), settings)
     }
 
     pub fn from_image(
@@ -160,19 +237,31 @@ impl Texture {
         label: Option<&str>
     ) -> Result<Self> {
 
+        Self::from_image_settings(device, queue, img, label, &TextureSettings::default())
+    }
+
+    pub fn from_image_settings(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        settings: &TextureSettings
+    ) -> Result<Self> {
+
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
+        let levels = build_mip_chain(&rgba, dimensions.0, dimensions.1, settings);
 
         let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
+            width: levels[0].1,
+            height: levels[0].2,
             depth: 1,
         };
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
                 label,
                 size,
-                mip_level_count: 1,
+                mip_level_count: levels.len() as u32,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
                 format: wgpu::TextureFormat::Rgba8UnormSrgb,
@@ -180,21 +269,28 @@ impl Texture {
             }
         );
 
-        queue.write_texture(
-            wgpu::TextureCopyView {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::TextureDataLayout {
-                offset: 0,
-                bytes_per_row: 4 * dimensions.0,
-                rows_per_image: dimensions.1,
-            },
-            size,
-        );
+        for (mip_level, (data, width, height)) in levels.iter().enumerate() {
+            queue.write_texture(
+                wgpu::TextureCopyView {
+                    texture: &texture,
+                    mip_level: mip_level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                data,
+                wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: 4 * width,
+                    rows_per_image: *height,
+                },
+                wgpu::Extent3d {
+                    width: *width,
+                    height: *height,
+                    depth: 1,
+                },
+            );
+        }
 
+        //Trilinear filtering
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler (
             &wgpu::SamplerDescriptor {
@@ -202,12 +298,79 @@ impl Texture {
                 address_mode_v: wgpu::AddressMode::Repeat,
                 address_mode_w: wgpu::AddressMode::Repeat,
                 mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                anisotropy_clamp: settings.anisotropy.and_then(NonZeroU8::new),
                 ..Default::default()
             }
         );
 
         Ok(Self { texture, view, sampler })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height * 4).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    fn sizes(levels: &Vec<(Vec<u8>, u32, u32)>) -> Vec<(u32, u32)> {
+        levels.iter().map(|(rgba, w, h)| {
+            assert_eq!(rgba.len(), (w * h * 4) as usize);
+            (*w, *h)
+        }).collect()
+    }
+
+    #[test]
+    fn downsample_averages() {
+        let rgba = vec![0, 10, 255, 255, 2, 20, 255, 0, 4, 30, 0, 255, 6, 40, 0, 0];
+        let (out, w, h) = downsample(&rgba, 2, 2);
+        assert_eq!((w, h), (1, 1));
+        assert_eq!(out, vec![3, 25, 128, 128]);
+    }
+
+    #[test]
+    fn downsample_odd_sizes() {
+        //The last column of an odd width is only averaged with itself
+        let rgba = vec![0, 0, 0, 0, 100, 100, 100, 100, 200, 200, 200, 200];
+        let (out, w, h) = downsample(&rgba, 3, 1);
+        assert_eq!((w, h), (1, 1));
+        assert_eq!(out, vec![50, 50, 50, 50]);
+
+        let settings = TextureSettings::default();
+        assert_eq!(sizes(&build_mip_chain(&image(5, 3), 5, 3, &settings)), vec![(5, 3), (2, 1), (1, 1)]);
+        assert_eq!(sizes(&build_mip_chain(&image(1, 7), 1, 7, &settings)), vec![(1, 7), (1, 3), (1, 1)]);
+        assert_eq!(sizes(&build_mip_chain(&image(1, 1), 1, 1, &settings)), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn picmip_drops_levels() {
+        let rgba = image(8, 4);
+        let settings = TextureSettings { picmip: 1, ..TextureSettings::default() };
+        let levels = build_mip_chain(&rgba, 8, 4, &settings);
+        assert_eq!(sizes(&levels), vec![(4, 2), (2, 1), (1, 1)]);
+        assert_eq!(levels[0].0, downsample(&rgba, 8, 4).0);
+
+        //Never below a single pixel
+        let settings = TextureSettings { picmip: 10, ..TextureSettings::default() };
+        assert_eq!(sizes(&build_mip_chain(&rgba, 8, 4, &settings)), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn max_size_drops_levels() {
+        let settings = TextureSettings { max_size: 2, ..TextureSettings::default() };
+        assert_eq!(sizes(&build_mip_chain(&image(8, 4), 8, 4, &settings)), vec![(2, 1), (1, 1)]);
+        assert_eq!(sizes(&build_mip_chain(&image(2, 16), 2, 16, &settings)), vec![(1, 2), (1, 1)]);
+    }
+
+    #[test]
+    fn no_mipmaps() {
+        let settings = TextureSettings { mipmaps: false, ..TextureSettings::default() };
+        assert_eq!(sizes(&build_mip_chain(&image(8, 4), 8, 4, &settings)), vec![(8, 4)]);
+        let settings = TextureSettings { mipmaps: false, picmip: 1, max_size: 2, ..TextureSettings::default() };
+        assert_eq!(sizes(&build_mip_chain(&image(8, 4), 8, 4, &settings)), vec![(2, 1)]);
+    }
 }
\ No newline at end of file
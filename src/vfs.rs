@@ -0,0 +1,136 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+//Game data search path, directories and pk3 files looked through in priority order like Quake 3.
+//Inside a game directory later pk3s override earlier ones and loose files override every pk3
+
+enum Source {
+    Directory(PathBuf),
+    Pak {
+        path: PathBuf,
+        archive: RefCell<zip::ZipArchive<std::io::BufReader<std::fs::File>>>,
+        //Lower case name to the name stored in the pak
+        names: HashMap<String, String>,
+    },
+}
+
+pub struct FileSystem {
+    //Highest priority first
+    sources: Vec<Source>,
+}
+
+impl FileSystem {
+
+    pub fn new() -> Self {
+        FileSystem { sources: Vec::new() }
+    }
+
+    //Loose files in dir, searched before everything mounted so far
+    pub fn mount_dir(&mut self, dir: &Path) {
+
+        if !dir.is_dir() {
+            println!("Can not mount {}, it is not a directory", dir.display());
+            return;
+        }
+        self.sources.insert(0, Source::Directory(dir.to_path_buf()));
+    }
+
+    //The pk3 files of a game directory such as baseq3 and then the directory itself
+    pub fn mount_game_dir(&mut self, dir: &Path) {
+
+        let mut paks = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path())
+                .filter(|p| p.extension().map(|e| e.to_ascii_lowercase() == "pk3").unwrap_or(false))
+                .collect::<Vec<PathBuf>>(),
+            Err(e) => {
+                println!("Can not mount game directory {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        paks.sort();
+
+        for pak in paks {
+            match FileSystem::open_pak(&pak) {
+                Ok(source) => self.sources.insert(0, source),
+                Err(e) => println!("Can not open {}: {}", pak.display(), e),
+            }
+        }
+        self.mount_dir(dir);
+    }
+
+    //A loose map in an editor output directory, <dir>/maps/name.bsp, brings its textures and scripts from <dir>
+    pub fn mount_map_dir(&mut self, map: &Path) {
+
+        if let Some(maps) = map.parent() {
+            if maps.file_name().map(|n| n.to_ascii_lowercase() == "maps").unwrap_or(false) {
+                if let Some(dir) = maps.parent() {
+                    self.mount_dir(dir);
+                }
+            }
+        }
+    }
+
+    fn open_pak(path: &Path) -> zip::result::ZipResult<Source> {
+
+        let file = std::fs::File::open(path)?;
+        let archive = zip::ZipArchive::new(std::io::BufReader::new(file))?;
+        let names = archive.file_names().map(|n| (n.to_lowercase(), n.to_string())).collect::<HashMap<String, String>>();
+        Ok(Source::Pak { path: path.to_path_buf(), archive: RefCell::new(archive), names })
+    }
+
+    //Paths use forward slashes and are not case sensitive inside pk3s
+    pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+
+        let path = path.trim_start_matches('/');
+        for source in self.sources.iter() {
+            match source {
+                Source::Directory(dir) => {
+                    if let Ok(bytes) = std::fs::read(dir.join(path)) {
+                        return Some(bytes);
+                    }
+                }
+                Source::Pak { path: pak, archive, names } => {
+                    if let Some(name) = names.get(&path.to_lowercase()) {
+                        let mut archive = archive.borrow_mut();
+                        let result = archive.by_name(name).and_then(|mut file| {
+                            let mut bytes = Vec::new();
+                            file.read_to_end(&mut bytes)?;
+                            Ok(bytes)
+                        });
+                        match result {
+                            Ok(bytes) => return Some(bytes),
+                            Err(e) => println!("Can not read {} from {}: {}", name, pak.display(), e),
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    //Files in a directory with an extension, each name once and in the order of the source that has it first
+    pub fn list(&self, dir: &str, extension: &str) -> Vec<String> {
+
+        let dir = format!("{}/", dir.trim_end_matches('/').to_lowercase());
+        let extension = extension.to_lowercase();
+        let mut files: Vec<String> = Vec::new();
+        for source in self.sources.iter() {
+            let mut found: Vec<String> = match source {
+                Source::Directory(root) => match std::fs::read_dir(root.join(&dir)) {
+                    Ok(entries) => entries.filter_map(|e| e.ok()).filter_map(|e| e.file_name().to_str().map(|n| format!("{}{}", dir, n))).collect(),
+                    Err(_) => Vec::new(),
+                },
+                Source::Pak { names, .. } => names.keys().filter(|n| n.starts_with(&dir) && !n[dir.len()..].contains('/')).cloned().collect(),
+            };
+            found.sort();
+            for name in found {
+                if name.to_lowercase().ends_with(&extension) && !files.iter().any(|f| f.eq_ignore_ascii_case(&name)) {
+                    files.push(name);
+                }
+            }
+        }
+        files
+    }
+}
\ No newline at end of file